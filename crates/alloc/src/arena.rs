@@ -1,3 +1,4 @@
+use alloc::{boxed::Box, vec, vec::Vec};
 use core::{
     alloc::Layout,
     cell::{Cell, UnsafeCell},
@@ -5,11 +6,14 @@ use core::{
 };
 
 use bitvec::{bitarr, order::Lsb0, BitArr};
-use bytesize::{ByteSize, KIB};
+#[cfg(feature = "log")]
+use bytesize::ByteSize;
+#[cfg(feature = "log")]
 use log::error;
 
-use super::ptr::*;
+use super::{ptr::*, traits::Allocator};
 
+const KIB: u64 = 1 << 10;
 const OS_PAGE_SIZE: usize = 4 * KIB as usize;
 const OS_PAGE_SHIFT: usize = 12; // 4 KiB == 4096 B == 1 << 12
 const OS_PAGE_MASK: usize = !(OS_PAGE_SIZE - 1); // masks off the lower bits
@@ -76,6 +80,8 @@ enum AllocInit {
 pub enum AllocError {
     OutOfMemory,
     RequestTooLarge,
+    /// The alignment requested is larger than the arena can guarantee (an OS page).
+    AlignmentTooLarge,
     PointerOutsideRange,
     PointerNotAligned,
     BlockAlreadyFree,
@@ -118,16 +124,56 @@ struct Bin {
     free_page: Option<usize>,
 }
 
+/// Usage of the blocks of one size, see [`ArenaStats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BinStats {
+    /// The size of each block (in bytes).
+    pub block_size: usize,
+    /// The number of pages divided into blocks of this size.
+    pub pages: usize,
+    /// The number of blocks that are allocated.
+    pub used_blocks: usize,
+    /// The number of blocks that are free in pages divided into blocks of this size.
+    pub free_blocks: usize,
+}
+
+/// A snapshot of how much of an [`Arena`] is in use and how fragmented it is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArenaStats {
+    /// The number of pages that are not divided into blocks yet.
+    pub free_pages: usize,
+    /// The usage of each block size, indexed by bin.
+    pub bins: Vec<BinStats>,
+    /// The number of bytes in allocated blocks.
+    pub bytes_in_use: usize,
+}
+
+impl ArenaStats {
+    /// Returns the number of bytes in free blocks of pages that are already divided.
+    ///
+    /// These bytes can only be reused by allocations of the same block size, so a large value
+    /// (compared to the free pages) means memory is fragmented.
+    pub fn free_block_bytes(&self) -> usize {
+        self.bins
+            .iter()
+            .map(|bin| bin.free_blocks * bin.block_size)
+            .sum()
+    }
+}
+
 /// A non-global allocator that (re)allocates blocks of fixed sizes from a contiguous memory region.
 /// Blocks can be individually freed and reused.
 ///
 /// For portability, this allocator returns pointers relative to its memory region's base address.
 pub struct Arena {
     buf: UnsafeCell<Box<[u8]>>,
+    meta_start: usize,
     heap_start: usize,
+    heap_end: usize,
     page_size: usize,
     page_count: usize,
     bin_count: usize,
+    bytes_in_use: Cell<usize>,
 }
 
 impl Arena {
@@ -148,54 +194,138 @@ impl Arena {
             + (mem::size_of::<Bin>() * bin_count);
         let heap_size = page_size * page_count;
 
-        let mut buf = vec![0u8; meta_size + heap_size];
-        let (meta, heap) = buf.split_at_mut(meta_size);
+        // the buffer is only byte-aligned, so the heap starts at the first OS page boundary
+        // after enough room for the metadata (which is then aligned too, its size being a
+        // multiple of its alignment)
+        let buf = vec![0u8; (OS_PAGE_SIZE - 1) + meta_size + heap_size].into_boxed_slice();
+        let base = buf.as_ptr() as usize;
+        let heap_start = ((base + meta_size + OS_PAGE_SIZE - 1) & OS_PAGE_MASK) - base;
+
+        let arena = Self {
+            buf: UnsafeCell::new(buf),
+            meta_start: heap_start - meta_size,
+            heap_start,
+            heap_end: heap_start + heap_size,
+            page_size,
+            page_count,
+            bin_count,
+            bytes_in_use: Cell::new(0),
+        };
+        arena.write_metadata();
 
-        // SAFETY: allocated enough space
+        // SAFETY: the head of the free page list is the last piece of metadata
+        unsafe {
+            assert_eq!(
+                arena.free_page().add(1) as usize,
+                (*arena.buf.get())[arena.heap_start..].as_ptr() as usize
+            );
+        }
+
+        arena
+    }
+
+    /// Frees every block at once, returning all pages to the free page list.
+    ///
+    /// This restores the arena to the state it was constructed in, without reallocating its
+    /// memory. Every [`RelPtr`] into the arena is invalid afterward.
+    pub fn reset(&mut self) {
+        self.write_metadata();
+        self.bytes_in_use.set(0);
+    }
+
+    /// Writes the metadata of an arena with no allocations: every bin is empty and every page is
+    /// in the free page list.
+    fn write_metadata(&self) {
+        // SAFETY: the metadata region was sized for this
         unsafe {
-            let ptr = meta.as_mut_ptr();
             // write bin metadata
-            let mut ptr = ptr.cast::<Bin>();
-            for i in 0..bin_count {
+            for i in 0..self.bin_count {
                 let block_size = bin_to_size(i);
-                ptr.write(Bin {
+                self.get_bin_unchecked(i).write(Bin {
                     index: i,
                     block_size,
                     block_capacity: if block_size == 0 {
                         usize::MAX
                     } else {
-                        page_size / block_size
+                        self.page_size / block_size
                     },
                     free_page: None,
                 });
-                ptr = ptr.add(1);
             }
             // write page metadata
-            let mut ptr = ptr.cast::<Page>();
-            for i in 0..page_count {
-                ptr.write(Page {
+            for i in 0..self.page_count {
+                self.get_page_unchecked(i).write(Page {
                     index: i,
                     free: None,
-                    next: Some(i + 1),
+                    next: if i == (self.page_count - 1) { None } else { Some(i + 1) },
                     prev: if i == 0 { None } else { Some(i - 1) },
                     bin: None,
                     used: 0,
                     bitset: bitarr![Cell<usize>, Lsb0; 0; 2048],
                 });
-                ptr = ptr.add(1);
             }
             // write head of free page list
-            let ptr = ptr.cast::<Option<usize>>();
-            ptr.write(Some(0));
-            assert_eq!(ptr.add(1) as usize, heap.as_ptr() as usize);
+            self.free_page().write(Some(0));
         }
+    }
 
-        Self {
-            buf: UnsafeCell::new(buf.into_boxed_slice()),
-            heap_start: meta_size,
-            page_size,
-            page_count,
-            bin_count,
+    /// Returns the number of bytes that are not in allocated blocks.
+    ///
+    /// Free blocks in partially used pages are counted, even though they can only be reused by
+    /// allocations of the same block size. Use [`stats`](Self::stats) for the details.
+    #[inline]
+    pub fn bytes_remaining(&self) -> usize {
+        (self.page_size * self.page_count) - self.bytes_in_use.get()
+    }
+
+    /// Returns the current usage of the arena.
+    ///
+    /// This walks the page lists, so it takes time proportional to the number of pages.
+    pub fn stats(&self) -> ArenaStats {
+        let mut bins = Vec::with_capacity(self.bin_count);
+        let mut bytes_in_use = 0;
+
+        // SAFETY: indexes come from the arena's own metadata
+        unsafe {
+            for index in 0..self.bin_count {
+                let bin = self.get_bin(index);
+                let mut stats = BinStats {
+                    block_size: (*bin).block_size,
+                    ..Default::default()
+                };
+
+                // pages with free blocks
+                let mut next = (*bin).free_page;
+                while let Some(index) = next {
+                    let page = self.get_page(index);
+                    stats.free_blocks += (*bin).block_capacity - (*page).used;
+                    next = (*page).next;
+                }
+
+                bins.push(stats);
+            }
+
+            for index in 0..self.page_count {
+                let page = self.get_page(index);
+                if let Some(bin) = (*page).bin {
+                    bins[bin].pages += 1;
+                    bins[bin].used_blocks += (*page).used;
+                    bytes_in_use += (*page).used * bins[bin].block_size;
+                }
+            }
+
+            let mut free_pages = 0;
+            let mut next = *self.free_page();
+            while let Some(index) = next {
+                free_pages += 1;
+                next = (*self.get_page(index)).next;
+            }
+
+            ArenaStats {
+                free_pages,
+                bins,
+                bytes_in_use,
+            }
         }
     }
 
@@ -207,13 +337,21 @@ impl Arena {
         self.get_ptr::<T>(rel_ptr.addr())
     }
 
+    /// Returns a pointer from a [`RelPtr`], without checking that its pointee is in use.
+    ///
+    /// ## Safety
+    /// - `rel_ptr` must point inside the heap.
+    pub unsafe fn get_unchecked<T>(&self, rel_ptr: RelPtr<T, usize>) -> *mut T {
+        self.get_ptr_unchecked::<T>(rel_ptr.addr())
+    }
+
     /// Returns a pointer to the data at `addr`.
     unsafe fn get_ptr<T>(&self, addr: usize) -> Option<*mut T> {
         assert!(
             mem::size_of::<T>() != 0,
             "we aren't ready to handle zero-sized types"
         );
-        if addr >= (*self.buf.get())[self.heap_start..].len() {
+        if addr >= (*self.buf.get())[self.heap_start..self.heap_end].len() {
             // outside heap
             return None;
         }
@@ -253,7 +391,7 @@ impl Arena {
     /// This does not check if `addr` points to an actual block, if that block is in use,
     /// or if the block is large enough to hold a `T`.
     unsafe fn get_ptr_unchecked<T>(&self, addr: usize) -> *mut T {
-        (*self.buf.get())[self.heap_start..]
+        (*self.buf.get())[self.heap_start..self.heap_end]
             .as_mut_ptr()
             .add(addr)
             .cast()
@@ -268,17 +406,7 @@ impl Arena {
     ///
     /// Returns `Err` if there is no memory available that meets the requirements.
     pub fn allocate(&self, layout: Layout) -> Result<RelPtr<[u8], usize>, AllocError> {
-        let size = layout.size();
-        assert!(size != 0, "we aren't ready to handle zero-sized types");
-
-        if size > self.page_size {
-            error!(
-                "size requested is larger than the maximum block size: {} > {}",
-                ByteSize::b(size as u64).to_string_as(true),
-                ByteSize::b(self.page_size as u64).to_string_as(true)
-            );
-            return Err(AllocError::RequestTooLarge);
-        }
+        let size = self.block_size_for(layout)?;
 
         unsafe {
             let bin = self.get_bin_unchecked(size_to_bin(size));
@@ -306,6 +434,7 @@ impl Arena {
                                 };
                                 block.write(Block { next });
                             }
+                            (*page).free = Some(RelPtr::with_addr((*page).index * self.page_size));
                             self.push_page(ptr::addr_of_mut!((*bin).free_page), page);
                             page
                         }
@@ -330,6 +459,7 @@ impl Arena {
             (*block).next = None;
             (*page).bitset.set_aliased_unchecked(block_index, true);
             (*page).used += 1;
+            self.bytes_in_use.set(self.bytes_in_use.get() + (*bin).block_size);
 
             if (*page).used == (*bin).block_capacity {
                 self.remove_page(ptr::addr_of_mut!((*bin).free_page), page);
@@ -349,13 +479,16 @@ impl Arena {
     pub fn deallocate(&self, rel_ptr: RelPtr<u8, usize>) -> Result<(), AllocError> {
         unsafe {
             let addr = rel_ptr.addr();
-            if addr >= (*self.buf.get())[self.heap_start..].len() {
+            if addr >= (*self.buf.get())[self.heap_start..self.heap_end].len() {
                 return Err(AllocError::PointerOutsideRange);
             }
 
             let page = self.get_page_unchecked(self.get_page_index(addr));
-            assert!((*page).bin.is_some());
-            let bin = self.get_bin((*page).bin.unwrap());
+            let Some(bin) = (*page).bin else {
+                // every block in the page is free
+                return Err(AllocError::BlockAlreadyFree);
+            };
+            let bin = self.get_bin(bin);
 
             let addr_in_page = self.get_addr_in_page(addr);
             if (addr_in_page % (*bin).block_size) != 0 {
@@ -376,6 +509,7 @@ impl Arena {
             (*page).free = Some(rel_ptr.cast());
             (*page).bitset.set_aliased_unchecked(block_index, false);
             (*page).used -= 1;
+            self.bytes_in_use.set(self.bytes_in_use.get() - (*bin).block_size);
 
             if (*page).used == 0 {
                 self.remove_page(ptr::addr_of_mut!((*bin).free_page), page);
@@ -405,13 +539,16 @@ impl Arena {
         new_layout: Layout,
     ) -> Result<RelPtr<[u8], usize>, AllocError> {
         unsafe {
-            if rel_ptr.addr() >= (*self.buf.get())[self.heap_start..].len() {
+            if rel_ptr.addr() >= (*self.buf.get())[self.heap_start..self.heap_end].len() {
                 return Err(AllocError::PointerOutsideRange);
             }
 
             let old_page = self.get_page_unchecked(self.get_page_index(rel_ptr.addr()));
-            assert!((*old_page).bin.is_some());
-            let old_bin = self.get_bin((*old_page).bin.unwrap());
+            let Some(old_bin) = (*old_page).bin else {
+                // every block in the page is free
+                return Err(AllocError::BlockAlreadyFree);
+            };
+            let old_bin = self.get_bin(old_bin);
             let old_size = (*old_bin).block_size;
 
             let addr_in_page = self.get_addr_in_page(rel_ptr.addr());
@@ -424,7 +561,7 @@ impl Arena {
                 return Err(AllocError::BlockAlreadyFree);
             }
 
-            let new_size = new_layout.size();
+            let new_size = self.block_size_for(new_layout)?;
             let new_bin = self.get_bin(size_to_bin(new_size));
 
             if (*new_bin).index != (*old_bin).index {
@@ -446,6 +583,7 @@ impl Arena {
                     (*old_page).free = Some(rel_ptr.cast());
                     (*old_page).bitset.set_aliased_unchecked(block_index, false);
                     (*old_page).used -= 1;
+                    self.bytes_in_use.set(self.bytes_in_use.get() - old_size);
 
                     if (*old_page).used == 0 {
                         self.remove_page(ptr::addr_of_mut!((*old_bin).free_page), old_page);
@@ -460,11 +598,48 @@ impl Arena {
         }
     }
 
+    /// Returns the size to look up the bin of a block for `layout` with.
+    ///
+    /// Pages are aligned to (at least) an OS page and their blocks are placed at multiples of
+    /// the block size, so the size is rounded up to a multiple of the alignment. Every block
+    /// size of the bin it maps to is then a multiple of the alignment too.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the block would be larger than a page, or the alignment larger than an
+    /// OS page.
+    fn block_size_for(&self, layout: Layout) -> Result<usize, AllocError> {
+        assert!(layout.size() != 0, "we aren't ready to handle zero-sized types");
+
+        if layout.align() > OS_PAGE_SIZE {
+            #[cfg(feature = "log")]
+            error!(
+                "alignment requested is larger than the maximum alignment: {} > {}",
+                ByteSize::b(layout.align() as u64).to_string_as(true),
+                ByteSize::b(OS_PAGE_SIZE as u64).to_string_as(true)
+            );
+            return Err(AllocError::AlignmentTooLarge);
+        }
+
+        let size = layout.pad_to_align().size();
+        if size > self.page_size {
+            #[cfg(feature = "log")]
+            error!(
+                "size requested is larger than the maximum block size: {} > {}",
+                ByteSize::b(size as u64).to_string_as(true),
+                ByteSize::b(self.page_size as u64).to_string_as(true)
+            );
+            return Err(AllocError::RequestTooLarge);
+        }
+
+        Ok(size)
+    }
+
     /// Returns `true` if the allocator contains the pointer address.
     #[inline]
     pub fn contains(&self, ptr: *const u8) -> bool {
         unsafe {
-            (*self.buf.get())[self.heap_start..]
+            (*self.buf.get())[self.heap_start..self.heap_end]
                 .as_ptr_range()
                 .contains(&ptr)
         }
@@ -485,7 +660,7 @@ impl Arena {
     /// Returns a pointer to the metadata for the specified page bin.
     #[inline]
     unsafe fn get_bin_unchecked(&self, index: usize) -> *mut Bin {
-        (*self.buf.get())[..self.heap_start]
+        (*self.buf.get())[self.meta_start..self.heap_start]
             .as_mut_ptr()
             .cast::<Bin>()
             .add(index)
@@ -494,7 +669,7 @@ impl Arena {
     /// Returns a pointer to the metadata for the specified page.
     #[inline]
     unsafe fn get_page_unchecked(&self, index: usize) -> *mut Page {
-        (*self.buf.get())[..self.heap_start]
+        (*self.buf.get())[self.meta_start..self.heap_start]
             .as_mut_ptr()
             .cast::<Bin>()
             .add(self.bin_count)
@@ -529,7 +704,7 @@ impl Arena {
     fn free_page(&self) -> *mut Option<usize> {
         // SAFETY: fixed address
         unsafe {
-            (*self.buf.get())[..self.heap_start]
+            (*self.buf.get())[self.meta_start..self.heap_start]
                 .as_mut_ptr()
                 .cast::<Bin>()
                 .add(self.bin_count)
@@ -541,22 +716,10 @@ impl Arena {
 
     // TODO: Replace these with linked list struct.
     unsafe fn pop_page(&self, list: *mut Option<usize>) -> Option<*mut Page> {
-        (*list).and_then(|index| {
+        (*list).map(|index| {
             let page = self.get_page(index);
-
-            if let Some(prev_index) = (*page).prev {
-                let prev_page = self.get_page(prev_index);
-                (*prev_page).next = (*page).next;
-                (*page).next = None;
-            }
-
-            if let Some(next_index) = (*page).next {
-                let next_page = self.get_page(next_index);
-                (*next_page).prev = (*page).prev;
-                (*page).prev = None;
-            }
-
-            Some(page)
+            self.remove_page(list, page);
+            page
         })
     }
 
@@ -573,27 +736,227 @@ impl Arena {
     }
 
     unsafe fn remove_page(&self, list: *mut Option<usize>, page: *mut Page) {
+        let prev = (*page).prev.take();
+        let next = (*page).next.take();
+
         if *list == Some((*page).index) {
-            *list = (*page).next;
+            *list = next;
         }
 
-        if let Some(prev_index) = (*page).prev {
-            let prev_page = self.get_page(prev_index);
-            (*prev_page).next = (*page).next;
-            (*page).next = None;
+        if let Some(prev_index) = prev {
+            (*self.get_page(prev_index)).next = next;
         }
 
-        if let Some(next_index) = (*page).next {
-            let next_page = self.get_page(next_index);
-            (*next_page).prev = (*page).prev;
-            (*page).prev = None;
+        if let Some(next_index) = next {
+            (*self.get_page(next_index)).prev = prev;
         }
     }
 }
 
+impl Allocator for Arena {
+    #[inline]
+    fn allocate(&self, layout: Layout) -> Result<RelPtr<[u8], usize>, AllocError> {
+        Arena::allocate(self, layout)
+    }
+
+    #[inline]
+    fn deallocate(&self, ptr: RelPtr<u8, usize>) -> Result<(), AllocError> {
+        Arena::deallocate(self, ptr)
+    }
+
+    #[inline]
+    fn reallocate(
+        &self,
+        ptr: RelPtr<[u8], usize>,
+        new_layout: Layout,
+    ) -> Result<RelPtr<[u8], usize>, AllocError> {
+        Arena::reallocate(self, ptr, new_layout)
+    }
+
+    #[inline]
+    unsafe fn get(&self, ptr: RelPtr<u8, usize>) -> Option<*mut u8> {
+        Arena::get(self, ptr)
+    }
+
+    #[inline]
+    unsafe fn get_unchecked<T>(&self, ptr: RelPtr<T, usize>) -> *mut T {
+        Arena::get_unchecked(self, ptr)
+    }
+
+    #[inline]
+    fn contains(&self, ptr: *const u8) -> bool {
+        Arena::contains(self, ptr)
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 8).unwrap()
+    }
 
     #[test]
     fn test() {}
+
+    #[test]
+    fn stats() {
+        let page_size = 4 * KIB as usize;
+        let arena = Arena::new(page_size, 4);
+        let stats = arena.stats();
+        assert_eq!(stats.free_pages, 4);
+        assert_eq!(stats.bytes_in_use, 0);
+        assert_eq!(arena.bytes_remaining(), 4 * page_size);
+
+        let small = (0..3)
+            .map(|_| arena.allocate(layout(64)).unwrap())
+            .collect::<Vec<_>>();
+        // rounded up to a 1 KiB block
+        arena.allocate(layout(1000)).unwrap();
+
+        let stats = arena.stats();
+        assert_eq!(stats.free_pages, 2);
+        assert_eq!(
+            stats.bins[size_to_bin(64)],
+            BinStats {
+                block_size: 64,
+                pages: 1,
+                used_blocks: 3,
+                free_blocks: (page_size / 64) - 3,
+            }
+        );
+        assert_eq!(
+            stats.bins[size_to_bin(1000)],
+            BinStats {
+                block_size: 1024,
+                pages: 1,
+                used_blocks: 1,
+                free_blocks: 3,
+            }
+        );
+        assert_eq!(stats.bytes_in_use, (3 * 64) + 1024);
+        assert_eq!(arena.bytes_remaining(), (4 * page_size) - stats.bytes_in_use);
+        assert_eq!(stats.free_block_bytes(), (page_size - (3 * 64)) + (3 * 1024));
+
+        // freeing every block in a page returns it
+        for ptr in small {
+            arena.deallocate(ptr.cast()).unwrap();
+        }
+
+        let stats = arena.stats();
+        assert_eq!(stats.free_pages, 3);
+        assert_eq!(stats.bins[size_to_bin(64)], BinStats { block_size: 64, ..Default::default() });
+        assert_eq!(stats.bytes_in_use, 1024);
+        assert_eq!(arena.bytes_remaining(), (4 * page_size) - 1024);
+    }
+
+    #[test]
+    fn stats_full_pages() {
+        let page_size = 4 * KIB as usize;
+        let arena = Arena::new(page_size, 2);
+        for _ in 0..4 {
+            arena.allocate(layout(1024)).unwrap();
+        }
+
+        // a full page isn't in its bin's free list
+        let stats = arena.stats();
+        assert_eq!(stats.free_pages, 1);
+        assert_eq!(stats.bins[size_to_bin(1024)].used_blocks, 4);
+        assert_eq!(stats.bins[size_to_bin(1024)].free_blocks, 0);
+        assert_eq!(arena.bytes_remaining(), page_size);
+
+        arena.allocate(layout(1024)).unwrap();
+        assert_eq!(arena.stats().free_pages, 0);
+        assert_eq!(arena.allocate(layout(4096)).err(), Some(AllocError::OutOfMemory));
+    }
+
+    #[test]
+    fn reset() {
+        let page_size = 4 * KIB as usize;
+        let mut arena = Arena::new(page_size, 4);
+        let ptrs = [64, 64, 1000, 4096, 4096]
+            .map(|size| arena.allocate(layout(size)).unwrap());
+        assert_eq!(arena.allocate(layout(4096)).err(), Some(AllocError::OutOfMemory));
+
+        arena.reset();
+        assert_eq!(arena.bytes_remaining(), 4 * page_size);
+        let stats = arena.stats();
+        assert_eq!(stats.free_pages, 4);
+        assert_eq!(stats.bytes_in_use, 0);
+        assert!(stats.bins.iter().all(|bin| bin.pages == 0 && bin.free_blocks == 0));
+
+        // pointers from before are dangling
+        for ptr in ptrs {
+            assert!(unsafe { arena.get(ptr.cast::<u8>()) }.is_none());
+        }
+
+        // every page can be used again, for any block size
+        for _ in 0..4 {
+            let ptr = arena.allocate(layout(4096)).unwrap();
+            fill(&arena, ptr, 4096);
+        }
+        assert_eq!(arena.bytes_remaining(), 0);
+
+        arena.reset();
+        let ptr = arena.allocate(layout(64)).unwrap();
+        fill(&arena, ptr, 64);
+        assert_eq!(arena.bytes_remaining(), (4 * page_size) - 64);
+    }
+
+    #[test]
+    fn blocks_meet_alignment() {
+        let arena = Arena::new(16 * KIB as usize, 8);
+        for align in (0..=OS_PAGE_SHIFT).map(|shift| 1 << shift) {
+            for size in [1, 8, 24, 100, 1000, 3000] {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = arena.allocate(layout).unwrap();
+                let raw = unsafe { arena.get(ptr.cast::<u8>()) }.unwrap();
+                assert_eq!(raw as usize % align, 0, "size {size}, align {align}");
+
+                // moving to another block keeps it aligned
+                let layout = Layout::from_size_align(size + 40, align).unwrap();
+                let ptr = arena.reallocate(ptr, layout).unwrap();
+                let raw = unsafe { arena.get(ptr.cast::<u8>()) }.unwrap();
+                assert_eq!(raw as usize % align, 0, "size {size}, align {align}");
+                arena.deallocate(ptr.cast()).unwrap();
+            }
+        }
+        assert_eq!(arena.stats().bytes_in_use, 0);
+
+        let layout = Layout::from_size_align(64, 2 * OS_PAGE_SIZE).unwrap();
+        assert_eq!(arena.allocate(layout).err(), Some(AllocError::AlignmentTooLarge));
+    }
+
+    fn fill(alloc: &impl Allocator, ptr: RelPtr<[u8], usize>, len: usize) {
+        unsafe {
+            let raw = alloc.get(ptr.cast()).unwrap();
+            for i in 0..len {
+                raw.add(i).write(i as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn allocator_trait_object() {
+        let arena = Arena::new(4 * KIB as usize, 2);
+        let alloc: &dyn Allocator = &arena;
+
+        let ptr = alloc.allocate(layout(64)).unwrap();
+        fill(&arena, ptr, 64);
+        let raw = unsafe { alloc.get(ptr.cast()) }.unwrap();
+        assert!(alloc.contains(raw));
+        assert!(!alloc.contains(&0u8));
+
+        // moving to a larger block keeps the contents
+        let ptr = alloc.reallocate(ptr, layout(256)).unwrap();
+        let raw = unsafe { alloc.get(ptr.cast()) }.unwrap();
+        let bytes = unsafe { core::slice::from_raw_parts(raw, 64) };
+        assert!(bytes.iter().enumerate().all(|(i, byte)| *byte == i as u8));
+
+        alloc.deallocate(ptr.cast()).unwrap();
+        assert!(unsafe { alloc.get(ptr.cast()) }.is_none());
+        assert_eq!(alloc.deallocate(ptr.cast()), Err(AllocError::BlockAlreadyFree));
+        assert_eq!(arena.stats().bytes_in_use, 0);
+    }
 }
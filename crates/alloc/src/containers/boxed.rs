@@ -1,88 +1,338 @@
-use std::mem::MaybeUninit;
+use core::{
+    alloc::Layout,
+    mem::{self, MaybeUninit},
+    ops::{Deref, DerefMut},
+    ptr, slice,
+};
 
-use crate::{ptr::RelPtr, traits::Allocator};
+use super::Owned;
+use crate::{
+    arena::AllocError,
+    ptr::RelPtr,
+    traits::{AllocDrop, Allocator},
+};
 
 /// A pointer type for heap allocation.
-pub struct Box<T: ?Sized>(RelPtr<T, usize>);
+pub struct Box<T: ?Sized> {
+    ptr: RelPtr<T, usize>,
+    // number of elements (if `T` is a slice)
+    len: usize,
+}
 
-pub struct Owned<'alloc, T, A: Allocator> {
-    pub(crate) alloc: &'alloc A,
-    pub(crate) inner: T,
+/// Allocates a block for `len` values of `T`.
+fn allocate_array<T, A: Allocator>(len: usize, alloc: &A) -> Result<RelPtr<T, usize>, AllocError> {
+    assert!(
+        mem::size_of::<T>() != 0 && len != 0,
+        "we aren't ready to handle zero-sized types"
+    );
+    let layout = Layout::array::<T>(len).map_err(|_| AllocError::RequestTooLarge)?;
+    alloc.allocate(layout).map(|ptr| ptr.cast())
 }
 
-impl<T> Box<T> {   
-    pub fn new_in<A: Allocator>(value: T, alloc: &A) -> Owned<'_, Box<T>, A> {
-        Owned { 
+impl<T> Box<T> {
+    /// Allocates memory in `alloc` and then places `value` into it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the allocator has no block large enough.
+    pub fn new_in<A: Allocator>(value: T, alloc: &A) -> Result<Owned<'_, Box<T>, A>, AllocError> {
+        Box::new_uninit_in(alloc).map(|boxed| boxed.write(value))
+    }
+
+    /// Constructs a new box with uninitialized contents in `alloc`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the allocator has no block large enough.
+    pub fn new_uninit_in<A: Allocator>(
+        alloc: &A,
+    ) -> Result<Owned<'_, Box<MaybeUninit<T>>, A>, AllocError> {
+        Ok(Owned {
             alloc,
-            inner: Box(RelPtr::with_addr(0)),
-        }
+            inner: Box {
+                ptr: allocate_array::<MaybeUninit<T>, A>(1, alloc)?,
+                len: 1,
+            },
+        })
     }
 
-    pub fn new_uninit_in<A: Allocator>(alloc: &A) -> Owned<'_, Box<MaybeUninit<T>>, A> {
-        Owned { 
+    /// Constructs a new box with its contents filled with `0` bytes in `alloc`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the allocator has no block large enough.
+    pub fn new_zeroed_in<A: Allocator>(
+        alloc: &A,
+    ) -> Result<Owned<'_, Box<MaybeUninit<T>>, A>, AllocError> {
+        let boxed = Box::new_uninit_in(alloc)?;
+        // SAFETY: the block holds one `T`
+        unsafe { boxed.inner.ptr.write_bytes(0, 1, alloc) };
+        Ok(boxed)
+    }
+
+    /// Constructs a new boxed slice of `len` uninitialized values in `alloc`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the allocator has no block large enough.
+    pub fn new_uninit_slice_in<A: Allocator>(
+        len: usize,
+        alloc: &A,
+    ) -> Result<Owned<'_, Box<[MaybeUninit<T>]>, A>, AllocError> {
+        let ptr = allocate_array::<MaybeUninit<T>, A>(len, alloc)?;
+        Ok(Owned {
             alloc,
-            inner: Box(RelPtr::with_addr(0)),
+            inner: Box {
+                ptr: RelPtr::with_addr(ptr.addr()),
+                len,
+            },
+        })
+    }
+
+    /// Constructs a new boxed slice of `len` values filled with `0` bytes in `alloc`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the allocator has no block large enough.
+    pub fn new_zeroed_slice_in<A: Allocator>(
+        len: usize,
+        alloc: &A,
+    ) -> Result<Owned<'_, Box<[MaybeUninit<T>]>, A>, AllocError> {
+        let boxed = Box::new_uninit_slice_in(len, alloc)?;
+        // SAFETY: the block holds `len` values
+        unsafe {
+            alloc
+                .get_unchecked(boxed.inner.ptr.cast::<T>())
+                .write_bytes(0, len)
+        };
+        Ok(boxed)
+    }
+}
+
+impl<T> AllocDrop for Box<T> {
+    unsafe fn drop_in<A: Allocator>(&mut self, alloc: &A) {
+        self.ptr.drop_in_place(alloc);
+        // the block came from `alloc`, so this can't fail
+        let _ = alloc.deallocate(self.ptr.cast());
+    }
+}
+
+impl<T> AllocDrop for Box<[T]> {
+    unsafe fn drop_in<A: Allocator>(&mut self, alloc: &A) {
+        let data = alloc.get_unchecked(self.ptr.cast::<T>());
+        ptr::drop_in_place(ptr::slice_from_raw_parts_mut(data, self.len));
+        // the block came from `alloc`, so this can't fail
+        let _ = alloc.deallocate(self.ptr.cast());
+    }
+}
+
+impl<T, A: Allocator> Owned<'_, Box<T>, A> {
+    /// Moves the value out of the box and frees its block.
+    pub fn into_inner(boxed: Self) -> T {
+        let (alloc, inner) = boxed.into_parts();
+        // SAFETY: the value is initialized and its block is freed right after
+        unsafe {
+            let value = inner.ptr.read(alloc);
+            let _ = alloc.deallocate(inner.ptr.cast());
+            value
         }
     }
+}
 
-    pub fn new_zeroed_in<A: Allocator>(alloc: &A) -> Owned<'_, Box<MaybeUninit<T>>, A> {
-        Owned { 
-            alloc,
-            inner: Box(RelPtr::with_addr(0)),
+impl<T, A: Allocator> Deref for Owned<'_, Box<T>, A> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the value is initialized and owned by this box
+        unsafe { &*self.alloc.get_unchecked(self.inner.ptr) }
+    }
+}
+
+impl<T, A: Allocator> DerefMut for Owned<'_, Box<T>, A> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the value is initialized and owned by this box
+        unsafe { &mut *self.alloc.get_unchecked(self.inner.ptr) }
+    }
+}
+
+impl<T, A: Allocator> Deref for Owned<'_, Box<[T]>, A> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // SAFETY: the values are initialized and owned by this box
+        unsafe {
+            slice::from_raw_parts(
+                self.alloc.get_unchecked(self.inner.ptr.cast::<T>()),
+                self.inner.len,
+            )
         }
     }
+}
 
-    pub fn new_uninit_slice_in<A: Allocator>(alloc: &A) -> Owned<'_, Box<[MaybeUninit<T>]>, A> {
-        Owned { 
-            alloc,
-            inner: Box(RelPtr::with_addr(0)),
+impl<T, A: Allocator> DerefMut for Owned<'_, Box<[T]>, A> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        // SAFETY: the values are initialized and owned by this box
+        unsafe {
+            slice::from_raw_parts_mut(
+                self.alloc.get_unchecked(self.inner.ptr.cast::<T>()),
+                self.inner.len,
+            )
         }
     }
+}
 
-    pub fn new_zeroed_slice_in<A: Allocator>(alloc: &A) -> Owned<'_, Box<[MaybeUninit<T>]>, A> {
-        Owned { 
+impl<'alloc, T, A: Allocator> Owned<'alloc, Box<MaybeUninit<T>>, A> {
+    /// Converts to `Box<T>`.
+    ///
+    /// ## Safety
+    /// - The value must have been initialized.
+    pub unsafe fn assume_init(self) -> Owned<'alloc, Box<T>, A> {
+        let (alloc, inner) = self.into_parts();
+        Owned {
             alloc,
-            inner: Box(RelPtr::with_addr(0)),
+            inner: Box {
+                ptr: inner.ptr.cast(),
+                len: inner.len,
+            },
         }
     }
 
-}
-
-impl<T, A: Allocator> Owned<'_, Box<T>, A> {
-    pub fn into_inner(boxed: Self, value: T) -> T {
-        boxed.inner
+    /// Writes `value` into the box and converts it to `Box<T>`.
+    pub fn write(self, value: T) -> Owned<'alloc, Box<T>, A> {
+        // SAFETY: the block holds one `T`, and it's initialized right before the conversion
+        unsafe {
+            self.alloc.get_unchecked(self.inner.ptr).write(MaybeUninit::new(value));
+            self.assume_init()
+        }
     }
-    
 }
 
-impl<T, A: Allocator> Owned<'_, Box<MaybeUninit<T>>, A> {
-    pub fn assume_init(self) -> Owned<'_, Box<T>, A> {
-        Owned { 
+impl<'alloc, T, A: Allocator> Owned<'alloc, Box<[MaybeUninit<T>]>, A> {
+    /// Converts to `Box<[T]>`.
+    ///
+    /// ## Safety
+    /// - Every value must have been initialized.
+    pub unsafe fn assume_init(self) -> Owned<'alloc, Box<[T]>, A> {
+        let (alloc, inner) = self.into_parts();
+        Owned {
             alloc,
-            inner: Box(RelPtr::with_addr(0)),
+            inner: Box {
+                ptr: RelPtr::with_addr(inner.ptr.addr()),
+                len: inner.len,
+            },
         }
     }
 
-    pub fn write(self, value: T) -> Owned<'_, Box<T>, A> {
-        Owned { 
-            alloc,
-            inner: Box(RelPtr::with_addr(0)),
+    /// Fills the slice with clones of `value` and converts it to `Box<[T]>`.
+    pub fn write(mut boxed: Self, value: T) -> Owned<'alloc, Box<[T]>, A>
+    where
+        T: Clone,
+    {
+        for slot in boxed.iter_mut() {
+            slot.write(value.clone());
         }
+
+        // SAFETY: every value was initialized
+        unsafe { boxed.assume_init() }
     }
 }
 
-impl<T, A: Allocator> Owned<'_, Box<[MaybeUninit<T>]>, A> {
-    pub fn assume_init(self) -> Owned<'_, Box<[T]>, A> {
-        Owned { 
-            alloc,
-            inner: Box(RelPtr::with_addr(0)),
-        }     
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::arena::Arena;
+
+    #[derive(Debug, PartialEq)]
+    struct Position {
+        x: f32,
+        y: f32,
+        z: f32,
     }
 
-    pub fn write(boxed: Self, value: T) -> Owned<'_, Box<[T]>, A> {
-        Owned { 
-            alloc,
-            inner: Box(RelPtr::with_addr(0)),
+    /// Counts how many times it has been dropped.
+    #[derive(Clone)]
+    struct Tracked<'a>(&'a Cell<usize>);
+
+    impl Drop for Tracked<'_> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn new_in() {
+        let arena = Arena::new(4096, 2);
+        let mut boxed = Box::new_in(Position { x: 1.0, y: 2.0, z: 3.0 }, &arena).unwrap();
+        assert_eq!(*boxed, Position { x: 1.0, y: 2.0, z: 3.0 });
+        assert!(arena.stats().bytes_in_use >= mem::size_of::<Position>());
+
+        boxed.y = 5.0;
+        let value = Owned::into_inner(boxed);
+        assert_eq!(value, Position { x: 1.0, y: 5.0, z: 3.0 });
+        assert_eq!(arena.stats().bytes_in_use, 0);
+    }
+
+    #[test]
+    fn drop_frees_block() {
+        let arena = Arena::new(4096, 2);
+        let drops = Cell::new(0);
+
+        let boxed = Box::new_in(Tracked(&drops), &arena).unwrap();
+        assert_ne!(arena.stats().bytes_in_use, 0);
+        drop(boxed);
+        assert_eq!(drops.get(), 1);
+        assert_eq!(arena.stats().bytes_in_use, 0);
+
+        // moving the value out doesn't drop it
+        let boxed = Box::new_in(Tracked(&drops), &arena).unwrap();
+        let value = Owned::into_inner(boxed);
+        assert_eq!(drops.get(), 1);
+        drop(value);
+        assert_eq!(drops.get(), 2);
+
+        let slice = Box::<Tracked>::new_uninit_slice_in(3, &arena).unwrap();
+        let slice = Owned::<Box<[MaybeUninit<Tracked>]>, _>::write(slice, Tracked(&drops));
+        // the original value is dropped after being cloned
+        assert_eq!(drops.get(), 3);
+        drop(slice);
+        assert_eq!(drops.get(), 6);
+        assert_eq!(arena.stats().bytes_in_use, 0);
+    }
+
+    #[test]
+    fn new_in_aligns_value() {
+        #[repr(align(64))]
+        #[derive(Debug, PartialEq)]
+        struct CacheLine([u8; 8]);
+
+        let arena = Arena::new(4096, 4);
+        // take a small block first, so that the next ones aren't at the start of a page
+        let _byte = Box::new_in(1u8, &arena).unwrap();
+
+        let wide = Box::new_in(7u128, &arena).unwrap();
+        assert_eq!(&*wide as *const u128 as usize % mem::align_of::<u128>(), 0);
+        assert_eq!(*wide, 7);
+
+        let line = Box::new_in(CacheLine([3; 8]), &arena).unwrap();
+        assert_eq!(&*line as *const CacheLine as usize % 64, 0);
+        assert_eq!(*line, CacheLine([3; 8]));
+
+        let slice = Box::<u128>::new_zeroed_slice_in(3, &arena).unwrap();
+        let slice = unsafe { slice.assume_init() };
+        assert_eq!(slice.as_ptr() as usize % mem::align_of::<u128>(), 0);
+        assert_eq!(&*slice, &[0; 3]);
+    }
+
+    #[test]
+    fn new_zeroed_in() {
+        let arena = Arena::new(4096, 2);
+        let boxed = Box::<u64>::new_zeroed_in(&arena).unwrap();
+        assert_eq!(*unsafe { boxed.assume_init() }, 0);
+
+        let slice = Box::<u32>::new_zeroed_slice_in(16, &arena).unwrap();
+        let slice = unsafe { slice.assume_init() };
+        assert_eq!(&*slice, &[0; 16]);
+    }
+}
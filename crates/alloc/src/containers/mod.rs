@@ -1,2 +1,31 @@
+use core::{mem, ptr};
+
+use crate::traits::{AllocDrop, Allocator};
+
 mod boxed;
 mod vec;
+
+/// A container whose memory is owned by an allocator (e.g. `Owned<'_, Box<T>, A>`).
+///
+/// The container's contents are dropped and its memory is returned to the allocator when this
+/// goes out of scope.
+pub struct Owned<'alloc, T: AllocDrop, A: Allocator> {
+    pub(crate) alloc: &'alloc A,
+    pub(crate) inner: T,
+}
+
+impl<T: AllocDrop, A: Allocator> Drop for Owned<'_, T, A> {
+    fn drop(&mut self) {
+        // SAFETY: `inner` came from `alloc` and is never used again
+        unsafe { self.inner.drop_in(self.alloc) }
+    }
+}
+
+impl<'alloc, T: AllocDrop, A: Allocator> Owned<'alloc, T, A> {
+    /// Splits into the allocator and the value, without dropping the value.
+    fn into_parts(self) -> (&'alloc A, T) {
+        let this = mem::ManuallyDrop::new(self);
+        // SAFETY: `this` is never used (or dropped) again
+        unsafe { (this.alloc, ptr::read(&this.inner)) }
+    }
+}
@@ -1,4 +1,14 @@
-use crate::{ptr::RelPtr, traits::Allocator};
+use core::{alloc::Layout, mem, ptr, slice};
+
+use super::Owned;
+use crate::{
+    arena::AllocError,
+    ptr::RelPtr,
+    traits::{AllocDrop, Allocator},
+};
+
+// capacity of the first allocation
+const MIN_NON_ZERO_CAP: usize = 4;
 
 /// A contiguous growable array type, written as `Vec<T>`, short for 'vector'.
 pub struct Vec<T> {
@@ -8,12 +18,6 @@ pub struct Vec<T> {
 }
 
 
-pub struct Owned<'alloc, T, A: Allocator> {
-    alloc: &'alloc A,
-    inner: T,
-}
-
-
 impl<T> Vec<T> {
     pub fn new_in<A: Allocator>(alloc: &A) -> Owned<'_, Vec<T>, A> {
         Owned { 
@@ -26,30 +30,160 @@ impl<T> Vec<T> {
         }
     }
 
-    pub fn with_capacity_in<A: Allocator>(capacity: usize, alloc: &A) -> Owned<'_, Vec<T>, A> {
-        // alloc.allocate(capacity * mem::size_of::<T>())
-        Owned { 
-            alloc,
-            inner: Vec {
-                ptr: RelPtr::with_addr(0),
-                len: 0,
-                cap: 0,
-            },
+    /// Constructs a new, empty `Vec<T>` with space for `capacity` elements.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the allocator has no block large enough.
+    pub fn with_capacity_in<A: Allocator>(
+        capacity: usize,
+        alloc: &A,
+    ) -> Result<Owned<'_, Vec<T>, A>, AllocError> {
+        let mut vec = Self::new_in(alloc);
+        if capacity > 0 {
+            vec.grow_to(capacity)?;
+        }
+
+        Ok(vec)
+    }
+}
+
+impl<T> AllocDrop for Vec<T> {
+    unsafe fn drop_in<A: Allocator>(&mut self, alloc: &A) {
+        if self.cap == 0 {
+            // nothing was allocated
+            return;
+        }
+
+        if mem::needs_drop::<T>() {
+            let data = alloc.get_unchecked(self.ptr);
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(data, self.len));
         }
+        // the block came from `alloc`, so this can't fail
+        let _ = alloc.deallocate(self.ptr.cast());
     }
 }
 
 impl<T, A: Allocator> Owned<'_, Vec<T>, A> {
+    /// Returns the number of elements in the vector.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.inner.len
+    }
+
+    /// Returns `true` if the vector contains no elements.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.inner.len == 0
+    }
+
+    /// Returns the number of elements the vector can hold without reallocating.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.inner.cap
+    }
+
+    /// Extracts a slice containing the entire vector.
+    pub fn as_slice(&self) -> &[T] {
+        if self.inner.cap == 0 {
+            return &[];
+        }
+
+        // SAFETY: the block holds `cap` elements and the first `len` are initialized
+        unsafe { slice::from_raw_parts(self.alloc.get_unchecked(self.inner.ptr), self.inner.len) }
+    }
+
+    /// Extracts a mutable slice of the entire vector.
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        if self.inner.cap == 0 {
+            return &mut [];
+        }
+
+        // SAFETY: the block holds `cap` elements and the first `len` are initialized
+        unsafe {
+            slice::from_raw_parts_mut(self.alloc.get_unchecked(self.inner.ptr), self.inner.len)
+        }
+    }
+
+    /// Appends an element to the back of the vector.
+    ///
+    /// If the vector is full, its capacity is doubled first.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the vector is full and the allocator has no block large enough. The
+    /// vector is left unchanged.
+    pub fn push(&mut self, value: T) -> Result<(), AllocError> {
+        if self.inner.len == self.inner.cap {
+            let capacity = if self.inner.cap == 0 {
+                MIN_NON_ZERO_CAP
+            } else {
+                self.inner.cap * 2
+            };
+            self.grow_to(capacity)?;
+        }
+
+        // SAFETY: len < cap
+        unsafe {
+            self.alloc
+                .get_unchecked(self.inner.ptr)
+                .add(self.inner.len)
+                .write(value);
+        }
+        self.inner.len += 1;
+
+        Ok(())
+    }
+
+    /// Removes the last element from the vector and returns it, or `None` if it's empty.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.inner.len == 0 {
+            return None;
+        }
+
+        self.inner.len -= 1;
+        // SAFETY: the element was initialized and is no longer part of the vector
+        unsafe {
+            Some(
+                self.alloc
+                    .get_unchecked(self.inner.ptr)
+                    .add(self.inner.len)
+                    .read(),
+            )
+        }
+    }
+
+    /// Moves the elements into a block that holds `capacity` elements.
+    fn grow_to(&mut self, capacity: usize) -> Result<(), AllocError> {
+        assert!(
+            mem::size_of::<T>() != 0,
+            "we aren't ready to handle zero-sized types"
+        );
+        debug_assert!(capacity >= self.inner.len);
+
+        let layout = Layout::array::<T>(capacity).map_err(|_| AllocError::RequestTooLarge)?;
+        let ptr = if self.inner.cap == 0 {
+            self.alloc.allocate(layout)?
+        } else {
+            // copies the elements if they have to move to a larger block
+            self.alloc
+                .reallocate(RelPtr::with_addr(self.inner.ptr.addr()), layout)?
+        };
+
+        self.inner.ptr = ptr.cast();
+        self.inner.cap = capacity;
+        // SAFETY: the block was just allocated
+        debug_assert_eq!(
+            unsafe { self.alloc.get_unchecked(self.inner.ptr) } as usize % mem::align_of::<T>(),
+            0,
+            "allocator returned a misaligned block"
+        );
+        Ok(())
+    }
+
     // append(&mut self, other: &mut ???)
-    // as_mut_slice(&mut self) -> &mut [T]
-    // as_slice(&self) -> &[T]
     // clear
-    // is_empty
-    // capacity
-    // len
     // insert
-    // push
-    // pop
     // remove
     // swap_remove
     // truncate
@@ -76,3 +210,127 @@ impl<T, A: Allocator> Owned<'_, Vec<T>, A> {
 
 // Deref<Target=[T]>
 // DerefMut<Target=[T]>
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arena::Arena;
+
+    #[test]
+    fn push_past_capacity() {
+        let arena = Arena::new(4096, 4);
+        let mut vec = Vec::<u32>::with_capacity_in(2, &arena).unwrap();
+        assert_eq!(vec.capacity(), 2);
+        assert!(vec.is_empty());
+
+        let mut capacities = std::vec![vec.capacity()];
+        for i in 0..100 {
+            vec.push(i).unwrap();
+            if vec.capacity() != *capacities.last().unwrap() {
+                capacities.push(vec.capacity());
+            }
+        }
+
+        // doubles every time it's full
+        assert_eq!(capacities, [2, 4, 8, 16, 32, 64, 128]);
+        assert_eq!(vec.len(), 100);
+        assert_eq!(vec.as_slice(), (0..100).collect::<std::vec::Vec<_>>());
+
+        // the old blocks were freed when the elements moved
+        let block_bytes = arena.stats().bytes_in_use;
+        assert!(block_bytes >= 128 * mem::size_of::<u32>());
+        assert!(block_bytes < 256 * mem::size_of::<u32>());
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let arena = Arena::new(4096, 4);
+        let mut vec = Vec::<u64>::new_in(&arena);
+        assert_eq!(vec.capacity(), 0);
+        assert_eq!(vec.as_slice(), &[]);
+        assert_eq!(vec.pop(), None);
+
+        for i in 0..10 {
+            vec.push(i).unwrap();
+        }
+        vec.as_mut_slice()[0] = 42;
+
+        assert_eq!(vec.pop(), Some(9));
+        assert_eq!(vec.len(), 9);
+        assert_eq!(vec.as_slice(), [42, 1, 2, 3, 4, 5, 6, 7, 8]);
+        while vec.pop().is_some() {}
+        assert!(vec.is_empty());
+        assert_eq!(vec.capacity(), 16);
+    }
+
+    #[test]
+    fn push_keeps_elements_aligned() {
+        let arena = Arena::new(4096, 8);
+        // take a small block first, so that the next ones aren't at the start of a page
+        let mut bytes = Vec::<u8>::with_capacity_in(3, &arena).unwrap();
+        bytes.push(1).unwrap();
+
+        let mut vec = Vec::<u128>::new_in(&arena);
+        for i in 0..100 {
+            vec.push(i << 64 | i).unwrap();
+            assert_eq!(vec.as_slice().as_ptr() as usize % mem::align_of::<u128>(), 0);
+        }
+        vec.as_mut_slice()[0] = u128::MAX;
+
+        assert_eq!(vec.pop(), Some(99 << 64 | 99));
+        assert_eq!(vec.as_slice()[0], u128::MAX);
+        assert!(vec.as_slice()[1..].iter().zip(1..).all(|(&value, i)| value == i << 64 | i));
+    }
+
+    #[test]
+    fn push_fails_when_arena_is_full() {
+        let arena = Arena::new(4096, 1);
+        let mut vec = Vec::<u64>::with_capacity_in(512, &arena).unwrap();
+        for i in 0..512 {
+            vec.push(i).unwrap();
+        }
+
+        // a block can't be larger than a page
+        assert_eq!(vec.push(512), Err(AllocError::RequestTooLarge));
+        assert_eq!(vec.len(), 512);
+        assert_eq!(vec.as_slice()[511], 511);
+    }
+
+    #[test]
+    fn drop_frees_block() {
+        let arena = Arena::new(4096, 4);
+        let bytes_remaining = arena.bytes_remaining();
+
+        let mut vec = Vec::<u64>::new_in(&arena);
+        for i in 0..100 {
+            vec.push(i).unwrap();
+        }
+        assert!(arena.bytes_remaining() < bytes_remaining);
+        drop(vec);
+        assert_eq!(arena.bytes_remaining(), bytes_remaining);
+        assert_eq!(arena.stats().free_pages, 4);
+
+        // an empty vector never allocated anything
+        drop(Vec::<u64>::new_in(&arena));
+        assert_eq!(arena.bytes_remaining(), bytes_remaining);
+    }
+
+    #[test]
+    fn drop_drops_elements() {
+        use std::rc::Rc;
+
+        let arena = Arena::new(4096, 4);
+        let shared = Rc::new(());
+
+        let mut vec = Vec::new_in(&arena);
+        for _ in 0..10 {
+            vec.push(Rc::clone(&shared)).unwrap();
+        }
+        drop(vec.pop());
+        assert_eq!(Rc::strong_count(&shared), 10);
+
+        drop(vec);
+        assert_eq!(Rc::strong_count(&shared), 1);
+        assert_eq!(arena.stats().bytes_in_use, 0);
+    }
+}
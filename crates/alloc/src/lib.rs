@@ -1,6 +1,13 @@
+#![cfg_attr(not(test), no_std)]
 #![feature(generic_associated_types)]
 #![feature(int_log)]
+extern crate alloc;
+
 mod arena;
 mod containers;
 mod ptr;
 mod traits;
+
+pub use arena::{AllocError, Arena};
+pub use ptr::{AddressError, RelPtr};
+pub use traits::Allocator;
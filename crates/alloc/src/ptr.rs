@@ -1,11 +1,15 @@
-use core::{marker::PhantomData, mem};
+use core::{marker::PhantomData, mem, ptr};
 use num_traits::{PrimInt, Unsigned};
 
+use crate::traits::Allocator;
+
 /// An error where the distance between two memory locations cannot be represented by the offset type.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AddressError {
     /// The offset overflowed the range of `isize`.
     IsizeOverflow,
+    /// The address overflowed the range of the pointer's address type.
+    AddressOverflow,
 }
 
 fn offset_between(from: usize, to: usize) -> Result<isize, AddressError> {
@@ -20,15 +24,16 @@ fn offset_between(from: usize, to: usize) -> Result<isize, AddressError> {
 }
 
 pub trait Address: PrimInt + Unsigned {
-    fn from_usize(addr: usize) -> Self;
+    /// Converts `addr`, or returns `None` if it doesn't fit.
+    fn try_from_usize(addr: usize) -> Option<Self>;
     fn to_usize(self) -> usize;
 }
 
 macro_rules! impl_address {
     ($ty:ty) => {
         impl Address for $ty {
-            fn from_usize(addr: usize) -> Self {
-                addr as Self
+            fn try_from_usize(addr: usize) -> Option<Self> {
+                Self::try_from(addr).ok()
             }
 
             fn to_usize(self) -> usize {
@@ -63,77 +68,292 @@ impl<T: ?Sized, P: Address> Clone for RelPtr<T, P> {
     }
 }
 
-impl<T: ?Sized, P: Address> RelPtr<T, P> {
+impl<T: ?Sized> RelPtr<T, usize> {
+    /// Returns a pointer to `addr`, which always fits in a `usize` address.
     pub(crate) fn with_addr(addr: usize) -> Self {
         Self {
-            addr: P::from_usize(addr),
+            addr,
             _marker: PhantomData,
         }
     }
+}
+
+impl<T: ?Sized, P: Address> RelPtr<T, P> {
+    /// Returns a pointer to `addr`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AddressOverflow`](AddressError::AddressOverflow) if `addr` can't be represented
+    /// by `P` (e.g. an address past 4 GiB in a [`RelPtrU32`]).
+    pub fn try_with_addr(addr: usize) -> Result<Self, AddressError> {
+        let addr = P::try_from_usize(addr).ok_or(AddressError::AddressOverflow)?;
+        Ok(Self {
+            addr,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Converts to a pointer with a different address type.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AddressOverflow`](AddressError::AddressOverflow) if the address can't be
+    /// represented by `Q`.
+    pub fn try_with_addr_type<Q: Address>(self) -> Result<RelPtr<T, Q>, AddressError> {
+        RelPtr::try_with_addr(self.addr())
+    }
 
     pub fn addr(self) -> usize {
         self.addr.to_usize()
     }
 
     pub fn cast<U>(self) -> RelPtr<U, P> {
-        RelPtr::with_addr(self.addr.to_usize())
+        RelPtr {
+            addr: self.addr,
+            _marker: PhantomData,
+        }
     }
 }
 
 // pointer arithmetic
 impl<T: Sized, P: Address> RelPtr<T, P> {
-    unsafe fn add(self, count: usize) -> Self {
-        let addr = self
-            .addr
-            .to_usize()
-            .wrapping_add(count * mem::size_of::<T>());
-        Self {
-            addr: P::from_usize(addr),
-            _marker: PhantomData,
-        }
+    /// Returns a pointer `count` values after `self`, or `None` if its address can't be
+    /// represented by `P`.
+    fn add(self, count: usize) -> Option<Self> {
+        let addr = count
+            .checked_mul(mem::size_of::<T>())
+            .and_then(|bytes| self.addr().checked_add(bytes))?;
+        RelPtr::try_with_addr(addr).ok()
     }
 
-    unsafe fn sub(self, count: usize) -> Self {
-        let addr = self
-            .addr
-            .to_usize()
-            .wrapping_sub(count * mem::size_of::<T>());
-        Self {
-            addr: P::from_usize(addr),
-            _marker: PhantomData,
-        }
+    /// Returns a pointer `count` values before `self`, or `None` if it would be below address 0.
+    fn sub(self, count: usize) -> Option<Self> {
+        let addr = count
+            .checked_mul(mem::size_of::<T>())
+            .and_then(|bytes| self.addr().checked_sub(bytes))?;
+        RelPtr::try_with_addr(addr).ok()
+    }
+}
+
+// resolve
+impl<T: Sized, P: Address> RelPtr<T, P> {
+    /// Returns the absolute pointer for `self` in `alloc`'s memory.
+    ///
+    /// ## Safety
+    /// - `self` must point inside a block allocated by `alloc`.
+    #[inline]
+    unsafe fn resolve<A: Allocator>(self, alloc: &A) -> *mut T {
+        alloc.get_unchecked(RelPtr::<T, usize>::with_addr(self.addr()))
     }
 }
 
 // read
 impl<T: Sized, P: Address> RelPtr<T, P> {
-    unsafe fn read<A>(self, alloc: A) -> () {}
+    /// Reads the value at `self` without moving it (see [`ptr::read`]).
+    ///
+    /// ## Safety
+    /// - `self` must point to an initialized, aligned `T` in a block allocated by `alloc`.
+    pub unsafe fn read<A: Allocator>(self, alloc: &A) -> T {
+        self.resolve(alloc).read()
+    }
 
-    unsafe fn read_volatile<A>(self, alloc: A) -> () {}
+    /// Reads the value at `self` with a volatile read (see [`ptr::read_volatile`]).
+    ///
+    /// ## Safety
+    /// - Same as [`read`](Self::read).
+    pub unsafe fn read_volatile<A: Allocator>(self, alloc: &A) -> T {
+        self.resolve(alloc).read_volatile()
+    }
 
-    unsafe fn read_unaligned<A>(self, alloc: A) -> () {}
+    /// Reads the value at `self`, which doesn't have to be aligned (see [`ptr::read_unaligned`]).
+    ///
+    /// ## Safety
+    /// - `self` must point to an initialized `T` in a block allocated by `alloc`.
+    pub unsafe fn read_unaligned<A: Allocator>(self, alloc: &A) -> T {
+        self.resolve(alloc).read_unaligned()
+    }
 
-    unsafe fn copy_from<A>(self, src: Self, count: usize, alloc: A) {}
+    /// Copies `count` values from `src` to `self`. The regions may overlap (see [`ptr::copy`]).
+    ///
+    /// ## Safety
+    /// - Both regions must lie inside blocks allocated by `alloc` and be aligned.
+    /// - `src` must hold `count` initialized values.
+    pub unsafe fn copy_from<A: Allocator>(self, src: Self, count: usize, alloc: &A) {
+        ptr::copy(src.resolve(alloc), self.resolve(alloc), count);
+    }
 
-    unsafe fn copy_from_nonoverlapping<A>(self, src: Self, count: usize, alloc: A) {}
+    /// Copies `count` values from `src` to `self`. The regions must not overlap (see
+    /// [`ptr::copy_nonoverlapping`]).
+    ///
+    /// ## Safety
+    /// - Same as [`copy_from`](Self::copy_from), and the regions must not overlap.
+    pub unsafe fn copy_from_nonoverlapping<A: Allocator>(self, src: Self, count: usize, alloc: &A) {
+        ptr::copy_nonoverlapping(src.resolve(alloc), self.resolve(alloc), count);
+    }
 }
 
 // write
 impl<T: Sized, P: Address> RelPtr<T, P> {
-    unsafe fn write<A>(self, val: T, alloc: A) {}
+    /// Overwrites the value at `self` without reading or dropping the old one (see
+    /// [`ptr::write`]).
+    ///
+    /// ## Safety
+    /// - `self` must point to an aligned `T` in a block allocated by `alloc`.
+    pub unsafe fn write<A: Allocator>(self, val: T, alloc: &A) {
+        self.resolve(alloc).write(val);
+    }
 
-    unsafe fn write_bytes<A>(self, val: u8, count: usize, alloc: A) {}
+    /// Sets `count * size_of::<T>()` bytes starting at `self` to `val` (see [`ptr::write_bytes`]).
+    ///
+    /// ## Safety
+    /// - `self` must point to room for `count` aligned values in a block allocated by `alloc`.
+    pub unsafe fn write_bytes<A: Allocator>(self, val: u8, count: usize, alloc: &A) {
+        self.resolve(alloc).write_bytes(val, count);
+    }
 
-    unsafe fn write_volatile<A>(self, val: T, alloc: A) {}
+    /// Overwrites the value at `self` with a volatile write (see [`ptr::write_volatile`]).
+    ///
+    /// ## Safety
+    /// - Same as [`write`](Self::write).
+    pub unsafe fn write_volatile<A: Allocator>(self, val: T, alloc: &A) {
+        self.resolve(alloc).write_volatile(val);
+    }
 
-    unsafe fn write_unaligned<A>(self, val: T, alloc: A) {}
+    /// Overwrites the value at `self`, which doesn't have to be aligned (see
+    /// [`ptr::write_unaligned`]).
+    ///
+    /// ## Safety
+    /// - `self` must point inside a block allocated by `alloc`.
+    pub unsafe fn write_unaligned<A: Allocator>(self, val: T, alloc: &A) {
+        self.resolve(alloc).write_unaligned(val);
+    }
 
-    unsafe fn replace<A>(self, val: T, alloc: A) -> () {}
+    /// Replaces the value at `self` with `val` and returns the old value (see [`ptr::replace`]).
+    ///
+    /// ## Safety
+    /// - Same as [`read`](Self::read).
+    pub unsafe fn replace<A: Allocator>(self, val: T, alloc: &A) -> T {
+        ptr::replace(self.resolve(alloc), val)
+    }
 
-    unsafe fn swap<A>(self, with: Self, alloc: A) {}
+    /// Swaps the values at `self` and `with`, which may overlap (see [`ptr::swap`]).
+    ///
+    /// ## Safety
+    /// - Both must point to initialized, aligned values in blocks allocated by `alloc`.
+    pub unsafe fn swap<A: Allocator>(self, with: Self, alloc: &A) {
+        ptr::swap(self.resolve(alloc), with.resolve(alloc));
+    }
 }
 
 // drop
-impl<T: ?Sized, P: Address> RelPtr<T, P> {
-    unsafe fn drop_in_place<A>(self, alloc: A) {}
+impl<T: Sized, P: Address> RelPtr<T, P> {
+    /// Runs the destructor of the value at `self` (see [`ptr::drop_in_place`]). Its block isn't
+    /// freed.
+    ///
+    /// ## Safety
+    /// - Same as [`read`](Self::read), and the value must not be used afterward.
+    pub unsafe fn drop_in_place<A: Allocator>(self, alloc: &A) {
+        ptr::drop_in_place(self.resolve(alloc));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::alloc::Layout;
+
+    use super::*;
+    use crate::arena::Arena;
+
+    fn allocate<T>(arena: &Arena, len: usize) -> RelPtr<T, usize> {
+        arena.allocate(Layout::array::<T>(len).unwrap()).unwrap().cast()
+    }
+
+    #[test]
+    fn write_then_read() {
+        let arena = Arena::new(4096, 2);
+        let ptr = allocate::<u64>(&arena, 1);
+        unsafe {
+            ptr.write(0xdead_beef, &arena);
+            assert_eq!(ptr.read(&arena), 0xdead_beef);
+            assert_eq!(ptr.replace(7, &arena), 0xdead_beef);
+            assert_eq!(ptr.read(&arena), 7);
+        }
+
+        // a narrower pointer to the same address works the same
+        let narrow = ptr.try_with_addr_type::<u32>().unwrap();
+        unsafe { assert_eq!(narrow.read(&arena), 7) };
+    }
+
+    #[test]
+    fn copy_and_write_bytes() {
+        let arena = Arena::new(4096, 2);
+        let src = allocate::<u32>(&arena, 4);
+        let dst = allocate::<u32>(&arena, 4);
+        unsafe {
+            src.write_bytes(0xab, 4, &arena);
+            dst.copy_from_nonoverlapping(src, 4, &arena);
+            for i in 0..4 {
+                assert_eq!(dst.add(i).unwrap().read(&arena), 0xabab_abab);
+            }
+
+            // overlapping copies shift the values
+            src.write(1, &arena);
+            src.add(1).unwrap().write(2, &arena);
+            src.add(1).unwrap().copy_from(src, 2, &arena);
+            assert_eq!(src.add(2).unwrap().read(&arena), 2);
+            assert_eq!(src.add(1).unwrap().read(&arena), 1);
+        }
+    }
+
+    #[test]
+    fn drop_in_place_runs_destructor() {
+        use core::cell::Cell;
+
+        struct Counted<'a>(&'a Cell<usize>);
+
+        impl Drop for Counted<'_> {
+            fn drop(&mut self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        let drops = Cell::new(0);
+        let arena = Arena::new(4096, 2);
+        let ptr = allocate::<Counted>(&arena, 1);
+        unsafe {
+            ptr.write(Counted(&drops), &arena);
+            ptr.drop_in_place(&arena);
+        }
+        assert_eq!(drops.get(), 1);
+        // the block is still allocated
+        assert!(unsafe { arena.get(ptr.cast::<u8>()) }.is_some());
+    }
+
+    #[test]
+    fn try_with_addr_checks_range() {
+        let max = u32::MAX as usize;
+        assert_eq!(RelPtrU32::<u8>::try_with_addr(max).unwrap().addr(), max);
+        #[cfg(target_pointer_width = "64")]
+        {
+            let addr = max + 1;
+            assert_eq!(RelPtrU32::<u8>::try_with_addr(addr).err(), Some(AddressError::AddressOverflow));
+            assert_eq!(RelPtrU64::<u8>::try_with_addr(addr).unwrap().addr(), addr);
+
+            let wide = RelPtrUsize::<u8>::with_addr(addr);
+            assert_eq!(wide.try_with_addr_type::<u32>().err(), Some(AddressError::AddressOverflow));
+            assert_eq!(wide.try_with_addr_type::<u64>().unwrap().addr(), addr);
+        }
+    }
+
+    #[test]
+    fn arithmetic_checks_range() {
+        let ptr = RelPtrU32::<u32>::try_with_addr(8).unwrap();
+        assert_eq!(ptr.add(2).unwrap().addr(), 16);
+        assert_eq!(ptr.sub(2).unwrap().addr(), 0);
+        assert!(ptr.sub(3).is_none());
+        assert!(ptr.add(usize::MAX).is_none());
+        // the address has to fit in the pointer type, not just in a usize
+        assert!(ptr.add(u32::MAX as usize / 4).is_none());
+        assert_eq!(ptr.cast::<u8>().addr(), 8);
+    }
 }
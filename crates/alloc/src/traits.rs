@@ -1,7 +1,54 @@
-pub trait Allocator {}
+use core::alloc::Layout;
+
+use crate::{arena::AllocError, ptr::RelPtr};
+
+/// A non-global allocator that hands out pointers relative to its own memory region.
+pub trait Allocator {
+    /// Allocates a block that fits `layout`.
+    ///
+    /// The block must meet both the size and the alignment of `layout` (as must the one
+    /// returned by [`reallocate`](Self::reallocate)), since containers build references to
+    /// their contents from it.
+    fn allocate(&self, layout: Layout) -> Result<RelPtr<[u8], usize>, AllocError>;
+
+    /// Frees an allocated block.
+    fn deallocate(&self, ptr: RelPtr<u8, usize>) -> Result<(), AllocError>;
+
+    /// Resizes an allocated block, moving (and copying) it if needed.
+    fn reallocate(
+        &self,
+        ptr: RelPtr<[u8], usize>,
+        new_layout: Layout,
+    ) -> Result<RelPtr<[u8], usize>, AllocError>;
+
+    /// Returns the absolute pointer for `ptr`, or `None` if it doesn't point to a block in use.
+    ///
+    /// ## Safety
+    /// - Pointee must have been initialized before it's read.
+    unsafe fn get(&self, ptr: RelPtr<u8, usize>) -> Option<*mut u8>;
+
+    /// Returns the absolute pointer for `ptr`, without checking that it points to a block in use.
+    ///
+    /// ## Safety
+    /// - `ptr` must point inside a block allocated by this allocator.
+    unsafe fn get_unchecked<T>(&self, ptr: RelPtr<T, usize>) -> *mut T
+    where
+        Self: Sized;
+
+    /// Returns `true` if `ptr` points into this allocator's memory.
+    fn contains(&self, ptr: *const u8) -> bool;
+}
 
 pub trait AllocClone {}
-pub trait AllocDrop {}
+
+/// Drops a value whose memory is owned by an [`Allocator`].
+pub trait AllocDrop {
+    /// Drops the pointee(s) and returns their memory to `alloc`.
+    ///
+    /// ## Safety
+    /// - Must be called at most once, with the allocator that owns the memory.
+    unsafe fn drop_in<A: Allocator>(&mut self, alloc: &A);
+}
 
 pub(crate) trait Borrow {
     type Ref<'a>
@@ -0,0 +1,25 @@
+//! Checks that the allocator works without `std`.
+//!
+//! Run with `cargo test --no-default-features --test no_std`.
+#![no_std]
+
+use core::alloc::Layout;
+
+use parrot_alloc::{AllocError, Allocator, Arena};
+
+#[test]
+fn allocate_and_deallocate() {
+    let arena = Arena::new(4096, 2);
+    let capacity = arena.bytes_remaining();
+
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let ptr = arena.allocate(layout).unwrap();
+    unsafe { arena.get_unchecked(ptr.cast::<u8>()).write_bytes(0xaa, 64) };
+    assert_eq!(arena.bytes_remaining(), capacity - 64);
+
+    let large = Layout::from_size_align(8192, 8).unwrap();
+    assert!(matches!(arena.allocate(large), Err(AllocError::RequestTooLarge)));
+
+    arena.deallocate(ptr.cast()).unwrap();
+    assert_eq!(arena.bytes_remaining(), capacity);
+}
@@ -0,0 +1,73 @@
+use std::net::SocketAddr;
+
+use super::{config::Config, enums::DisconnectReason};
+
+/// Decides whether a server lets a new peer connect.
+///
+/// The server consults its policy when a peer first asks to connect. If the policy refuses, the
+/// peer is sent a [`Deny`](crate::enums::Request::Deny) with the reason instead of an
+/// [`Accept`](crate::enums::Request::Accept).
+pub trait AcceptPolicy {
+    /// Returns `Ok` if the peer at `addr` may connect, or the reason it may not.
+    ///
+    /// `payload` is whatever the peer sent after its connection request (e.g. a token).
+    fn accept(&mut self, addr: SocketAddr, payload: &[u8]) -> Result<(), DisconnectReason>;
+
+    /// Called when a connection this policy accepted (with the peer at `addr`) is removed.
+    fn closed(&mut self, addr: SocketAddr) {
+        let _ = addr;
+    }
+}
+
+/// The default [`AcceptPolicy`], which lets peers connect until the server has
+/// [`max_connections`](Config::max_connections) of them.
+pub struct MaxConnections {
+    max_connections: usize,
+    /// The number of accepted connections that haven't been removed yet.
+    len: usize,
+}
+
+impl MaxConnections {
+    /// Constructs a new `MaxConnections` with the limit from `config`.
+    pub fn new(config: &Config) -> Self {
+        Self {
+            max_connections: config.max_connections(),
+            len: 0,
+        }
+    }
+}
+
+impl AcceptPolicy for MaxConnections {
+    fn accept(&mut self, _addr: SocketAddr, _payload: &[u8]) -> Result<(), DisconnectReason> {
+        if self.len >= self.max_connections {
+            return Err(DisconnectReason::ServerFull);
+        }
+
+        self.len += 1;
+        Ok(())
+    }
+
+    fn closed(&mut self, _addr: SocketAddr) {
+        self.len = self.len.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_connections_frees_slots_when_closed() {
+        let config = Config::builder().max_connections(2).build().unwrap();
+        let mut policy = MaxConnections::new(&config);
+        let a = "10.0.0.1:5000".parse().unwrap();
+        let b = "10.0.0.2:5000".parse().unwrap();
+
+        assert_eq!(policy.accept(a, &[]), Ok(()));
+        assert_eq!(policy.accept(b, &[]), Ok(()));
+        assert_eq!(policy.accept(b, &[]), Err(DisconnectReason::ServerFull));
+
+        policy.closed(a);
+        assert_eq!(policy.accept(b, &[]), Ok(()));
+    }
+}
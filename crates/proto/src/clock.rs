@@ -0,0 +1,82 @@
+use std::{
+    cell::Cell,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// A source of monotonic time.
+///
+/// Everything time-dependent (timeouts, heartbeats, round-trip times) reads the time from a
+/// clock instead of calling [`Instant::now`], so that it can be tested deterministically.
+pub trait Clock {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+}
+
+/// A [`Clock`] that reads the system's monotonic clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A [`Clock`] that only moves when told to.
+///
+/// Clones share the same time, so a test can keep one and hand the other out.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    now: Rc<Cell<Instant>>,
+}
+
+impl ManualClock {
+    /// Constructs a new `ManualClock` stopped at `start`.
+    pub fn new(start: Instant) -> Self {
+        Self {
+            now: Rc::new(Cell::new(start)),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+
+    /// Moves the clock to `time`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `time` is earlier than the current time, since the clock is monotonic.
+    pub fn set(&self, time: Instant) {
+        assert!(time >= self.now.get(), "clock can't go backwards");
+        self.now.set(time);
+    }
+}
+
+impl Clock for ManualClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_shares_time() {
+        let start = Instant::now();
+        let clock = ManualClock::new(start);
+        let shared = clock.clone();
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(shared.now(), start + Duration::from_secs(1));
+
+        shared.set(start + Duration::from_secs(5));
+        assert_eq!(clock.now(), start + Duration::from_secs(5));
+    }
+}
@@ -1,6 +1,13 @@
-use std::{default::Default, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    default::Default,
+    time::Duration,
+};
 
-use super::constants::*;
+use super::{
+    connection::{Receive, Send},
+    constants::*,
+};
 
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -29,12 +36,61 @@ pub struct Config {
     heartbeat_timeout: Option<Duration>,
     /// The amount of time that can pass without hearing from a peer before the connection is dropped.
     idle_timeout: Duration,
-    /// The maximum chain of sent packets that can remain unacknowledged before the connection is dropped.
+    /// How long a closed connection lingers (absorbing late and duplicate packets) before its id
+    /// is freed.
+    linger_time: Duration,
+    /// How long a connect token stays valid. A connection that hasn't finished connecting by then
+    /// is dropped.
+    connect_token_lifetime: Duration,
+    /// How long to wait for an answer to a connection request before sending it again.
+    request_timeout: Duration,
+    /// The number of connection requests (including the first) sent before giving up.
+    max_connection_attempts: usize,
+    /// The maximum chain of sent packets that can remain unacknowledged. Once it's reached, no
+    /// more data is sent until an acknowledgement arrives, and the connection is dropped if none
+    /// arrives within `send_window_timeout`.
     max_packets_in_flight: usize,
+    /// How long the send window of a reliable channel (or the chain of packets in flight) can stay
+    /// full before the connection is dropped.
+    send_window_timeout: Duration,
+    /// How long a partially received message is kept waiting for the rest of its fragments
+    /// before it's dropped.
+    reassembly_timeout: Duration,
+    /// How long the next message of an ordered channel that [skips gaps](ChannelConfig::skip_gaps)
+    /// can hold up later ones before it's given up on.
+    head_of_line_timeout: Duration,
+    /// The number of duplicate acknowledgements reporting the same missing message after which
+    /// it's resent right away (instead of waiting for a timeout).
+    fast_retransmit_threshold: usize,
+    /// The shortest time between two packets that carry nothing but acknowledgements.
+    ack_interval: Duration,
+    /// The number of messages before the newest one that each acknowledgement also covers
+    /// (either 32 or 64). Both endpoints must use the same width.
+    ack_mask_bits: usize,
+    /// The smallest packet size (in bytes) assumed to work on any path. Connections start at this
+    /// size and probe for larger ones.
+    min_mtu: usize,
+    /// How often a connection probes the path MTU.
+    mtu_probe_interval: Duration,
+    /// The maximum number of packets sent to a peer each tick. Together with the MTU, this
+    /// bounds the number of bytes scheduled for sending each tick.
+    max_packets_per_tick: usize,
+    /// The pacing rate, as a multiple of the estimated bandwidth. Packets are spread out so
+    /// that they leave no faster than this.
+    pacing_gain: f32,
     /// The factor which will smooth out network jitter (EWMA).
     rtt_smoothing_factor: f32,
     /// The maximum round trip time that can be considered healthy (in milliseconds).
     rtt_max_good_value: Duration,
+    /// The fraction of recent messages that can be lost before the connection is considered unhealthy.
+    packet_loss_threshold: f32,
+    /// How long the packet loss can stay above `packet_loss_threshold` before the connection is dropped.
+    packet_loss_timeout: Duration,
+    /// The number of handshake packets each source address can send per second (sustained).
+    /// Guards against connection floods.
+    handshake_rate: f32,
+    /// The number of handshake packets each source address can send in a burst.
+    handshake_burst: usize,
 }
 
 impl Default for Config {
@@ -45,15 +101,729 @@ impl Default for Config {
             socket_event_buffer_size: 1024,
             socket_should_block: false,
             socket_polling_timeout: Some(Duration::from_millis(0)),
-            max_fragments: MAX_FRAGMENTS,
+            max_fragments: DEFAULT_MAX_FRAGMENTS,
             max_fragment_bytes: MAX_FRAGMENT_BYTES,
-            max_payload_bytes: MAX_FRAGMENTS * MAX_FRAGMENT_BYTES,
+            max_payload_bytes: DEFAULT_MAX_FRAGMENTS * MAX_FRAGMENT_BYTES,
             max_connections: 32,
             heartbeat_timeout: None,
             idle_timeout: Duration::from_secs(5),
+            linger_time: Duration::from_secs(2),
+            connect_token_lifetime: Duration::from_secs(30),
+            request_timeout: Duration::from_secs(1),
+            max_connection_attempts: 5,
             max_packets_in_flight: 256,
+            send_window_timeout: Duration::from_secs(10),
+            reassembly_timeout: Duration::from_secs(5),
+            head_of_line_timeout: Duration::from_secs(1),
+            fast_retransmit_threshold: 3,
+            ack_interval: Duration::from_millis(20),
+            ack_mask_bits: REDUNDANT_ACK_MASK_BITS,
+            min_mtu: 1200,
+            mtu_probe_interval: Duration::from_secs(5),
+            max_packets_per_tick: 8,
+            pacing_gain: 1.25,
             rtt_smoothing_factor: 0.1,
             rtt_max_good_value: Duration::from_millis(250),
+            packet_loss_threshold: 0.1,
+            packet_loss_timeout: Duration::from_secs(5),
+            handshake_rate: 4.0,
+            handshake_burst: 8,
         }
     }
 }
+
+impl Config {
+    /// Returns a [`ConfigBuilder`] initialized with the default configuration.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::new()
+    }
+
+    /// The maximum number of connections.
+    #[inline]
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    /// The maximum number of fragments a payload can be split into.
+    #[inline]
+    pub fn max_fragments(&self) -> usize {
+        self.max_fragments
+    }
+
+    /// The maximum size of a fragment.
+    #[inline]
+    pub fn max_fragment_bytes(&self) -> usize {
+        self.max_fragment_bytes
+    }
+
+    /// The maximum size of a payload (before fragmentation).
+    #[inline]
+    pub fn max_payload_bytes(&self) -> usize {
+        self.max_payload_bytes
+    }
+
+    /// The maximum number of datagrams received from the socket per call.
+    #[inline]
+    pub fn socket_event_buffer_size(&self) -> usize {
+        self.socket_event_buffer_size
+    }
+
+    /// The amount of time that can pass without hearing from a peer before the connection is dropped.
+    #[inline]
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// How long a closed connection lingers (absorbing late and duplicate packets) before its id
+    /// is freed.
+    #[inline]
+    pub fn linger_time(&self) -> Duration {
+        self.linger_time
+    }
+
+    /// How long a connect token stays valid.
+    #[inline]
+    pub fn connect_token_lifetime(&self) -> Duration {
+        self.connect_token_lifetime
+    }
+
+    /// How long to wait for an answer to a connection request before sending it again.
+    #[inline]
+    pub fn request_timeout(&self) -> Duration {
+        self.request_timeout
+    }
+
+    /// The number of connection requests (including the first) sent before giving up.
+    #[inline]
+    pub fn max_connection_attempts(&self) -> usize {
+        self.max_connection_attempts
+    }
+
+    /// The maximum chain of sent packets that can remain unacknowledged. Once it's reached, no
+    /// more data is sent until an acknowledgement arrives, and the connection is dropped if none
+    /// arrives within [`send_window_timeout`](Self::send_window_timeout).
+    #[inline]
+    pub fn max_packets_in_flight(&self) -> usize {
+        self.max_packets_in_flight
+    }
+
+    /// How long the send window of a reliable channel (or the chain of packets in flight) can stay
+    /// full before the connection is dropped.
+    #[inline]
+    pub fn send_window_timeout(&self) -> Duration {
+        self.send_window_timeout
+    }
+
+    /// How long a partially received message is kept waiting for the rest of its fragments
+    /// before it's dropped.
+    #[inline]
+    pub fn reassembly_timeout(&self) -> Duration {
+        self.reassembly_timeout
+    }
+
+    /// How long the next message of an ordered channel that [skips gaps](ChannelConfig::skip_gaps)
+    /// can hold up later ones before it's given up on.
+    #[inline]
+    pub fn head_of_line_timeout(&self) -> Duration {
+        self.head_of_line_timeout
+    }
+
+    /// The shortest time between two packets that carry nothing but acknowledgements.
+    #[inline]
+    pub fn ack_interval(&self) -> Duration {
+        self.ack_interval
+    }
+
+    /// The number of duplicate acknowledgements reporting the same missing message after which
+    /// it's resent right away.
+    #[inline]
+    pub fn fast_retransmit_threshold(&self) -> usize {
+        self.fast_retransmit_threshold
+    }
+
+    /// The number of messages before the newest one that each acknowledgement also covers.
+    #[inline]
+    pub fn ack_mask_bits(&self) -> usize {
+        self.ack_mask_bits
+    }
+
+    /// The smallest packet size (in bytes) assumed to work on any path.
+    #[inline]
+    pub fn min_mtu(&self) -> usize {
+        self.min_mtu
+    }
+
+    /// How often a connection probes the path MTU.
+    #[inline]
+    pub fn mtu_probe_interval(&self) -> Duration {
+        self.mtu_probe_interval
+    }
+
+    /// The maximum number of packets sent to a peer each tick.
+    #[inline]
+    pub fn max_packets_per_tick(&self) -> usize {
+        self.max_packets_per_tick
+    }
+
+    /// The pacing rate, as a multiple of the estimated bandwidth.
+    #[inline]
+    pub fn pacing_gain(&self) -> f32 {
+        self.pacing_gain
+    }
+
+    /// The factor which will smooth out network jitter (EWMA).
+    #[inline]
+    pub fn rtt_smoothing_factor(&self) -> f32 {
+        self.rtt_smoothing_factor
+    }
+
+    /// The fraction of recent messages that can be lost before the connection is considered unhealthy.
+    #[inline]
+    pub fn packet_loss_threshold(&self) -> f32 {
+        self.packet_loss_threshold
+    }
+
+    /// How long the packet loss can stay above the threshold before the connection is dropped.
+    #[inline]
+    pub fn packet_loss_timeout(&self) -> Duration {
+        self.packet_loss_timeout
+    }
+
+    /// The number of handshake packets each source address can send per second (sustained).
+    #[inline]
+    pub fn handshake_rate(&self) -> f32 {
+        self.handshake_rate
+    }
+
+    /// The number of handshake packets each source address can send in a burst.
+    #[inline]
+    pub fn handshake_burst(&self) -> usize {
+        self.handshake_burst
+    }
+}
+
+/// The channels that connections can use, and the guarantees of each.
+///
+/// Both endpoints declare the same channels before any connection is established. Messages can
+/// only be sent on a declared channel, and data received on any other channel is dropped.
+#[derive(Clone, Debug, Default)]
+pub struct ChannelConfig {
+    channels: HashMap<u64, (Send, Receive, u8)>,
+    skip_gaps: HashSet<u64>,
+}
+
+impl ChannelConfig {
+    /// Constructs a new `ChannelConfig` without any channels.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declares the channel `id` with the given guarantees, replacing any earlier declaration.
+    ///
+    /// The channel has the lowest priority (`0`).
+    pub fn channel(self, id: u64, send_guarantee: Send, recv_guarantee: Receive) -> Self {
+        self.channel_with_priority(id, send_guarantee, recv_guarantee, 0)
+    }
+
+    /// Declares the channel `id` with the given guarantees and `priority`, replacing any earlier
+    /// declaration.
+    ///
+    /// Among channels with the same send guarantee, the ones with a higher priority send
+    /// everything they have before those with a lower one get to send anything (e.g. so that
+    /// voice isn't held up by a download). Only the sending endpoint's priorities matter.
+    pub fn channel_with_priority(
+        mut self,
+        id: u64,
+        send_guarantee: Send,
+        recv_guarantee: Receive,
+        priority: u8,
+    ) -> Self {
+        self.channels.insert(id, (send_guarantee, recv_guarantee, priority));
+        self
+    }
+
+    /// Lets the ordered channel `id` skip a message that has held up later ones for longer than
+    /// the [`head_of_line_timeout`](Config::head_of_line_timeout).
+    ///
+    /// The skipped message is reported with a
+    /// [`MessageSkipped`](crate::enums::ConnectionEvent::MessageSkipped) event and never
+    /// delivered, even if it arrives later. By default, an ordered channel waits for every
+    /// message. Only the receiving endpoint's configuration matters.
+    pub fn skip_gaps(mut self, id: u64) -> Self {
+        self.skip_gaps.insert(id);
+        self
+    }
+
+    /// The guarantees the channel `id` was declared with, or `None` if it wasn't declared.
+    #[inline]
+    pub fn guarantees(&self, id: u64) -> Option<(Send, Receive)> {
+        self.channels
+            .get(&id)
+            .map(|&(send_guarantee, recv_guarantee, _)| (send_guarantee, recv_guarantee))
+    }
+
+    /// The priority the channel `id` was declared with, or `None` if it wasn't declared.
+    #[inline]
+    pub fn priority(&self, id: u64) -> Option<u8> {
+        self.channels.get(&id).map(|&(.., priority)| priority)
+    }
+
+    /// Returns `true` if the channel `id` [skips gaps](Self::skip_gaps).
+    #[inline]
+    pub fn skips_gaps(&self, id: u64) -> bool {
+        self.skip_gaps.contains(&id)
+    }
+}
+
+/// An error where a [`Config`] violates one of its invariants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// `max_fragments` is zero or greater than [`MAX_FRAGMENTS`].
+    MaxFragmentsOutOfRange,
+    /// `max_fragment_bytes` is zero or greater than [`MAX_FRAGMENT_BYTES`].
+    MaxFragmentBytesOutOfRange,
+    /// `rtt_smoothing_factor` is not in the range `(0, 1]`.
+    RttSmoothingFactorOutOfRange,
+    /// `max_connections` is zero.
+    MaxConnectionsZero,
+    /// `max_connection_attempts` is zero.
+    MaxConnectionAttemptsZero,
+    /// `max_packets_per_tick` is zero.
+    MaxPacketsPerTickZero,
+    /// `max_packets_in_flight` is zero.
+    MaxPacketsInFlightZero,
+    /// `pacing_gain` is not positive and finite.
+    PacingGainOutOfRange,
+    /// `min_mtu` is less than [`MIN_PACKET_BYTES`] or greater than [`MAX_PACKET_BYTES`].
+    MinMtuOutOfRange,
+    /// `packet_loss_threshold` is not in the range `[0, 1]`.
+    PacketLossThresholdOutOfRange,
+    /// `handshake_rate` is not positive and finite.
+    HandshakeRateOutOfRange,
+    /// `handshake_burst` is zero.
+    HandshakeBurstZero,
+    /// `ack_mask_bits` is neither 32 nor 64.
+    AckMaskBitsOutOfRange,
+}
+
+/// Builds a [`Config`], checking that the values are valid.
+///
+/// `max_payload_bytes` is not set directly. It is always recomputed from `max_fragments` and
+/// `max_fragment_bytes`.
+#[derive(Clone, Debug)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigBuilder {
+    /// Constructs a new `ConfigBuilder` initialized with the default configuration.
+    pub fn new() -> Self {
+        Self {
+            config: Config::default(),
+        }
+    }
+
+    /// Sets the size of the underlying socket's internal buffer that holds incoming packets.
+    pub fn socket_recv_buffer_bytes(mut self, bytes: usize) -> Self {
+        self.config.socket_recv_buffer_bytes = bytes;
+        self
+    }
+
+    /// Sets the size of the underlying socket's internal buffer that holds outgoing packets.
+    pub fn socket_send_buffer_bytes(mut self, bytes: usize) -> Self {
+        self.config.socket_send_buffer_bytes = bytes;
+        self
+    }
+
+    /// Sets the size of the event buffer into which we receive socket events.
+    pub fn socket_event_buffer_size(mut self, size: usize) -> Self {
+        self.config.socket_event_buffer_size = size;
+        self
+    }
+
+    /// Makes the underlying socket block if `true`, non-blocking otherwise.
+    pub fn socket_should_block(mut self, should_block: bool) -> Self {
+        self.config.socket_should_block = should_block;
+        self
+    }
+
+    /// Sets how long polling for socket events blocks.
+    pub fn socket_polling_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.socket_polling_timeout = timeout;
+        self
+    }
+
+    /// Sets the maximum number of fragments a payload can be split into.
+    pub fn max_fragments(mut self, count: usize) -> Self {
+        self.config.max_fragments = count;
+        self
+    }
+
+    /// Sets the maximum size of a fragment.
+    pub fn max_fragment_bytes(mut self, bytes: usize) -> Self {
+        self.config.max_fragment_bytes = bytes;
+        self
+    }
+
+    /// Sets the maximum number of connections.
+    pub fn max_connections(mut self, count: usize) -> Self {
+        self.config.max_connections = count;
+        self
+    }
+
+    /// Sets the interval between heartbeats. If `None`, no heartbeats will be sent.
+    pub fn heartbeat_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.heartbeat_timeout = timeout;
+        self
+    }
+
+    /// Sets the amount of time that can pass without hearing from a peer before the connection is dropped.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = timeout;
+        self
+    }
+
+    /// Sets how long a closed connection lingers (absorbing late and duplicate packets) before
+    /// its id is freed.
+    pub fn linger_time(mut self, time: Duration) -> Self {
+        self.config.linger_time = time;
+        self
+    }
+
+    /// Sets how long a connect token stays valid.
+    pub fn connect_token_lifetime(mut self, lifetime: Duration) -> Self {
+        self.config.connect_token_lifetime = lifetime;
+        self
+    }
+
+    /// Sets how long to wait for an answer to a connection request before sending it again.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.config.request_timeout = timeout;
+        self
+    }
+
+    /// Sets the number of connection requests (including the first) sent before giving up.
+    pub fn max_connection_attempts(mut self, count: usize) -> Self {
+        self.config.max_connection_attempts = count;
+        self
+    }
+
+    /// Sets the maximum chain of sent packets that can remain unacknowledged.
+    pub fn max_packets_in_flight(mut self, count: usize) -> Self {
+        self.config.max_packets_in_flight = count;
+        self
+    }
+
+    /// Sets how long the send window of a reliable channel (or the chain of packets in flight) can
+    /// stay full before the connection is dropped.
+    pub fn send_window_timeout(mut self, timeout: Duration) -> Self {
+        self.config.send_window_timeout = timeout;
+        self
+    }
+
+    /// Sets how long a partially received message is kept waiting for the rest of its fragments
+    /// before it's dropped.
+    pub fn reassembly_timeout(mut self, timeout: Duration) -> Self {
+        self.config.reassembly_timeout = timeout;
+        self
+    }
+
+    /// Sets how long the next message of an ordered channel that
+    /// [skips gaps](ChannelConfig::skip_gaps) can hold up later ones before it's given up on.
+    pub fn head_of_line_timeout(mut self, timeout: Duration) -> Self {
+        self.config.head_of_line_timeout = timeout;
+        self
+    }
+
+    /// Sets the shortest time between two packets that carry nothing but acknowledgements.
+    pub fn ack_interval(mut self, interval: Duration) -> Self {
+        self.config.ack_interval = interval;
+        self
+    }
+
+    /// Sets the number of duplicate acknowledgements reporting the same missing message after
+    /// which it's resent right away.
+    pub fn fast_retransmit_threshold(mut self, count: usize) -> Self {
+        self.config.fast_retransmit_threshold = count;
+        self
+    }
+
+    /// Sets the number of messages before the newest one that each acknowledgement also covers
+    /// (either 32 or 64).
+    pub fn ack_mask_bits(mut self, bits: usize) -> Self {
+        self.config.ack_mask_bits = bits;
+        self
+    }
+
+    /// Sets the smallest packet size (in bytes) assumed to work on any path.
+    pub fn min_mtu(mut self, bytes: usize) -> Self {
+        self.config.min_mtu = bytes;
+        self
+    }
+
+    /// Sets how often a connection probes the path MTU.
+    pub fn mtu_probe_interval(mut self, interval: Duration) -> Self {
+        self.config.mtu_probe_interval = interval;
+        self
+    }
+
+    /// Sets the maximum number of packets sent to a peer each tick.
+    pub fn max_packets_per_tick(mut self, count: usize) -> Self {
+        self.config.max_packets_per_tick = count;
+        self
+    }
+
+    /// Sets the pacing rate, as a multiple of the estimated bandwidth.
+    pub fn pacing_gain(mut self, gain: f32) -> Self {
+        self.config.pacing_gain = gain;
+        self
+    }
+
+    /// Sets the factor which will smooth out network jitter (EWMA).
+    pub fn rtt_smoothing_factor(mut self, factor: f32) -> Self {
+        self.config.rtt_smoothing_factor = factor;
+        self
+    }
+
+    /// Sets the maximum round trip time that can be considered healthy.
+    pub fn rtt_max_good_value(mut self, rtt: Duration) -> Self {
+        self.config.rtt_max_good_value = rtt;
+        self
+    }
+
+    /// Sets the fraction of recent messages that can be lost before the connection is
+    /// considered unhealthy.
+    pub fn packet_loss_threshold(mut self, threshold: f32) -> Self {
+        self.config.packet_loss_threshold = threshold;
+        self
+    }
+
+    /// Sets how long the packet loss can stay above the threshold before the connection is dropped.
+    pub fn packet_loss_timeout(mut self, timeout: Duration) -> Self {
+        self.config.packet_loss_timeout = timeout;
+        self
+    }
+
+    /// Sets the number of handshake packets each source address can send per second (sustained).
+    pub fn handshake_rate(mut self, rate: f32) -> Self {
+        self.config.handshake_rate = rate;
+        self
+    }
+
+    /// Sets the number of handshake packets each source address can send in a burst.
+    pub fn handshake_burst(mut self, count: usize) -> Self {
+        self.config.handshake_burst = count;
+        self
+    }
+
+    /// Validates the configuration and returns it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` with the first invariant that the configuration violates.
+    pub fn build(self) -> Result<Config, ConfigError> {
+        let mut config = self.config;
+
+        if config.max_fragments == 0 || config.max_fragments > MAX_FRAGMENTS {
+            return Err(ConfigError::MaxFragmentsOutOfRange);
+        }
+
+        if config.max_fragment_bytes == 0 || config.max_fragment_bytes > MAX_FRAGMENT_BYTES {
+            return Err(ConfigError::MaxFragmentBytesOutOfRange);
+        }
+
+        // written this way so that NaN is also rejected
+        if !(config.rtt_smoothing_factor > 0.0 && config.rtt_smoothing_factor <= 1.0) {
+            return Err(ConfigError::RttSmoothingFactorOutOfRange);
+        }
+
+        if config.max_connections == 0 {
+            return Err(ConfigError::MaxConnectionsZero);
+        }
+
+        if config.max_connection_attempts == 0 {
+            return Err(ConfigError::MaxConnectionAttemptsZero);
+        }
+
+        if config.max_packets_per_tick == 0 {
+            return Err(ConfigError::MaxPacketsPerTickZero);
+        }
+
+        if config.max_packets_in_flight == 0 {
+            return Err(ConfigError::MaxPacketsInFlightZero);
+        }
+
+        if !(config.pacing_gain > 0.0 && config.pacing_gain.is_finite()) {
+            return Err(ConfigError::PacingGainOutOfRange);
+        }
+
+        if config.min_mtu < MIN_PACKET_BYTES || config.min_mtu > MAX_PACKET_BYTES {
+            return Err(ConfigError::MinMtuOutOfRange);
+        }
+
+        if !(0.0..=1.0).contains(&config.packet_loss_threshold) {
+            return Err(ConfigError::PacketLossThresholdOutOfRange);
+        }
+
+        if !(config.handshake_rate > 0.0 && config.handshake_rate.is_finite()) {
+            return Err(ConfigError::HandshakeRateOutOfRange);
+        }
+
+        if config.handshake_burst == 0 {
+            return Err(ConfigError::HandshakeBurstZero);
+        }
+
+        if !matches!(config.ack_mask_bits, 32 | 64) {
+            return Err(ConfigError::AckMaskBitsOutOfRange);
+        }
+
+        config.max_payload_bytes = config.max_fragments * config.max_fragment_bytes;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_default() {
+        let config = Config::builder()
+            .max_connections(4)
+            .max_fragments(16)
+            .max_fragment_bytes(1000)
+            .idle_timeout(Duration::from_secs(10))
+            .build()
+            .unwrap();
+
+        assert_eq!(config.max_connections, 4);
+        assert_eq!(config.max_fragment_bytes(), 1000);
+        assert_eq!(config.max_payload_bytes, 16 * 1000);
+        assert_eq!(config.idle_timeout, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn channel_config_declares_priorities() {
+        let config = ChannelConfig::new()
+            .channel(0, Send::Reliable, Receive::Ordered)
+            .channel_with_priority(1, Send::Unreliable, Receive::Sequenced, 7);
+
+        assert_eq!(config.guarantees(1), Some((Send::Unreliable, Receive::Sequenced)));
+        assert_eq!(config.priority(0), Some(0));
+        assert_eq!(config.priority(1), Some(7));
+        assert_eq!(config.priority(2), None);
+
+        // declaring a channel again replaces its priority too
+        let config = config.channel(1, Send::Unreliable, Receive::Sequenced);
+        assert_eq!(config.priority(1), Some(0));
+    }
+
+    #[test]
+    fn build_rejects_invalid_fragments() {
+        let result = Config::builder().max_fragments(0).build();
+        assert_eq!(result.unwrap_err(), ConfigError::MaxFragmentsOutOfRange);
+
+        let result = Config::builder().max_fragments(MAX_FRAGMENTS + 1).build();
+        assert_eq!(result.unwrap_err(), ConfigError::MaxFragmentsOutOfRange);
+
+        let result = Config::builder().max_fragment_bytes(0).build();
+        assert_eq!(result.unwrap_err(), ConfigError::MaxFragmentBytesOutOfRange);
+
+        let result = Config::builder()
+            .max_fragment_bytes(MAX_FRAGMENT_BYTES + 1)
+            .build();
+        assert_eq!(result.unwrap_err(), ConfigError::MaxFragmentBytesOutOfRange);
+    }
+
+    #[test]
+    fn build_rejects_invalid_rtt_smoothing_factor() {
+        for factor in [0.0, -0.5, 1.5, f32::NAN] {
+            let result = Config::builder().rtt_smoothing_factor(factor).build();
+            assert_eq!(result.unwrap_err(), ConfigError::RttSmoothingFactorOutOfRange);
+        }
+
+        assert!(Config::builder().rtt_smoothing_factor(1.0).build().is_ok());
+    }
+
+    #[test]
+    fn build_rejects_zero_connections() {
+        let result = Config::builder().max_connections(0).build();
+        assert_eq!(result.unwrap_err(), ConfigError::MaxConnectionsZero);
+
+        let result = Config::builder().max_connection_attempts(0).build();
+        assert_eq!(result.unwrap_err(), ConfigError::MaxConnectionAttemptsZero);
+    }
+
+    #[test]
+    fn build_rejects_invalid_min_mtu() {
+        for bytes in [0, MIN_PACKET_BYTES - 1, MAX_PACKET_BYTES + 1] {
+            let result = Config::builder().min_mtu(bytes).build();
+            assert_eq!(result.unwrap_err(), ConfigError::MinMtuOutOfRange);
+        }
+
+        assert!(Config::builder().min_mtu(MIN_PACKET_BYTES).build().is_ok());
+        assert!(Config::builder().min_mtu(MAX_PACKET_BYTES).build().is_ok());
+    }
+
+    #[test]
+    fn build_rejects_invalid_packet_loss_threshold() {
+        for threshold in [-0.1, 1.1, f32::NAN] {
+            let result = Config::builder().packet_loss_threshold(threshold).build();
+            assert_eq!(result.unwrap_err(), ConfigError::PacketLossThresholdOutOfRange);
+        }
+
+        assert!(Config::builder().packet_loss_threshold(0.0).build().is_ok());
+        assert!(Config::builder().packet_loss_threshold(1.0).build().is_ok());
+    }
+
+    #[test]
+    fn build_rejects_zero_packets_per_tick() {
+        let result = Config::builder().max_packets_per_tick(0).build();
+        assert_eq!(result.unwrap_err(), ConfigError::MaxPacketsPerTickZero);
+    }
+
+    #[test]
+    fn build_rejects_zero_packets_in_flight() {
+        let result = Config::builder().max_packets_in_flight(0).build();
+        assert_eq!(result.unwrap_err(), ConfigError::MaxPacketsInFlightZero);
+    }
+
+    #[test]
+    fn build_rejects_invalid_pacing_gain() {
+        for gain in [0.0, -1.0, f32::INFINITY, f32::NAN] {
+            let result = Config::builder().pacing_gain(gain).build();
+            assert_eq!(result.unwrap_err(), ConfigError::PacingGainOutOfRange);
+        }
+
+        assert!(Config::builder().pacing_gain(1.0).build().is_ok());
+    }
+
+    #[test]
+    fn build_rejects_invalid_handshake_limit() {
+        for rate in [0.0, -1.0, f32::INFINITY, f32::NAN] {
+            let result = Config::builder().handshake_rate(rate).build();
+            assert_eq!(result.unwrap_err(), ConfigError::HandshakeRateOutOfRange);
+        }
+
+        let result = Config::builder().handshake_burst(0).build();
+        assert_eq!(result.unwrap_err(), ConfigError::HandshakeBurstZero);
+    }
+
+    #[test]
+    fn build_rejects_invalid_ack_mask_bits() {
+        for bits in [0, 16, 33, 128] {
+            let result = Config::builder().ack_mask_bits(bits).build();
+            assert_eq!(result.unwrap_err(), ConfigError::AckMaskBitsOutOfRange);
+        }
+
+        assert_eq!(Config::default().ack_mask_bits(), REDUNDANT_ACK_MASK_BITS);
+        assert!(Config::builder().ack_mask_bits(32).build().is_ok());
+        assert!(Config::builder().ack_mask_bits(64).build().is_ok());
+    }
+}
@@ -1,15 +1,27 @@
-use std::{collections::HashMap, net::UdpSocket, time::{Duration, Instant}, mem::MaybeUninit};
+use std::{cmp::Reverse, collections::{HashMap, VecDeque}, net::UdpSocket, time::{Duration, Instant}, mem::{self, MaybeUninit}};
 
-use std::{io, net::SocketAddr};
+use std::{io, iter, net::SocketAddr};
 
 use super::{
-    constants::*, 
+    accept::{AcceptPolicy, MaxConnections},
+    clock::{Clock, SystemClock},
+    config::{ChannelConfig, Config},
+    constants::*,
     cursor::BytesMut,
+    enums::{ConnectionEvent, ConnectionState, DisconnectReason, Request, Role},
+    error::{ErrorKind, ProtoError},
+    id::ConnectionIds,
     packet::{
-        frames::{Frame, Header, PacketType},
+        frames::{Frame, Handshake, Header, PacketType},
+        acknowledgment::{ack_mask, PacketAcknowledgment},
+        mtu::MtuDiscovery,
+        packer::PacketPacker,
         pool::{BufferHandle, BufferPool},
-        sequence_buffer::{SequenceBuffer, SequenceNumber},
+        protection::{NullProtector, PacketProtector},
+        sequence_buffer::{sequence_distance, sequence_greater_than, SequenceBuffer, SequenceNumber},
     },
+    pacing::Pacer,
+    rate_limit::HandshakeLimiter,
 };
 
 type ConnectionId = u64;
@@ -17,90 +29,875 @@ type ChannelId = u64;
 
 pub struct Connections {
     conn: HashMap<ConnectionId, Connection>,
+    /// Hands out the ids of local endpoints.
+    ids: ConnectionIds,
     pool: BufferPool,
+    /// Seals outgoing and opens incoming packet payloads.
+    protector: Box<dyn PacketProtector>,
+    /// Where the time comes from.
+    clock: Box<dyn Clock>,
     config: Config,
+    /// The channels the application declared, and their guarantees.
+    channel_config: ChannelConfig,
+    /// Throttles handshake packets from each source address.
+    handshake_limiter: HandshakeLimiter,
+    /// Decides which peers can connect.
+    accept_policy: Box<dyn AcceptPolicy>,
+    stats: EndpointStats,
+}
+
+/// Counts the packets dropped before reaching a connection and the messages dropped by one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EndpointStats {
+    pub(crate) unknown_connection: u64,
+    pub(crate) handshakes_throttled: u64,
+    pub(crate) stale_connection: u64,
+    pub(crate) reassemblies_expired: u64,
+    pub(crate) duplicate_fragments: u64,
+}
+
+impl EndpointStats {
+    /// The number of packets addressed to a connection that doesn't exist.
+    #[inline]
+    pub fn unknown_connection(&self) -> u64 {
+        self.unknown_connection
+    }
+
+    /// The number of handshake packets dropped because their source sent too many.
+    #[inline]
+    pub fn handshakes_throttled(&self) -> u64 {
+        self.handshakes_throttled
+    }
+
+    /// The number of packets addressed to a connection that has been removed (its id may have
+    /// been reused since).
+    #[inline]
+    pub fn stale_connection(&self) -> u64 {
+        self.stale_connection
+    }
+
+    /// The number of partially received messages dropped because the rest of their fragments
+    /// didn't arrive in time.
+    #[inline]
+    pub fn reassemblies_expired(&self) -> u64 {
+        self.reassemblies_expired
+    }
+
+    /// The number of fragments dropped because their message had already been received.
+    #[inline]
+    pub fn duplicate_fragments(&self) -> u64 {
+        self.duplicate_fragments
+    }
 }
 
 impl Connections {
-    pub fn recv_on(&mut self, socket: UdpSocket) -> io::Result<usize> {
-        let handle = self.pool.acquire().unwrap();
-        let buf = self.pool.get_mut(handle).unwrap();
+    /// Constructs a new `Connections` without any connections or channels.
+    ///
+    /// Its buffer pool is sized from `config`: every connection can have
+    /// [`max_packets_in_flight`](Config::max_packets_in_flight) packets waiting for an
+    /// acknowledgement and a message of [`max_fragments`](Config::max_fragments) fragments
+    /// being stored or reassembled, and every buffer can hold a whole packet carrying a fragment
+    /// of [`max_fragment_bytes`](Config::max_fragment_bytes). Buffers are only allocated once
+    /// they're needed, so the pool only grows that large under load.
+    ///
+    /// Packets aren't protected until [`set_protector`](Self::set_protector) is called, and the
+    /// time is read from the [`SystemClock`].
+    pub fn new(config: Config) -> Self {
+        let buffer_size = MAX_PAYLOAD_BYTES
+            .max(Header::short_header_bytes() + DATA_FRAME_BYTES + config.max_fragment_bytes());
+        let capacity = config.max_connections() * (config.max_packets_in_flight() + config.max_fragments());
+
+        Self {
+            conn: HashMap::new(),
+            ids: ConnectionIds::new(),
+            pool: BufferPool::new(buffer_size, capacity),
+            protector: Box::new(NullProtector),
+            clock: Box::new(SystemClock),
+            channel_config: ChannelConfig::new(),
+            handshake_limiter: HandshakeLimiter::new(&config),
+            accept_policy: Box::new(MaxConnections::new(&config)),
+            stats: EndpointStats::default(),
+            config,
+        }
+    }
+
+    /// Replaces the [`PacketProtector`] used for every connection.
+    ///
+    /// Both endpoints must use compatible protectors.
+    pub fn set_protector(&mut self, protector: impl PacketProtector + 'static) {
+        self.protector = Box::new(protector);
+    }
+
+    /// Replaces the [`Clock`] that every connection reads the time from.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Replaces the [`AcceptPolicy`] that decides which peers can connect.
+    ///
+    /// The default policy is [`MaxConnections`](crate::accept::MaxConnections).
+    pub fn set_accept_policy(&mut self, accept_policy: impl AcceptPolicy + 'static) {
+        self.accept_policy = Box::new(accept_policy);
+    }
+
+    /// Declares the channels that every connection can use.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a connection has already been established, since its peer has to agree on
+    /// the same channels.
+    pub fn set_channel_config(&mut self, channel_config: ChannelConfig) {
+        assert!(self.conn.is_empty(), "channels must be declared before connecting");
+        self.channel_config = channel_config;
+    }
+
+    /// The channels that every connection can use.
+    #[inline]
+    pub fn channel_config(&self) -> &ChannelConfig {
+        &self.channel_config
+    }
+
+    /// The number of packets and messages dropped, by reason.
+    #[inline]
+    pub fn stats(&self) -> EndpointStats {
+        self.stats
+    }
+
+    /// Returns the connection `id`, or `None` if there's no such connection.
+    #[inline]
+    pub fn connection(&self, id: ConnectionId) -> Option<&Connection> {
+        self.conn.get(&id)
+    }
+
+    /// Returns every connection (including those that are closed but still lingering) with
+    /// its id, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (ConnectionId, &Connection)> + '_ {
+        self.conn.iter().map(|(&id, connection)| (id, connection))
+    }
+
+    /// Returns an unused id for a new connection's local endpoint.
+    ///
+    /// Ids are reused once their connection is removed, but never with the same value, so a
+    /// peer replaying the old id can't reach the connection that now has it.
+    pub fn allocate_id(&mut self) -> ConnectionId {
+        self.ids.allocate()
+    }
+
+    /// Removes the connection `id`, returns its buffers to the pool, and frees its id.
+    ///
+    /// Returns `false` if there's no such connection.
+    pub fn remove(&mut self, id: ConnectionId) -> bool {
+        let Some(mut connection) = self.conn.remove(&id) else {
+            return false;
+        };
+        connection.flush_send_window(&mut self.pool);
+        connection.clear_recv_buffers(&mut self.pool);
+        if connection.accepted {
+            self.accept_policy.closed(connection.peer_addr);
+        }
+        self.ids.free(id);
+        true
+    }
+
+    /// Updates every connection and returns the events that happened since the last call.
+    ///
+    /// Events are taken one at a time, so the ones left when the iterator is dropped are
+    /// returned by the next call. A closed connection is removed (see [`remove`](Self::remove))
+    /// once it has lingered for [`linger_time`](Config::linger_time) and all of its events
+    /// have been taken.
+    pub fn poll(&mut self) -> impl Iterator<Item = ConnectionEvent> + '_ {
+        let now = self.clock.now();
+        let lingered = self
+            .conn
+            .iter()
+            .filter(|(_, connection)| {
+                matches!(connection.state, ConnectionState::Disconnected(until) if now >= until)
+                    && connection.events.is_empty()
+            })
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in lingered {
+            self.remove(id);
+        }
+
+        for connection in self.conn.values_mut() {
+            // a connection closed since the last call starts lingering right away
+            let closing = matches!(connection.state, ConnectionState::Disconnecting(_));
+            connection.update(now);
+            let expired = connection.expire_reassemblies(now, &mut self.pool);
+            self.stats.reassemblies_expired += expired as u64;
+            connection.skip_gaps(now, &self.channel_config, &mut self.pool);
+            if closing || matches!(connection.state, ConnectionState::Disconnecting(_)) {
+                connection.flush_send_window(&mut self.pool);
+            }
+        }
+
+        self.conn
+            .values_mut()
+            .flat_map(|connection| iter::from_fn(move || connection.events.pop_front()))
+    }
+
+    /// Queues `data` to be sent to the connection `conn` on `channel`.
+    ///
+    /// If the channel isn't open yet, it's opened with the guarantees it was declared with (see
+    /// [`set_channel_config`](Self::set_channel_config)).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ChannelNotDeclared`](ErrorKind::ChannelNotDeclared) if the channel wasn't
+    /// declared, [`ConnectionNotFound`](ErrorKind::ConnectionNotFound) if there's no such
+    /// connection, [`ConnectionNotConnected`](ErrorKind::ConnectionNotConnected) if it isn't
+    /// connected, or the reason the message couldn't be queued.
+    pub fn send_message(&mut self, conn: ConnectionId, channel: ChannelId, data: &[u8]) -> Result<(), ProtoError> {
+        let now = self.clock.now();
+        let (send_guarantee, recv_guarantee) = self
+            .channel_config
+            .guarantees(channel)
+            .ok_or(ErrorKind::ChannelNotDeclared)?;
+        let priority = self.channel_config.priority(channel).unwrap_or(0);
+        let connection = self.conn.get_mut(&conn).ok_or(ErrorKind::ConnectionNotFound)?;
+        if connection.state != ConnectionState::Connected {
+            return Err(ErrorKind::ConnectionNotConnected.into());
+        }
+
+        // take the channel out so that both it and its connection can be borrowed
+        let mut channel = connection
+            .channels
+            .remove(&channel)
+            .unwrap_or_else(|| Channel::new(channel, send_guarantee, recv_guarantee));
+        // the remote endpoint may have opened the channel, without knowing its priority
+        channel.priority = priority;
+
+        let result = ConnectionRef {
+            connection: &mut *connection,
+            channel: &mut channel,
+            pool: &mut self.pool,
+        }
+        .store_outgoing_data(data, now);
+
+        connection.channels.insert(channel.id, channel);
+        result
+    }
+
+    /// Drops the message `sequence` queued on `channel` of the connection `conn` before it's
+    /// sent. Returns `true` if it was cancelled (see [`Channel::cancel`] for which messages
+    /// can be).
+    ///
+    /// Messages are numbered per channel, from 0, in the order they're queued.
+    pub fn cancel_message(&mut self, conn: ConnectionId, channel: ChannelId, sequence: SequenceNumber) -> bool {
+        let Some(channel) = self.conn.get_mut(&conn).and_then(|connection| connection.channels.get_mut(&channel)) else {
+            return false;
+        };
+        channel.cancel(sequence, &mut self.pool)
+    }
 
-        if let Ok((number_of_bytes, src_addr)) = socket.recv_from(buf) {
-            let header = Header::read(buf).unwrap();
-            let connection = self.connection.get_mut(&header.dst_id).unwrap();
+    /// Processes a datagram received from `from`.
+    ///
+    /// This (along with [`poll_transmit`](Self::poll_transmit)) lets the caller own the socket,
+    /// e.g. when it belongs to another event loop. [`recv_on`](Self::recv_on) does the same
+    /// for a [`UdpSocket`]. Datagrams that can't be parsed or authenticated are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there's no buffer available to copy the datagram into or it doesn't
+    /// fit in one.
+    pub fn handle_datagram(&mut self, from: SocketAddr, data: &[u8], now: Instant) -> io::Result<()> {
+        let handle = self.pool.acquire().map_err(|_| {
+            io::Error::new_const(io::ErrorKind::OutOfMemory, &"no buffers available")
+        })?;
+        let buf = unsafe { MaybeUninit::slice_assume_init_mut(self.pool.get_mut(handle).unwrap()) };
+        if data.len() > buf.len() {
+            let released = self.pool.release(handle);
+            debug_assert!(released.is_ok());
+            return Err(io::Error::new_const(io::ErrorKind::InvalidInput, &"datagram too large"));
+        }
+        buf[..data.len()].copy_from_slice(data);
+
+        // a bad packet only affects itself
+        let _ = self.recv_packet(from, handle, data.len(), now);
+        let released = self.pool.release(handle);
+        debug_assert!(released.is_ok());
+        Ok(())
+    }
 
-            match header.packet_type {
-                PacketType::Handshake => {
-                    // handle request
+    /// Receives and processes the datagrams waiting on `socket`, up to
+    /// [`socket_event_buffer_size`](Config::socket_event_buffer_size) of them per call.
+    ///
+    /// The socket must be non-blocking. Returns the number of datagrams received once it has
+    /// nothing left to read or the budget is spent. Datagrams that can't be parsed or
+    /// authenticated are dropped.
+    pub fn recv_on(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        let now = self.clock.now();
+        let mut received = 0;
+        while received < self.config.socket_event_buffer_size() {
+            let handle = self.pool.acquire().map_err(|_| {
+                io::Error::new_const(io::ErrorKind::OutOfMemory, &"no buffers available")
+            })?;
+            let buf = unsafe { MaybeUninit::slice_assume_init_mut(self.pool.get_mut(handle).unwrap()) };
+
+            // read straight into the buffer instead of going through handle_datagram
+            let (len, from) = match socket.recv_from(buf) {
+                Ok(received) => received,
+                Err(err) => {
+                    let released = self.pool.release(handle);
+                    debug_assert!(released.is_ok());
+                    if err.kind() == io::ErrorKind::WouldBlock {
+                        break;
+                    }
+                    return Err(err);
                 },
-                PacketType::Data => {
-                    while let Some(frame) = Frame::read(buf) {
-                        match self {
-                            Frame::Padding { len } => {
-                                continue;
-                            },
-                            Frame::Ping => {
-                                // queue ping to be sent back
-                            },
-                            Frame::Ack {
-                                ack_sequence,
-                                ack_mask,
-                            } => {
-                                connection.acknowledge(ack_sequence, ack_mask);
-                            },
-                            // TODO: Frame for creating channels.
-                            Frame::Data {
-                                channel_id,
-                                channel_sequence,
-                                fragment_index,
-                                fragment_count,
-                                len,
-                            } => {
-                                let channel = connection.channels.get_mut(&channel_id).unwrap();
-                                // store incoming data
-                            },
-                        }
+            };
+            received += 1;
+
+            // a bad packet only affects itself
+            let _ = self.recv_packet(from, handle, len, now);
+            let released = self.pool.release(handle);
+            debug_assert!(released.is_ok());
+        }
+
+        Ok(received)
+    }
+
+    /// Parses the datagram (from `from`) in the first `len` bytes of the buffer `handle` and
+    /// passes what it carries to its connection.
+    ///
+    /// Message data is copied into buffers of its own, so the datagram's buffer can be released
+    /// right after.
+    fn recv_packet(&mut self, from: SocketAddr, handle: BufferHandle, len: usize, now: Instant) -> io::Result<()> {
+        let packet = unsafe { MaybeUninit::slice_assume_init_mut(self.pool.get_mut(handle).unwrap()) };
+        let packet = &mut packet[..len];
+
+        let mut buf = BytesMut::new(packet);
+        let header = Header::read(&mut buf)?;
+        let payload_start = buf.position();
+        let (packet_number, dst_id, packet_type) = match header {
+            Header::Long { packet_number, dst_id, packet_type, .. }
+            | Header::Short { packet_number, dst_id, packet_type, .. } => (packet_number, dst_id, packet_type),
+        };
+        // checked before anything else, since a flood of handshakes is the cheapest way to
+        // make the endpoint do work
+        if packet_type == PacketType::Handshake && !self.handshake_limiter.allow(from.ip(), now) {
+            self.stats.handshakes_throttled += 1;
+            return Err(io::Error::new_const(io::ErrorKind::ConnectionRefused, &"too many handshakes"));
+        }
+        if self.ids.is_stale(dst_id) {
+            self.stats.stale_connection += 1;
+            return Err(io::Error::new_const(io::ErrorKind::NotFound, &"stale connection id"));
+        }
+        let Some(connection) = self.conn.get_mut(&dst_id) else {
+            self.stats.unknown_connection += 1;
+            return Err(io::Error::new_const(io::ErrorKind::NotFound, &"unknown connection"));
+        };
+        // a closed connection absorbs the packets that were still on their way
+        if matches!(connection.state, ConnectionState::Disconnecting(_) | ConnectionState::Disconnected(_)) {
+            return Ok(());
+        }
+
+        // decrypt and authenticate the payload in place
+        let payload = &mut packet[payload_start..];
+        let payload_len = connection.open_payload(&*self.protector, &header, payload)?;
+        let mut buf = BytesMut::new(&mut payload[..payload_len]);
+
+        // only followed once the packet is known to be genuine, so a spoofed source address
+        // can't steal the connection, and only by the newest packet, so one that was reordered
+        // behind a migration can't move it back
+        let newest = connection
+            .acks
+            .ack_packet_number()
+            .map_or(true, |last_recv| sequence_greater_than(packet_number, last_recv));
+        if from != connection.peer_addr && connection.state != ConnectionState::Created && newest {
+            connection.migrate(from);
+        }
+
+        // (frame, start of its data in the packet)
+        let mut data_frames = Vec::new();
+        // (channel, acked, acked mask)
+        let mut channel_acks = Vec::new();
+        match packet_type {
+            PacketType::Handshake => {
+                let Header::Long { version, .. } = header else {
+                    return Err(io::Error::new_const(io::ErrorKind::InvalidData, &"handshake without long header"));
+                };
+                // a server doesn't know where its peer is until it asks to connect
+                if connection.state == ConnectionState::Created {
+                    connection.peer_addr = from;
+                }
+                connection.pending_request =
+                    connection.recv_handshake(version, &mut buf, &mut *self.accept_policy)?;
+            },
+            PacketType::Data => {
+                connection.acks.record_recv(packet_number);
+                while buf.remaining() > 0 {
+                    let frame = Frame::read(&mut buf)?;
+                    match frame {
+                        Frame::Padding { .. } => {
+                            continue;
+                        },
+                        Frame::Ping => {
+                            // acknowledge it even if nothing else is
+                            connection.ping_pending = true;
+                        },
+                        Frame::Ack { ack_sequence, ack_mask } => {
+                            connection.acks.record_delivered(ack_sequence);
+                            connection.on_mtu_probe_ack(ack_sequence, ack_mask);
+                            if !connection.in_flight_full() {
+                                connection.time_in_flight_full = None;
+                            }
+                        },
+                        Frame::ChannelAck {
+                            channel_id,
+                            ack_sequence,
+                            ack_mask,
+                        } => {
+                            channel_acks.push((channel_id, ack_sequence, ack_mask));
+                        },
+                        Frame::OpenChannel {
+                            channel_id,
+                            send_guarantee,
+                            recv_guarantee,
+                        } => {
+                            // the peer has to agree with what was declared
+                            if self.channel_config.guarantees(channel_id) != Some((send_guarantee, recv_guarantee)) {
+                                return Err(io::Error::new_const(
+                                    io::ErrorKind::InvalidData,
+                                    &"channel not declared",
+                                ));
+                            }
+                            if connection.open_channel(channel_id, send_guarantee, recv_guarantee).is_err() {
+                                return Err(io::Error::new_const(
+                                    io::ErrorKind::InvalidData,
+                                    &"channel guarantees mismatch",
+                                ));
+                            }
+                        },
+                        Frame::Close { reason } => {
+                            connection.handle_close(reason);
+                        },
+                        Frame::Data { len, .. } => {
+                            if buf.remaining() < len as usize {
+                                return Err(io::Error::new_const(
+                                    io::ErrorKind::InvalidData,
+                                    &"data frame longer than packet",
+                                ));
+                            }
+                            data_frames.push((frame, payload_start + buf.position()));
+                            buf.seek(io::SeekFrom::Current(len as i64))?;
+                        },
+                    }
+                }
+            },
+        }
+
+        // each channel's messages are acknowledged on their own, before the data that came with
+        // the acknowledgements is delivered
+        for (channel_id, acked, acked_mask) in channel_acks {
+            let Some(mut channel) = connection.channels.remove(&channel_id) else {
+                continue;
+            };
+            ConnectionRef {
+                connection: &mut *connection,
+                channel: &mut channel,
+                pool: &mut self.pool,
+            }
+            .acknowledge(acked, acked_mask, now);
+            connection.channels.insert(channel_id, channel);
+        }
+
+        for (frame, start) in data_frames {
+            let Frame::Data {
+                channel_id,
+                channel_sequence,
+                fragment_index,
+                fragment_count,
+                len,
+            } = frame else {
+                unreachable!();
+            };
+            let len = len as usize;
+
+            // take the channel out so that both it and its connection can be borrowed (data can
+            // arrive before the frame opening its channel, the guarantees are known either way)
+            let channel = connection.channels.remove(&channel_id).or_else(|| {
+                let (send_guarantee, recv_guarantee) = self.channel_config.guarantees(channel_id)?;
+                Some(Channel::new(channel_id, send_guarantee, recv_guarantee))
+            });
+            let Some(mut channel) = channel else {
+                // channel wasn't declared
+                continue;
+            };
+
+            // a packet can carry fragments of several messages, so each one gets its own buffer
+            let result = self.pool.acquire().and_then(|fragment| {
+                match self.pool.copy(handle, start, fragment, len) {
+                    Ok(()) => Ok(fragment),
+                    Err(()) => {
+                        let released = self.pool.release(fragment);
+                        debug_assert!(released.is_ok());
+                        Err(())
+                    },
+                }
+            });
+            if let Ok(fragment) = result {
+                let mut conn = ConnectionRef {
+                    connection: &mut *connection,
+                    channel: &mut channel,
+                    pool: &mut self.pool,
+                };
+                let stored = conn.store_incoming_data(
+                    channel_sequence,
+                    fragment_index,
+                    fragment_count,
+                    fragment,
+                    0,
+                    len,
+                    now,
+                );
+                if let Err(err) = stored {
+                    // duplicate or stale
+                    if matches!(err, ProtoError::Protocol(ErrorKind::MessageAlreadyReceived)) {
+                        self.stats.duplicate_fragments += 1;
                     }
+                    let released = self.pool.release(fragment);
+                    debug_assert!(released.is_ok());
                 }
             }
+
+            connection.channels.insert(channel_id, channel);
+        }
+
+        Ok(())
+    }
+
+    /// Writes the next datagram that has to be sent into `buf` and returns where it should be
+    /// sent and its length, or `None` if there's nothing left to send.
+    ///
+    /// This is the other half of [`handle_datagram`](Self::handle_datagram). The caller should
+    /// keep calling it until it returns `None`. [`send_on`](Self::send_on) does the same for a
+    /// [`UdpSocket`]. `buf` should be able to hold [`MAX_PACKET_BYTES`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a packet couldn't be written or sealed.
+    pub fn poll_transmit(&mut self, buf: &mut [u8], now: Instant) -> io::Result<Option<(SocketAddr, usize)>> {
+        let mut ids = self.conn.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        for id in ids {
+            if let Some(len) = self.write_packet(id, buf, now, true)? {
+                return Ok(Some((self.conn[&id].peer_addr, len)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Writes the next packet the connection `id` has to send into `data` and returns its
+    /// length, or `None` (leaving `data` as it was) if it has nothing to send.
+    ///
+    /// A pending handshake request goes first. Then, a closed connection sends a single
+    /// [`Close`](Frame::Close) frame. Otherwise, a connected connection packs as many message
+    /// fragments as fit, in the order [`Connection::schedule`] picks them (up to the
+    /// [`send_budget`](Connection::send_budget)), once its pacer has earned the credit to send and
+    /// fewer than [`max_packets_in_flight`](Config::max_packets_in_flight) packets (and less
+    /// than the [`congestion_window`](Connection::congestion_window)) are waiting for an
+    /// acknowledgement. Data packets carry the acknowledgements that are due along with the
+    /// fragments. If it can't send fragments but has received messages since its last
+    /// acknowledgement, it sends a packet with just the acknowledgements instead (at most once
+    /// per [`ack_interval`](Config::ack_interval)).
+    ///
+    /// A connected connection also sends a probe of the next MTU candidate whenever
+    /// [`MtuDiscovery`] asks for one (before anything but handshakes and close frames).
+    ///
+    /// If `paced` is `false`, fragments are packed regardless of the pacer and the packets in
+    /// flight, and the pacer isn't charged for them. No probes are sent either.
+    fn write_packet(&mut self, id: ConnectionId, data: &mut [u8], now: Instant, paced: bool) -> io::Result<Option<usize>> {
+        let connection = self.conn.get_mut(&id).unwrap();
+        let held_back = paced && connection.in_flight_full();
+        // the probe has to be as large as the size it's probing, so `data` must hold it
+        let probe = if paced
+            && connection.state == ConnectionState::Connected
+            && connection.pending_request.is_none()
+            && connection.disconnect_reason.is_none()
+            && data.len() >= MAX_PAYLOAD_BYTES
+        {
+            connection.path_mtu.poll(now)
         } else {
-            self.pool.release(handle);
+            None
+        };
+        // leave room for the protector's tag
+        let limit = data
+            .len()
+            .min(probe.unwrap_or(connection.mtu()) - IPV6_HEADER_BYTES - UDP_HEADER_BYTES)
+            .saturating_sub(self.protector.overhead());
+        let mut buf = BytesMut::new(&mut data[..limit]);
+
+        if let Some(request) = connection.pending_request.take() {
+            connection.write_handshake(request, &mut buf)?;
+        } else if !connection.close_sent && connection.write_close(&mut buf)? {
+            // a closed connection sends nothing else
+            connection.close_sent = true;
+            connection.acks.next_packet_number = connection.acks.next_packet_number.wrapping_add(1);
+            connection.time_latest_send = Some(now);
+        } else if probe.is_some() {
+            // probes aren't paced, and don't count as data in flight
+            connection.write_mtu_probe(&mut buf)?;
+            connection.acks.next_packet_number = connection.acks.next_packet_number.wrapping_add(1);
+            connection.time_latest_send = Some(now);
+        } else if connection.state == ConnectionState::Connected
+            && (!connection.has_pending_fragments() || held_back)
+            && connection.ack_due(now)
+        {
+            // nothing to carry the acknowledgements, so they go on their own (and aren't paced)
+            connection.write_ack(&mut buf)?;
+            connection.acks.next_packet_number = connection.acks.next_packet_number.wrapping_add(1);
+            connection.time_latest_send = Some(now);
+            connection.time_latest_ack = Some(now);
+        } else {
+            if connection.state != ConnectionState::Connected {
+                return Ok(None);
+            }
+
+            // the channels hold on to their messages until the remote endpoint catches up
+            if held_back {
+                if connection.has_pending_fragments() {
+                    connection.time_in_flight_full.get_or_insert(now);
+                }
+                return Ok(None);
+            }
+            if !connection.in_flight_full() {
+                connection.time_in_flight_full = None;
+            }
+
+            // handshakes and close frames aren't paced, data is
+            if paced && !connection.can_send_paced(now) {
+                return Ok(None);
+            }
+
+            // the fragments are picked a tick's worth of budget at a time (but a packet can go
+            // out whenever the window isn't full, even if it doesn't have room for all of it)
+            if connection.scheduled.is_empty() {
+                let budget = if paced {
+                    connection.send_budget().max(connection.max_fragment_bytes())
+                } else {
+                    usize::MAX
+                };
+                connection.scheduled = connection.schedule(budget).into();
+            }
+
+            let mut scheduled = mem::take(&mut connection.scheduled);
+            let packet = PacketPacker::new().pack(connection, &self.pool, &mut scheduled, &mut buf, now);
+            connection.scheduled = scheduled;
+            let Some(mut packet) = packet? else {
+                return Ok(None);
+            };
+
+            packet.size += self.protector.overhead();
+            if paced {
+                connection.pacer.on_sent(packet.size);
+            }
+            connection.record_sent_packet(packet);
+        }
+
+        let len = buf.position();
+        self.seal_packet(data, len).map(Some)
+    }
+
+    /// Seals the payload (everything after the header) of the packet in the first `len` bytes
+    /// of `data` and returns the length of the sealed packet.
+    fn seal_packet(&self, data: &mut [u8], len: usize) -> io::Result<usize> {
+        let mut buf = BytesMut::new(&mut data[..len]);
+        let header = Header::read(&mut buf)?;
+        let payload_start = buf.position();
+
+        let payload = &mut data[payload_start..(len + self.protector.overhead())];
+        let sealed_len = self.protector.seal(&header, payload)?;
+        Ok(payload_start + sealed_len)
+    }
+
+    /// Sends every fragment waiting on the connection `id` on `socket` right away, e.g. before
+    /// a clean shutdown, and returns the number of datagrams sent.
+    ///
+    /// Unlike [`send_on`](Self::send_on), this ignores the pacer, the
+    /// [`max_packets_per_tick`](Config::max_packets_per_tick) budget, and the
+    /// [`max_packets_in_flight`](Config::max_packets_in_flight) and
+    /// [`congestion_window`](Connection::congestion_window) limits. Only the MTU limits how
+    /// much goes in each packet. Sent fragments are tracked as usual, so reliable ones are still
+    /// resent if they're lost.
+    ///
+    /// The socket must be non-blocking. Flushing stops early if the socket can't take more.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with [`NotFound`](io::ErrorKind::NotFound) if there's no connection `id`.
+    pub fn flush(&mut self, id: ConnectionId, socket: &UdpSocket) -> io::Result<usize> {
+        let Some(connection) = self.conn.get(&id) else {
+            return Err(io::Error::new_const(io::ErrorKind::NotFound, &"unknown connection"));
+        };
+        let addr = connection.peer_addr;
+        let now = self.clock.now();
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        let mut sent = 0;
+        while let Some(len) = self.write_packet(id, &mut data, now, false)? {
+            match socket.send_to(&data[..len], addr) {
+                Ok(_) => sent += 1,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
         }
 
+        Ok(sent)
     }
 
-    pub fn send_on(&mut self, socket: UdpSocket) -> io::Result<usize> {
-        // messages from channels with the same guarantees can be packed together
-        // iterate channels with same guarantees
-        // iterate messages to be sent
-        // if there's enough space in the packet, add frame
-        // up to limit of number of packets
+    /// Sends what every connection has to send on `socket`, up to
+    /// [`max_packets_per_tick`](Config::max_packets_per_tick) datagrams per call.
+    ///
+    /// The socket must be non-blocking. Returns the number of datagrams sent. A datagram the
+    /// socket can't take is dropped (reliable fragments in it are resent once they time out).
+    pub fn send_on(&mut self, socket: &UdpSocket) -> io::Result<usize> {
+        let now = self.clock.now();
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        let mut sent = 0;
+        while sent < self.config.max_packets_per_tick() {
+            let Some((addr, len)) = self.poll_transmit(&mut data, now)? else {
+                break;
+            };
+
+            match socket.send_to(&data[..len], addr) {
+                Ok(_) => sent += 1,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(sent)
     }
 }
 
+/// A data packet sent to the remote endpoint.
 pub struct SendPacket {
     pub(crate) sequence: u64,
-    pub(crate) included: [Option<(ChannelId, SequenceNumber, u8)>; 8],
+    pub(crate) included: [Option<(ChannelId, SequenceNumber, u16)>; 8],
+    /// The size of the packet (in bytes).
+    pub(crate) size: usize,
 }
 
 pub struct Connection {
     pub(crate) src_id: ConnectionId,
     pub(crate) dst_id: ConnectionId,
     pub(crate) peer_addr: SocketAddr,
+    pub(crate) role: Role,
     pub(crate) state: ConnectionState,
-    pub(crate) acks: Acknowledgment,
+    pub(crate) acks: PacketAcknowledgment,
     pub(crate) channels: HashMap<ChannelId, Channel>,
     pub(crate) send_buffer: SequenceBuffer<SendPacket>,
     pub(crate) time_created: Instant,
+    /// The connection is dropped if it hasn't finished connecting by this time.
+    pub(crate) token_expire_time: Instant,
     pub(crate) time_latest_recv: Option<Instant>,
     pub(crate) time_latest_send: Option<Instant>,
+    /// When a packet carrying nothing but acknowledgements was last sent.
+    pub(crate) time_latest_ack: Option<Instant>,
+    /// When [`max_packets_in_flight`](Config::max_packets_in_flight) was reached with data
+    /// waiting to be sent, if no acknowledgement has arrived since.
+    pub(crate) time_in_flight_full: Option<Instant>,
+    /// Why the connection was closed, once it has been.
+    pub(crate) disconnect_reason: Option<DisconnectReason>,
+    /// Whether the remote endpoint has been told why the connection was closed (or closed it
+    /// itself).
+    pub(crate) close_sent: bool,
+    /// Whether the server's [`AcceptPolicy`] let the remote endpoint connect.
+    pub(crate) accepted: bool,
+    /// The handshake request waiting to be sent by [`Connections::poll_transmit`].
+    pub(crate) pending_request: Option<Request>,
     pub(crate) rtt: Duration,
-    pub(crate) mtu: usize,
+    /// The smoothed mean deviation of [`rtt`](Self::rtt).
+    pub(crate) rtt_var: Duration,
+    pub(crate) path_mtu: MtuDiscovery,
+    /// The packet number of the MTU probe in flight.
+    pub(crate) mtu_probe: Option<u64>,
+    /// Whether a [`Ping`](Frame::Ping) has been received since the last
+    /// [`Ack`](Frame::Ack) frame was sent.
+    pub(crate) ping_pending: bool,
+    /// Whether each of the most recently acknowledged messages was lost, oldest first.
+    pub(crate) delivery_outcomes: VecDeque<bool>,
+    /// The most recent acknowledgements that confirmed data as delivered (when each arrived,
+    /// and how many bytes it confirmed), oldest first.
+    pub(crate) deliveries: VecDeque<(Instant, usize)>,
+    /// The smoothed delivery rate (in bytes per second).
+    pub(crate) delivery_rate: f64,
+    /// Spreads the data packets out at the [`pacing_rate`](Self::pacing_rate).
+    pub(crate) pacer: Pacer,
+    /// The most data that can be in flight (in bytes), see
+    /// [`congestion_window`](Self::congestion_window).
+    pub(crate) congestion_window: usize,
+    /// When the current round trip without losses started, once an acknowledgement has
+    /// confirmed data as delivered.
+    pub(crate) time_cwnd_round: Option<Instant>,
+    /// When the congestion window was last halved.
+    pub(crate) time_cwnd_halved: Option<Instant>,
+    /// When [`packet_loss`](Self::packet_loss) rose above the configured threshold, if it
+    /// hasn't dropped back below it since.
+    pub(crate) time_packet_loss_exceeded: Option<Instant>,
+    /// The channel (for each send guarantee) that ran out of budget in the middle of its
+    /// turn, and will resume the next round of [`schedule`](Self::schedule).
+    pub(crate) drr_resume: [Option<ChannelId>; 2],
+    /// The fragments picked by [`schedule`](Self::schedule) that haven't been sent yet, in the
+    /// order they should be sent.
+    pub(crate) scheduled: VecDeque<(ChannelId, SequenceNumber, u16)>,
+    /// Events that haven't been returned by [`Connections::poll`] yet.
+    pub(crate) events: VecDeque<ConnectionEvent>,
+    pub(crate) config: Config,
     // TODO: Add connection-level stats
 }
 
 impl Connection {
+    pub(crate) fn new(
+        src_id: ConnectionId,
+        dst_id: ConnectionId,
+        peer_addr: SocketAddr,
+        role: Role,
+        config: Config,
+        time_created: Instant,
+    ) -> Self {
+        Self {
+            src_id,
+            dst_id,
+            peer_addr,
+            role,
+            state: ConnectionState::Created,
+            acks: PacketAcknowledgment::new(),
+            channels: HashMap::new(),
+            send_buffer: SequenceBuffer::with_capacity(DEFAULT_SEND_WINDOW_SIZE),
+            time_created,
+            token_expire_time: time_created + config.connect_token_lifetime(),
+            time_latest_recv: None,
+            time_latest_send: None,
+            time_latest_ack: None,
+            time_in_flight_full: None,
+            disconnect_reason: None,
+            close_sent: false,
+            accepted: false,
+            pending_request: None,
+            rtt: Duration::from_millis(DEFAULT_RTT_MS as u64),
+            rtt_var: Duration::from_millis(DEFAULT_RTT_MS as u64) / 2,
+            path_mtu: MtuDiscovery::new(config.min_mtu(), config.mtu_probe_interval(), time_created),
+            mtu_probe: None,
+            ping_pending: false,
+            delivery_outcomes: VecDeque::with_capacity(PACKET_LOSS_WINDOW),
+            deliveries: VecDeque::with_capacity(BANDWIDTH_WINDOW),
+            delivery_rate: 0.0,
+            pacer: Pacer::new(PACING_BURST_PACKETS * config.min_mtu(), time_created),
+            congestion_window: INITIAL_CONGESTION_WINDOW * config.min_mtu(),
+            time_cwnd_round: None,
+            time_cwnd_halved: None,
+            time_packet_loss_exceeded: None,
+            drr_resume: [None; 2],
+            scheduled: VecDeque::new(),
+            events: VecDeque::new(),
+            config,
+        }
+    }
+
     /// The session index of the local endpoint.
     #[inline]
     pub fn src_id(&self) -> ConnectionId {
@@ -113,6 +910,15 @@ impl Connection {
         self.dst_id
     }
 
+    /// The address of the remote endpoint.
+    ///
+    /// This changes if the remote endpoint migrates (see
+    /// [`Migrated`](ConnectionEvent::Migrated)).
+    #[inline]
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
     /// The current state of this connection.
     #[inline]
     pub fn state(&self) -> ConnectionState {
@@ -125,6 +931,12 @@ impl Connection {
         self.time_created
     }
 
+    /// The [Instant] the connection will be dropped if it hasn't finished connecting.
+    #[inline]
+    pub fn token_expire_time(&self) -> Instant {
+        self.token_expire_time
+    }
+
     /// The [Instant] a packet was last received on this connection.
     #[inline]
     pub fn time_latest_recv(&self) -> Instant {
@@ -143,615 +955,6204 @@ impl Connection {
         self.rtt
     }
 
+    /// The mean deviation of the round-trip time of this connection as [Duration].
+    #[inline]
+    pub fn rtt_var(&self) -> Duration {
+        self.rtt_var
+    }
+
     /// The maximum size of packets on this connection (in bytes).
     #[inline]
     pub fn mtu(&self) -> usize {
-        self.mtu
+        self.path_mtu.mtu()
     }
 
-    fn disconnect(&mut self, reason: DisconnectReason) {
-        // send an event to invoke other stuff
-        self.state = ConnectionState::Disconnecting;
+    /// The maximum size of message fragments on this connection (in bytes).
+    #[inline]
+    pub fn max_fragment_bytes(&self) -> usize {
+        self.mtu() - IPV6_HEADER_BYTES - UDP_HEADER_BYTES - FRAGMENT_FRAME_BYTES
     }
 
-    pub(crate) fn update(&mut self, time: Instant) {
-        // Check if connection token has expired.
-        if time >= self.token_expire_time() {
-            // send local event
-            self.disconnect(DisconnectReason::ConnectionTokenExpired);
-            return;
-        }
+    /// The maximum size of messages on this connection (in bytes), limited by both the number
+    /// of fragments and [`max_payload_bytes`](Config::max_payload_bytes).
+    #[inline]
+    pub fn max_message_bytes(&self) -> usize {
+        (self.max_fragment_bytes() * self.config.max_fragments()).min(self.config.max_payload_bytes())
+    }
 
-        match self.state {
-            // IP address can change while connecting and reconnecting
-            ConnectionState::Connecting(ref mut attempts, ref mut last_attempt) => {
-                // Have we exhausted all of our connection attempts?
-                if *attempts >= self.config.max_connection_attempts() {
-                    // send local event
-                    self.disconnect(DisconnectReason::ConnectionAttemptsExhausted);
-                    return;
-                } 
-                
-                // No? Then is it time to resend request?
-                if time.saturating_duration_since(*last_attempt) >= self.config.request_timeout() {                    
-                    // send local event
-                    // send connect request packet
-                    *last_attempt = time;
-                    *attempts += 1;
-                    return;
-                }
-            },
-            ConnectionState::Connected => {
-                // Have we timed out?
-                if time.saturating_duration_since(self.latest_recv) >= self.config.connection_timeout() {
-                    // send local event
-                    // send disconnect notification packet with timeout as reason
-                    // flush send window
-                    self.disconnect(DisconnectReason::ConnectionTimeout);
-                    return;
-                }
+    /// The retransmission timeout of this connection (before backoff).
+    ///
+    /// A reliable message that goes unacknowledged for this long after it was last sent is
+    /// considered lost.
+    #[inline]
+    pub fn rto(&self) -> Duration {
+        (self.rtt + 4 * self.rtt_var).clamp(MIN_RTO, MAX_RTO)
+    }
 
-                // Do we have any packets to send?
-                // No? Is it time to send another keep-alive packet?
-            },
-            ConnectionState::Disconnecting(reason) => {
-                // send local event
-                // send disconnect notification packet with reason
-                self.state = ConnectionState::Disconnected;
-            },
-            ConnectionState::Disconnected(timeout) => {
-                if time >= timeout {
-                    // remove connection, increment generation
-                }
-            },
-            _ => {},
-        }
+    /// The total size of the data packets sent to the remote endpoint that it hasn't
+    /// acknowledged yet (in bytes).
+    ///
+    /// Acknowledgements are cumulative, so a packet stops counting once the remote endpoint
+    /// acknowledges it or a later one (which means it was lost). Packets that carry no data
+    /// never count.
+    pub fn bytes_in_flight(&self) -> usize {
+        let next_packet_number = self.acks.packet_number();
+        let in_flight = self.acks.sent_packets_in_flight().min(self.send_buffer.capacity()) as u64;
+        (1..=in_flight)
+            .filter_map(|offset| self.send_buffer.get(next_packet_number.wrapping_sub(offset))?.as_ref())
+            .map(|packet| packet.size)
+            .sum()
     }
 
-    pub(crate) fn handle_request(&mut self, request: Request) {
-        // ignore requests coming from disconnected connections
-        if let Request::Disconnect(reason) = request {
-            if self.state == ConnectionState::Connected {
-                // flush send window (say packet lost)
-                self.disconnect(reason);
-                return;
-            }
+    /// Records that the data `packet` was sent, so that it counts toward
+    /// [`bytes_in_flight`](Self::bytes_in_flight) until it's acknowledged.
+    pub(crate) fn record_sent_packet(&mut self, packet: SendPacket) {
+        self.send_buffer.insert(packet.sequence, packet);
+    }
+
+    /// The fraction of the most recently acknowledged messages that were lost, from `0.0` to `1.0`.
+    ///
+    /// Only a window of the most recent outcomes is counted, so this recovers once the losses stop.
+    pub fn packet_loss(&self) -> f32 {
+        if self.delivery_outcomes.is_empty() {
+            return 0.0;
         }
 
-        match (self.role, self.state, request) {
-            (Role::Client, ConnectionState::Connecting, Request::Accepted) => {
-                // send local event
-                self.state = ConnectionState::Connected;
-            },
-            (Role::Client, ConnectionState::Connection, Request::Denied) => {
-                self.disconnect(DisconnectReason::ConnectionDenied);
-            },
-            (Role::Server, ConnectionState::Created, Request::Connect) => {
-                // send local event
-                // TODO: Authentication
-                self.state = ConnectionState::Connected;
-                // send Request::Accepted
-            },
-            (Role::Server, ConnectionState::Connected, Request::Connect) => {
-                // The requester has not received our acceptance.
-                // send Request::Accepted
-            },
-            _ => {
-                panic!("Invalid connection state: {}, {}, {}", self.role, self.state, request);
-            }
+        let lost = self.delivery_outcomes.iter().filter(|&&lost| lost).count();
+        lost as f32 / self.delivery_outcomes.len() as f32
+    }
+
+    /// Records whether an acknowledged message was delivered or lost.
+    pub(crate) fn record_delivery(&mut self, lost: bool) {
+        if self.delivery_outcomes.len() == PACKET_LOSS_WINDOW {
+            self.delivery_outcomes.pop_front();
         }
+        self.delivery_outcomes.push_back(lost);
     }
-}
 
-pub enum Send {
-    Unreliable,
-    Reliable,
-}
+    /// Folds a round-trip time sample into [`rtt`](Self::rtt) and [`rtt_var`](Self::rtt_var)
+    /// using an exponentially weighted moving average.
+    pub(crate) fn update_rtt(&mut self, sample: Duration) {
+        let factor = self.config.rtt_smoothing_factor() as f64;
+        let rtt = self.rtt.as_secs_f64();
+        let rtt_var = self.rtt_var.as_secs_f64();
+        let sample = sample.as_secs_f64();
 
-pub enum Receive {
-    Unordered,
-    Sequenced,
-    Ordered,
-}
+        // update the deviation first, it's measured against the previous estimate
+        self.rtt_var = Duration::from_secs_f64(rtt_var + factor * ((sample - rtt).abs() - rtt_var));
+        self.rtt = Duration::from_secs_f64(rtt + factor * (sample - rtt));
+    }
 
-pub struct SendInfo {
-    addr: SocketAddr,
-    time: Instant,
-    delivered: bool,
-}
+    /// The estimated rate at which data can be delivered to the remote endpoint (in bytes per
+    /// second), going by how fast it has been acknowledged. This is `0.0` until two
+    /// acknowledgements have confirmed data as delivered.
+    #[inline]
+    pub fn estimated_bandwidth(&self) -> f64 {
+        self.delivery_rate
+    }
 
-pub struct RecvInfo {
-    addr: SocketAddr,
-    time: Instant,
-    delivered: bool,
-}
+    /// Records that an acknowledgement received at `instant` confirmed `bytes` of data as
+    /// delivered, and folds the delivery rate over the recent acknowledgements into
+    /// [`estimated_bandwidth`](Self::estimated_bandwidth) using an exponentially weighted
+    /// moving average.
+    pub(crate) fn record_delivered_bytes(&mut self, bytes: usize, instant: Instant) {
+        if self.deliveries.len() == BANDWIDTH_WINDOW {
+            self.deliveries.pop_front();
+        }
+        self.deliveries.push_back((instant, bytes));
 
-pub struct Acknowledgement {
-    pub(crate) next_send: SequenceNumber,
-    pub(crate) latest_recv: Option<SequenceNumber>,
-    pub(crate) latest_recv_mask: u32,
-    pub(crate) latest_send_acked: Option<SequenceNumber>,
-    pub(crate) oldest_send_unacked: Option<SequenceNumber>,
-    pub(crate) next_recv_ordered: Option<SequenceNumber>,
-}
+        let (oldest, _) = self.deliveries[0];
+        let elapsed = instant.saturating_duration_since(oldest).as_secs_f64();
+        if elapsed == 0.0 {
+            return;
+        }
 
-impl Acknowledgement {
-    pub fn new() -> Self {
-        // TODO: start at somewhat random values?
-        Acknowledgement {
-            next_send: 0,
-            latest_recv: None,
-            latest_recv_mask: 0,
-            latest_send_acked: None,
-            oldest_send_unacked: None,
-            next_recv_ordered: None,
+        // the oldest acknowledgement only marks the start of the window
+        let bytes = self.deliveries.iter().skip(1).map(|&(_, bytes)| bytes).sum::<usize>();
+        let sample = bytes as f64 / elapsed;
+        if self.delivery_rate == 0.0 {
+            self.delivery_rate = sample;
+        } else {
+            let factor = self.config.rtt_smoothing_factor() as f64;
+            self.delivery_rate += factor * (sample - self.delivery_rate);
         }
     }
-    
-    /// The next packet to send to the remote endpoint of this channel.
-    pub fn sequence(&self) -> SequenceNumber {
-        self.next_send
+
+    /// The rate at which data packets are sent (in bytes per second), which is the
+    /// [`estimated_bandwidth`](Self::estimated_bandwidth) times the configured
+    /// [`pacing_gain`](Config::pacing_gain).
+    ///
+    /// Until the bandwidth has been estimated, this assumes a few packets per round trip.
+    pub fn pacing_rate(&self) -> f64 {
+        let bandwidth = if self.delivery_rate > 0.0 {
+            self.delivery_rate
+        } else {
+            // a zero round-trip time would make the rate infinite
+            let rtt = self.rtt.as_secs_f64().max(0.001);
+            (INITIAL_PACING_WINDOW * self.mtu()) as f64 / rtt
+        };
+        bandwidth * self.config.pacing_gain() as f64
     }
-    
-    /// Bit array of the last T::BITS packets received from the remote endpoint.
-    pub fn latest_recv_mask(&self) -> u32 {
-        self.latest_recv_mask
+
+    /// The time between full-size data packets at the [`pacing_rate`](Self::pacing_rate).
+    pub fn pacing_interval(&self) -> Duration {
+        Pacer::interval(self.pacing_rate(), self.mtu())
     }
 
-    /// The last packet we received from the remote endpoint on this channel.
-    pub fn latest_recv(&self) -> Option<SequenceNumber> {
-        self.latest_recv
+    /// Returns `true` if the pacer has earned enough credit to send a full-size data packet by
+    /// `now`.
+    pub(crate) fn can_send_paced(&mut self, now: Instant) -> bool {
+        let rate = self.pacing_rate();
+        let mtu = self.mtu();
+        self.pacer.can_send(rate, PACING_BURST_PACKETS * mtu, mtu, now)
     }
-    
-    pub fn latest_send_acked(&self) -> Option<SequenceNumber> {
-        self.latest_send_acked
+
+    /// The number of bytes that can be scheduled for sending this tick (no more than the
+    /// [`congestion_window`](Self::congestion_window) has room for).
+    pub fn send_budget(&self) -> usize {
+        let packets = self
+            .config
+            .max_packets_per_tick()
+            .min(self.config.max_packets_in_flight());
+        let room = self.congestion_window.saturating_sub(self.bytes_in_flight());
+        (packets * self.mtu()).min(room)
     }
 
-    pub fn next_recv_ordered(&self) -> Option<SequenceNumber> {
-        self.next_recv_ordered
+    /// The most data that can be in flight (in bytes) before more is held back, see
+    /// [`bytes_in_flight`](Self::bytes_in_flight).
+    ///
+    /// The window grows by one full-size packet every round trip in which acknowledgements only
+    /// confirm deliveries, and is halved (at most once per round trip) when one reports a
+    /// message as lost. It never drops below a couple of packets, and never grows past
+    /// [`max_packets_in_flight`](Config::max_packets_in_flight) full-size packets.
+    #[inline]
+    pub fn congestion_window(&self) -> usize {
+        self.congestion_window
     }
 
-    pub fn oldest_send_unacked(&self) -> Option<SequenceNumber> {
-        self.oldest_send_unacked
+    /// Grows or shrinks the [`congestion_window`](Self::congestion_window) after an
+    /// acknowledgement received at `instant` confirmed data as delivered or reported some as
+    /// `lost`.
+    pub(crate) fn update_congestion_window(&mut self, lost: bool, instant: Instant) {
+        let mtu = self.mtu();
+        if lost {
+            // the losses of a round trip come from the same congestion
+            let halved = self.time_cwnd_halved.map_or(false, |time_halved| {
+                instant.saturating_duration_since(time_halved) < self.rtt
+            });
+            if !halved {
+                self.congestion_window = (self.congestion_window / 2).max(MIN_CONGESTION_WINDOW * mtu);
+                self.time_cwnd_halved = Some(instant);
+            }
+            self.time_cwnd_round = Some(instant);
+            return;
+        }
+
+        let time_round = *self.time_cwnd_round.get_or_insert(instant);
+        if instant.saturating_duration_since(time_round) >= self.rtt {
+            let max_window = self.config.max_packets_in_flight() * mtu;
+            if self.congestion_window < max_window {
+                self.congestion_window = (self.congestion_window + mtu).min(max_window);
+            }
+            self.time_cwnd_round = Some(instant);
+        }
     }
-}
 
-pub struct RecvMessage {
-    pub(crate) sequence: u64,
-    pub(crate) fragment_count: u8,
-    pub(crate) fragment_recv: u8,
-    pub(crate) fragment_data: [Option<(BufferHandle, usize, usize)>; MAX_FRAGMENTS],
-    pub(crate) time_created: Instant,
-    pub(crate) time_recv: Option<Instant>,
-}
+    /// Picks the pending fragments to send this tick, up to `budget` bytes.
+    ///
+    /// Unreliable channels are drained before reliable ones. Among channels with the same send
+    /// guarantee, those with a higher [`priority`](ChannelConfig::channel_with_priority) are
+    /// drained first, and those with the same priority share the budget using deficit
+    /// round-robin, so none of them can starve the others. Fragments from the same channel are
+    /// picked in the order given by [`Channel::resend_order`].
+    ///
+    /// Returns `(channel, sequence, fragment index)` for each fragment, in the order they
+    /// should be sent.
+    pub(crate) fn schedule(&mut self, mut budget: usize) -> Vec<(ChannelId, SequenceNumber, u16)> {
+        let mut ids = self.channels.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable_by_key(|id| (Reverse(self.channels[id].priority), *id));
 
-pub struct SendMessage {
-    pub(crate) sequence: u64,
-    pub(crate) fragment_count: u8,
-    pub(crate) fragment_sent: u8,
-    pub(crate) fragment_data: [Option<(BufferHandle, usize, usize)>; MAX_FRAGMENTS],
-    pub(crate) fragment_status: [SendStatus; MAX_FRAGMENTS], 
-    pub(crate) time_created: Instant,
-    pub(crate) time_sent: Option<Instant>,
-}
+        let mut scheduled = Vec::new();
+        for (tier, send_guarantee) in [Send::Unreliable, Send::Reliable].into_iter().enumerate() {
+            let mut queues = Vec::new();
+            for id in ids.iter().copied() {
+                let channel = self.channels.get_mut(&id).unwrap();
+                if channel.send_guarantee != send_guarantee {
+                    continue;
+                }
 
-pub enum SendStatus {
-    Unsent,
-    Sent,
-    Delivered,
-    Lost,
-}
+                let pending = channel
+                    .resend_order()
+                    .into_iter()
+                    .filter_map(|(sequence, index)| Some((sequence, index, channel.fragment_len(sequence, index)?)))
+                    .collect::<VecDeque<_>>();
+                if pending.is_empty() {
+                    // idle channels don't bank credit
+                    channel.deficit = 0;
+                } else {
+                    queues.push((id, channel.priority, pending));
+                }
+            }
 
-pub struct Channel {
-    pub(crate) id: u64,
-    pub(crate) acks: Acknowledgement,
-    pub(crate) send_guarantee: Send, 
-    pub(crate) recv_guarantee: Receive,
-    pub(crate) send_buffer: SequenceBuffer<SendMessage>,
-    pub(crate) recv_buffer: SequenceBuffer<RecvMessage>,
-    pub(crate) time_latest_send: Option<Instant>,
-    pub(crate) time_latest_recv: Option<Instant>,
-    // TODO: add statistics (# messages sent, received, etc.)
-}
+            let resume = self.drr_resume[tier].take();
+            'levels: while let Some(&(_, priority, _)) = queues.first() {
+                // lower priorities wait until the higher ones are drained
+                let len = queues.iter().take_while(|(_, p, _)| *p == priority).count();
+                let mut level = queues.drain(..len).collect::<Vec<_>>();
 
-impl Channel {
-    pub fn new(id: usize, send_guarantee: Send, recv_guarantee: Receive) -> Self {
-        Self {
-            id,
-            send_guarantee,
-            recv_guarantee,
-            acks: Acknowledgement::default(),
-            send_buffer: None,
-            recv_buffer: None,
-            time_latest_send: None,
-            time_latest_recv: None,
+                // continue the round where the previous tick left off
+                let resume = resume.filter(|id| self.channels.get(id).map_or(false, |channel| channel.priority == priority));
+                if let Some(resume) = resume {
+                    let start = level.partition_point(|(id, ..)| *id < resume);
+                    level.rotate_left(start);
+                }
+
+                let mut first_visit = true;
+                while !level.is_empty() {
+                    for (id, _, queue) in level.iter_mut() {
+                        let channel = self.channels.get_mut(id).unwrap();
+                        // a resumed channel already got its quantum for this round
+                        if !(first_visit && resume == Some(*id)) {
+                            channel.deficit += DRR_QUANTUM_BYTES;
+                        }
+                        first_visit = false;
+
+                        while let Some(&(sequence, index, len)) = queue.front() {
+                            if len > channel.deficit {
+                                break;
+                            }
+                            if len > budget {
+                                self.drr_resume[tier] = Some(*id);
+                                break 'levels;
+                            }
+
+                            channel.deficit -= len;
+                            budget -= len;
+                            scheduled.push((*id, sequence, index));
+                            queue.pop_front();
+                        }
+
+                        if queue.is_empty() {
+                            channel.deficit = 0;
+                        }
+                    }
+
+                    level.retain(|(_, _, queue)| !queue.is_empty());
+                }
+            }
         }
+
+        scheduled
     }
 
-    pub fn acknowledge(
-        &mut self, 
-        recv: SequenceNumber,
-        acked: SequenceNumber,
-        acked_mask: u64,
-    ) {
-        let gap;
-        match self.acks.latest_recv {
-            Some(latest_recv) => {
-                if recv <= latest_recv {
-                    // message is stale or duplicate
+    /// Returns the channel with the given `id`, creating it with the given guarantees if it
+    /// doesn't exist yet.
+    ///
+    /// This handles both channels opened locally and [`OpenChannel`](Frame::OpenChannel)
+    /// frames from the remote endpoint, which may be repeated if the first one was lost.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ChannelGuaranteesMismatch`](ErrorKind::ChannelGuaranteesMismatch) if the
+    /// channel already exists with different guarantees.
+    pub(crate) fn open_channel(
+        &mut self,
+        id: ChannelId,
+        send_guarantee: Send,
+        recv_guarantee: Receive,
+    ) -> Result<&mut Channel, ErrorKind> {
+        let channel = self
+            .channels
+            .entry(id)
+            .or_insert_with(|| Channel::new(id, send_guarantee, recv_guarantee));
+
+        if channel.send_guarantee != send_guarantee || channel.recv_guarantee != recv_guarantee {
+            return Err(ErrorKind::ChannelGuaranteesMismatch);
+        }
+
+        Ok(channel)
+    }
+
+    /// Closes the connection.
+    ///
+    /// The send window is flushed by the next [`Connections::poll`] and the remote endpoint is
+    /// told why with a [`Close`](Frame::Close) frame (see [`write_close`](Self::write_close)).
+    fn disconnect(&mut self, reason: DisconnectReason) {
+        self.events.push_back(ConnectionEvent::Disconnected(self.src_id, reason));
+        self.state = ConnectionState::Disconnecting(reason);
+        self.disconnect_reason = Some(reason);
+    }
+
+    /// Sends to the remote endpoint at `addr` from now on and pushes a
+    /// [`Migrated`](ConnectionEvent::Migrated) event.
+    ///
+    /// Must only be called for a packet that has been authenticated.
+    pub(crate) fn migrate(&mut self, addr: SocketAddr) {
+        self.events.push_back(ConnectionEvent::Migrated {
+            connection: self.src_id,
+            from: self.peer_addr,
+            to: addr,
+        });
+        self.peer_addr = addr;
+    }
+
+    /// Processes a [`Close`](Frame::Close) frame from the remote endpoint.
+    pub(crate) fn handle_close(&mut self, reason: DisconnectReason) {
+        if self.disconnect_reason.is_none() {
+            self.disconnect(reason.to_peer());
+            // the peer already knows
+            self.close_sent = true;
+        }
+    }
+
+    /// Drops the partially received messages that have waited longer than the
+    /// [`reassembly_timeout`](Config::reassembly_timeout), returning their buffers to `pool`.
+    ///
+    /// Returns the number of messages dropped.
+    pub(crate) fn expire_reassemblies(&mut self, time: Instant, pool: &mut BufferPool) -> usize {
+        let timeout = self.config.reassembly_timeout();
+        self.channels
+            .values_mut()
+            .map(|channel| channel.expire_reassemblies(time, timeout, pool))
+            .sum()
+    }
+
+    /// Gives up on the next message of each ordered channel that
+    /// [skips gaps](ChannelConfig::skip_gaps) once it has held up later ones for longer than the
+    /// [`head_of_line_timeout`](Config::head_of_line_timeout), returning its buffers to `pool`.
+    ///
+    /// Returns the number of messages skipped.
+    pub(crate) fn skip_gaps(&mut self, time: Instant, channel_config: &ChannelConfig, pool: &mut BufferPool) -> usize {
+        let timeout = self.config.head_of_line_timeout();
+        let mut ids = self
+            .channels
+            .keys()
+            .copied()
+            .filter(|&id| channel_config.skips_gaps(id))
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        let mut skipped = 0;
+        for id in ids {
+            // take the channel out so that both it and its connection can be borrowed
+            let mut channel = self.channels.remove(&id).unwrap();
+            if (ConnectionRef {
+                connection: &mut *self,
+                channel: &mut channel,
+                pool: &mut *pool,
+            })
+            .skip_gap(time, timeout)
+            {
+                skipped += 1;
+            }
+            self.channels.insert(id, channel);
+        }
+
+        skipped
+    }
+
+    /// Drops every message received but not yet read, returning its fragment buffers to `pool`.
+    pub(crate) fn clear_recv_buffers(&mut self, pool: &mut BufferPool) {
+        for channel in self.channels.values_mut() {
+            channel.clear_recv_buffer(pool);
+        }
+    }
+
+    /// Gives up on every message that hasn't been acknowledged yet, oldest first.
+    ///
+    /// Each message is removed from its channel's send buffer, its fragment buffers are returned
+    /// to `pool`, and a [`DeliveryLost`](ConnectionEvent::DeliveryLost) event is pushed for it.
+    pub(crate) fn flush_send_window(&mut self, pool: &mut BufferPool) {
+        let mut ids = self.channels.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+
+        for id in ids {
+            let channel = self.channels.get_mut(&id).unwrap();
+            let window = channel.send_buffer.capacity() as u64;
+            for offset in 0..window {
+                let sequence = channel.acks.next_send.wrapping_sub(window - offset);
+                let Some(message) = channel.send_buffer.remove(sequence) else {
+                    continue;
+                };
+
+                for (handle, ..) in message.fragment_data.iter().flatten() {
+                    let released = pool.release(*handle);
+                    debug_assert!(released.is_ok());
+                }
+                self.events.push_back(ConnectionEvent::DeliveryLost {
+                    connection: self.src_id,
+                    channel: id,
+                    sequence,
+                });
+            }
+            channel.acks.oldest_send_unacked = None;
+            channel.time_send_window_full = None;
+            channel.dup_acks = None;
+        }
+    }
+
+    /// Returns `true` if some message fragment is waiting to be sent (or resent).
+    pub(crate) fn has_pending_fragments(&self) -> bool {
+        self.channels
+            .values()
+            .any(|channel| channel.pending_fragments().next().is_some())
+    }
+
+    /// Returns `true` if [`max_packets_in_flight`](Config::max_packets_in_flight) packets have been
+    /// sent since the last one the remote endpoint acknowledged, or if the
+    /// [`congestion_window`](Self::congestion_window) is full.
+    pub(crate) fn in_flight_full(&self) -> bool {
+        self.acks.sent_packets_in_flight() >= self.config.max_packets_in_flight()
+            || self.bytes_in_flight() >= self.congestion_window
+    }
+
+    /// Returns `true` if there are acknowledgements the remote endpoint hasn't been sent yet
+    /// (or it sent a [`Ping`](Frame::Ping)) and the last packet carrying only acknowledgements
+    /// was sent at least [`ack_interval`](Config::ack_interval) ago.
+    pub(crate) fn ack_due(&self, time: Instant) -> bool {
+        let waited = self.time_latest_ack.map_or(true, |time_latest_ack| {
+            time.saturating_duration_since(time_latest_ack) >= self.config.ack_interval()
+        });
+        waited && (self.ping_pending || self.channels.values().any(Channel::ack_pending))
+    }
+
+    /// Returns the [`Ack`](Frame::Ack) frame for the packets received, or `None` if nothing
+    /// has been received yet.
+    pub(crate) fn ack_frame(&self) -> Option<Frame> {
+        Some(Frame::Ack {
+            ack_sequence: self.acks.ack_packet_number()?,
+            ack_mask: self.acks.ack_packet_mask() & ack_mask(self.config.ack_mask_bits()),
+        })
+    }
+
+    /// Writes a packet carrying only acknowledgements into `buf`: an [`Ack`](Frame::Ack) frame
+    /// for the packets received (once there are any), then a [`ChannelAck`](Frame::ChannelAck)
+    /// frame for each channel that has received messages since its last one, as many as fit.
+    ///
+    /// No channel sequence is used up, only a packet number.
+    pub(crate) fn write_ack(&mut self, buf: &mut BytesMut) -> io::Result<()> {
+        let header = Header::Short {
+            packet_number: self.acks.packet_number(),
+            packet_type: PacketType::Data,
+            dst_id: self.dst_id,
+        };
+        header.write(buf)?;
+
+        if let Some(ack) = self.ack_frame() {
+            ack.write(buf)?;
+            self.ping_pending = false;
+        }
+
+        let mut channel_ids = self.channels.keys().copied().collect::<Vec<_>>();
+        channel_ids.sort_unstable();
+        for channel_id in channel_ids {
+            let channel = self.channels.get_mut(&channel_id).unwrap();
+            if !channel.ack_pending() {
+                continue;
+            }
+            let Some(frame) = channel.ack_frame() else {
+                continue;
+            };
+            if buf.remaining() < frame.encoded_len() {
+                // the rest go in the next one
+                break;
+            }
+
+            frame.write(buf)?;
+            channel.on_ack_sent();
+        }
+
+        Ok(())
+    }
+
+    /// Writes a packet telling the remote endpoint why the connection was closed into `buf`.
+    ///
+    /// Returns `false` (and writes nothing) if the connection hasn't been closed.
+    pub(crate) fn write_close(&self, buf: &mut BytesMut) -> io::Result<bool> {
+        let Some(reason) = self.disconnect_reason else {
+            return Ok(false);
+        };
+
+        let header = Header::Short {
+            packet_number: self.acks.packet_number(),
+            packet_type: PacketType::Data,
+            dst_id: self.dst_id,
+        };
+        header.write(buf)?;
+        Frame::Close { reason }.write(buf)?;
+
+        Ok(true)
+    }
+
+    pub(crate) fn update(&mut self, time: Instant) {
+        // Check if connection token has expired (it only matters until we're connected).
+        let connecting = matches!(
+            self.state,
+            ConnectionState::Created | ConnectionState::Connecting(..) | ConnectionState::Authenticating(..)
+        );
+        if connecting && time >= self.token_expire_time() {
+            // send local event
+            self.disconnect(DisconnectReason::ConnectTokenExpired);
+            return;
+        }
+
+        match self.state {
+            // IP address can change while connecting and reconnecting
+            ConnectionState::Connecting(ref mut attempts, ref mut last_attempt) => {
+                // Has the last request gone unanswered for too long?
+                if time.saturating_duration_since(*last_attempt) >= self.config.request_timeout() {
+                    // Have we exhausted all of our connection attempts?
+                    if *attempts >= self.config.max_connection_attempts() {
+                        self.disconnect(DisconnectReason::ConnectionAttemptsExhausted);
+                        return;
+                    }
+
+                    // No? Then resend the request.
+                    self.pending_request = Some(Request::Connect);
+                    *last_attempt = time;
+                    *attempts += 1;
+                    return;
+                }
+            },
+            ConnectionState::Connected => {
+                // Have we timed out?
+                let latest_recv = self.time_latest_recv.unwrap_or(self.time_created);
+                if time.saturating_duration_since(latest_recv) >= self.config.idle_timeout() {
+                    self.disconnect(DisconnectReason::ConnectionIdleTimeout);
                     return;
                 }
-                
-                gap = recv - latest_recv;
-                if gap > self.recv_buffer.capacity() {
-                    // disconnect
+
+                // Has a send window been full for too long?
+                let send_window_timeout = self.config.send_window_timeout();
+                let jammed = self.channels.values().any(|channel| {
+                    channel.time_send_window_full.map_or(false, |time_full| {
+                        time.saturating_duration_since(time_full) >= send_window_timeout
+                    })
+                });
+                // Or has the remote endpoint stopped acknowledging packets?
+                let stalled = self.time_in_flight_full.map_or(false, |time_full| {
+                    time.saturating_duration_since(time_full) >= send_window_timeout
+                });
+                if jammed || stalled {
+                    self.disconnect(DisconnectReason::SendBufferIsFull);
                     return;
                 }
+
+                // Have we been losing too many messages for too long?
+                if self.packet_loss() > self.config.packet_loss_threshold() {
+                    let time_exceeded = *self.time_packet_loss_exceeded.get_or_insert(time);
+                    if time.saturating_duration_since(time_exceeded) >= self.config.packet_loss_timeout() {
+                        self.disconnect(DisconnectReason::ExcessivePacketLoss);
+                        return;
+                    }
+                } else {
+                    self.time_packet_loss_exceeded = None;
+                }
+
+                // Have any reliable messages gone unacknowledged for too long?
+                let rto = self.rto();
+                for channel in self.channels.values_mut() {
+                    channel.expire_unacked(time, rto);
+                }
+
+                // Do we have any packets to send?
+                // No? Is it time to send another keep-alive packet?
+            },
+            ConnectionState::Disconnecting(_) => {
+                // the Close frame is sent by Connections::poll_transmit
+                self.state = ConnectionState::Disconnected(time + self.config.linger_time());
+            },
+            ConnectionState::Disconnected(_) => {
+                // removed (and its id freed) by Connections::poll once it has lingered
+            },
+            _ => {},
+        }
+    }
+
+    /// Opens the payload of a packet received from the remote endpoint (in place) and returns its
+    /// length.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the payload cannot be authenticated. The connection is also
+    /// disconnected with [`EncryptionInvalid`](DisconnectReason::EncryptionInvalid).
+    pub(crate) fn open_payload(
+        &mut self,
+        protector: &dyn PacketProtector,
+        header: &Header,
+        payload: &mut [u8],
+    ) -> io::Result<usize> {
+        protector.open(header, payload).map_err(|err| {
+            self.disconnect(DisconnectReason::EncryptionInvalid);
+            err
+        })
+    }
+
+    /// Writes a packet probing the path MTU into `buf`, padded to fill it, and remembers its
+    /// packet number until an acknowledgement says whether it arrived (see
+    /// [`on_mtu_probe_ack`](Self::on_mtu_probe_ack)).
+    ///
+    /// The size of `buf` should be the candidate size [`MtuDiscovery::poll`] returned (minus
+    /// the IP and UDP headers, and the protector's tag).
+    pub(crate) fn write_mtu_probe(&mut self, buf: &mut BytesMut) -> io::Result<()> {
+        let packet_number = self.acks.packet_number();
+        let header = Header::Short {
+            packet_number,
+            packet_type: PacketType::Data,
+            dst_id: self.dst_id,
+        };
+        header.write(buf)?;
+        if let Some(ack) = self.ack_frame() {
+            ack.write(buf)?;
+            self.ping_pending = false;
+        }
+        // the ping makes sure the probe is acknowledged
+        Frame::Ping.write(buf)?;
+        ConnectionRef::write_padding(buf, usize::MAX)?;
+
+        self.mtu_probe = Some(packet_number);
+        Ok(())
+    }
+
+    /// Reports the MTU probe in flight to [`path_mtu`](Self::path_mtu) as acknowledged or lost,
+    /// once an [`Ack`](Frame::Ack) frame of the packets up to `ack_sequence` covers it.
+    ///
+    /// A probe further behind `ack_sequence` than the mask reaches is treated as lost.
+    pub(crate) fn on_mtu_probe_ack(&mut self, ack_sequence: u64, ack_mask: u64) {
+        let Some(probe) = self.mtu_probe else {
+            return;
+        };
+        if sequence_greater_than(probe, ack_sequence) {
+            // not far enough along to say
+            return;
+        }
+
+        let gap = ack_sequence.wrapping_sub(probe);
+        if gap < self.config.ack_mask_bits() as u64 && ack_mask & (1 << gap) != 0 {
+            self.path_mtu.on_probe_acked();
+        } else {
+            self.path_mtu.on_probe_lost();
+        }
+        self.mtu_probe = None;
+    }
+
+    /// Starts connecting to the remote endpoint.
+    ///
+    /// A [`Request::Connect`] handshake is queued to be sent by [`Connections::poll_transmit`].
+    pub(crate) fn connect(&mut self, time: Instant) {
+        self.state = ConnectionState::Connecting(1, time);
+        self.pending_request = Some(Request::Connect);
+    }
+
+    /// Writes a handshake packet carrying `request` into `buf`.
+    pub(crate) fn write_handshake(&self, request: Request, buf: &mut BytesMut) -> io::Result<()> {
+        let header = Header::Long {
+            // handshake packets aren't acknowledged
+            packet_number: 0,
+            packet_type: PacketType::Handshake,
+            version: PROTOCOL_VERSION_CHECKSUM,
+            src_id: self.src_id,
+            dst_id: self.dst_id,
+        };
+
+        header.write(buf)?;
+        Handshake {
+            connection_id: self.src_id,
+            request,
+        }
+        .write(buf)
+    }
+
+    /// Reads the handshake payload that follows a [`Header::Long`] carrying the `version`
+    /// checksum and processes its request.
+    ///
+    /// Returns the request that should be sent back, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the payload is malformed. If the peer is using a different protocol
+    /// version, the connection is also disconnected with
+    /// [`ProtocolVersionInvalid`](DisconnectReason::ProtocolVersionInvalid).
+    pub(crate) fn recv_handshake(
+        &mut self,
+        version: u32,
+        buf: &mut BytesMut,
+        accept_policy: &mut dyn AcceptPolicy,
+    ) -> io::Result<Option<Request>> {
+        if version != PROTOCOL_VERSION_CHECKSUM {
+            self.disconnect(DisconnectReason::ProtocolVersionInvalid);
+            return Err(io::Error::new_const(io::ErrorKind::Unsupported, &"protocol version mismatch"));
+        }
+        let handshake = Handshake::read(buf)?;
+
+        match (self.state, handshake.request) {
+            (ConnectionState::Created | ConnectionState::Connecting(..), Request::Connect | Request::Accept) => {
+                // the id the peer wants us to address it by
+                self.dst_id = handshake.connection_id;
+            },
+            // once connected, the peer can only repeat itself
+            (ConnectionState::Connected, _) if handshake.connection_id != self.dst_id => {
+                return Ok(None);
+            },
+            _ => {},
+        }
+
+        // a server asks its policy before letting a new peer in
+        if let (Role::Server, ConnectionState::Created, Request::Connect) = (self.role, self.state, handshake.request) {
+            if let Err(reason) = accept_policy.accept(self.peer_addr, buf.as_ref()) {
+                self.disconnect(reason);
+                // the denial says why
+                self.close_sent = true;
+                return Ok(Some(Request::Deny(reason)));
             }
-            None => {
-                gap = 0;
+            self.accepted = true;
+        }
+
+        Ok(self.handle_request(handshake.request))
+    }
+
+    /// Processes a request from the remote endpoint.
+    ///
+    /// Returns the request that should be sent back, if any.
+    pub(crate) fn handle_request(&mut self, request: Request) -> Option<Request> {
+        // ignore requests coming from disconnected connections
+        if let Request::Disconnect = request {
+            if self.state == ConnectionState::Connected {
+                self.disconnect(DisconnectReason::PeerClosed);
             }
+            return None;
+        }
+
+        match (self.role, self.state, request) {
+            (Role::Client, ConnectionState::Connecting(..), Request::Accept) => {
+                self.events.push_back(ConnectionEvent::Connected(self.src_id));
+                self.state = ConnectionState::Connected;
+                None
+            },
+            (Role::Client, ConnectionState::Connecting(..), Request::Deny(reason)) => {
+                self.disconnect(reason);
+                None
+            },
+            (Role::Server, ConnectionState::Created, Request::Connect) => {
+                // TODO: Authentication
+                self.events.push_back(ConnectionEvent::Connected(self.src_id));
+                self.state = ConnectionState::Connected;
+                Some(Request::Accept)
+            },
+            (Role::Server, ConnectionState::Connected, Request::Connect) => {
+                // The requester has not received our acceptance.
+                Some(Request::Accept)
+            },
+            _ => {
+                // requests that don't apply to the current state (e.g. duplicates) are ignored,
+                // a peer must not be able to crash us
+                None
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Send {
+    Unreliable,
+    /// Lost fragments are resent until the message is acknowledged.
+    ///
+    /// On a [`Sequenced`](Receive::Sequenced) channel, only the latest message is guaranteed
+    /// to arrive. Sending a message cancels the older ones that haven't been acknowledged yet,
+    /// so they aren't resent (and neither delivery event is reported for them).
+    Reliable,
+}
+
+impl Send {
+    /// Returns the byte that represents this guarantee on the wire.
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            Send::Unreliable => 0x00,
+            Send::Reliable => 0x01,
+        }
+    }
+
+    /// Returns the guarantee represented by `byte`, or `None` if it's unknown.
+    pub(crate) fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            0x00 => Some(Send::Unreliable),
+            0x01 => Some(Send::Reliable),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Receive {
+    Unordered,
+    Sequenced,
+    Ordered,
+}
+
+impl Receive {
+    /// Returns the byte that represents this guarantee on the wire.
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            Receive::Unordered => 0x00,
+            Receive::Sequenced => 0x01,
+            Receive::Ordered => 0x02,
+        }
+    }
+
+    /// Returns the guarantee represented by `byte`, or `None` if it's unknown.
+    pub(crate) fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            0x00 => Some(Receive::Unordered),
+            0x01 => Some(Receive::Sequenced),
+            0x02 => Some(Receive::Ordered),
+            _ => None,
+        }
+    }
+}
+
+pub struct SendInfo {
+    addr: SocketAddr,
+    time: Instant,
+    delivered: bool,
+}
+
+pub struct RecvInfo {
+    addr: SocketAddr,
+    time: Instant,
+    delivered: bool,
+}
+
+/// Tracks which messages have been sent to and received from the remote endpoint on a channel.
+///
+/// This is channel-level state. Messages are numbered per channel and acknowledged once all of
+/// their fragments have arrived, independently of the packets that carried them (see
+/// [`PacketAcknowledgment`]).
+pub struct ChannelAcknowledgment {
+    pub(crate) next_send: SequenceNumber,
+    pub(crate) latest_recv: Option<SequenceNumber>,
+    pub(crate) latest_recv_mask: u64,
+    /// The number of bits of [`latest_recv_mask`](Self::latest_recv_mask) in use.
+    pub(crate) latest_recv_mask_bits: usize,
+    pub(crate) latest_send_acked: Option<SequenceNumber>,
+    pub(crate) oldest_send_unacked: Option<SequenceNumber>,
+    pub(crate) next_recv_ordered: Option<SequenceNumber>,
+    /// The `(latest_recv, latest_recv_mask)` last sent to the remote endpoint.
+    pub(crate) latest_ack_sent: Option<(SequenceNumber, u64)>,
+}
+
+impl ChannelAcknowledgment {
+    pub fn new() -> Self {
+        // TODO: start at somewhat random values?
+        ChannelAcknowledgment {
+            next_send: 0,
+            latest_recv: None,
+            latest_recv_mask: 0,
+            latest_recv_mask_bits: REDUNDANT_ACK_MASK_BITS,
+            latest_send_acked: None,
+            oldest_send_unacked: None,
+            next_recv_ordered: None,
+            latest_ack_sent: None,
+        }
+    }
+    
+    /// The next packet to send to the remote endpoint of this channel.
+    pub fn sequence(&self) -> SequenceNumber {
+        self.next_send
+    }
+    
+    /// Bit array of the last [`ack_mask_bits`](Config::ack_mask_bits) messages received from
+    /// the remote endpoint on this channel (bit `n` is set if `latest_recv - n` was received).
+    pub fn latest_recv_mask(&self) -> u64 {
+        self.latest_recv_mask
+    }
+
+    /// The last packet we received from the remote endpoint on this channel.
+    pub fn latest_recv(&self) -> Option<SequenceNumber> {
+        self.latest_recv
+    }
+    
+    pub fn latest_send_acked(&self) -> Option<SequenceNumber> {
+        self.latest_send_acked
+    }
+
+    pub fn next_recv_ordered(&self) -> Option<SequenceNumber> {
+        self.next_recv_ordered
+    }
+
+    pub fn oldest_send_unacked(&self) -> Option<SequenceNumber> {
+        self.oldest_send_unacked
+    }
+
+    /// Records that the message `sequence` has been received (in full) and returns the
+    /// previous [`latest_recv`](Self::latest_recv).
+    ///
+    /// Only the `mask_bits` newest messages are tracked in the mask, so a message further
+    /// behind than that isn't acknowledged again.
+    pub(crate) fn record_recv(
+        &mut self,
+        sequence: SequenceNumber,
+        mask_bits: usize,
+    ) -> Option<SequenceNumber> {
+        let prev_recv = self.latest_recv;
+        self.latest_recv_mask_bits = mask_bits;
+        match prev_recv {
+            None => {
+                self.latest_recv = Some(sequence);
+                self.latest_recv_mask = 1;
+            },
+            Some(latest_recv) if sequence_greater_than(sequence, latest_recv) => {
+                let gap = sequence.wrapping_sub(latest_recv);
+                self.latest_recv = Some(sequence);
+                self.latest_recv_mask = if gap >= mask_bits as u64 {
+                    1
+                } else {
+                    ((self.latest_recv_mask << gap) | 1) & ack_mask(mask_bits)
+                };
+            },
+            Some(latest_recv) => {
+                let gap = latest_recv.wrapping_sub(sequence);
+                if gap < mask_bits as u64 {
+                    self.latest_recv_mask |= 1 << gap;
+                }
+            },
+        }
+
+        prev_recv
+    }
+}
+
+pub struct RecvMessage {
+    pub(crate) sequence: u64,
+    pub(crate) fragment_count: u16,
+    pub(crate) fragment_recv: u16,
+    /// The buffer, start, and end of each fragment, one per fragment.
+    pub(crate) fragment_data: Box<[Option<(BufferHandle, usize, usize)>]>,
+    pub(crate) time_created: Instant,
+    pub(crate) time_recv: Option<Instant>,
+}
+
+pub struct SendMessage {
+    pub(crate) sequence: u64,
+    pub(crate) fragment_count: u16,
+    pub(crate) fragment_sent: u16,
+    /// The buffer, start, and length of each fragment's frame, one per fragment.
+    pub(crate) fragment_data: Box<[Option<(BufferHandle, usize, usize)>]>,
+    pub(crate) fragment_status: Box<[SendStatus]>,
+    pub(crate) time_created: Instant,
+    pub(crate) time_sent: Option<Instant>,
+    /// When a fragment of this message was last sent (or resent).
+    pub(crate) time_latest_send: Option<Instant>,
+    /// The number of times in a row this message has timed out.
+    pub(crate) retransmits: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendStatus {
+    Unsent,
+    Sent,
+    Delivered,
+    Lost,
+}
+
+pub struct Channel {
+    pub(crate) id: u64,
+    pub(crate) acks: ChannelAcknowledgment,
+    pub(crate) send_guarantee: Send, 
+    pub(crate) recv_guarantee: Receive,
+    pub(crate) send_buffer: SequenceBuffer<SendMessage>,
+    pub(crate) recv_buffer: SequenceBuffer<RecvMessage>,
+    /// Sequences of complete messages that are ready to be delivered, in delivery order.
+    pub(crate) recv_queue: VecDeque<SequenceNumber>,
+    /// The only incomplete message a sequenced channel keeps (older ones are released as soon
+    /// as a fragment of a newer one arrives).
+    pub(crate) recv_partial: Option<SequenceNumber>,
+    /// The complete messages received within the window of an unordered channel, so that
+    /// duplicates aren't delivered again once they've left the receive buffer.
+    pub(crate) recv_completed: SequenceBuffer<()>,
+    /// When the send window filled up, if it still is.
+    pub(crate) time_send_window_full: Option<Instant>,
+    /// When the next message of an ordered channel started holding up later ones, if it still
+    /// is.
+    pub(crate) time_head_blocked: Option<Instant>,
+    /// The oldest message that acknowledgements keep reporting as missing, and how many
+    /// duplicates of that report have arrived.
+    pub(crate) dup_acks: Option<(SequenceNumber, usize)>,
+    pub(crate) time_latest_send: Option<Instant>,
+    pub(crate) time_latest_recv: Option<Instant>,
+    /// The number of bytes this channel can still send in the current round of
+    /// [`Connection::schedule`].
+    pub(crate) deficit: usize,
+    /// Channels with a higher priority send before those with a lower one (see
+    /// [`ChannelConfig::channel_with_priority`]).
+    pub(crate) priority: u8,
+    // TODO: add statistics (# messages sent, received, etc.)
+}
+
+impl Channel {
+    pub fn new(id: ChannelId, send_guarantee: Send, recv_guarantee: Receive) -> Self {
+        Self {
+            id,
+            send_guarantee,
+            recv_guarantee,
+            acks: ChannelAcknowledgment::new(),
+            send_buffer: SequenceBuffer::with_capacity(DEFAULT_SEND_WINDOW_SIZE),
+            recv_buffer: SequenceBuffer::with_capacity(DEFAULT_SEND_WINDOW_SIZE),
+            recv_queue: VecDeque::new(),
+            recv_partial: None,
+            recv_completed: SequenceBuffer::with_capacity(DEFAULT_SEND_WINDOW_SIZE),
+            time_send_window_full: None,
+            time_head_blocked: None,
+            dup_acks: None,
+            time_latest_send: None,
+            time_latest_recv: None,
+            deficit: 0,
+            priority: 0,
+        }
+    }
+
+    /// Returns `true` if a reliable channel can't send another message without overwriting one
+    /// that hasn't been acknowledged yet (i.e. the oldest unacknowledged message is a whole send
+    /// window behind).
+    pub fn send_window_full(&self) -> bool {
+        if self.send_guarantee != Send::Reliable {
+            return false;
+        }
+
+        let index = self.send_buffer.index_of(self.acks.next_send);
+        self.send_buffer.get_index(index).1.is_some()
+    }
+
+    /// Returns `(sequence, fragment index, len)` for each fragment that is waiting to be sent,
+    /// oldest first.
+    pub(crate) fn pending_fragments(&self) -> impl Iterator<Item = (SequenceNumber, u16, usize)> + '_ {
+        let window = self.send_buffer.capacity() as u64;
+        let next_send = self.acks.next_send;
+        (0..window)
+            .map(move |offset| next_send.wrapping_sub(window - offset))
+            .filter_map(|sequence| self.send_buffer.get(sequence)?.as_ref())
+            .flat_map(|message| {
+                let fragment_count = message.fragment_count as usize;
+                (0..fragment_count).filter_map(move |index| {
+                    match message.fragment_status[index] {
+                        SendStatus::Unsent | SendStatus::Lost => {
+                            let (_, _, len) = message.fragment_data[index]?;
+                            Some((message.sequence, index as u16, len))
+                        },
+                        SendStatus::Sent | SendStatus::Delivered => None,
+                    }
+                })
+            })
+    }
+
+    /// Returns the length of fragment `index` of message `sequence` if it's waiting to be sent
+    /// (or resent).
+    pub(crate) fn fragment_len(&self, sequence: SequenceNumber, index: u16) -> Option<usize> {
+        let message = self.send_buffer.get(sequence)?.as_ref()?;
+        match message.fragment_status.get(index as usize)? {
+            SendStatus::Unsent | SendStatus::Lost => {
+                let (_, _, len) = message.fragment_data[index as usize]?;
+                Some(len)
+            },
+            SendStatus::Sent | SendStatus::Delivered => None,
+        }
+    }
+
+    /// Returns `(sequence, fragment index)` for each fragment of a reliable channel that has to
+    /// be sent, starting from the oldest message whose delivery hasn't been confirmed.
+    ///
+    /// Lost fragments come before those that have never been sent, so that a message that is
+    /// holding up delivery gets through as soon as possible.
+    pub(crate) fn resend_order(&self) -> Vec<(SequenceNumber, u16)> {
+        let next_send = self.acks.next_send;
+        let start = self
+            .acks
+            .oldest_send_unacked
+            .unwrap_or_else(|| next_send.wrapping_sub(self.send_buffer.capacity() as u64));
+
+        let mut lost = Vec::new();
+        let mut unsent = Vec::new();
+        for offset in 0..sequence_distance(next_send, start).max(0) {
+            let sequence = start.wrapping_add(offset as u64);
+            if let Some(Some(message)) = self.send_buffer.get(sequence) {
+                for index in 0..message.fragment_count {
+                    match message.fragment_status[index as usize] {
+                        SendStatus::Lost => lost.push((sequence, index)),
+                        SendStatus::Unsent => unsent.push((sequence, index)),
+                        SendStatus::Sent | SendStatus::Delivered => {},
+                    }
+                }
+            }
+        }
+
+        lost.append(&mut unsent);
+        lost
+    }
+
+    /// Returns the messages in the receive window that haven't been received in full, oldest
+    /// first. These are the messages a negative acknowledgement would ask for.
+    ///
+    /// On an ordered channel, the window runs from [`next_recv_ordered`] up to [`latest_recv`].
+    /// On an unordered channel, messages are handed out as they complete, so only the ones the
+    /// acknowledgement mask still covers can be told apart from the ones that never arrived.
+    /// A sequenced channel doesn't wait for older messages, so none of them are missing.
+    ///
+    /// [`next_recv_ordered`]: ChannelAcknowledgment::next_recv_ordered
+    /// [`latest_recv`]: ChannelAcknowledgment::latest_recv
+    pub fn missing_sequences(&self) -> impl Iterator<Item = SequenceNumber> + '_ {
+        let (start, latest_recv) = match self.acks.latest_recv {
+            Some(latest_recv) => {
+                let start = match self.recv_guarantee {
+                    Receive::Unordered => {
+                        let window = self.recv_buffer.capacity().min(self.acks.latest_recv_mask_bits);
+                        // nothing was sent before the first message
+                        latest_recv.saturating_sub(window as u64 - 1)
+                    },
+                    Receive::Ordered => self.acks.next_recv_ordered.unwrap_or(0),
+                    Receive::Sequenced => latest_recv,
+                };
+                (start, latest_recv)
+            },
+            None => (0, 0),
+        };
+
+        // the latest message is complete, or it wouldn't be the latest
+        let len = sequence_distance(latest_recv, start).max(0) as u64;
+        (0..len)
+            .map(move |offset| start.wrapping_add(offset))
+            .filter(move |&sequence| !self.has_received(sequence))
+    }
+
+    /// Returns `true` if the message `sequence` has been received in full, going by the
+    /// acknowledgement mask and the receive buffer.
+    fn has_received(&self, sequence: SequenceNumber) -> bool {
+        let acks = &self.acks;
+        let acked = acks.latest_recv.map_or(false, |latest_recv| {
+            let gap = latest_recv.wrapping_sub(sequence);
+            gap < acks.latest_recv_mask_bits as u64 && acks.latest_recv_mask & (1 << gap) != 0
+        });
+
+        acked
+            || matches!(
+                self.recv_buffer.get(sequence),
+                Some(Some(message)) if message.fragment_recv == message.fragment_count
+            )
+    }
+
+    /// Returns the oldest message the receive buffer still has to hold on to when the message
+    /// `sequence` arrives. Older messages can be evicted to make room for it.
+    pub(crate) fn recv_window_floor(&self, sequence: SequenceNumber) -> SequenceNumber {
+        match self.recv_guarantee {
+            Receive::Unordered => {
+                let newest = match self.acks.latest_recv {
+                    Some(latest_recv) if sequence_greater_than(latest_recv, sequence) => latest_recv,
+                    _ => sequence,
+                };
+                newest.wrapping_sub(self.recv_buffer.capacity() as u64 - 1)
+            },
+            // everything before the next message to deliver has been delivered
+            Receive::Ordered => self.acks.next_recv_ordered.unwrap_or(0),
+            // only the newest message matters
+            Receive::Sequenced => sequence,
+        }
+    }
+
+    /// Writes fragment `index` of message `sequence` into `packet` as a [`Data`](Frame::Data)
+    /// frame followed by its bytes (from `pool`), and marks it as sent.
+    ///
+    /// A message's `time_sent` is set once all of its fragments have been sent at least once.
+    /// Returns `false` (and writes nothing) if the fragment doesn't fit.
+    pub(crate) fn write_fragment(
+        &mut self,
+        pool: &BufferPool,
+        packet: &mut BytesMut,
+        sequence: SequenceNumber,
+        index: u16,
+        instant: Instant,
+    ) -> io::Result<bool> {
+        let fragment = self
+            .send_buffer
+            .get_mut(sequence)
+            .and_then(Option::as_mut)
+            .and_then(|message| Some((message.fragment_data.get(index as usize).copied().flatten()?, message)));
+        let Some(((handle, start, len), message)) = fragment else {
+            return Err(io::Error::new_const(io::ErrorKind::NotFound, &"no such fragment"));
+        };
+        let frame = Frame::Data {
+            channel_id: self.id,
+            channel_sequence: sequence,
+            fragment_index: index,
+            fragment_count: message.fragment_count,
+            len: len as u16,
+        };
+        if packet.remaining() < frame.encoded_len() + len {
+            return Ok(false);
+        }
+
+        frame.write(packet)?;
+
+        let src = unsafe { MaybeUninit::slice_assume_init_ref(pool.get(handle).unwrap()) };
+        packet.copy_from_slice(&src[start..(start + len)])?;
+
+        let status = &mut message.fragment_status[index as usize];
+        if *status == SendStatus::Unsent {
+            message.fragment_sent += 1;
+        }
+        *status = SendStatus::Sent;
+        message.time_latest_send = Some(instant);
+
+        if message.fragment_sent == message.fragment_count && message.time_sent.is_none() {
+            message.time_sent = Some(instant);
+        }
+
+        Ok(true)
+    }
+
+    /// Marks the sent fragments of each reliable message that has gone unacknowledged for longer
+    /// than its retransmission timeout as lost, so that they get resent.
+    ///
+    /// The timeout starts at `rto` and doubles every time the same message times out (up to
+    /// [`MAX_RTO`]). Returns the number of messages that timed out.
+    pub(crate) fn expire_unacked(&mut self, time: Instant, rto: Duration) -> usize {
+        if self.send_guarantee != Send::Reliable {
+            return 0;
+        }
+
+        let mut expired = 0;
+        let window = self.send_buffer.capacity() as u64;
+        for offset in 0..window {
+            let sequence = self.acks.next_send.wrapping_sub(window - offset);
+            let Some(Some(message)) = self.send_buffer.get_mut(sequence) else {
+                continue;
+            };
+            let Some(time_latest_send) = message.time_latest_send else {
+                continue;
+            };
+            if time.saturating_duration_since(time_latest_send) <= backoff(rto, message.retransmits) {
+                continue;
+            }
+
+            let mut lost = false;
+            for status in message.fragment_status.iter_mut() {
+                if *status == SendStatus::Sent {
+                    *status = SendStatus::Lost;
+                    lost = true;
+                }
+            }
+
+            // don't back off again until the lost fragments have been resent
+            if lost {
+                message.retransmits += 1;
+                expired += 1;
+            }
+        }
+
+        expired
+    }
+
+    /// Drops each partially received message that has been waiting for the rest of its fragments
+    /// for longer than `timeout`, returning its fragment buffers to `pool`.
+    ///
+    /// Returns the number of messages dropped.
+    pub(crate) fn expire_reassemblies(&mut self, time: Instant, timeout: Duration, pool: &mut BufferPool) -> usize {
+        let mut expired = 0;
+        for index in 0..self.recv_buffer.capacity() {
+            let (_, Some(message)) = self.recv_buffer.get_index(index) else {
+                continue;
+            };
+            if message.fragment_recv >= message.fragment_count
+                || time.saturating_duration_since(message.time_created) <= timeout
+            {
+                continue;
+            }
+
+            let (Some(sequence), Some(message)) = self.recv_buffer.remove_index(index) else {
+                continue;
+            };
+            for (handle, ..) in message.fragment_data.iter().flatten() {
+                let released = pool.release(*handle);
+                debug_assert!(released.is_ok());
+            }
+            if self.recv_partial == Some(sequence) {
+                self.recv_partial = None;
+            }
+            expired += 1;
+        }
+
+        expired
+    }
+
+    /// Drops the message `sequence` from the send buffer before it's sent, returning its
+    /// fragment buffers to `pool`. Returns `true` if the message was cancelled.
+    ///
+    /// A reliable message can only be cancelled while none of its fragments have been sent,
+    /// since the remote endpoint would keep waiting for the rest. If the remote endpoint
+    /// delivers messages in order, it also has to be the newest one, or the ones after it
+    /// would never be delivered. An unreliable message can be cancelled until all of its
+    /// fragments have been sent (the ones already sent are never completed, as if the others
+    /// were lost).
+    pub fn cancel(&mut self, sequence: SequenceNumber, pool: &mut BufferPool) -> bool {
+        let Some(Some(message)) = self.send_buffer.get(sequence) else {
+            return false;
+        };
+
+        let unsent = message.fragment_status.iter().all(|&status| status == SendStatus::Unsent);
+        let newest = sequence == self.acks.next_send.wrapping_sub(1);
+        let cancellable = match self.send_guarantee {
+            Send::Reliable => unsent && (newest || self.recv_guarantee != Receive::Ordered),
+            Send::Unreliable => message.fragment_status.contains(&SendStatus::Unsent),
+        };
+        if !cancellable {
+            return false;
+        }
+
+        let message = self.send_buffer.remove(sequence).unwrap();
+        for (handle, ..) in message.fragment_data.iter().flatten() {
+            let released = pool.release(*handle);
+            debug_assert!(released.is_ok());
+        }
+        // the remote endpoint hasn't seen any of it, so the next message can take its place
+        if unsent && newest {
+            self.acks.next_send = sequence;
+        }
+        self.update_oldest_send_unacked();
+        if !self.send_window_full() {
+            self.time_send_window_full = None;
+        }
+
+        true
+    }
+
+    /// Moves [`oldest_send_unacked`](ChannelAcknowledgment::oldest_send_unacked) up to the
+    /// oldest message still in the send buffer (or `None` if it's empty).
+    pub(crate) fn update_oldest_send_unacked(&mut self) {
+        let next_send = self.acks.next_send;
+        let window = self.send_buffer.capacity() as u64;
+        let start = self.acks.oldest_send_unacked.unwrap_or_else(|| next_send.wrapping_sub(window));
+        let count = sequence_distance(next_send, start).clamp(0, window as i64) as u64;
+
+        self.acks.oldest_send_unacked = (0..count)
+            .map(|offset| start.wrapping_add(offset))
+            .find(|&sequence| matches!(self.send_buffer.get(sequence), Some(Some(_))));
+    }
+
+    /// Drops every message received on this channel, returning its fragment buffers to `pool`.
+    pub(crate) fn clear_recv_buffer(&mut self, pool: &mut BufferPool) {
+        self.recv_buffer.clear(|_, message| {
+            for (handle, ..) in message.fragment_data.iter().flatten() {
+                let released = pool.release(*handle);
+                debug_assert!(released.is_ok());
+            }
+        });
+        self.recv_completed.clear(|_, _| {});
+        self.recv_queue.clear();
+        self.recv_partial = None;
+    }
+
+    /// Returns the frame that tells the remote endpoint to open this channel.
+    pub fn open_frame(&self) -> Frame {
+        Frame::OpenChannel {
+            channel_id: self.id,
+            send_guarantee: self.send_guarantee,
+            recv_guarantee: self.recv_guarantee,
+        }
+    }
+
+    /// Returns the frame that acknowledges the messages received on this channel, or `None` if
+    /// nothing has been received yet.
+    pub fn ack_frame(&self) -> Option<Frame> {
+        Some(Frame::ChannelAck {
+            channel_id: self.id,
+            ack_sequence: self.acks.latest_recv?,
+            ack_mask: self.acks.latest_recv_mask,
+        })
+    }
+
+    /// Returns `true` if messages have been received since the last acknowledgement was sent.
+    pub(crate) fn ack_pending(&self) -> bool {
+        self.acks
+            .latest_recv
+            .map_or(false, |latest_recv| self.acks.latest_ack_sent != Some((latest_recv, self.acks.latest_recv_mask)))
+    }
+
+    /// Records that the [`ack_frame`](Self::ack_frame) has been sent to the remote endpoint.
+    pub(crate) fn on_ack_sent(&mut self) {
+        self.acks.latest_ack_sent = self.acks.latest_recv.map(|latest_recv| (latest_recv, self.acks.latest_recv_mask));
+    }
+
+    /// Processes an acknowledgement from the remote endpoint (a
+    /// [`ChannelAck`](Frame::ChannelAck) frame for this channel), removing the messages it
+    /// confirms (or implies were lost) from the send buffer.
+    ///
+    /// `acked` is the newest message the remote endpoint has received and `acked_mask` says
+    /// which of the ones before it it has received too. Acknowledgements that aren't newer than
+    /// the last one are ignored.
+    ///
+    /// On a reliable channel, messages it implies were lost stay in the send buffer. Their
+    /// fragments are only marked as lost (to be resent) once `dup_ack_threshold` more
+    /// acknowledgements have reported the same oldest missing message (fast retransmit).
+    ///
+    /// Only the low `ack_mask_bits` bits of `acked_mask` are read. A message further behind
+    /// `acked` than that is treated as lost.
+    ///
+    /// Pushes a delivery event for each message onto `events`. Returns the round-trip time
+    /// sample of the newest message confirmed as delivered (if there is one) and the total size
+    /// of the messages confirmed as delivered (in bytes).
+    pub fn acknowledge(
+        &mut self,
+        acked: SequenceNumber,
+        acked_mask: u64,
+        dup_ack_threshold: usize,
+        ack_mask_bits: usize,
+        instant: Instant,
+        connection: ConnectionId,
+        events: &mut VecDeque<ConnectionEvent>,
+    ) -> (Option<Duration>, usize) {
+        if let Some(latest_send_acked) = self.acks.latest_send_acked {
+            if !sequence_greater_than(acked, latest_send_acked) {
+                // acknowledgement is stale or duplicate
+                return (None, 0);
+            }
+
+            if acked.wrapping_sub(latest_send_acked) > self.send_buffer.capacity() as u64 {
+                // disconnect
+                return (None, 0);
+            }
+        }
+
+        if !sequence_greater_than(self.acks.next_send, acked) {
+            // the remote endpoint can't have received a message we haven't sent
+            return (None, 0);
+        }
+
+        self.acks.latest_send_acked = Some(acked);
+
+        let window = self.send_buffer.capacity() as u64;
+        let start = self
+            .acks
+            .oldest_send_unacked
+            .unwrap_or_else(|| self.acks.next_send.wrapping_sub(window));
+        let mut rtt_sample = None;
+        let mut delivered_bytes = 0;
+        let mut missing = Vec::new();
+
+        // If `acked` is behind `start`, all unacknowledged messages in flight are newer. Either
+        // way, no more than the send buffer can hold are looked at.
+        let count = (sequence_distance(acked, start) + 1).clamp(0, window as i64) as u64;
+        for offset in 0..count {
+            let sequence = start.wrapping_add(offset);
+            if let Some(Some(message)) = self.send_buffer.get_mut(sequence) {
+                let gap = acked.wrapping_sub(sequence);
+                if (gap >= ack_mask_bits as u64) || ((acked_mask & (1 << gap)) == 0) {
+                    // Packet was *probably* lost.
+                    if self.send_guarantee == Send::Reliable {
+                        // keep the message around so its fragments get resent
+                        missing.push(sequence);
+                        continue;
+                    }
+
+                    events.push_back(ConnectionEvent::DeliveryLost {
+                        connection,
+                        channel: self.id,
+                        sequence,
+                    });
+                } else {
+                    // Packet was delivered.
+                    events.push_back(ConnectionEvent::DeliveryConfirmed {
+                        connection,
+                        channel: self.id,
+                        sequence,
+                    });
+                    delivered_bytes += message
+                        .fragment_data
+                        .iter()
+                        .flatten()
+                        .map(|&(_, _, len)| len)
+                        .sum::<usize>();
+
+                    // A send time later than now means the clock went backwards, so ignore it.
+                    if let Some(sample) = message
+                        .time_sent
+                        .and_then(|time_sent| instant.checked_duration_since(time_sent))
+                    {
+                        rtt_sample = Some(sample);
+                    }
+                }
+    
+                self.send_buffer.remove(sequence);
+            }
+        }
+        self.update_oldest_send_unacked();
+
+        // count the acknowledgements that report the same hole
+        self.dup_acks = match (missing.first(), self.dup_acks) {
+            (None, _) => None,
+            (Some(&hole), Some((sequence, count))) if hole == sequence => Some((hole, count + 1)),
+            (Some(&hole), _) => Some((hole, 0)),
+        };
+
+        if let Some((hole, count)) = self.dup_acks {
+            if count >= dup_ack_threshold {
+                for sequence in missing {
+                    let message = self.send_buffer.get_mut(sequence).unwrap().as_mut().unwrap();
+                    for status in message.fragment_status.iter_mut() {
+                        if *status == SendStatus::Sent {
+                            *status = SendStatus::Lost;
+                        }
+                    }
+                }
+
+                // the resent fragments need their own duplicates
+                self.dup_acks = Some((hole, 0));
+            }
+        }
+
+        if !self.send_window_full() {
+            self.time_send_window_full = None;
+        }
+
+        (rtt_sample, delivered_bytes)
+    }
+}
+
+/// Returns the retransmission timeout of a message that has timed out `retransmits` times in a
+/// row (doubled each time, up to [`MAX_RTO`]).
+fn backoff(rto: Duration, retransmits: u32) -> Duration {
+    rto.checked_mul(1 << retransmits.min(31)).unwrap_or(MAX_RTO).min(MAX_RTO)
+}
+
+/// A received message that's still in the buffers its fragments were received into (see
+/// [`ConnectionRef::recv_borrowed`]).
+///
+/// The buffers are returned to the pool when this is dropped.
+pub struct FragmentedMessage<'a> {
+    /// The buffer, start, and end of each fragment, in order.
+    fragments: Vec<(BufferHandle, usize, usize)>,
+    pool: &'a mut BufferPool,
+}
+
+impl<'a> FragmentedMessage<'a> {
+    /// The length of the message (in bytes).
+    pub fn len(&self) -> usize {
+        self.fragments.iter().map(|(_, start, end)| end - start).sum()
+    }
+
+    /// Returns `true` if the message is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the fragments of the message, in order.
+    pub fn fragments(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        self.fragments.iter().map(|(handle, start, end)| {
+            let src = unsafe { MaybeUninit::slice_assume_init_ref(self.pool.get(*handle).unwrap()) };
+            &src[*start..*end]
+        })
+    }
+
+    /// Copies the message into `buf` and returns its length (in bytes).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RecvBufferTooSmall`](ErrorKind::RecvBufferTooSmall) (with the required length)
+    /// if `buf` cannot hold the message.
+    pub fn read_into(&self, buf: &mut [u8]) -> Result<usize, ProtoError> {
+        let len = self.len();
+        if len > buf.len() {
+            return Err(ErrorKind::RecvBufferTooSmall(len).into());
+        }
+
+        let mut pos = 0;
+        for fragment in self.fragments() {
+            buf[pos..(pos + fragment.len())].copy_from_slice(fragment);
+            pos += fragment.len();
+        }
+
+        Ok(len)
+    }
+}
+
+impl<'a> Drop for FragmentedMessage<'a> {
+    fn drop(&mut self) {
+        for (handle, ..) in self.fragments.drain(..) {
+            let released = self.pool.release(handle);
+            debug_assert!(released.is_ok());
+        }
+    }
+}
+
+pub struct ConnectionRef<'a> {
+    connection: &'a mut Connection,
+    channel: &'a mut Channel,
+    pool: &'a mut BufferPool,
+}
+
+impl<'a> ConnectionRef<'a> {
+
+    pub fn read(&mut self, handle: BufferHandle, now: Instant) {
+
+        // TODO: need to read all data frames
+        let buf = {
+            let slice = unsafe {
+                MaybeUninit::slice_assume_init_mut(self.pool.get_mut(handle)?)
+            };
+            BytesMut::new(slice)
+        };
+
+        match Frame::read(&mut buf).unwrap() {
+            Frame::Padding { len } => {
+                todo!();
+            },
+            Frame::Ping => {
+                todo!();
+            },
+            Frame::Ack {
+                ack_sequence,
+                ack_mask,
+            } => {
+                todo!();
+            },
+            Frame::OpenChannel {
+                channel_id,
+                send_guarantee,
+                recv_guarantee,
+            } => {
+                self.connection.open_channel(channel_id, send_guarantee, recv_guarantee);
+            },
+            Frame::Data {
+                channel_id,
+                channel_sequence,
+                fragment_index,
+                fragment_count,
+                len,
+            } => {
+                // drop data for channels the peer hasn't opened
+                let Some(channel) = self.connection.channels.get_mut(&channel_id) else {
+                    return;
+                };
+
+                channel
+                    .store_incoming_data(
+                        channel_sequence,
+                        fragment_index,
+                        fragment_count,
+                        handle,
+                        buf.position(),
+                        buf.position() + len as usize,
+                        now,
+                    );
+            },
+        }
+    }
+
+    /// Processes an acknowledgement for this channel and updates the connection's round-trip time
+    /// and packet loss.
+    pub fn acknowledge(
+        &mut self,
+        acked: SequenceNumber,
+        acked_mask: u64,
+        instant: Instant,
+    ) {
+        let connection = self.connection.src_id;
+        let dup_ack_threshold = self.connection.config.fast_retransmit_threshold();
+        let ack_mask_bits = self.connection.config.ack_mask_bits();
+        let events = &mut self.connection.events;
+        let first_event = events.len();
+        let (rtt_sample, delivered_bytes) = self.channel.acknowledge(
+            acked,
+            acked_mask,
+            dup_ack_threshold,
+            ack_mask_bits,
+            instant,
+            connection,
+            events,
+        );
+
+        let outcomes = events
+            .range(first_event..)
+            .filter_map(|event| match event {
+                ConnectionEvent::DeliveryConfirmed { .. } => Some(false),
+                ConnectionEvent::DeliveryLost { .. } => Some(true),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for &lost in &outcomes {
+            self.connection.record_delivery(lost);
+        }
+        if !outcomes.is_empty() {
+            self.connection.update_congestion_window(outcomes.contains(&true), instant);
+        }
+
+        if let Some(sample) = rtt_sample {
+            self.connection.update_rtt(sample);
+        }
+
+        if delivered_bytes > 0 {
+            self.connection.record_delivered_bytes(delivered_bytes, instant);
+        }
+    }
+
+    // TODO: len is optional field (LSB in frame type 1 == has length, 0 == full length)
+    pub fn store_incoming_data(
+        &mut self,
+        sequence: u64,
+        fragment_index: u16,
+        fragment_count: u16,
+        handle: BufferHandle,
+        start: usize,
+        end: usize,
+        instant: Instant,
+    ) -> Result<(), ProtoError> {
+        // checked up front, since a new message's storage is sized by its fragment count
+        if fragment_count == 0 {
+            return Err(ErrorKind::FragmentCountInvalid.into());
+        }
+        if fragment_count as usize > self.connection.config.max_fragments() {
+            return Err(ErrorKind::FragmentCountExceedsMax.into());
+        }
+        if fragment_index >= fragment_count {
+            return Err(ErrorKind::FragmentIndexInvalid.into());
+        }
+
+        match self.channel.recv_guarantee {
+            Receive::Unordered => {
+                if let Some(latest_recv) = self.channel.acks.latest_recv {
+                    if sequence_distance(latest_recv, sequence) > self.channel.recv_buffer.capacity() as i64 {
+                        return Err(ErrorKind::MessageOlderThanThreshold.into());
+                    }
+                }
+                if self.channel.recv_completed.contains(sequence) {
+                    return Err(ErrorKind::MessageAlreadyReceived.into());
+                }
+            },
+            Receive::Ordered => {
+                if let Some(next_recv_ordered) = self.channel.acks.next_recv_ordered {
+                    if sequence_greater_than(next_recv_ordered, sequence) {
+                        return Err(ErrorKind::MessageOlderThanThreshold.into());
+                    }
+                }
+            },
+            Receive::Sequenced => {
+                // only the newest message matters, so anything not newer than the last complete
+                // one (duplicates included) is dropped
+                if let Some(latest_recv) = self.channel.acks.latest_recv {
+                    if !sequence_greater_than(sequence, latest_recv) {
+                        return Err(ErrorKind::MessageOlderThanThreshold.into());
+                    }
+                }
+
+                // and at most one incomplete message is kept
+                match self.channel.recv_partial {
+                    Some(partial) if sequence_greater_than(partial, sequence) => {
+                        return Err(ErrorKind::MessageOlderThanThreshold.into());
+                    },
+                    Some(partial) if partial != sequence => {
+                        if let Some(message) = self.channel.recv_buffer.remove(partial) {
+                            for (handle, ..) in message.fragment_data.iter().flatten() {
+                                let released = self.pool.release(*handle);
+                                debug_assert!(released.is_ok());
+                            }
+                        }
+                    },
+                    _ => {},
+                }
+                self.channel.recv_partial = Some(sequence);
+            },
+        }
+
+        let message = {
+            if let Some(Some(message)) = self.channel.recv_buffer.get_mut(sequence) {
+                if fragment_count != message.fragment_count {
+                    return Err(ErrorKind::FragmentCountInvalid.into());
+                }
+                if fragment_index >= message.fragment_count {
+                    return Err(ErrorKind::FragmentIndexInvalid.into());
+                }
+                if message.fragment_data[fragment_index as usize].is_some() {
+                    return Err(ErrorKind::FragmentIndexAlreadyReceived.into());
+                }
+                message
+            }
+            else {
+                let index = self.channel.recv_buffer.index_of(sequence);
+                // the slot can hold a message that maps to the same index, which is only evicted
+                // once it has fallen out of the window
+                if let (Some(old), _) = self.channel.recv_buffer.get_index(index) {
+                    if !sequence_greater_than(self.channel.recv_window_floor(sequence), *old) {
+                        return Err(ErrorKind::MessageOlderThanThreshold.into());
+                    }
+                }
+                if let (Some(_), Some(message)) = self.channel.recv_buffer.remove_index(index) {
+                    // release buffers held by old message
+                    for location in message.fragment_data.iter().flatten() {
+                        let released = self.pool.release(location.0);
+                        debug_assert!(released.is_ok());
+                    }
+                }
+                self.channel.recv_buffer.insert(
+                    sequence,
+                    RecvMessage {
+                        sequence,
+                        fragment_count,
+                        fragment_recv: 0,
+                        fragment_data: vec![None; fragment_count as usize].into_boxed_slice(),
+                        time_created: instant,
+                        time_recv: None,
+                    },
+                )
+            }
+        };
+
+        message.fragment_recv += 1;
+        message.fragment_data[fragment_index as usize] = Some((handle, start, end));
+       
+        if message.fragment_recv == message.fragment_count {
+            self.connection.time_latest_recv = Some(instant);
+            self.channel.time_latest_recv = Some(instant);
+            message.time_recv = Some(instant);
+
+            let mask_bits = self.connection.config.ack_mask_bits();
+            let prev_recv = self.channel.acks.record_recv(sequence, mask_bits);
+            
+            match self.channel.recv_guarantee {
+                Receive::Unordered => {
+                    self.channel.recv_completed.insert(sequence, ());
+                    self.deliver(sequence);
+                },
+                Receive::Ordered => {
+                    let start = self.channel.acks.next_recv_ordered.unwrap_or(0);
+                    self.deliver_ordered(start);
+                },
+                Receive::Sequenced => {
+                    self.channel.recv_partial = None;
+                    // only deliver the message if nothing newer has been completed
+                    if prev_recv.map_or(true, |latest_recv| sequence_greater_than(sequence, latest_recv)) {
+                        self.deliver(sequence);
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+    
+    /// Delivers the run of complete messages of an ordered channel starting at `start` and moves
+    /// [`next_recv_ordered`](ChannelAcknowledgment::next_recv_ordered) past it.
+    fn deliver_ordered(&mut self, start: SequenceNumber) {
+        // return messages in the order they were sent (they stay in the receive buffer until
+        // `recv` copies them out)
+        let ready = self.channel.recv_buffer.ready_len(start, |message| {
+            message.fragment_recv == message.fragment_count
+        }) as u64;
+        for offset in 0..ready {
+            self.deliver(start.wrapping_add(offset));
+        }
+        self.channel.acks.next_recv_ordered = Some(start.wrapping_add(ready));
+    }
+
+    /// Gives up on the next message of an ordered channel if it has held up later ones (which
+    /// are complete) for longer than `timeout`, and delivers the ones that were waiting on it.
+    ///
+    /// Whatever was received of the skipped message is released and a
+    /// [`MessageSkipped`](ConnectionEvent::MessageSkipped) event is pushed for it. Only one
+    /// message is skipped at a time, the timeout starts over for the next one. Returns `true` if
+    /// a message was skipped.
+    pub(crate) fn skip_gap(&mut self, time: Instant, timeout: Duration) -> bool {
+        if self.channel.recv_guarantee != Receive::Ordered {
+            return false;
+        }
+
+        // the next message would have been delivered already if it were complete, so later
+        // messages are held up as soon as one of them is
+        let head = self.channel.acks.next_recv_ordered.unwrap_or(0);
+        let blocked = self
+            .channel
+            .acks
+            .latest_recv
+            .map_or(false, |latest_recv| !sequence_greater_than(head, latest_recv));
+        if !blocked {
+            self.channel.time_head_blocked = None;
+            return false;
+        }
+
+        let time_blocked = *self.channel.time_head_blocked.get_or_insert(time);
+        if time.saturating_duration_since(time_blocked) <= timeout {
+            return false;
+        }
+
+        // the slot can hold a later message that maps to the same index
+        if let Some(Some(_)) = self.channel.recv_buffer.get(head) {
+            let message = self.channel.recv_buffer.remove(head).unwrap();
+            for (handle, ..) in message.fragment_data.iter().flatten() {
+                let released = self.pool.release(*handle);
+                debug_assert!(released.is_ok());
+            }
+        }
+        self.connection.events.push_back(ConnectionEvent::MessageSkipped {
+            connection: self.connection.src_id,
+            channel: self.channel.id,
+            sequence: head,
+        });
+        self.deliver_ordered(head.wrapping_add(1));
+        self.channel.time_head_blocked = None;
+
+        true
+    }
+
+    /// Queues the complete message `sequence` to be received and pushes an event for it.
+    fn deliver(&mut self, sequence: SequenceNumber) {
+        let len = match self.channel.recv_buffer.get(sequence) {
+            Some(Some(message)) => message
+                .fragment_data
+                .iter()
+                .flatten()
+                .map(|(_, start, end)| end - start)
+                .sum(),
+            _ => return,
+        };
+
+        self.channel.recv_queue.push_back(sequence);
+        self.connection.events.push_back(ConnectionEvent::MessageReceived {
+            connection: self.connection.src_id,
+            channel: self.channel.id,
+            len,
+        });
+    }
+
+    pub fn store_outgoing_data(&mut self, data: &[u8], instant: Instant) -> Result<(), ProtoError> {
+        if data.len() == 0 {
+            return Err(ErrorKind::SendMessageZeroLength.into());
+        }
+        if data.len() > self.connection.config.max_payload_bytes() {
+            return Err(ErrorKind::SendMessageTooLarge.into());
+        }
+
+        // the connection is dropped if this goes on for too long (see `Connection::update`)
+        if self.channel.send_window_full() {
+            self.channel.time_send_window_full.get_or_insert(instant);
+            return Err(ErrorKind::SendWindowFull.into());
+        }
+        
+        let sequence = self.channel.acks.next_send;
+        let fragment_bytes = self.connection.max_fragment_bytes();
+        if data.len() <= fragment_bytes {
+            self.store_single_fragment(data, instant)?;
+        } else {
+            self.store_fragments(data, fragment_bytes, instant)?;
+        }
+
+        if self.channel.send_guarantee == Send::Reliable && self.channel.recv_guarantee == Receive::Sequenced {
+            self.cancel_superseded(sequence);
+        }
+        Ok(())
+    }
+
+    /// Drops the messages older than `sequence` that are still in the send buffer and returns
+    /// their buffers to the pool, so that none of their fragments are resent.
+    fn cancel_superseded(&mut self, sequence: SequenceNumber) {
+        for offset in 1..self.channel.send_buffer.capacity() as u64 {
+            let older = sequence.wrapping_sub(offset);
+            if !self.channel.send_buffer.contains(older) {
+                continue;
+            }
+            if let Some(message) = self.channel.send_buffer.remove(older) {
+                for &(handle, _, _) in message.fragment_data.iter().flatten() {
+                    let released = self.pool.release(handle);
+                    debug_assert!(released.is_ok());
+                }
+            }
+        }
+        self.channel.update_oldest_send_unacked();
+    }
+
+    /// Stores a message that fits in one fragment, without the bookkeeping of splitting it up.
+    ///
+    /// The fragment is written exactly like [`store_fragments`](Self::store_fragments) writes
+    /// the first one.
+    fn store_single_fragment(&mut self, data: &[u8], instant: Instant) -> Result<(), ProtoError> {
+        let handle = self.pool.acquire().map_err(|_| ErrorKind::NotEnoughBuffersAvailable)?;
+        let sequence = self.channel.acks.next_send;
+        let frame = Frame::Data {
+            channel_id: self.channel.id,
+            channel_sequence: sequence,
+            fragment_count: 1,
+            fragment_index: 0,
+            len: data.len() as u16,
+        };
+        let start = match Self::write_fragment(self.pool, handle, frame, data) {
+            Ok(start) => start,
+            Err(err) => {
+                let released = self.pool.release(handle);
+                debug_assert!(released.is_ok());
+                return Err(err);
+            },
+        };
+
+        self.channel.acks.next_send += 1;
+        self.channel.acks.oldest_send_unacked.get_or_insert(sequence);
+        self.channel.send_buffer.insert(
+            sequence,
+            SendMessage {
+                sequence,
+                fragment_count: 1,
+                fragment_sent: 0,
+                fragment_data: Box::new([Some((handle, start, data.len()))]),
+                fragment_status: Box::new([SendStatus::Unsent]),
+                time_created: instant,
+                time_sent: None,
+                time_latest_send: None,
+                retransmits: 0,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Splits a message into fragments of up to `fragment_bytes` and stores them.
+    ///
+    /// The message is stored whole or not at all. If a fragment can't be written (e.g. the pool
+    /// runs out of buffers), the buffers of the fragments written so far are released.
+    fn store_fragments(&mut self, data: &[u8], fragment_bytes: usize, instant: Instant) -> Result<(), ProtoError> {
+        // calculate the number of fragments and check that it's valid
+        let fragment_count = (data.len() / fragment_bytes) + 
+                                  ((data.len() % fragment_bytes) != 0) as usize;
+        if fragment_count > self.connection.config.max_fragments() {
+            return Err(ErrorKind::FragmentCountExceedsMax.into());
+        }
+
+        // TODO: add buffer for user data
+        let sequence = self.channel.acks.next_send;
+        let mut fragment_data = vec![None; fragment_count].into_boxed_slice();
+        if let Err(err) = self.write_fragments(data, fragment_bytes, sequence, &mut fragment_data) {
+            for &(handle, ..) in fragment_data.iter().flatten() {
+                let released = self.pool.release(handle);
+                debug_assert!(released.is_ok());
+            }
+            return Err(err);
+        }
+
+        self.channel.acks.next_send += 1;
+        self.channel.acks.oldest_send_unacked.get_or_insert(sequence);
+        self.channel.send_buffer.insert(
+            sequence,
+            SendMessage {
+                sequence,
+                fragment_count: fragment_count as u16,
+                fragment_sent: 0,
+                fragment_data,
+                fragment_status: vec![SendStatus::Unsent; fragment_count].into_boxed_slice(),
+                time_created: instant,
+                time_sent: None,
+                time_latest_send: None,
+                retransmits: 0,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Writes each fragment of message `sequence` into a buffer of its own and records where
+    /// it is in `fragment_data`.
+    ///
+    /// Stops at the first fragment that can't be written, leaving the ones before it in place.
+    fn write_fragments(
+        &mut self,
+        data: &[u8],
+        fragment_bytes: usize,
+        sequence: SequenceNumber,
+        fragment_data: &mut [Option<(BufferHandle, usize, usize)>],
+    ) -> Result<(), ProtoError> {
+        let fragment_count = fragment_data.len();
+        for index in 0..fragment_count {
+            let handle = self.pool.acquire().map_err(|_| ErrorKind::NotEnoughBuffersAvailable)?;
+            let start = index * fragment_bytes;
+            let end = (start + fragment_bytes).min(data.len());
+            let len = end - start;
+
+            let frame = Frame::Data {
+                channel_id: self.channel.id,
+                channel_sequence: sequence,
+                fragment_count: fragment_count as u16,
+                fragment_index: index as u16,
+                len: len as u16,
+            };
+            let position = match Self::write_fragment(self.pool, handle, frame, &data[start..end]) {
+                Ok(position) => position,
+                Err(err) => {
+                    let released = self.pool.release(handle);
+                    debug_assert!(released.is_ok());
+                    return Err(err);
+                },
+            };
+            fragment_data[index] = Some((handle, position, len));
+        }
+
+        Ok(())
+    }
+
+    /// Writes `frame` and its `data` into the buffer `handle`, after room for the header.
+    ///
+    /// Returns where the data starts in the buffer.
+    fn write_fragment(pool: &mut BufferPool, handle: BufferHandle, frame: Frame, data: &[u8]) -> Result<usize, ProtoError> {
+        let slice = unsafe {
+            MaybeUninit::slice_assume_init_mut(pool.get_mut(handle).ok_or(ErrorKind::NotEnoughBuffersAvailable)?)
+        };
+        let mut buf = BytesMut::new(slice);
+
+        // reserve space for the header, it's filled in by `backfill_header` once the
+        // fragment is sent and its packet number is known
+        buf.advance(Header::short_header_bytes())?;
+        frame.write(&mut buf)?;
+        let position = buf.position();
+        buf.copy_from_slice(data)?;
+        Ok(position)
+    }
+
+    /// Fills in the header reserved at the front of the buffer holding fragment `index` of
+    /// message `sequence` (see [`store_outgoing_data`](Self::store_outgoing_data)), which makes
+    /// the buffer a complete packet numbered `packet_number`.
+    ///
+    /// Returns the buffer and the length of the packet in it.
+    pub(crate) fn backfill_header(
+        &mut self,
+        sequence: SequenceNumber,
+        index: u16,
+        packet_number: u64,
+    ) -> io::Result<(BufferHandle, usize)> {
+        let fragment = self
+            .channel
+            .send_buffer
+            .get(sequence)
+            .and_then(Option::as_ref)
+            .and_then(|message| message.fragment_data.get(index as usize).copied().flatten());
+        let Some((handle, start, len)) = fragment else {
+            return Err(io::Error::new_const(io::ErrorKind::NotFound, &"no such fragment"));
+        };
+
+        let slice = unsafe {
+            MaybeUninit::slice_assume_init_mut(self.pool.get_mut(handle).unwrap())
+        };
+        let header = Header::Short {
+            packet_number,
+            packet_type: PacketType::Data,
+            dst_id: self.connection.dst_id,
+        };
+        header.write_at(&mut BytesMut::new(slice), 0)?;
+
+        Ok((handle, start + len))
+    }
+
+    /// Pads `packet` with [`Padding`](Frame::Padding) until it's `len` bytes long (or full, if
+    /// it's shorter than that) and returns the number of padding bytes written.
+    ///
+    /// Padding is written after every other frame. It's used to size MTU probes and to make
+    /// packets the same size, so that their contents can't be guessed from their size.
+    pub(crate) fn write_padding(packet: &mut BytesMut, len: usize) -> io::Result<usize> {
+        let target = len.min(packet.position() + packet.remaining());
+        let padding = target.saturating_sub(packet.position());
+
+        // consecutive padding frames are read back as one
+        let mut written = 0;
+        while written < padding {
+            let len = (padding - written).min(u16::MAX as usize);
+            Frame::Padding { len: len as u16 }.write(packet)?;
+            written += len;
+        }
+
+        Ok(written)
+    }
+
+    /// Writes as many fragments of this channel as fit into `packet`, in the order given by
+    /// [`Channel::resend_order`], and marks them as sent.
+    ///
+    /// Fragments of unreliable channels are never lost, so they're just sent in order.
+    ///
+    /// A message's `time_sent` is set once all of its fragments have been sent at least once.
+    ///
+    /// Returns the number of fragments written.
+    pub(crate) fn write_reliable(&mut self, packet: &mut BytesMut, instant: Instant) -> io::Result<usize> {
+        let mut written = 0;
+        for (sequence, index) in self.channel.resend_order() {
+            // keep the order, don't skip ahead to a smaller fragment
+            if !self.channel.write_fragment(self.pool, packet, sequence, index, instant)? {
+                break;
+            }
+            written += 1;
+        }
+
+        Ok(written)
+    }
+
+    /// Copies the next deliverable message into `buf` and returns its length (in bytes).
+    /// Returns `Ok(0)` if no message is ready.
+    ///
+    /// Fragments are written in index order and their buffers are returned to the pool.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RecvBufferTooSmall`](ErrorKind::RecvBufferTooSmall) (with the required length)
+    /// if `buf` cannot hold the message. The message stays queued in that case.
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize, ProtoError> {
+        while let Some(&sequence) = self.channel.recv_queue.front() {
+            let message = match self.channel.recv_buffer.get(sequence) {
+                Some(Some(message)) if message.fragment_recv == message.fragment_count => message,
+                _ => {
+                    // message was evicted before it could be delivered
+                    self.channel.recv_queue.pop_front();
+                    continue;
+                },
+            };
+
+            // most messages are a single fragment, which can be copied as is
+            if let [Some((handle, start, end))] = message.fragment_data[..] {
+                let len = end - start;
+                if len > buf.len() {
+                    return Err(ErrorKind::RecvBufferTooSmall(len).into());
+                }
+
+                let src = unsafe { MaybeUninit::slice_assume_init_ref(self.pool.get(handle).unwrap()) };
+                buf[..len].copy_from_slice(&src[start..end]);
+
+                self.channel.recv_queue.pop_front();
+                self.channel.recv_buffer.remove(sequence);
+                let released = self.pool.release(handle);
+                debug_assert!(released.is_ok());
+                return Ok(len);
+            }
+
+            let fragments = &message.fragment_data[..message.fragment_count as usize];
+            let len = fragments
+                .iter()
+                .flatten()
+                .map(|(_, start, end)| end - start)
+                .sum::<usize>();
+
+            if len > buf.len() {
+                return Err(ErrorKind::RecvBufferTooSmall(len).into());
+            }
+
+            let mut pos = 0;
+            for (handle, start, end) in fragments.iter().flatten() {
+                let src = unsafe {
+                    MaybeUninit::slice_assume_init_ref(self.pool.get(*handle).unwrap())
+                };
+                buf[pos..(pos + end - start)].copy_from_slice(&src[*start..*end]);
+                pos += end - start;
+            }
+
+            self.channel.recv_queue.pop_front();
+            let message = self.channel.recv_buffer.remove(sequence).unwrap();
+            for (handle, ..) in message.fragment_data.iter().flatten() {
+                let released = self.pool.release(*handle);
+                debug_assert!(released.is_ok());
+            }
+
+            return Ok(len);
+        }
+
+        Ok(0)
+    }
+    
+    /// Returns the next deliverable message without copying it, or `None` if no message is
+    /// ready.
+    ///
+    /// The message is read straight from the buffers its fragments were received into. They're
+    /// returned to the pool when the view is dropped, and the pool can't be used until then.
+    pub fn recv_borrowed(&mut self) -> Option<FragmentedMessage<'_>> {
+        while let Some(sequence) = self.channel.recv_queue.pop_front() {
+            let message = match self.channel.recv_buffer.get(sequence) {
+                Some(Some(message)) if message.fragment_recv == message.fragment_count => message,
+                // message was evicted before it could be delivered
+                _ => continue,
+            };
+
+            let fragments = message.fragment_data[..message.fragment_count as usize]
+                .iter()
+                .flatten()
+                .copied()
+                .collect();
+            self.channel.recv_buffer.remove(sequence);
+
+            return Some(FragmentedMessage {
+                fragments,
+                pool: &mut *self.pool,
+            });
+        }
+
+        None
+    }
+
+    pub fn send(&mut self, socket: impl Socket, instant: Instant) -> Result<(), ProtoError> {
+
+        // reliable non-sequenced has head of line blocking (prioritize resending lost messages)
+        // reliable sequenced is only reliable for the latest packet
+
+        // array of (sequence, fragment) to go with packet sequence
+
+        // send from unreliable channels, then from reliable channels
+        // unreliable must send whole message
+
+        use std::sync::mpsc::sync_channel;
+        let (sender, receiver) = sync_channel::<SendMessage>(1024);
+        sender.clone();
+        
+        match sender.try_send(t) {
+            Ok(_) => {
+
+            },
+            Err(e) => {
+
+            },
+        }
+
+        // for channel in unreliable channels with pending messages
+        // basically send all of them, packed as much as possible
+
+        // for channel in reliable channels with lost and pending messages
+        // basically send all of them, packed as much as possible
+
+        // Connection::schedule picks which fragments fit in the budget, across all channels.
+
+        match self.send_guarantee {
+            Send::Unreliable => {
+                let sequence = 0;
+                let message = self.channel.send_buffer.get_mut(sequence).as_mut().unwrap();
+                // send fragments
+                // write header
+
+                // send and release
+            },
+            Send::Reliable => {
+                loop {
+                    let handle = self.pool.acquire()?;
+                    let mut packet = {
+                        let slice = unsafe {
+                            MaybeUninit::slice_assume_init_mut(self.pool.get_mut(handle)?)
+                        };
+                        BytesMut::new(slice)
+                    };
+
+                    // reserve space for the header, the frames decide whether there's a packet
+                    packet.advance(Header::short_header_bytes())?;
+                    if self.write_reliable(&mut packet, instant)? == 0 {
+                        let released = self.pool.release(handle);
+                        debug_assert!(released.is_ok());
+                        break;
+                    }
+
+                    let header = Header::Short {
+                        packet_number: self.connection.acks.packet_number(),
+                        packet_type: PacketType::Data,
+                        dst_id: self.connection.dst_id,
+                    };
+                    header.write_at(&mut packet, 0)?;
+
+                    // send
+                }
+            },
+        }
+
+        self.connection.time_latest_send = Some(instant);
+        self.channel.time_latest_send = Some(instant);
+        message.time_latest_send = Some(instant);
+
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::ManualClock, packet::frames::version_checksum, stream::SendStream};
+
+    #[test]
+    fn recv_reassembles_fragments_in_order() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = Connection::new(
+            0,
+            1,
+            "127.0.0.1:0".parse().unwrap(),
+            Role::Client,
+            Config::default(),
+            now,
+        );
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+
+        let data = (0..300).map(|i| i as u8).collect::<Vec<_>>();
+        // deliver the fragments out of order
+        for index in [2, 0, 1] {
+            let chunk = &data[(index * 100)..((index + 1) * 100)];
+            let handle = conn.pool.acquire().unwrap();
+            let buf = conn.pool.get_mut(handle).unwrap();
+            MaybeUninit::write_slice(&mut buf[..chunk.len()], chunk);
+            conn.store_incoming_data(0, index as u16, 3, handle, 0, chunk.len(), now)
+                .unwrap();
+        }
+
+        let mut buf = [0u8; 300];
+        assert_eq!(conn.recv(&mut buf[..299]), Err(ErrorKind::RecvBufferTooSmall(300).into()));
+        assert_eq!(conn.recv(&mut buf), Ok(300));
+        assert_eq!(&buf[..], &data[..]);
+        assert_eq!(conn.pool.capacity_remaining(), 8);
+
+        // nothing left to deliver
+        assert_eq!(conn.recv(&mut buf), Ok(0));
+    }
+
+    #[test]
+    fn recv_borrowed_reads_from_pool() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = connected_server();
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        assert!(conn.recv_borrowed().is_none());
+
+        let data = (0..300).map(|i| i as u8).collect::<Vec<_>>();
+        for (index, chunk) in data.chunks(100).enumerate() {
+            let handle = conn.pool.acquire().unwrap();
+            let buf = conn.pool.get_mut(handle).unwrap();
+            MaybeUninit::write_slice(&mut buf[..chunk.len()], chunk);
+            conn.store_incoming_data(0, index as u16, 3, handle, 0, chunk.len(), now)
+                .unwrap();
+        }
+        assert_eq!(conn.pool.capacity_remaining(), 5);
+
+        let message = conn.recv_borrowed().unwrap();
+        assert_eq!(message.len(), 300);
+        assert_eq!(message.fragments().collect::<Vec<_>>(), data.chunks(100).collect::<Vec<_>>());
+
+        let mut buf = [0u8; 300];
+        assert_eq!(message.read_into(&mut buf[..299]), Err(ErrorKind::RecvBufferTooSmall(300).into()));
+        assert_eq!(message.read_into(&mut buf), Ok(300));
+        assert_eq!(&buf[..], &data[..]);
+
+        // the buffers are only released once the message is dropped
+        drop(message);
+        assert_eq!(conn.pool.capacity_remaining(), 8);
+        assert!(conn.recv_borrowed().is_none());
+        assert_eq!(conn.recv(&mut buf), Ok(0));
+    }
+
+    #[test]
+    fn recv_reassembles_max_fragments_on_small_stack() {
+        // far less than a whole message, so reassembly can't go through the stack
+        const STACK_BYTES: usize = 128 * 1024;
+        const FRAGMENT_COUNT: usize = u8::MAX as usize;
+
+        let thread = std::thread::Builder::new()
+            .stack_size(STACK_BYTES)
+            .spawn(|| {
+                let now = Instant::now();
+                let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, FRAGMENT_COUNT);
+                let mut connection = Connection::new(
+                    0,
+                    1,
+                    "127.0.0.1:0".parse().unwrap(),
+                    Role::Client,
+                    Config::default(),
+                    now,
+                );
+                let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+                let mut conn = ConnectionRef {
+                    connection: &mut connection,
+                    channel: &mut channel,
+                    pool: &mut pool,
+                };
+
+                let data = (0..(FRAGMENT_COUNT * MAX_FRAGMENT_BYTES))
+                    .map(|i| i as u8)
+                    .collect::<Vec<_>>();
+                for (index, chunk) in data.chunks(MAX_FRAGMENT_BYTES).enumerate().rev() {
+                    let handle = conn.pool.acquire().unwrap();
+                    let buf = conn.pool.get_mut(handle).unwrap();
+                    MaybeUninit::write_slice(&mut buf[..chunk.len()], chunk);
+                    conn.store_incoming_data(
+                        0,
+                        index as u16,
+                        FRAGMENT_COUNT as u16,
+                        handle,
+                        0,
+                        chunk.len(),
+                        now,
+                    )
+                    .unwrap();
+                }
+
+                let mut buf = vec![0u8; data.len()];
+                assert_eq!(conn.recv(&mut buf), Ok(data.len()));
+                assert_eq!(buf, data);
+                assert_eq!(conn.pool.capacity_remaining(), FRAGMENT_COUNT);
+            })
+            .unwrap();
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn message_with_more_than_256_fragments_round_trips() {
+        const FRAGMENT_COUNT: usize = 300;
+
+        let now = Instant::now();
+        let config = Config::builder().max_fragments(FRAGMENT_COUNT).build().unwrap();
+        let new_connection = |role| Connection::new(0, 1, "127.0.0.1:0".parse().unwrap(), role, config.clone(), now);
+
+        let mut sender = new_connection(Role::Client);
+        let mut send_pool = BufferPool::new(MAX_PAYLOAD_BYTES, FRAGMENT_COUNT);
+        let mut send_channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+        let mut conn = ConnectionRef {
+            connection: &mut sender,
+            channel: &mut send_channel,
+            pool: &mut send_pool,
+        };
+        let fragment_bytes = conn.connection.max_fragment_bytes();
+        let data = (0..(FRAGMENT_COUNT * fragment_bytes - 1))
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        conn.store_outgoing_data(&data, now).unwrap();
+        let message = conn.channel.send_buffer.get(0).unwrap().as_ref().unwrap();
+        assert_eq!(message.fragment_count as usize, FRAGMENT_COUNT);
+
+        let mut receiver = new_connection(Role::Server);
+        let mut recv_pool = BufferPool::new(MAX_PAYLOAD_BYTES, FRAGMENT_COUNT);
+        let mut recv_channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+        let mut recv_conn = ConnectionRef {
+            connection: &mut receiver,
+            channel: &mut recv_channel,
+            pool: &mut recv_pool,
+        };
+
+        // read each fragment's frame back out of its buffer, newest first
+        for &(handle, start, len) in message.fragment_data.iter().flatten().rev() {
+            let packet = unsafe { MaybeUninit::slice_assume_init_mut(conn.pool.get_mut(handle).unwrap()) };
+            let mut buf = BytesMut::new(&mut packet[Header::short_header_bytes()..start]);
+            let Frame::Data { fragment_index, fragment_count, len: frame_len, .. } = Frame::read(&mut buf).unwrap() else {
+                panic!("expected a data frame");
+            };
+            assert_eq!(frame_len as usize, len);
+
+            let fragment = recv_conn.pool.acquire().unwrap();
+            MaybeUninit::write_slice(
+                &mut recv_conn.pool.get_mut(fragment).unwrap()[..len],
+                &packet[start..(start + len)],
+            );
+            recv_conn
+                .store_incoming_data(0, fragment_index, fragment_count, fragment, 0, len, now)
+                .unwrap();
+        }
+
+        let mut buf = vec![0u8; data.len()];
+        assert_eq!(recv_conn.recv(&mut buf), Ok(data.len()));
+        assert_eq!(buf, data);
+        assert_eq!(recv_conn.pool.capacity_remaining(), FRAGMENT_COUNT);
+    }
+
+    #[test]
+    fn store_incoming_data_rejects_fragment_count_over_max() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = connected_server();
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Unordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+
+        let max_fragments = conn.connection.config.max_fragments() as u16;
+        assert!(matches!(
+            store_fragment(&mut conn, 0, 0, max_fragments + 1, 0, now),
+            Err(ProtoError::Protocol(ErrorKind::FragmentCountExceedsMax))
+        ));
+        assert!(matches!(
+            store_fragment(&mut conn, 0, 0, 0, 0, now),
+            Err(ProtoError::Protocol(ErrorKind::FragmentCountInvalid))
+        ));
+        assert!(matches!(
+            store_fragment(&mut conn, 0, 5, 5, 0, now),
+            Err(ProtoError::Protocol(ErrorKind::FragmentIndexInvalid))
+        ));
+        assert_eq!(conn.pool.capacity_remaining(), 8);
+        store_fragment(&mut conn, 0, max_fragments - 1, max_fragments, 0, now).unwrap();
+    }
+
+    #[test]
+    fn write_padding_fills_packet() {
+        let frames = [
+            Frame::Ping,
+            Frame::Ack {
+                ack_sequence: 7,
+                ack_mask: 0b1011,
+            },
+            Frame::OpenChannel {
+                channel_id: 3,
+                send_guarantee: Send::Reliable,
+                recv_guarantee: Receive::Sequenced,
+            },
+        ];
+
+        let mut data = [0xffu8; 128];
+        let mut packet = BytesMut::new(&mut data);
+        for frame in frames.iter() {
+            frame.write(&mut packet).unwrap();
+        }
+        let frames_bytes = packet.position();
+        assert_eq!(ConnectionRef::write_padding(&mut packet, 100).unwrap(), 100 - frames_bytes);
+        assert_eq!(packet.position(), 100);
+
+        // already long enough
+        assert_eq!(ConnectionRef::write_padding(&mut packet, 50).unwrap(), 0);
+
+        let mut packet = BytesMut::new(&mut data[..100]);
+        for frame in frames.iter() {
+            assert_eq!(Frame::read(&mut packet).unwrap(), *frame);
+        }
+        assert_eq!(
+            Frame::read(&mut packet).unwrap(),
+            Frame::Padding { len: (100 - frames_bytes) as u16 },
+        );
+        assert_eq!(packet.remaining(), 0);
+    }
+
+    #[test]
+    fn write_padding_stops_at_end_of_buffer() {
+        let mut data = [0xffu8; 32];
+        let mut packet = BytesMut::new(&mut data);
+        Frame::Ping.write(&mut packet).unwrap();
+        assert_eq!(ConnectionRef::write_padding(&mut packet, 1200).unwrap(), 31);
+        assert_eq!(packet.remaining(), 0);
+
+        let mut packet = BytesMut::new(&mut data);
+        assert_eq!(Frame::read(&mut packet).unwrap(), Frame::Ping);
+        assert_eq!(Frame::read(&mut packet).unwrap(), Frame::Padding { len: 31 });
+    }
+
+    /// Stores a 100-byte fragment filled with `value`.
+    fn store_fragment(
+        conn: &mut ConnectionRef,
+        sequence: SequenceNumber,
+        fragment_index: u16,
+        fragment_count: u16,
+        value: u8,
+        instant: Instant,
+    ) -> Result<(), ProtoError> {
+        let handle = conn.pool.acquire().unwrap();
+        let buf = conn.pool.get_mut(handle).unwrap();
+        MaybeUninit::write_slice(&mut buf[..100], &[value; 100]);
+        let result = conn.store_incoming_data(sequence, fragment_index, fragment_count, handle, 0, 100, instant);
+        if result.is_err() {
+            conn.pool.release(handle).unwrap();
+        }
+        result
+    }
+
+    #[test]
+    fn store_incoming_data_reports_protocol_errors() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = connected_server();
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Unordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+
+        store_fragment(&mut conn, 0, 0, 3, 0, now).unwrap();
+        assert!(matches!(
+            store_fragment(&mut conn, 0, 1, 2, 0, now),
+            Err(ProtoError::Protocol(ErrorKind::FragmentCountInvalid))
+        ));
+        assert!(matches!(
+            store_fragment(&mut conn, 0, 3, 3, 0, now),
+            Err(ProtoError::Protocol(ErrorKind::FragmentIndexInvalid))
+        ));
+        assert!(matches!(
+            store_fragment(&mut conn, 0, 0, 3, 0, now),
+            Err(ProtoError::Protocol(ErrorKind::FragmentIndexAlreadyReceived))
+        ));
+        assert_eq!(conn.pool.capacity_remaining(), 7);
+    }
+
+    #[test]
+    fn store_outgoing_data_reports_protocol_and_io_errors() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = connected_server();
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        let fragment_bytes = conn.connection.max_fragment_bytes();
+
+        assert!(matches!(
+            conn.store_outgoing_data(b"", now),
+            Err(ProtoError::Protocol(ErrorKind::SendMessageZeroLength))
+        ));
+        assert!(matches!(
+            conn.store_outgoing_data(&vec![0; (DEFAULT_MAX_FRAGMENTS * fragment_bytes) + 1], now),
+            Err(ProtoError::Protocol(ErrorKind::FragmentCountExceedsMax))
+        ));
+        assert!(matches!(
+            conn.store_outgoing_data(&vec![0; 9 * fragment_bytes], now),
+            Err(ProtoError::Protocol(ErrorKind::NotEnoughBuffersAvailable))
+        ));
+
+        // a fragment that doesn't fit in its buffer
+        let mut pool = BufferPool::new(64, 8);
+        conn.pool = &mut pool;
+        assert!(matches!(
+            conn.store_outgoing_data(&[0; 100], now),
+            Err(ProtoError::Io(io::ErrorKind::InvalidInput))
+        ));
+    }
+
+    #[test]
+    fn store_outgoing_data_releases_buffers_when_pool_runs_out() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let held = (0..6).map(|_| pool.acquire().unwrap()).collect::<Vec<_>>();
+        let mut connection = connected_server();
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        let fragment_bytes = conn.connection.max_fragment_bytes();
+
+        // two buffers go to the first fragments before the third can't get one
+        assert!(matches!(
+            conn.store_outgoing_data(&vec![0; 3 * fragment_bytes], now),
+            Err(ProtoError::Protocol(ErrorKind::NotEnoughBuffersAvailable))
+        ));
+        assert_eq!(conn.pool.capacity_remaining(), 2);
+        assert!(!conn.channel.send_buffer.contains(0));
+        assert_eq!(conn.channel.acks.next_send, 0);
+
+        // the same goes for a fragment that doesn't fit in its buffer
+        let mut small_pool = BufferPool::new(64, 8);
+        conn.pool = &mut small_pool;
+        assert!(matches!(
+            conn.store_outgoing_data(&vec![0; 2 * fragment_bytes], now),
+            Err(ProtoError::Io(io::ErrorKind::InvalidInput))
+        ));
+        assert_eq!(conn.pool.capacity_remaining(), 8);
+
+        // once there's room, the message gets the sequence that was never used
+        for handle in held {
+            pool.release(handle).unwrap();
+        }
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        conn.store_outgoing_data(&vec![0; 3 * fragment_bytes], now).unwrap();
+        assert_eq!(conn.pool.in_use(), 3);
+        assert!(conn.channel.send_buffer.contains(0));
+    }
+
+    #[test]
+    fn store_outgoing_data_honors_configured_limits() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+
+        // payloads of up to 400 bytes
+        let config = Config::builder().max_fragments(4).max_fragment_bytes(100).build().unwrap();
+        let mut connection = Connection::new(1, 0, "127.0.0.1:0".parse().unwrap(), Role::Server, config, now);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        assert!(matches!(
+            conn.store_outgoing_data(&[0; 401], now),
+            Err(ProtoError::Protocol(ErrorKind::SendMessageTooLarge))
+        ));
+        conn.store_outgoing_data(&[0; 400], now).unwrap();
+
+        // the payload limit allows more than two of this connection's fragments
+        let config = Config::builder().max_fragments(2).build().unwrap();
+        let mut connection = Connection::new(1, 0, "127.0.0.1:0".parse().unwrap(), Role::Server, config, now);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        let fragment_bytes = conn.connection.max_fragment_bytes();
+        assert!(2 * fragment_bytes < conn.connection.config.max_payload_bytes());
+        assert!(matches!(
+            conn.store_outgoing_data(&vec![0; 2 * fragment_bytes + 1], now),
+            Err(ProtoError::Protocol(ErrorKind::FragmentCountExceedsMax))
+        ));
+        conn.store_outgoing_data(&vec![0; 2 * fragment_bytes], now).unwrap();
+        assert_eq!(pool.in_use(), 3);
+    }
+
+    #[test]
+    fn single_fragment_fast_path_matches_general_path() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = connected_server();
+        let mut fast = Channel::new(0, Send::Reliable, Receive::Ordered);
+        let mut general = Channel::new(0, Send::Reliable, Receive::Ordered);
+        let fragment_bytes = connection.max_fragment_bytes();
+
+        for len in [1, 5, fragment_bytes] {
+            let data = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            let mut conn = ConnectionRef {
+                connection: &mut connection,
+                channel: &mut fast,
+                pool: &mut pool,
+            };
+            conn.store_outgoing_data(&data, now).unwrap();
+            conn.channel = &mut general;
+            conn.store_fragments(&data, fragment_bytes, now).unwrap();
+
+            let sequence = fast.acks.next_send - 1;
+            let fast = fast.send_buffer.get(sequence).unwrap().as_ref().unwrap();
+            let general = general.send_buffer.get(sequence).unwrap().as_ref().unwrap();
+            assert_eq!(fast.fragment_count, general.fragment_count);
+            assert_eq!(fast.fragment_status, general.fragment_status);
+
+            let [Some((fast_handle, fast_start, fast_len))] = fast.fragment_data[..] else {
+                panic!("expected one fragment");
+            };
+            let [Some((general_handle, general_start, general_len))] = general.fragment_data[..] else {
+                panic!("expected one fragment");
+            };
+            assert_eq!((fast_start, fast_len), (general_start, general_len));
+            // the header isn't written until the fragment is sent, so only the data is compared
+            let range = fast_start..(fast_start + fast_len);
+            let written = |handle| unsafe { MaybeUninit::slice_assume_init_ref(&pool.get(handle).unwrap()[range.clone()]) };
+            assert_eq!(written(fast_handle), written(general_handle));
+            assert_eq!(written(fast_handle), &data[..]);
+
+            pool.release(fast_handle).unwrap();
+            pool.release(general_handle).unwrap();
+        }
+    }
+
+    #[test]
+    fn single_fragment_message_round_trips() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = connected_server();
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        store_fragment(&mut conn, 0, 0, 1, 7, now).unwrap();
+        store_fragment(&mut conn, 1, 0, 1, 8, now).unwrap();
+
+        // the message stays queued if it doesn't fit
+        let mut buf = [0u8; 100];
+        assert_eq!(conn.recv(&mut buf[..99]), Err(ErrorKind::RecvBufferTooSmall(100).into()));
+        assert_eq!(conn.recv(&mut buf), Ok(100));
+        assert!(buf.iter().all(|&byte| byte == 7));
+        assert_eq!(conn.recv(&mut buf), Ok(100));
+        assert!(buf.iter().all(|&byte| byte == 8));
+        assert_eq!(conn.recv(&mut buf), Ok(0));
+        assert_eq!(conn.pool.capacity_remaining(), 8);
+    }
+
+    #[test]
+    fn ordered_channel_keeps_aliased_message_in_window() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = connected_server();
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        let aliased = conn.channel.recv_buffer.capacity() as u64;
+
+        // the first message hasn't been delivered, so it's still in the window
+        store_fragment(&mut conn, 0, 0, 2, 0, now).unwrap();
+        assert_eq!(
+            store_fragment(&mut conn, aliased, 0, 1, 1, now),
+            Err(ErrorKind::MessageOlderThanThreshold.into())
+        );
+        assert_eq!(conn.pool.capacity_remaining(), 7);
+
+        store_fragment(&mut conn, 0, 1, 2, 0, now).unwrap();
+        let mut buf = [0u8; 300];
+        assert_eq!(conn.recv(&mut buf), Ok(200));
+        assert!(buf[..200].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn unordered_channel_evicts_aliased_message_out_of_window() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = connected_server();
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Unordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        let capacity = conn.channel.recv_buffer.capacity() as u64;
+
+        store_fragment(&mut conn, 100, 0, 1, 0, now).unwrap();
+        assert_eq!(conn.recv(&mut [0u8; 100]), Ok(100));
+
+        // a newer message in progress isn't clobbered by an older one in the same slot
+        let newer = 40 + capacity;
+        store_fragment(&mut conn, newer, 0, 2, 1, now).unwrap();
+        assert_eq!(
+            store_fragment(&mut conn, 40, 0, 1, 2, now),
+            Err(ErrorKind::MessageOlderThanThreshold.into())
+        );
+        assert!(conn.channel.recv_buffer.contains(newer));
+        assert_eq!(conn.pool.capacity_remaining(), 7);
+
+        // but once the window moves past it, it's released
+        let partial = 101;
+        store_fragment(&mut conn, partial, 0, 2, 3, now).unwrap();
+        store_fragment(&mut conn, partial + capacity, 0, 1, 4, now).unwrap();
+        assert!(!conn.channel.recv_buffer.contains(partial));
+        assert_eq!(conn.pool.capacity_remaining(), 6);
+    }
+
+    #[test]
+    fn sequenced_channel_releases_older_partial_message() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = Connection::new(
+            0,
+            1,
+            "127.0.0.1:0".parse().unwrap(),
+            Role::Client,
+            Config::default(),
+            now,
+        );
+        let mut channel = Channel::new(0, Send::Unreliable, Receive::Sequenced);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+
+        // two fragments of the first snapshot arrive
+        store_fragment(&mut conn, 0, 0, 3, 0, now).unwrap();
+        store_fragment(&mut conn, 0, 2, 3, 0, now).unwrap();
+        assert_eq!(conn.pool.capacity_remaining(), 6);
+
+        // a fragment of the second snapshot releases the first
+        store_fragment(&mut conn, 1, 1, 2, 1, now).unwrap();
+        assert_eq!(conn.pool.capacity_remaining(), 7);
+        assert!(conn.channel.recv_buffer.get(0).map_or(true, Option::is_none));
+
+        // the rest of the first snapshot is dropped
+        assert_eq!(
+            store_fragment(&mut conn, 0, 1, 3, 0, now),
+            Err(ErrorKind::MessageOlderThanThreshold.into())
+        );
+        assert_eq!(conn.pool.capacity_remaining(), 7);
+
+        store_fragment(&mut conn, 1, 0, 2, 1, now).unwrap();
+        assert_eq!(conn.channel.acks.latest_recv(), Some(1));
+        assert_eq!(conn.channel.recv_partial, None);
+
+        // duplicates of a complete snapshot are dropped too
+        assert_eq!(
+            store_fragment(&mut conn, 1, 0, 2, 1, now),
+            Err(ErrorKind::MessageOlderThanThreshold.into())
+        );
+
+        let mut buf = [0u8; 300];
+        assert_eq!(conn.recv(&mut buf), Ok(200));
+        assert!(buf[..200].iter().all(|&byte| byte == 1));
+        assert_eq!(conn.recv(&mut buf), Ok(0));
+        assert_eq!(conn.pool.capacity_remaining(), 8);
+    }
+
+    fn sent_message(sequence: SequenceNumber, time_sent: Instant) -> SendMessage {
+        SendMessage {
+            sequence,
+            fragment_count: 1,
+            fragment_sent: 1,
+            fragment_data: vec![None].into_boxed_slice(),
+            fragment_status: vec![SendStatus::Sent].into_boxed_slice(),
+            time_created: time_sent,
+            time_sent: Some(time_sent),
+            time_latest_send: Some(time_sent),
+            retransmits: 0,
+        }
+    }
+
+    /// Puts `message` in the send buffer of `channel` as the newest message sent on it.
+    fn insert_sent(channel: &mut Channel, message: SendMessage) {
+        let sequence = message.sequence;
+        channel.send_buffer.insert(sequence, message);
+        channel.acks.next_send = sequence + 1;
+        channel.acks.oldest_send_unacked.get_or_insert(sequence);
+    }
+
+    #[test]
+    fn acknowledge_updates_rtt() {
+        let start = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = Connection::new(
+            0,
+            1,
+            "127.0.0.1:0".parse().unwrap(),
+            Role::Client,
+            Config::default(),
+            start,
+        );
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+
+        let factor = Config::default().rtt_smoothing_factor() as f64;
+        let sample = Duration::from_millis(50);
+        let mut expected = DEFAULT_RTT_MS as f64 / 1000.0;
+
+        for sequence in 0..64 {
+            let time_sent = start + Duration::from_millis(10 * sequence);
+            insert_sent(conn.channel, sent_message(sequence, time_sent));
+            conn.acknowledge(sequence, 1, time_sent + sample);
+
+            expected += factor * (sample.as_secs_f64() - expected);
+            let rtt = conn.connection.rtt().as_secs_f64();
+            assert!((rtt - expected).abs() < 1e-6);
+        }
+
+        // converged on the true round-trip time
+        assert!((conn.connection.rtt().as_secs_f64() - sample.as_secs_f64()).abs() < 1e-3);
+
+        // a send time in the future must not produce a sample
+        let time_sent = start + Duration::from_secs(10);
+        insert_sent(conn.channel, sent_message(64, time_sent));
+        let rtt = conn.connection.rtt();
+        conn.acknowledge(64, 1, start);
+        assert_eq!(conn.connection.rtt(), rtt);
+    }
+
+    #[test]
+    fn estimated_bandwidth_converges_to_delivery_rate() {
+        let start = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let handle = pool.acquire().unwrap();
+        let mut connection = connected_server();
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        assert_eq!(conn.connection.estimated_bandwidth(), 0.0);
+
+        // a 1000-byte message acknowledged every 20ms, then every 10ms
+        let mut time = start;
+        for sequence in 0..220 {
+            time += Duration::from_millis(if sequence < 20 { 20 } else { 10 });
+            let mut message = sent_message(sequence, time);
+            message.fragment_data[0] = Some((handle, 0, 1000));
+            insert_sent(conn.channel, message);
+            conn.acknowledge(sequence, 1, time);
+
+            if sequence == 19 {
+                assert!((conn.connection.estimated_bandwidth() - 50_000.0).abs() < 1.0);
+            }
+        }
+
+        assert!((conn.connection.estimated_bandwidth() - 100_000.0).abs() < 1_000.0);
+
+        // acknowledgements that don't confirm anything don't count
+        let bandwidth = conn.connection.estimated_bandwidth();
+        conn.acknowledge(220, 0, time + Duration::from_secs(1));
+        assert_eq!(conn.connection.estimated_bandwidth(), bandwidth);
+    }
+
+    #[test]
+    fn unacked_message_resends_after_rto() {
+        let clock = ManualClock::new(Instant::now());
+        let mut server = connected_server();
+        let rto = server.rto();
+        assert_eq!(rto, Duration::from_millis(DEFAULT_RTT_MS as u64) * 3);
+
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+        channel.send_buffer.insert(0, sent_message(0, clock.now()));
+        channel.acks.next_send = 1;
+        server.channels.insert(0, channel);
+
+        fn message(server: &mut Connection) -> &mut SendMessage {
+            server.channels.get_mut(&0).unwrap().send_buffer.get_mut(0).unwrap().as_mut().unwrap()
+        }
+        let tick = Duration::from_millis(1);
+
+        for retransmits in 0..3 {
+            let timeout = rto * (1 << retransmits);
+            clock.advance(timeout);
+            server.time_latest_recv = Some(clock.now());
+            server.update(clock.now());
+            assert_eq!(message(&mut server).fragment_status[0], SendStatus::Sent);
+
+            clock.advance(tick);
+            server.update(clock.now());
+            assert_eq!(message(&mut server).fragment_status[0], SendStatus::Lost);
+            assert_eq!(message(&mut server).retransmits, retransmits + 1);
+
+            // not resent yet, so it doesn't time out again
+            clock.advance(timeout * 4);
+            server.time_latest_recv = Some(clock.now());
+            server.update(clock.now());
+            assert_eq!(message(&mut server).retransmits, retransmits + 1);
+
+            // resend it
+            message(&mut server).fragment_status[0] = SendStatus::Sent;
+            message(&mut server).time_latest_send = Some(clock.now());
+        }
+
+        // the timeout stops growing eventually
+        assert_eq!(backoff(rto, 2), rto * 4);
+        assert_eq!(backoff(rto, 20), MAX_RTO);
+        assert_eq!(backoff(rto, u32::MAX), MAX_RTO);
+    }
+
+    #[test]
+    fn duplicate_acks_trigger_fast_retransmit() {
+        let start = Instant::now();
+        let config = Config::builder().fast_retransmit_threshold(3).build().unwrap();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = Connection::new(
+            0,
+            1,
+            "127.0.0.1:0".parse().unwrap(),
+            Role::Client,
+            config,
+            start,
+        );
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+
+        for sequence in 0..6 {
+            conn.channel.send_buffer.insert(sequence, sent_message(sequence, start));
+        }
+        conn.channel.acks.next_send = 6;
+        let status = |conn: &ConnectionRef| {
+            conn.channel.send_buffer.get(0).unwrap().as_ref().unwrap().fragment_status[0]
+        };
+
+        // every ack confirms the next message, but message 0 never arrives
+        conn.acknowledge(1, 0b1, start);
+        assert_eq!(conn.channel.dup_acks, Some((0, 0)));
+        for acked in 2..4 {
+            conn.acknowledge(acked, (1 << acked) - 1, start);
+            assert_eq!(status(&conn), SendStatus::Sent);
+        }
+
+        // the third duplicate resends it
+        conn.acknowledge(4, 0b1111, start);
+        assert_eq!(status(&conn), SendStatus::Lost);
+        assert_eq!(conn.channel.resend_order(), vec![(0, 0)]);
+        assert_eq!(conn.channel.dup_acks, Some((0, 0)));
+
+        // filling the hole stops counting
+        conn.acknowledge(5, 0b111111, start);
+        assert!(conn.channel.send_buffer.get(0).map_or(true, Option::is_none));
+        assert_eq!(conn.channel.dup_acks, None);
+    }
+
+    #[test]
+    fn channel_ack_frames_track_each_channel() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = connected_server();
+        let mut ordered = Channel::new(5, Send::Reliable, Receive::Ordered);
+        let mut unordered = Channel::new(6, Send::Unreliable, Receive::Unordered);
+        assert_eq!(ordered.ack_frame(), None);
+
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut ordered,
+            pool: &mut pool,
+        };
+        for sequence in [0, 1, 3] {
+            store_fragment(&mut conn, sequence, 0, 1, 1, now).unwrap();
+        }
+        // a late message fills in its bit
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut unordered,
+            pool: &mut pool,
+        };
+        for sequence in [4, 2] {
+            store_fragment(&mut conn, sequence, 0, 1, 1, now).unwrap();
+        }
+
+        assert_eq!(
+            ordered.ack_frame(),
+            Some(Frame::ChannelAck { channel_id: 5, ack_sequence: 3, ack_mask: 0b1101 }),
+        );
+        assert_eq!(
+            unordered.ack_frame(),
+            Some(Frame::ChannelAck { channel_id: 6, ack_sequence: 4, ack_mask: 0b101 }),
+        );
+    }
+
+    #[test]
+    fn record_recv_tracks_mask_width_boundary() {
+        for bits in [32, 64] {
+            let mut acks = ChannelAcknowledgment::new();
+            acks.record_recv(0, bits);
+
+            // the oldest message that still fits in the mask
+            let edge = bits as u64 - 1;
+            acks.record_recv(edge, bits);
+            assert_eq!(acks.latest_recv_mask(), 1 | (1 << edge));
+
+            // one more and it falls out
+            acks.record_recv(edge + 1, bits);
+            assert_eq!(acks.latest_recv_mask(), 0b11);
+
+            // a late message at the edge is recorded, one past it isn't
+            acks.record_recv(1, bits);
+            assert_eq!(acks.latest_recv_mask(), 0b11 | (1 << edge));
+            acks.record_recv(0, bits);
+            assert_eq!(acks.latest_recv_mask(), 0b11 | (1 << edge));
+        }
+    }
+
+    #[test]
+    fn acknowledge_only_covers_messages_in_flight() {
+        let now = Instant::now();
+        let mut channel = Channel::new(0, Send::Unreliable, Receive::Unordered);
+        let mut events = VecDeque::new();
+        for sequence in 0..4 {
+            insert_sent(&mut channel, sent_message(sequence, now));
+        }
+        assert_eq!(channel.acks.oldest_send_unacked(), Some(0));
+
+        // a message that hasn't been sent can't be acknowledged
+        channel.acknowledge(4, 1, 0, 32, now, 0, &mut events);
+        channel.acknowledge(u64::MAX / 2, u64::MAX, 0, 32, now, 0, &mut events);
+        assert!(events.is_empty());
+        assert_eq!(channel.acks.latest_send_acked(), None);
+
+        channel.acknowledge(1, 0b11, 0, 32, now, 0, &mut events);
+        assert_eq!(events.len(), 2);
+        assert_eq!(channel.acks.oldest_send_unacked(), Some(2));
+
+        channel.acknowledge(3, 0b11, 0, 32, now, 0, &mut events);
+        assert_eq!(events.len(), 4);
+        assert_eq!(channel.acks.oldest_send_unacked(), None);
+    }
+
+    #[test]
+    fn acknowledge_classifies_message_at_mask_width_boundary() {
+        let now = Instant::now();
+        for bits in [32, 64] {
+            let mut channel = Channel::new(0, Send::Unreliable, Receive::Unordered);
+            let acked = bits as u64;
+            for sequence in 0..=acked {
+                insert_sent(&mut channel, sent_message(sequence, now));
+            }
+
+            // every bit is set, but only the low `bits` bits count
+            let mut events = VecDeque::new();
+            channel.acknowledge(acked, u64::MAX, 0, bits, now, 0, &mut events);
+
+            let lost = events
+                .iter()
+                .filter_map(|event| match event {
+                    ConnectionEvent::DeliveryLost { sequence, .. } => Some(*sequence),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(lost, [0]);
+            assert!(events.contains(&ConnectionEvent::DeliveryConfirmed {
+                connection: 0,
+                channel: 0,
+                sequence: 1,
+            }));
+        }
+    }
+
+    #[test]
+    fn missing_sequences_reports_holes_and_partial_messages() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 16);
+        let mut connection = connected_server();
+        let mut ordered = Channel::new(5, Send::Reliable, Receive::Ordered);
+        let mut unordered = Channel::new(6, Send::Unreliable, Receive::Unordered);
+        let mut sequenced = Channel::new(7, Send::Unreliable, Receive::Sequenced);
+        assert_eq!(ordered.missing_sequences().count(), 0);
+
+        for channel in [&mut ordered, &mut unordered, &mut sequenced] {
+            let mut conn = ConnectionRef {
+                connection: &mut connection,
+                channel,
+                pool: &mut pool,
+            };
+            for sequence in [0, 3] {
+                store_fragment(&mut conn, sequence, 0, 1, 1, now).unwrap();
+            }
+            // only half of 4 has arrived
+            store_fragment(&mut conn, 4, 0, 2, 1, now).unwrap();
+            store_fragment(&mut conn, 6, 0, 1, 1, now).unwrap();
+        }
+
+        // 0 has been delivered, 3 and 6 are waiting on the holes
+        assert_eq!(ordered.missing_sequences().collect::<Vec<_>>(), [1, 2, 4, 5]);
+        assert_eq!(unordered.missing_sequences().collect::<Vec<_>>(), [1, 2, 4, 5]);
+        assert_eq!(sequenced.missing_sequences().count(), 0);
+
+        // messages that were handed out don't count as missing
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut unordered,
+            pool: &mut pool,
+        };
+        let mut buf = [0u8; 128];
+        for _ in 0..3 {
+            assert_eq!(conn.recv(&mut buf), Ok(100));
+        }
+        assert_eq!(conn.recv(&mut buf), Ok(0));
+        assert_eq!(unordered.missing_sequences().collect::<Vec<_>>(), [1, 2, 4, 5]);
+
+        // filling a hole in the ordered channel moves the window along
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut ordered,
+            pool: &mut pool,
+        };
+        for sequence in [1, 2] {
+            store_fragment(&mut conn, sequence, 0, 1, 1, now).unwrap();
+        }
+        assert_eq!(ordered.acks.next_recv_ordered(), Some(4));
+        assert_eq!(ordered.missing_sequences().collect::<Vec<_>>(), [4, 5]);
+    }
+
+    #[test]
+    fn channel_ack_only_advances_its_channel() {
+        let now = Instant::now();
+        let mut server = connected_server();
+        for channel_id in [5, 6] {
+            let mut channel = Channel::new(channel_id, Send::Reliable, Receive::Ordered);
+            for sequence in 0..2 {
+                insert_sent(&mut channel, sent_message(sequence, now));
+            }
+            server.channels.insert(channel_id, channel);
+        }
+
+        let mut connections = endpoint(Config::default(), HashMap::from([(1, server)]));
+
+        let mut data = [0u8; 64];
+        let mut buf = BytesMut::new(&mut data);
+        Header::Short {
+            packet_number: 0,
+            packet_type: PacketType::Data,
+            dst_id: 1,
+        }
+        .write(&mut buf)
+        .unwrap();
+        Frame::ChannelAck {
+            channel_id: 5,
+            ack_sequence: 1,
+            ack_mask: 0b11,
+        }
+        .write(&mut buf)
+        .unwrap();
+        let len = buf.position();
+        connections
+            .handle_datagram("127.0.0.1:0".parse().unwrap(), &data[..len], now)
+            .unwrap();
+
+        let server = &connections.conn[&1];
+        let acked = &server.channels[&5];
+        assert_eq!(acked.acks.latest_send_acked(), Some(1));
+        assert!((0..2).all(|sequence| acked.send_buffer.get(sequence).map_or(true, Option::is_none)));
+        let unacked = &server.channels[&6];
+        assert_eq!(unacked.acks.latest_send_acked(), None);
+        assert!((0..2).all(|sequence| matches!(unacked.send_buffer.get(sequence), Some(Some(_)))));
+
+        assert_eq!(
+            connections.poll().collect::<Vec<_>>(),
+            [
+                ConnectionEvent::DeliveryConfirmed { connection: 1, channel: 5, sequence: 0 },
+                ConnectionEvent::DeliveryConfirmed { connection: 1, channel: 5, sequence: 1 },
+            ],
+        );
+    }
+
+    #[test]
+    fn open_channel_over_loopback() {
+        let now = Instant::now();
+        let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut client = Connection::new(
+            0,
+            1,
+            server_socket.local_addr().unwrap(),
+            Role::Client,
+            Config::default(),
+            now,
+        );
+        let mut server = Connection::new(
+            1,
+            0,
+            client_socket.local_addr().unwrap(),
+            Role::Server,
+            Config::default(),
+            now,
+        );
+
+        let frame = client
+            .open_channel(5, Send::Unreliable, Receive::Sequenced)
+            .unwrap()
+            .open_frame();
+
+        let mut data = [0u8; 32];
+        let mut buf = BytesMut::new(&mut data);
+        frame.write(&mut buf).unwrap();
+        let len = buf.position();
+        client_socket
+            .send_to(&data[..len], server_socket.local_addr().unwrap())
+            .unwrap();
+
+        let mut data = [0u8; 32];
+        let (len, _) = server_socket.recv_from(&mut data).unwrap();
+        match Frame::read(&mut BytesMut::new(&mut data[..len])).unwrap() {
+            Frame::OpenChannel {
+                channel_id,
+                send_guarantee,
+                recv_guarantee,
+            } => {
+                server.open_channel(channel_id, send_guarantee, recv_guarantee).unwrap();
+            },
+            frame => panic!("unexpected frame: {:?}", frame),
+        }
+
+        let client_channel = &client.channels[&5];
+        let server_channel = &server.channels[&5];
+        assert_eq!(server_channel.send_guarantee, client_channel.send_guarantee);
+        assert_eq!(server_channel.recv_guarantee, client_channel.recv_guarantee);
+
+        // a repeated frame is harmless, but conflicting guarantees are rejected
+        assert!(server.open_channel(5, Send::Unreliable, Receive::Sequenced).is_ok());
+        assert_eq!(
+            server.open_channel(5, Send::Reliable, Receive::Ordered).err(),
+            Some(ErrorKind::ChannelGuaranteesMismatch),
+        );
+    }
+
+    /// Sends a data packet opening `channel_id` to the connection `1` at `addr`.
+    fn send_open_channel(socket: &UdpSocket, addr: SocketAddr, channel_id: ChannelId) {
+        let mut data = [0u8; 64];
+        let mut buf = BytesMut::new(&mut data);
+        Header::Short {
+            packet_number: channel_id,
+            packet_type: PacketType::Data,
+            dst_id: 1,
+        }
+        .write(&mut buf)
+        .unwrap();
+        Frame::OpenChannel {
+            channel_id,
+            send_guarantee: Send::Reliable,
+            recv_guarantee: Receive::Ordered,
+        }
+        .write(&mut buf)
+        .unwrap();
+        let len = buf.position();
+        socket.send_to(&data[..len], addr).unwrap();
+    }
+
+    #[test]
+    fn recv_on_drains_socket() {
+        let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server_socket.set_nonblocking(true).unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+
+        let mut server = connected_server();
+        server.peer_addr = client_socket.local_addr().unwrap();
+
+        let mut connections = endpoint(Config::default(), HashMap::from([(1, server)]));
+
+        // more datagrams than there are buffers, since each one is released after parsing
+        for channel_id in 0..12 {
+            send_open_channel(&client_socket, server_addr, channel_id);
+        }
+        // a malformed packet is dropped
+        client_socket.send_to(&[0x10, 0, 0], server_addr).unwrap();
+
+        assert_eq!(connections.recv_on(&server_socket).unwrap(), 13);
+        assert_eq!(connections.conn[&1].channels.len(), 12);
+        assert_eq!(connections.pool.capacity_remaining(), 8);
+
+        // nothing left
+        assert_eq!(connections.recv_on(&server_socket).unwrap(), 0);
+    }
+
+    #[test]
+    fn recv_on_delivers_data_packet() {
+        let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server_socket.set_nonblocking(true).unwrap();
+
+        let mut server = connected_server();
+        server.peer_addr = client_socket.local_addr().unwrap();
+
+        let mut connections = endpoint(Config::default(), HashMap::from([(1, server)]));
+
+        // one packet opens the channel and carries a message in two fragments
+        let mut data = [0u8; 128];
+        let mut buf = BytesMut::new(&mut data);
+        Header::Short {
+            packet_number: 0,
+            packet_type: PacketType::Data,
+            dst_id: 1,
+        }
+        .write(&mut buf)
+        .unwrap();
+        Frame::OpenChannel {
+            channel_id: 5,
+            send_guarantee: Send::Reliable,
+            recv_guarantee: Receive::Ordered,
+        }
+        .write(&mut buf)
+        .unwrap();
+        for (index, chunk) in [&b"hello "[..], &b"world"[..]].into_iter().enumerate() {
+            Frame::Data {
+                channel_id: 5,
+                channel_sequence: 0,
+                fragment_index: index as u16,
+                fragment_count: 2,
+                len: chunk.len() as u16,
+            }
+            .write(&mut buf)
+            .unwrap();
+            buf.copy_from_slice(chunk).unwrap();
+        }
+        let len = buf.position();
+        client_socket
+            .send_to(&data[..len], server_socket.local_addr().unwrap())
+            .unwrap();
+
+        assert_eq!(connections.recv_on(&server_socket).unwrap(), 1);
+        assert_eq!(
+            connections.poll().collect::<Vec<_>>(),
+            [ConnectionEvent::MessageReceived { connection: 1, channel: 5, len: 11 }],
+        );
+
+        let connection = connections.conn.get_mut(&1).unwrap();
+        let mut channel = connection.channels.remove(&5).unwrap();
+        let mut conn = ConnectionRef {
+            connection,
+            channel: &mut channel,
+            pool: &mut connections.pool,
+        };
+        let mut buf = [0u8; 16];
+        assert_eq!(conn.recv(&mut buf), Ok(11));
+        assert_eq!(&buf[..11], b"hello world");
+        assert_eq!(conn.pool.capacity_remaining(), 8);
+    }
+
+    #[test]
+    fn recv_on_respects_packet_budget() {
+        let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server_socket.set_nonblocking(true).unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+
+        let mut server = connected_server();
+        server.peer_addr = client_socket.local_addr().unwrap();
+
+        let config = Config::builder().socket_event_buffer_size(2).build().unwrap();
+        let mut connections = endpoint(config, HashMap::from([(1, server)]));
+
+        for channel_id in 0..5 {
+            send_open_channel(&client_socket, server_addr, channel_id);
+        }
+
+        assert_eq!(connections.recv_on(&server_socket).unwrap(), 2);
+        assert_eq!(connections.recv_on(&server_socket).unwrap(), 2);
+        assert_eq!(connections.recv_on(&server_socket).unwrap(), 1);
+        assert_eq!(connections.recv_on(&server_socket).unwrap(), 0);
+        assert_eq!(connections.conn[&1].channels.len(), 5);
+    }
+
+    /// Returns the frames of the (unsealed) packet in `data`, after its header. The bytes of
+    /// each fragment are skipped.
+    fn read_frames(data: &mut [u8]) -> Vec<Frame> {
+        let mut buf = BytesMut::new(data);
+        Header::read(&mut buf).unwrap();
+        let mut frames = Vec::new();
+        while buf.remaining() > 0 {
+            let frame = Frame::read(&mut buf).unwrap();
+            if let Frame::Data { len, .. } = frame {
+                buf.advance(len as usize).unwrap();
+            }
+            frames.push(frame);
+        }
+        frames
+    }
+
+    /// Moves every datagram `from` has to send over to `to` (which is at `to_addr`) and returns
+    /// how many there were.
+    fn shuttle(from: &mut Connections, from_addr: SocketAddr, to: &mut Connections, to_addr: SocketAddr, now: Instant) -> usize {
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        let mut count = 0;
+        while let Some((addr, len)) = from.poll_transmit(&mut data, now).unwrap() {
+            assert_eq!(addr, to_addr);
+            to.handle_datagram(from_addr, &data[..len], now).unwrap();
+            count += 1;
+        }
+        count
+    }
+
+    #[test]
+    fn connect_and_send_message_without_sockets() {
+        let now = Instant::now();
+        let client_addr: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let server_addr: SocketAddr = "192.0.2.2:6000".parse().unwrap();
+
+        let mut client = Connection::new(7, 1, server_addr, Role::Client, Config::default(), now);
+        client.connect(now);
+        // the server learns the client's address from its first packet
+        let server = Connection::new(
+            1,
+            0,
+            "0.0.0.0:0".parse().unwrap(),
+            Role::Server,
+            Config::default(),
+            now,
+        );
+
+        let mut client = endpoint(Config::default(), HashMap::from([(7, client)]));
+        client.set_protector(XorProtector { key: 0x5a });
+        client.set_clock(ManualClock::new(now));
+        let mut server = endpoint(Config::default(), HashMap::from([(1, server)]));
+        server.set_protector(XorProtector { key: 0x5a });
+        server.set_clock(ManualClock::new(now));
+
+        // connect
+        assert_eq!(shuttle(&mut client, client_addr, &mut server, server_addr, now), 1);
+        assert_eq!(server.poll().collect::<Vec<_>>(), [ConnectionEvent::Connected(1)]);
+        assert_eq!(server.conn[&1].dst_id(), 7);
+        // accept
+        assert_eq!(shuttle(&mut server, server_addr, &mut client, client_addr, now), 1);
+        assert_eq!(client.poll().collect::<Vec<_>>(), [ConnectionEvent::Connected(7)]);
+
+        client.send_message(7, 5, b"hello").unwrap();
+        client.send_message(7, 6, b"world").unwrap();
+        // both channels fit in one packet
+        assert_eq!(shuttle(&mut client, client_addr, &mut server, server_addr, now), 1);
+        assert_eq!(shuttle(&mut client, client_addr, &mut server, server_addr, now), 0);
+        assert_eq!(client.conn[&7].acks.packet_number(), 1);
+
+        let mut events = server.poll().collect::<Vec<_>>();
+        events.sort_by_key(|event| match *event {
+            ConnectionEvent::MessageReceived { channel, .. } => channel,
+            _ => u64::MAX,
+        });
+        assert_eq!(
+            events,
+            [
+                ConnectionEvent::MessageReceived { connection: 1, channel: 5, len: 5 },
+                ConnectionEvent::MessageReceived { connection: 1, channel: 6, len: 5 },
+            ],
+        );
+
+        let connection = server.conn.get_mut(&1).unwrap();
+        for (channel_id, expected) in [(5, b"hello"), (6, b"world")] {
+            let mut channel = connection.channels.remove(&channel_id).unwrap();
+            let mut conn = ConnectionRef {
+                connection: &mut *connection,
+                channel: &mut channel,
+                pool: &mut server.pool,
+            };
+            let mut buf = [0u8; 16];
+            assert_eq!(conn.recv(&mut buf), Ok(5));
+            assert_eq!(&buf[..5], expected);
+        }
+        assert_eq!(server.pool.capacity_remaining(), 8);
+    }
+
+    #[test]
+    fn poll_transmit_acknowledges_received_data_on_its_own() {
+        let now = Instant::now();
+        let client_addr: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let server_addr: SocketAddr = "192.0.2.2:6000".parse().unwrap();
+
+        let mut client = Connection::new(7, 1, server_addr, Role::Client, Config::default(), now);
+        client.connect(now);
+        client.handle_request(Request::Accept);
+        client.pending_request = None;
+        let mut client = endpoint(Config::default(), HashMap::from([(7, client)]));
+        client.set_clock(ManualClock::new(now));
+        let mut server = connected_server();
+        server.dst_id = 7;
+        server.peer_addr = client_addr;
+        let mut server = endpoint(Config::default(), HashMap::from([(1, server)]));
+        server.set_clock(ManualClock::new(now));
+        assert_eq!(client.conn[&7].state(), ConnectionState::Connected);
+
+        // nothing received, nothing to acknowledge
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        assert_eq!(server.poll_transmit(&mut data, now).unwrap(), None);
+
+        client.send_message(7, 0, b"hello").unwrap();
+        assert_eq!(shuttle(&mut client, client_addr, &mut server, server_addr, now), 1);
+
+        // the server has nothing to send back, so the acknowledgement goes alone
+        let (addr, len) = server.poll_transmit(&mut data, now).unwrap().unwrap();
+        assert_eq!(addr, client_addr);
+        let mut buf = BytesMut::new(&mut data[..len]);
+        assert!(matches!(Header::read(&mut buf).unwrap(), Header::Short { packet_number: 0, .. }));
+        assert_eq!(Frame::read(&mut buf).unwrap(), Frame::Ack { ack_sequence: 0, ack_mask: 1 });
+        assert_eq!(
+            Frame::read(&mut buf).unwrap(),
+            Frame::ChannelAck { channel_id: 0, ack_sequence: 0, ack_mask: 1 },
+        );
+        assert_eq!(buf.remaining(), 0);
+        // and only once, without touching the packet in `data`
+        assert_eq!(server.poll_transmit(&mut data, now).unwrap(), None);
+        assert_eq!(server.conn[&1].channels[&0].acks.sequence(), 0);
+
+        client.handle_datagram(server_addr, &data[..len], now).unwrap();
+        assert!(!matches!(client.conn[&7].channels[&0].send_buffer.get(0), Some(Some(_))));
+
+        // more data right away waits for the ack interval
+        client.send_message(7, 0, b"world").unwrap();
+        assert_eq!(shuttle(&mut client, client_addr, &mut server, server_addr, now), 1);
+        assert_eq!(server.poll_transmit(&mut data, now).unwrap(), None);
+        let later = now + Config::default().ack_interval();
+        assert_eq!(shuttle(&mut server, server_addr, &mut client, client_addr, later), 1);
+        assert!(!matches!(client.conn[&7].channels[&0].send_buffer.get(1), Some(Some(_))));
+    }
+
+    #[test]
+    fn data_packets_carry_channel_acks_both_ways() {
+        let now = Instant::now();
+        let client_addr: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let server_addr: SocketAddr = "192.0.2.2:6000".parse().unwrap();
+
+        let mut client = Connection::new(7, 1, server_addr, Role::Client, Config::default(), now);
+        client.connect(now);
+        client.handle_request(Request::Accept);
+        client.pending_request = None;
+        let mut client = endpoint(Config::default(), HashMap::from([(7, client)]));
+        client.pool = BufferPool::new(MAX_PAYLOAD_BYTES, 32);
+        client.set_clock(ManualClock::new(now));
+        let mut server = connected_server();
+        server.dst_id = 7;
+        server.peer_addr = client_addr;
+        let mut server = endpoint(Config::default(), HashMap::from([(1, server)]));
+        server.pool = BufferPool::new(MAX_PAYLOAD_BYTES, 32);
+        server.set_clock(ManualClock::new(now));
+
+        // both ends keep sending, so there's always data to carry the acknowledgements
+        for sequence in 0..4 {
+            client.send_message(7, 0, b"ping").unwrap();
+            server.send_message(1, 0, b"pong").unwrap();
+            assert_eq!(shuttle(&mut client, client_addr, &mut server, server_addr, now), 1);
+            assert_eq!(shuttle(&mut server, server_addr, &mut client, client_addr, now), 1);
+
+            // the server's packet confirmed the message the client just sent
+            let channel = &client.conn[&7].channels[&0];
+            assert_eq!(channel.acks.latest_send_acked(), Some(sequence));
+            assert_eq!(channel.acks.oldest_send_unacked(), None);
+            // and the client's packet confirmed the one the server sent before
+            let channel = &server.conn[&1].channels[&0];
+            assert_eq!(channel.acks.latest_send_acked(), sequence.checked_sub(1));
+        }
+        assert!(client.poll().any(|event| event == ConnectionEvent::DeliveryConfirmed { connection: 7, channel: 0, sequence: 3 }));
+    }
+
+    #[test]
+    fn in_flight_limit_holds_data_until_acknowledged() {
+        let now = Instant::now();
+        let client_addr: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let server_addr: SocketAddr = "192.0.2.2:6000".parse().unwrap();
+        let config = Config::builder().max_packets_in_flight(1).build().unwrap();
+
+        let mut client = Connection::new(7, 1, server_addr, Role::Client, config.clone(), now);
+        client.connect(now);
+        client.handle_request(Request::Accept);
+        client.pending_request = None;
+        let mut client = endpoint(config, HashMap::from([(7, client)]));
+        client.set_clock(ManualClock::new(now));
+        let mut server = connected_server();
+        server.dst_id = 7;
+        server.peer_addr = client_addr;
+        let mut server = endpoint(Config::default(), HashMap::from([(1, server)]));
+        server.set_clock(ManualClock::new(now));
+
+        // too large to share a packet
+        client.send_message(7, 0, &[1; 700]).unwrap();
+        client.send_message(7, 1, &[2; 700]).unwrap();
+
+        // one packet goes out, the other message waits for it to be acknowledged
+        assert_eq!(shuttle(&mut client, client_addr, &mut server, server_addr, now), 1);
+        assert!(client.conn[&7].in_flight_full());
+        assert_eq!(client.conn[&7].time_in_flight_full, Some(now));
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        assert_eq!(client.poll_transmit(&mut data, now).unwrap(), None);
+
+        // the server's acknowledgement frees up room
+        assert_eq!(shuttle(&mut server, server_addr, &mut client, client_addr, now), 1);
+        assert!(!client.conn[&7].in_flight_full());
+        assert_eq!(client.conn[&7].time_in_flight_full, None);
+        assert_eq!(shuttle(&mut client, client_addr, &mut server, server_addr, now), 1);
+        assert!(client.conn[&7].in_flight_full());
+        assert_eq!(client.conn[&7].time_in_flight_full, None);
+    }
+
+    #[test]
+    fn bytes_in_flight_tracks_unacknowledged_packets() {
+        let now = Instant::now();
+        let mut connections = endpoint(Config::default(), HashMap::from([(1, connected_server())]));
+        connections.set_clock(ManualClock::new(now));
+        let peer_addr = connections.conn[&1].peer_addr;
+        assert_eq!(connections.conn[&1].bytes_in_flight(), 0);
+
+        // one packet each, all of different sizes
+        let mut sizes = Vec::new();
+        for len in [10, 200, 30] {
+            connections.send_message(1, 0, &vec![7; len]).unwrap();
+            let mut data = [0u8; MAX_PACKET_BYTES];
+            let (_, size) = connections.poll_transmit(&mut data, now).unwrap().unwrap();
+            sizes.push(size);
+            assert_eq!(connections.conn[&1].bytes_in_flight(), sizes.iter().sum::<usize>());
+        }
+
+        let mut ack = |packet_number: u64, ack_sequence: u64, ack_mask: u64| {
+            let mut data = [0u8; 64];
+            let mut buf = BytesMut::new(&mut data);
+            Header::Short {
+                packet_number,
+                packet_type: PacketType::Data,
+                dst_id: 1,
+            }
+            .write(&mut buf)
+            .unwrap();
+            Frame::Ack { ack_sequence, ack_mask }.write(&mut buf).unwrap();
+            let len = buf.position();
+            connections.handle_datagram(peer_addr, &data[..len], now).unwrap();
+            connections.conn[&1].bytes_in_flight()
+        };
+
+        assert_eq!(ack(0, 0, 0b1), sizes[1] + sizes[2]);
+        // a stale acknowledgement changes nothing
+        assert_eq!(ack(1, 0, 0b1), sizes[1] + sizes[2]);
+        // 2 arrived, so 1 was lost
+        assert_eq!(ack(2, 2, 0b101), 0);
+    }
+
+    #[test]
+    fn recv_handles_acknowledgements_before_data_in_any_order() {
+        let now = Instant::now();
+        let mut connections = endpoint(Config::default(), HashMap::from([(1, connected_server())]));
+        connections.set_clock(ManualClock::new(now));
+        let peer_addr = connections.conn[&1].peer_addr;
+        connections.send_message(1, 0, b"hello").unwrap();
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        connections.poll_transmit(&mut data, now).unwrap().unwrap();
+        assert_eq!(connections.conn[&1].acks.sent_packets_in_flight(), 1);
+
+        // the reply comes with its frames backwards: data, then acknowledgements
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        let mut buf = BytesMut::new(&mut data);
+        Header::Short {
+            packet_number: 0,
+            packet_type: PacketType::Data,
+            dst_id: 1,
+        }
+        .write(&mut buf)
+        .unwrap();
+        Frame::Data {
+            channel_id: 0,
+            channel_sequence: 0,
+            fragment_index: 0,
+            fragment_count: 1,
+            len: 5,
+        }
+        .write(&mut buf)
+        .unwrap();
+        buf.copy_from_slice(b"world").unwrap();
+        Frame::ChannelAck { channel_id: 0, ack_sequence: 0, ack_mask: 1 }.write(&mut buf).unwrap();
+        Frame::Ack { ack_sequence: 0, ack_mask: 1 }.write(&mut buf).unwrap();
+        let len = buf.position();
+        connections.handle_datagram(peer_addr, &data[..len], now).unwrap();
+
+        // everything was handled, and the delivery was confirmed before the reply was delivered
+        let server = connections.conn.get_mut(&1).unwrap();
+        assert_eq!(server.acks.sent_packets_in_flight(), 0);
+        assert_eq!(
+            server.events.drain(..).collect::<Vec<_>>(),
+            [
+                ConnectionEvent::DeliveryConfirmed { connection: 1, channel: 0, sequence: 0 },
+                ConnectionEvent::MessageReceived { connection: 1, channel: 0, len: 5 },
+            ],
+        );
+    }
+
+    #[test]
+    fn update_disconnects_stalled_packets_in_flight() {
+        let now = Instant::now();
+        let timeout = Config::default().send_window_timeout();
+        let mut server = connected_server();
+        server.time_in_flight_full = Some(now);
+
+        server.time_latest_recv = Some(now + timeout);
+        server.update(now + timeout / 2);
+        assert_eq!(server.state(), ConnectionState::Connected);
+
+        server.update(now + timeout);
+        assert_eq!(server.state(), ConnectionState::Disconnecting(DisconnectReason::SendBufferIsFull));
+        assert_eq!(
+            server.events.drain(..).collect::<Vec<_>>(),
+            [ConnectionEvent::Disconnected(1, DisconnectReason::SendBufferIsFull)],
+        );
+    }
+
+    #[test]
+    fn unordered_channel_delivers_each_message_once() {
+        let mut connections = endpoint(Config::default(), HashMap::new());
+        connections.set_channel_config(ChannelConfig::new().channel(2, Send::Unreliable, Receive::Unordered));
+        connections.conn.insert(1, connected_server());
+        let now = Instant::now();
+        let from = connections.conn[&1].peer_addr;
+
+        let mut packet_number = 0;
+        let mut deliver = |connections: &mut Connections, channel_sequence: SequenceNumber| {
+            let mut data = [0u8; 256];
+            let mut buf = BytesMut::new(&mut data);
+            Header::Short {
+                packet_number,
+                packet_type: PacketType::Data,
+                dst_id: 1,
+            }
+            .write(&mut buf)
+            .unwrap();
+            Frame::Data {
+                channel_id: 2,
+                channel_sequence,
+                fragment_index: 0,
+                fragment_count: 1,
+                len: 5,
+            }
+            .write(&mut buf)
+            .unwrap();
+            buf.copy_from_slice(b"hello").unwrap();
+            let len = buf.position();
+            connections.handle_datagram(from, &data[..len], now).unwrap();
+            packet_number += 1;
+
+            // copy out whatever was delivered, which releases its buffer
+            let connection = connections.conn.get_mut(&1).unwrap();
+            let mut channel = connection.channels.remove(&2).unwrap();
+            let mut conn = ConnectionRef {
+                connection: &mut *connection,
+                channel: &mut channel,
+                pool: &mut connections.pool,
+            };
+            let mut buf = [0u8; 16];
+            let len = conn.recv(&mut buf).unwrap();
+            connection.channels.insert(2, channel);
+            connections.poll().count();
+            len
+        };
+
+        assert_eq!(deliver(&mut connections, 0), 5);
+        assert_eq!(deliver(&mut connections, 0), 0);
+        assert_eq!(connections.stats().duplicate_fragments(), 1);
+
+        // still suppressed once the acknowledgement mask no longer covers it
+        assert_eq!(deliver(&mut connections, 100), 5);
+        assert_eq!(deliver(&mut connections, 0), 0);
+        assert_eq!(deliver(&mut connections, 100), 0);
+        assert_eq!(connections.stats().duplicate_fragments(), 3);
+
+        // an older message that never arrived is still delivered
+        assert_eq!(deliver(&mut connections, 1), 5);
+        assert_eq!(connections.pool.capacity_remaining(), 8);
+    }
+
+    #[test]
+    fn declared_channels_honor_their_guarantees() {
+        let mut connections = endpoint(Config::default(), HashMap::new());
+        connections.set_channel_config(
+            ChannelConfig::new()
+                .channel(1, Send::Reliable, Receive::Ordered)
+                .channel(2, Send::Unreliable, Receive::Unordered),
+        );
+        connections.conn.insert(1, connected_server());
+        let now = Instant::now();
+        let from = connections.conn[&1].peer_addr;
+
+        // a packet carrying one single-fragment message for each (channel, sequence)
+        let packet = |packet_number: u64, frames: &[Frame], messages: &[(ChannelId, SequenceNumber)]| {
+            let mut data = [0u8; 256];
+            let mut buf = BytesMut::new(&mut data);
+            Header::Short {
+                packet_number,
+                packet_type: PacketType::Data,
+                dst_id: 1,
+            }
+            .write(&mut buf)
+            .unwrap();
+            for frame in frames {
+                frame.write(&mut buf).unwrap();
+            }
+            for &(channel_id, channel_sequence) in messages {
+                Frame::Data {
+                    channel_id,
+                    channel_sequence,
+                    fragment_index: 0,
+                    fragment_count: 1,
+                    len: 5,
+                }
+                .write(&mut buf)
+                .unwrap();
+                buf.copy_from_slice(b"hello").unwrap();
+            }
+            let len = buf.position();
+            data[..len].to_vec()
+        };
+        let received = |connections: &mut Connections| {
+            connections
+                .poll()
+                .filter_map(|event| match event {
+                    ConnectionEvent::MessageReceived { channel, .. } => Some(channel),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // the second message of each channel arrives first, only the unordered one is delivered
+        let datagram = packet(0, &[], &[(1, 1), (2, 1), (3, 0)]);
+        connections.handle_datagram(from, &datagram, now).unwrap();
+        assert_eq!(received(&mut connections), [2]);
+
+        // the channels were opened with the declared guarantees, but not the undeclared one
+        let server = &connections.conn[&1];
+        assert_eq!(server.channels[&1].send_guarantee, Send::Reliable);
+        assert_eq!(server.channels[&1].recv_guarantee, Receive::Ordered);
+        assert_eq!(server.channels[&2].send_guarantee, Send::Unreliable);
+        assert_eq!(server.channels[&2].recv_guarantee, Receive::Unordered);
+        assert!(!server.channels.contains_key(&3));
+
+        // filling the gap releases both ordered messages
+        let datagram = packet(1, &[], &[(1, 0), (2, 0)]);
+        connections.handle_datagram(from, &datagram, now).unwrap();
+        assert_eq!(received(&mut connections), [1, 1, 2]);
+
+        // the peer can't open a channel with other guarantees than the ones declared
+        let open = Frame::OpenChannel {
+            channel_id: 2,
+            send_guarantee: Send::Reliable,
+            recv_guarantee: Receive::Ordered,
+        };
+        let datagram = packet(2, &[open], &[(2, 2)]);
+        connections.handle_datagram(from, &datagram, now).unwrap();
+        assert!(received(&mut connections).is_empty());
+
+        // nor can the application send on an undeclared one
+        assert_eq!(connections.send_message(1, 3, b"hello"), Err(ErrorKind::ChannelNotDeclared.into()));
+        connections.send_message(1, 2, b"hello").unwrap();
+        assert_eq!(connections.pool.capacity_remaining(), 3);
+    }
+
+    #[test]
+    fn handle_datagram_rejects_oversized_datagram() {
+        let mut connections = endpoint(Config::default(), HashMap::from([(1, connected_server())]));
+
+        let data = [0u8; MAX_PAYLOAD_BYTES + 1];
+        let err = connections
+            .handle_datagram("127.0.0.1:0".parse().unwrap(), &data, Instant::now())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(connections.pool.capacity_remaining(), 8);
+    }
+
+    /// Returns a `Connections` with a connected server (`1`) that allows `handshake_burst`
+    /// handshakes from each source.
+    fn guarded_connections(handshake_burst: usize) -> Connections {
+        let config = Config::builder().handshake_burst(handshake_burst).build().unwrap();
+        endpoint(config, HashMap::from([(1, connected_server())]))
+    }
+
+    #[test]
+    fn handle_datagram_drops_unknown_connection() {
+        let mut connections = guarded_connections(8);
+
+        let mut data = [0u8; 64];
+        let mut buf = BytesMut::new(&mut data);
+        Header::Short {
+            packet_number: 0,
+            packet_type: PacketType::Data,
+            dst_id: 99,
+        }
+        .write(&mut buf)
+        .unwrap();
+        Frame::Ping.write(&mut buf).unwrap();
+        let len = buf.position();
+
+        let from = "127.0.0.1:9000".parse().unwrap();
+        for _ in 0..3 {
+            connections.handle_datagram(from, &data[..len], Instant::now()).unwrap();
+        }
+        assert_eq!(connections.stats().unknown_connection(), 3);
+        assert_eq!(connections.stats().handshakes_throttled(), 0);
+        assert_eq!(connections.pool.capacity_remaining(), 8);
+    }
+
+    #[test]
+    fn handle_datagram_throttles_handshake_flood() {
+        let mut connections = guarded_connections(3);
+
+        let mut data = [0u8; 64];
+        let mut buf = BytesMut::new(&mut data);
+        Header::Long {
+            packet_number: 0,
+            packet_type: PacketType::Handshake,
+            version: PROTOCOL_VERSION_CHECKSUM,
+            src_id: 5,
+            dst_id: 99,
+        }
+        .write(&mut buf)
+        .unwrap();
+        let len = buf.position();
+
+        let now = Instant::now();
+        let flooder = "10.0.0.1".parse().unwrap();
+        for port in 0..10 {
+            // changing ports doesn't help
+            let from = SocketAddr::new(flooder, 9000 + port);
+            connections.handle_datagram(from, &data[..len], now).unwrap();
+        }
+        assert_eq!(connections.stats().handshakes_throttled(), 7);
+        assert_eq!(connections.stats().unknown_connection(), 3);
+
+        // another source still gets through
+        connections
+            .handle_datagram("10.0.0.2:9000".parse().unwrap(), &data[..len], now)
+            .unwrap();
+        assert_eq!(connections.stats().handshakes_throttled(), 7);
+        assert_eq!(connections.stats().unknown_connection(), 4);
+        assert_eq!(connections.pool.capacity_remaining(), 8);
+    }
+
+    #[test]
+    fn reused_id_rejects_old_generation() {
+        let mut connections = guarded_connections(8);
+        connections.conn.clear();
+        let from = "127.0.0.1:9000".parse().unwrap();
+
+        // a packet carrying a one-fragment message on channel 0
+        let packet = |dst_id| {
+            let mut data = [0u8; 64];
+            let mut buf = BytesMut::new(&mut data);
+            Header::Short {
+                packet_number: 0,
+                packet_type: PacketType::Data,
+                dst_id,
+            }
+            .write(&mut buf)
+            .unwrap();
+            Frame::Data {
+                channel_id: 0,
+                channel_sequence: 0,
+                fragment_index: 0,
+                fragment_count: 1,
+                len: 2,
+            }
+            .write(&mut buf)
+            .unwrap();
+            buf.copy_from_slice(b"hi").unwrap();
+            let len = buf.position();
+            data[..len].to_vec()
+        };
+
+        let old = connections.allocate_id();
+        let mut server = connected_server();
+        server.src_id = old;
+        connections.conn.insert(old, server);
+        assert!(connections.remove(old));
+        assert!(!connections.remove(old));
+
+        // the slot is reused under a different id
+        let new = connections.allocate_id();
+        assert_ne!(new, old);
+        let mut server = connected_server();
+        server.src_id = new;
+        server.peer_addr = from;
+        connections.conn.insert(new, server);
+
+        connections.handle_datagram(from, &packet(old), Instant::now()).unwrap();
+        assert_eq!(connections.stats().stale_connection(), 1);
+        assert_eq!(connections.poll().count(), 0);
+
+        connections.handle_datagram(from, &packet(new), Instant::now()).unwrap();
+        assert_eq!(connections.stats().stale_connection(), 1);
+        assert_eq!(
+            connections.poll().collect::<Vec<_>>(),
+            [ConnectionEvent::MessageReceived { connection: new, channel: 0, len: 2 }],
+        );
+    }
+
+    #[test]
+    fn remove_releases_received_buffers() {
+        let mut connections = guarded_connections(8);
+        let mut data = [0u8; 64];
+        let mut buf = BytesMut::new(&mut data);
+        Header::Short {
+            packet_number: 0,
+            packet_type: PacketType::Data,
+            dst_id: 1,
+        }
+        .write(&mut buf)
+        .unwrap();
+        // the first half of a two-fragment message
+        Frame::Data {
+            channel_id: 0,
+            channel_sequence: 0,
+            fragment_index: 0,
+            fragment_count: 2,
+            len: 2,
+        }
+        .write(&mut buf)
+        .unwrap();
+        buf.copy_from_slice(b"hi").unwrap();
+        let len = buf.position();
+
+        let from = connections.conn[&1].peer_addr;
+        connections.handle_datagram(from, &data[..len], Instant::now()).unwrap();
+        assert_eq!(connections.pool.capacity_remaining(), 7);
+
+        assert!(connections.remove(1));
+        assert_eq!(connections.pool.capacity_remaining(), 8);
+    }
+
+    /// Receives a handshake packet on `socket` and passes it to `connection`.
+    fn recv_handshake_on(socket: &UdpSocket, connection: &mut Connection) -> Option<Request> {
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        let (len, _) = socket.recv_from(&mut data).unwrap();
+        let mut buf = BytesMut::new(&mut data[..len]);
+        match Header::read(&mut buf).unwrap() {
+            Header::Long {
+                packet_type: PacketType::Handshake,
+                version,
+                ..
+            } => connection
+                .recv_handshake(version, &mut buf, &mut MaxConnections::new(&Config::default()))
+                .unwrap(),
+            header => panic!("unexpected header: {:?}", header),
+        }
+    }
+
+    /// Writes a handshake packet from `connection` and sends it on `socket`.
+    fn send_handshake_on(socket: &UdpSocket, connection: &Connection, request: Request) {
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        let mut buf = BytesMut::new(&mut data);
+        connection.write_handshake(request, &mut buf).unwrap();
+        let len = buf.position();
+        socket.send_to(&data[..len], connection.peer_addr).unwrap();
+    }
+
+    #[test]
+    fn handshake_over_loopback() {
+        let now = Instant::now();
+        let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut client = Connection::new(
+            7,
+            0,
+            server_socket.local_addr().unwrap(),
+            Role::Client,
+            Config::default(),
+            now,
+        );
+        let mut server = Connection::new(
+            9,
+            0,
+            client_socket.local_addr().unwrap(),
+            Role::Server,
+            Config::default(),
+            now,
+        );
+
+        client.connect(now);
+        send_handshake_on(&client_socket, &client, Request::Connect);
+
+        let reply = recv_handshake_on(&server_socket, &mut server);
+        assert_eq!(reply, Some(Request::Accept));
+        assert_eq!(server.state(), ConnectionState::Connected);
+        assert_eq!(server.dst_id(), client.src_id());
+
+        send_handshake_on(&server_socket, &server, reply.unwrap());
+
+        assert_eq!(recv_handshake_on(&client_socket, &mut client), None);
+        assert_eq!(client.state(), ConnectionState::Connected);
+        assert_eq!(client.dst_id(), server.src_id());
+
+        // a repeated request is answered again, but a handshake can't readdress the connection
+        send_handshake_on(&client_socket, &client, Request::Connect);
+        assert_eq!(recv_handshake_on(&server_socket, &mut server), Some(Request::Accept));
+        let impostor = Connection::new(8, 0, server_socket.local_addr().unwrap(), Role::Client, Config::default(), now);
+        send_handshake_on(&client_socket, &impostor, Request::Connect);
+        assert_eq!(recv_handshake_on(&server_socket, &mut server), None);
+        send_handshake_on(&server_socket, &server, Request::Accept);
+        assert_eq!(recv_handshake_on(&client_socket, &mut client), None);
+        assert_eq!((server.dst_id(), client.dst_id()), (client.src_id(), server.src_id()));
+    }
+
+    #[test]
+    fn handshake_rejects_other_protocol_version() {
+        let now = Instant::now();
+        let client = Connection::new(7, 0, "127.0.0.1:0".parse().unwrap(), Role::Client, Config::default(), now);
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        let mut buf = BytesMut::new(&mut data);
+        client.write_handshake(Request::Connect, &mut buf).unwrap();
+        let len = buf.position();
+
+        let mut policy = MaxConnections::new(&Config::default());
+        for (id, version) in [(1, PROTOCOL_VERSION), (2, "parrot-0.0.0")] {
+            let mut server = Connection::new(id, 0, "127.0.0.1:0".parse().unwrap(), Role::Server, Config::default(), now);
+            let mut packet = data;
+            let mut buf = BytesMut::new(&mut packet[..len]);
+            let Header::Long { version: sent, .. } = Header::read(&mut buf).unwrap() else {
+                panic!("handshake without long header");
+            };
+            assert_eq!(sent, PROTOCOL_VERSION_CHECKSUM);
+
+            let result = server.recv_handshake(version_checksum(version), &mut buf, &mut policy);
+            if version == PROTOCOL_VERSION {
+                assert_eq!(result.unwrap(), Some(Request::Accept));
+                assert_eq!(server.disconnect_reason, None);
+            } else {
+                assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Unsupported);
+                assert_eq!(server.state(), ConnectionState::Disconnecting(DisconnectReason::ProtocolVersionInvalid));
+                assert_eq!(server.disconnect_reason, Some(DisconnectReason::ProtocolVersionInvalid));
+            }
+        }
+    }
+
+    /// Denies one address and accepts every other.
+    struct Banlist(SocketAddr);
+
+    impl AcceptPolicy for Banlist {
+        fn accept(&mut self, addr: SocketAddr, _payload: &[u8]) -> Result<(), DisconnectReason> {
+            if addr == self.0 {
+                return Err(DisconnectReason::ConnectionDenied);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn accept_policy_denies_banned_address() {
+        let now = Instant::now();
+        let banned: SocketAddr = "192.0.2.66:5000".parse().unwrap();
+        let mut policy = Banlist(banned);
+        let client = Connection::new(7, 0, "192.0.2.2:6000".parse().unwrap(), Role::Client, Config::default(), now);
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        let mut buf = BytesMut::new(&mut data);
+        client.write_handshake(Request::Connect, &mut buf).unwrap();
+        let len = buf.position();
+
+        for (id, peer_addr) in [(1, "192.0.2.1:5000".parse().unwrap()), (2, banned)] {
+            let mut server = Connection::new(id, 0, peer_addr, Role::Server, Config::default(), now);
+            let mut packet = data;
+            let mut buf = BytesMut::new(&mut packet[..len]);
+            Header::read(&mut buf).unwrap();
+            let reply = server.recv_handshake(PROTOCOL_VERSION_CHECKSUM, &mut buf, &mut policy).unwrap();
+
+            if peer_addr == banned {
+                assert_eq!(reply, Some(Request::Deny(DisconnectReason::ConnectionDenied)));
+                assert_eq!(server.state(), ConnectionState::Disconnecting(DisconnectReason::ConnectionDenied));
+                assert!(!server.accepted);
+                // the denial already says why
+                assert!(server.close_sent);
+            } else {
+                assert_eq!(reply, Some(Request::Accept));
+                assert_eq!(server.state(), ConnectionState::Connected);
+                assert!(server.accepted);
+            }
+        }
+    }
+
+    #[test]
+    fn full_server_denies_connection() {
+        let now = Instant::now();
+        let client_addr: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let server_addr: SocketAddr = "192.0.2.2:6000".parse().unwrap();
+
+        let mut clients = HashMap::new();
+        for (id, dst_id) in [(7, 1), (8, 2)] {
+            let mut client = Connection::new(id, dst_id, server_addr, Role::Client, Config::default(), now);
+            client.connect(now);
+            clients.insert(id, client);
+        }
+        let mut servers = HashMap::new();
+        for id in [1, 2] {
+            let server = Connection::new(id, 0, "0.0.0.0:0".parse().unwrap(), Role::Server, Config::default(), now);
+            servers.insert(id, server);
+        }
+
+        let config = Config::builder().max_connections(1).build().unwrap();
+        let mut client = endpoint(Config::default(), clients);
+        client.set_clock(ManualClock::new(now));
+        let mut server = endpoint(config, servers);
+        server.set_clock(ManualClock::new(now));
+
+        // the first request takes the only slot
+        assert_eq!(shuttle(&mut client, client_addr, &mut server, server_addr, now), 2);
+        assert_eq!(server.conn[&1].state(), ConnectionState::Connected);
+        assert_eq!(server.conn[&2].state(), ConnectionState::Disconnecting(DisconnectReason::ServerFull));
+
+        // one accept and one denial, without a close frame after it
+        assert_eq!(shuttle(&mut server, server_addr, &mut client, client_addr, now), 2);
+        assert_eq!(client.conn[&7].state(), ConnectionState::Connected);
+        assert_eq!(client.conn[&8].state(), ConnectionState::Disconnecting(DisconnectReason::ServerFull));
+        assert_eq!(
+            client.conn.get_mut(&8).unwrap().events.drain(..).collect::<Vec<_>>(),
+            [ConnectionEvent::Disconnected(8, DisconnectReason::ServerFull)],
+        );
+        assert_eq!(
+            server.conn.get_mut(&2).unwrap().events.drain(..).collect::<Vec<_>>(),
+            [ConnectionEvent::Disconnected(2, DisconnectReason::ServerFull)],
+        );
+    }
+
+    /// Queues a message with `fragments` unsent fragments of `len` bytes each on `channel`.
+    fn queue_message(channel: &mut Channel, pool: &mut BufferPool, fragments: usize, len: usize) {
+        let sequence = channel.acks.next_send;
+        channel.acks.next_send += 1;
+
+        let handle = pool.acquire().unwrap();
+        let mut message = sent_message(sequence, Instant::now());
+        message.fragment_count = fragments as u16;
+        message.fragment_data = vec![Some((handle, 0, len)); fragments].into_boxed_slice();
+        message.fragment_status = vec![SendStatus::Unsent; fragments].into_boxed_slice();
+        message.time_sent = None;
+        channel.send_buffer.insert(sequence, message);
+    }
+
+    /// Marks the scheduled fragments as sent, like the send path would.
+    fn mark_sent(connection: &mut Connection, scheduled: &[(ChannelId, SequenceNumber, u16)]) {
+        for &(id, sequence, index) in scheduled {
+            let channel = connection.channels.get_mut(&id).unwrap();
+            let message = channel.send_buffer.get_mut(sequence).unwrap().as_mut().unwrap();
+            message.fragment_status[index as usize] = SendStatus::Sent;
+        }
+    }
+
+    fn bytes_per_channel(scheduled: &[(ChannelId, SequenceNumber, u16)], len: impl Fn(ChannelId) -> usize) -> HashMap<ChannelId, usize> {
+        let mut bytes = HashMap::new();
+        for &(id, ..) in scheduled {
+            *bytes.entry(id).or_insert(0) += len(id);
+        }
+        bytes
+    }
+
+    fn scheduling_connection() -> (Connection, BufferPool) {
+        let connection = Connection::new(
+            0,
+            1,
+            "127.0.0.1:0".parse().unwrap(),
+            Role::Client,
+            Config::default(),
+            Instant::now(),
+        );
+        (connection, BufferPool::new(MAX_PAYLOAD_BYTES, 64))
+    }
+
+    #[test]
+    fn schedule_drains_unreliable_first() {
+        let (mut connection, mut pool) = scheduling_connection();
+        for (id, send_guarantee) in [(0, Send::Reliable), (1, Send::Unreliable), (2, Send::Reliable), (3, Send::Unreliable)] {
+            let channel = connection.open_channel(id, send_guarantee, Receive::Unordered).unwrap();
+            queue_message(channel, &mut pool, 4, 500);
+        }
+
+        // enough for all of the unreliable fragments and a few more
+        let scheduled = connection.schedule(10 * 500);
+        assert_eq!(scheduled.len(), 10);
+        let unreliable = scheduled.iter().take_while(|(id, ..)| id % 2 == 1).count();
+        assert_eq!(unreliable, 8);
+
+        // reliable channels get nothing until unreliable ones are empty
+        mark_sent(&mut connection, &scheduled);
+        for id in [1, 3] {
+            queue_message(connection.channels.get_mut(&id).unwrap(), &mut pool, 4, 500);
+        }
+        let scheduled = connection.schedule(8 * 500);
+        assert!(scheduled.iter().all(|(id, ..)| id % 2 == 1));
+    }
+
+    #[test]
+    fn schedule_shares_budget_fairly() {
+        let (mut connection, mut pool) = scheduling_connection();
+        let len = |id: ChannelId| if id == 0 { 1000 } else { 100 };
+        for id in 0..3 {
+            let channel = connection.open_channel(id, Send::Reliable, Receive::Ordered).unwrap();
+            for _ in 0..8 {
+                queue_message(channel, &mut pool, 32, len(id));
+            }
+        }
+
+        let budget = 6000;
+        let scheduled = connection.schedule(budget);
+        let bytes = bytes_per_channel(&scheduled, len);
+        assert!(bytes.values().sum::<usize>() <= budget);
+        for id in 0..3 {
+            // every channel gets within one quantum of an even share
+            assert!(bytes[&id].abs_diff(budget / 3) <= DRR_QUANTUM_BYTES, "{:?}", bytes);
+        }
+
+        // fragments of each channel are picked in order
+        for id in 0..3 {
+            let picked = scheduled.iter().filter(|(channel, ..)| *channel == id).map(|&(_, sequence, index)| (sequence, index));
+            assert!(picked.clone().zip(picked.skip(1)).all(|(a, b)| a < b));
+        }
+    }
+
+    #[test]
+    fn schedule_does_not_starve_across_ticks() {
+        let (mut connection, mut pool) = scheduling_connection();
+        for id in 0..2 {
+            let channel = connection.open_channel(id, Send::Reliable, Receive::Ordered).unwrap();
+            queue_message(channel, &mut pool, 8, 800);
+        }
+
+        // only one fragment fits each tick
+        let mut total = HashMap::new();
+        for _ in 0..8 {
+            let scheduled = connection.schedule(1000);
+            assert_eq!(scheduled.len(), 1);
+            mark_sent(&mut connection, &scheduled);
+            for (id, bytes) in bytes_per_channel(&scheduled, |_| 800) {
+                *total.entry(id).or_insert(0) += bytes;
+            }
+        }
+
+        assert_eq!(total[&0], total[&1]);
+    }
+
+    #[test]
+    fn schedule_drains_higher_priority_first() {
+        let (mut connection, mut pool) = scheduling_connection();
+        // the bulk channel comes first by id
+        for (id, priority) in [(0, 0), (1, 5)] {
+            let channel = connection.open_channel(id, Send::Reliable, Receive::Ordered).unwrap();
+            channel.priority = priority;
+            queue_message(channel, &mut pool, 8, 500);
+        }
+
+        // the budget only covers the higher priority channel
+        let scheduled = connection.schedule(6 * 500);
+        assert_eq!(scheduled.len(), 6);
+        assert!(scheduled.iter().all(|(id, ..)| *id == 1));
+
+        // what it doesn't use goes to the lower priority channel
+        mark_sent(&mut connection, &scheduled);
+        let scheduled = connection.schedule(6 * 500);
+        assert_eq!(scheduled.iter().map(|(id, ..)| *id).collect::<Vec<_>>(), [1, 1, 0, 0, 0, 0]);
+
+        // priorities don't reorder send guarantees
+        mark_sent(&mut connection, &scheduled);
+        let channel = connection.open_channel(2, Send::Unreliable, Receive::Unordered).unwrap();
+        queue_message(channel, &mut pool, 1, 500);
+        let scheduled = connection.schedule(2 * 500);
+        assert_eq!(scheduled.iter().map(|(id, ..)| *id).collect::<Vec<_>>(), [2, 0]);
+    }
+
+    #[test]
+    fn send_message_gives_channel_its_declared_priority() {
+        let mut connections = guarded_connections(8);
+        connections.channel_config = reliable_channels().channel_with_priority(3, Send::Reliable, Receive::Ordered, 9);
+        // opened by the remote endpoint first
+        connections.conn.get_mut(&1).unwrap().open_channel(3, Send::Reliable, Receive::Ordered).unwrap();
+
+        connections.send_message(1, 3, b"voice").unwrap();
+        connections.send_message(1, 4, b"bulk").unwrap();
+        assert_eq!(connections.conn[&1].channels[&3].priority, 9);
+        assert_eq!(connections.conn[&1].channels[&4].priority, 0);
+    }
+
+    #[test]
+    fn cancel_drops_unsent_messages() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = connected_server();
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        for data in [&b"one"[..], b"two", b"three"] {
+            conn.store_outgoing_data(data, now).unwrap();
+        }
+        let message = channel.send_buffer.get_mut(0).unwrap().as_mut().unwrap();
+        message.fragment_status[0] = SendStatus::Sent;
+        assert_eq!(pool.in_use(), 3);
+
+        // a sent message can't be taken back, and an ordered channel can't skip a message
+        assert!(!channel.cancel(0, &mut pool));
+        assert!(!channel.cancel(1, &mut pool));
+        assert!(!channel.cancel(3, &mut pool));
+        assert_eq!(pool.in_use(), 3);
+
+        // the newest one can go, and its sequence is used again
+        assert!(channel.cancel(2, &mut pool));
+        assert!(!channel.send_buffer.contains(2));
+        assert_eq!(pool.in_use(), 2);
+        assert_eq!(channel.acks.sequence(), 2);
+        assert_eq!(channel.resend_order(), [(1, 0)]);
+
+        // unordered channels can drop any unsent message
+        let mut channel = Channel::new(1, Send::Reliable, Receive::Unordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        conn.store_outgoing_data(b"one", now).unwrap();
+        conn.store_outgoing_data(b"two", now).unwrap();
+        assert!(channel.cancel(0, &mut pool));
+        assert!(!channel.cancel(0, &mut pool));
+        assert_eq!(channel.acks.sequence(), 2);
+        assert_eq!(pool.in_use(), 3);
+
+        // an unreliable message can be cut short
+        let mut channel = Channel::new(2, Send::Unreliable, Receive::Ordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        let data = vec![7; conn.connection.max_fragment_bytes() + 1];
+        conn.store_outgoing_data(&data, now).unwrap();
+        conn.store_outgoing_data(b"two", now).unwrap();
+        assert_eq!(pool.in_use(), 6);
+        for sequence in [0, 1] {
+            let message = channel.send_buffer.get_mut(sequence).unwrap().as_mut().unwrap();
+            message.fragment_status[0] = SendStatus::Sent;
+        }
+        assert!(!channel.cancel(1, &mut pool));
+        assert!(channel.cancel(0, &mut pool));
+        assert_eq!(channel.acks.sequence(), 2);
+        assert_eq!(pool.in_use(), 4);
+    }
+
+    #[test]
+    fn send_stream_waits_for_room() {
+        let config = Config::builder().max_fragments(2).build().unwrap();
+        let mut connection = Connection::new(1, 0, "127.0.0.1:0".parse().unwrap(), Role::Server, config.clone(), Instant::now());
+        connection.handle_request(Request::Connect);
+        let mut connections = endpoint(config, HashMap::new());
+        connections.set_channel_config(reliable_channels().channel(16, Send::Reliable, Receive::Unordered));
+        connections.conn.insert(1, connection);
+        let max_message_bytes = connections.connection(1).unwrap().max_message_bytes();
+        assert_eq!(max_message_bytes, 2 * connections.connection(1).unwrap().max_fragment_bytes());
+
+        // five chunks, of two fragments each except for the last
+        let data = (0..10_000).map(|i| i as u8).collect::<Vec<_>>();
+        let mut stream = SendStream::new(1, &data[..]);
+        assert_eq!(stream.send(&mut connections, 1, 0), Ok(false));
+        assert_eq!(connections.pool.in_use(), 8);
+        assert_eq!(connections.conn[&1].channels[&0].acks.sequence(), 4);
+
+        // the chunk that didn't fit is queued once there's room
+        connections.conn.get_mut(&1).unwrap().flush_send_window(&mut connections.pool);
+        assert_eq!(stream.send(&mut connections, 1, 0), Ok(true));
+        assert_eq!(connections.pool.in_use(), 1);
+        assert_eq!(connections.conn[&1].channels[&0].acks.sequence(), 5);
+        assert_eq!(stream.send(&mut connections, 1, 0), Ok(true));
+
+        let mut stream = SendStream::new(2, &data[..]);
+        assert_eq!(
+            stream.send(&mut connections, 1, 16),
+            Err(ErrorKind::ChannelNotReliableOrdered.into()),
+        );
+        assert_eq!(stream.send(&mut connections, 1, 17), Err(ErrorKind::ChannelNotDeclared.into()));
+    }
+
+    #[test]
+    fn reliable_sequenced_channel_only_resends_latest_message() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = connected_server();
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Sequenced);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+
+        conn.store_outgoing_data(b"one", now).unwrap();
+        let mut data = [0u8; MAX_PAYLOAD_BYTES];
+        assert_eq!(conn.write_reliable(&mut BytesMut::new(&mut data), now).unwrap(), 1);
+
+        // each message supersedes the ones before it, sent or not
+        conn.store_outgoing_data(b"two", now).unwrap();
+        conn.store_outgoing_data(b"three", now).unwrap();
+        assert!(!conn.channel.send_buffer.contains(0));
+        assert!(!conn.channel.send_buffer.contains(1));
+        assert_eq!(conn.pool.in_use(), 1);
+        assert_eq!(conn.channel.resend_order(), [(2, 0)]);
+
+        let mut data = [0u8; MAX_PAYLOAD_BYTES];
+        assert_eq!(conn.write_reliable(&mut BytesMut::new(&mut data), now).unwrap(), 1);
+
+        // only the newest message is resent once it's lost
+        let message = conn.channel.send_buffer.get_mut(2).unwrap().as_mut().unwrap();
+        message.fragment_status[0] = SendStatus::Lost;
+        let later = now + Duration::from_millis(100);
+        let mut data = [0u8; MAX_PAYLOAD_BYTES];
+        assert_eq!(conn.write_reliable(&mut BytesMut::new(&mut data), later).unwrap(), 1);
+        assert_eq!(
+            Frame::read(&mut BytesMut::new(&mut data)).unwrap(),
+            Frame::Data {
+                channel_id: 0,
+                channel_sequence: 2,
+                fragment_index: 0,
+                fragment_count: 1,
+                len: 5,
+            },
+        );
+
+        // ordered channels keep everything
+        let mut channel = Channel::new(1, Send::Reliable, Receive::Ordered);
+        conn.channel = &mut channel;
+        conn.store_outgoing_data(b"one", now).unwrap();
+        conn.store_outgoing_data(b"two", now).unwrap();
+        assert_eq!(conn.channel.resend_order(), [(0, 0), (1, 0)]);
+    }
+
+    #[test]
+    fn write_reliable_resends_lost_fragments_first() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = Connection::new(
+            0,
+            1,
+            "127.0.0.1:0".parse().unwrap(),
+            Role::Client,
+            Config::default(),
+            now,
+        );
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+
+        // fragment payloads are [1; 100], [2; 100], and [3; 100]
+        let handle = pool.acquire().unwrap();
+        let payload = (1..=3).flat_map(|byte| [byte; 100]).collect::<Vec<u8>>();
+        MaybeUninit::write_slice(&mut pool.get_mut(handle).unwrap()[..300], &payload);
+
+        let queue = |channel: &mut Channel, fragments: &[usize]| {
+            let sequence = channel.acks.next_send;
+            channel.acks.next_send += 1;
+            let mut message = sent_message(sequence, now);
+            message.fragment_count = fragments.len() as u16;
+            message.fragment_sent = 0;
+            message.fragment_data = fragments.iter().map(|fragment| Some((handle, fragment * 100, 100))).collect();
+            message.fragment_status = vec![SendStatus::Unsent; fragments.len()].into_boxed_slice();
+            message.time_sent = None;
+            channel.send_buffer.insert(sequence, message);
+        };
+
+        queue(&mut channel, &[0, 1, 2]);
+        queue(&mut channel, &[0]);
+
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+
+        let mut data = [0u8; MAX_PAYLOAD_BYTES];
+        assert_eq!(conn.write_reliable(&mut BytesMut::new(&mut data), now).unwrap(), 4);
+        for sequence in 0..2 {
+            let message = conn.channel.send_buffer.get(sequence).unwrap().as_ref().unwrap();
+            assert_eq!(message.fragment_sent, message.fragment_count);
+            assert_eq!(message.time_sent, Some(now));
+        }
+
+        // the middle fragment of the first message was lost, and a new message is waiting
+        let later = now + Duration::from_millis(100);
+        let message = conn.channel.send_buffer.get_mut(0).unwrap().as_mut().unwrap();
+        message.fragment_status[1] = SendStatus::Lost;
+        queue(&mut *conn.channel, &[2]);
+
+        let mut data = [0u8; MAX_PAYLOAD_BYTES];
+        assert_eq!(conn.write_reliable(&mut BytesMut::new(&mut data), later).unwrap(), 2);
+
+        let mut packet = BytesMut::new(&mut data);
+        let frame = Frame::read(&mut packet).unwrap();
+        assert_eq!(
+            frame,
+            Frame::Data {
+                channel_id: 0,
+                channel_sequence: 0,
+                fragment_index: 1,
+                fragment_count: 3,
+                len: 100,
+            }
+        );
+        assert_eq!(&packet.as_ref()[..100], &[2; 100]);
+        packet.seek(io::SeekFrom::Current(100)).unwrap();
+
+        match Frame::read(&mut packet).unwrap() {
+            Frame::Data {
+                channel_sequence,
+                fragment_index,
+                ..
+            } => assert_eq!((channel_sequence, fragment_index), (2, 0)),
+            frame => panic!("unexpected frame: {:?}", frame),
+        }
+
+        // resending doesn't count as a first send
+        let message = conn.channel.send_buffer.get(0).unwrap().as_ref().unwrap();
+        assert_eq!(message.fragment_status[1], SendStatus::Sent);
+        assert_eq!(message.fragment_sent, 3);
+        assert_eq!(message.time_sent, Some(now));
+
+        // the ack path marks the fragments of unacknowledged messages as lost
+        conn.channel.acknowledge(2, 0b1, 0, REDUNDANT_ACK_MASK_BITS, later, 0, &mut VecDeque::new());
+        assert!(conn.channel.send_buffer.get(2).is_none());
+        let message = conn.channel.send_buffer.get(0).unwrap().as_ref().unwrap();
+        assert!(message.fragment_status[..3].iter().all(|status| *status == SendStatus::Lost));
+        assert_eq!(conn.channel.resend_order(), vec![(0, 0), (0, 1), (0, 2), (1, 0)]);
+    }
+
+    #[test]
+    fn poll_reports_idle_timeout() {
+        let clock = ManualClock::new(Instant::now());
+        let config = Config::default();
+        let mut connection = Connection::new(
+            0,
+            1,
+            "127.0.0.1:0".parse().unwrap(),
+            Role::Server,
+            config.clone(),
+            clock.now(),
+        );
+        connection.handle_request(Request::Connect);
+
+        let mut connections = endpoint(config.clone(), HashMap::from([(0, connection)]));
+        connections.set_clock(clock.clone());
+
+        let events = connections.poll().collect::<Vec<_>>();
+        assert_eq!(events, [ConnectionEvent::Connected(0)]);
+
+        // heard from the peer recently
+        clock.advance(config.idle_timeout() / 2);
+        connections.conn.get_mut(&0).unwrap().time_latest_recv = Some(clock.now());
+        clock.advance(config.idle_timeout() / 2);
+        assert_eq!(connections.poll().count(), 0);
+
+        clock.advance(config.idle_timeout() / 2);
+        let events = connections.poll().collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [ConnectionEvent::Disconnected(0, DisconnectReason::ConnectionIdleTimeout)],
+        );
+        assert_eq!(connections.conn[&0].state(), ConnectionState::Disconnecting(DisconnectReason::ConnectionIdleTimeout));
+    }
+
+    #[test]
+    fn iter_and_connection_list_connections() {
+        let now = Instant::now();
+        let client_addr: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let mut connections = guarded_connections(8);
+        connections.conn.insert(2, Connection::new(2, 0, client_addr, Role::Server, Config::default(), now));
+
+        let mut listed = connections
+            .iter()
+            .map(|(id, connection)| (id, connection.state(), connection.peer_addr()))
+            .collect::<Vec<_>>();
+        listed.sort_unstable_by_key(|&(id, ..)| id);
+        assert_eq!(
+            listed,
+            [
+                (1, ConnectionState::Connected, "127.0.0.1:0".parse().unwrap()),
+                (2, ConnectionState::Created, client_addr),
+            ],
+        );
+
+        let connection = connections.connection(2).unwrap();
+        assert_eq!(connection.src_id(), 2);
+        assert_eq!(connection.state(), ConnectionState::Created);
+        assert_eq!(connection.peer_addr(), client_addr);
+        assert_eq!(connection.rtt(), connections.connection(1).unwrap().rtt());
+        assert!(connections.connection(3).is_none());
+
+        assert!(connections.remove(2));
+        assert!(connections.connection(2).is_none());
+        assert_eq!(connections.iter().count(), 1);
+    }
+
+    #[test]
+    fn closed_connection_lingers_before_freeing_its_id() {
+        let clock = ManualClock::new(Instant::now());
+        let linger_time = Duration::from_secs(1);
+        let config = Config::builder().linger_time(linger_time).build().unwrap();
+        let mut server = connected_server_with(config.clone(), clock.now());
+        let mut connections = endpoint(config, HashMap::new());
+        connections.set_clock(clock.clone());
+        let id = connections.allocate_id();
+        server.src_id = id;
+        let peer_addr = server.peer_addr;
+        connections.conn.insert(id, server);
+
+        // a packet carrying a one-fragment message on channel 0
+        let mut data = [0u8; 64];
+        let mut buf = BytesMut::new(&mut data);
+        Header::Short {
+            packet_number: 0,
+            packet_type: PacketType::Data,
+            dst_id: id,
+        }
+        .write(&mut buf)
+        .unwrap();
+        Frame::Data {
+            channel_id: 0,
+            channel_sequence: 0,
+            fragment_index: 0,
+            fragment_count: 1,
+            len: 2,
+        }
+        .write(&mut buf)
+        .unwrap();
+        buf.copy_from_slice(b"hi").unwrap();
+        let len = buf.position();
+        let packet = &data[..len];
+
+        connections.conn.get_mut(&id).unwrap().disconnect(DisconnectReason::Closed);
+        assert_eq!(
+            connections.poll().collect::<Vec<_>>(),
+            [ConnectionEvent::Disconnected(id, DisconnectReason::Closed)],
+        );
+        let closed_at = clock.now();
+        assert_eq!(connections.conn[&id].state(), ConnectionState::Disconnected(closed_at + linger_time));
+
+        // late packets are absorbed while it lingers
+        clock.advance(linger_time / 2);
+        connections.handle_datagram(peer_addr, packet, clock.now()).unwrap();
+        assert_eq!(connections.poll().count(), 0);
+        assert!(connections.conn[&id].channels.is_empty());
+        assert_eq!(connections.pool.in_use(), 0);
+        assert_eq!(connections.stats(), EndpointStats::default());
+
+        // then its slot is freed under a new generation
+        clock.advance(linger_time / 2);
+        assert_eq!(connections.poll().count(), 0);
+        assert!(!connections.conn.contains_key(&id));
+        connections.handle_datagram(peer_addr, packet, clock.now()).unwrap();
+        assert_eq!(connections.stats().stale_connection(), 1);
+        assert_ne!(connections.allocate_id(), id);
+    }
+
+    #[test]
+    fn poll_keeps_events_that_were_not_taken() {
+        let clock = ManualClock::new(Instant::now());
+        let config = Config::builder().linger_time(Duration::ZERO).build().unwrap();
+        let mut server = connected_server_with(config.clone(), clock.now());
+        server.migrate("192.0.2.1:5000".parse().unwrap());
+        server.disconnect(DisconnectReason::Closed);
+        let mut connections = endpoint(config, HashMap::from([(1, server)]));
+        connections.set_clock(clock.clone());
+
+        assert!(matches!(connections.poll().next(), Some(ConnectionEvent::Migrated { .. })));
+        // the connection has lingered long enough, but isn't removed before its last event is taken
+        assert_eq!(
+            connections.poll().collect::<Vec<_>>(),
+            [ConnectionEvent::Disconnected(1, DisconnectReason::Closed)],
+        );
+        assert_eq!(connections.poll().count(), 0);
+        assert!(connections.conn.is_empty());
+    }
+
+    #[test]
+    fn poll_releases_expired_reassemblies() {
+        let clock = ManualClock::new(Instant::now());
+        let config = Config::builder().reassembly_timeout(Duration::from_secs(1)).build().unwrap();
+        let mut connection = Connection::new(
+            0,
+            1,
+            "127.0.0.1:0".parse().unwrap(),
+            Role::Server,
+            config.clone(),
+            clock.now(),
+        );
+        connection.handle_request(Request::Connect);
+        connection.events.clear();
+
+        // one fragment of a message and all of another
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Unordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        store_fragment(&mut conn, 0, 0, 3, 0, clock.now()).unwrap();
+        store_fragment(&mut conn, 1, 0, 1, 1, clock.now()).unwrap();
+        connection.channels.insert(0, channel);
+        assert_eq!(pool.in_use(), 2);
+
+        let mut connections = endpoint(config.clone(), HashMap::from([(0, connection)]));
+        connections.pool = pool;
+        connections.set_clock(clock.clone());
+
+        // the complete message is delivered either way
+        clock.advance(config.reassembly_timeout());
+        assert_eq!(
+            connections.poll().collect::<Vec<_>>(),
+            [ConnectionEvent::MessageReceived { connection: 0, channel: 0, len: 100 }],
+        );
+        assert_eq!(connections.pool.in_use(), 2);
+        assert_eq!(connections.stats().reassemblies_expired(), 0);
+
+        // only the incomplete message is dropped
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(connections.poll().count(), 0);
+        assert_eq!(connections.pool.in_use(), 1);
+        assert_eq!(connections.stats().reassemblies_expired(), 1);
+        let channel = &connections.conn[&0].channels[&0];
+        assert!(!matches!(channel.recv_buffer.get(0), Some(Some(_))));
+        assert!(matches!(channel.recv_buffer.get(1), Some(Some(_))));
+    }
+
+    #[test]
+    fn poll_skips_lost_message_on_channels_that_skip_gaps() {
+        let clock = ManualClock::new(Instant::now());
+        let config = Config::builder().head_of_line_timeout(Duration::from_secs(1)).build().unwrap();
+        let mut connection = Connection::new(
+            0,
+            1,
+            "127.0.0.1:0".parse().unwrap(),
+            Role::Server,
+            config.clone(),
+            clock.now(),
+        );
+        connection.handle_request(Request::Connect);
+
+        // 1 is lost for good (only half of it arrived) on both channels, 2 and 3 wait on it
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 16);
+        for id in [0, 1] {
+            let mut channel = Channel::new(id, Send::Unreliable, Receive::Ordered);
+            let mut conn = ConnectionRef {
+                connection: &mut connection,
+                channel: &mut channel,
+                pool: &mut pool,
+            };
+            store_fragment(&mut conn, 0, 0, 1, 0, clock.now()).unwrap();
+            store_fragment(&mut conn, 1, 0, 2, 1, clock.now()).unwrap();
+            for sequence in [2, 3] {
+                store_fragment(&mut conn, sequence, 0, 1, sequence as u8, clock.now()).unwrap();
+            }
+            connection.channels.insert(id, channel);
+        }
+        connection.events.clear();
+        assert_eq!(pool.in_use(), 8);
+
+        let mut connections = endpoint(config.clone(), HashMap::new());
+        connections.set_channel_config(
+            ChannelConfig::new()
+                .channel(0, Send::Unreliable, Receive::Ordered)
+                .channel(1, Send::Unreliable, Receive::Ordered)
+                .skip_gaps(1),
+        );
+        connections.pool = pool;
+        connections.set_clock(clock.clone());
+        connections.conn.insert(0, connection);
+
+        assert_eq!(connections.poll().count(), 0);
+        clock.advance(config.head_of_line_timeout());
+        assert_eq!(connections.poll().count(), 0);
+
+        // only the channel that skips gaps moves on
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(
+            connections.poll().collect::<Vec<_>>(),
+            [
+                ConnectionEvent::MessageSkipped { connection: 0, channel: 1, sequence: 1 },
+                ConnectionEvent::MessageReceived { connection: 0, channel: 1, len: 100 },
+                ConnectionEvent::MessageReceived { connection: 0, channel: 1, len: 100 },
+            ],
+        );
+        assert_eq!(connections.pool.in_use(), 7);
+
+        let mut buf = [0u8; 128];
+        for (id, expected) in [(0, &[0][..]), (1, &[0, 2, 3][..])] {
+            let connection = connections.conn.get_mut(&0).unwrap();
+            let mut channel = connection.channels.remove(&id).unwrap();
+            let mut conn = ConnectionRef {
+                connection: &mut *connection,
+                channel: &mut channel,
+                pool: &mut connections.pool,
+            };
+            for &value in expected {
+                assert_eq!(conn.recv(&mut buf), Ok(100));
+                assert_eq!(buf[..100], [value; 100]);
+            }
+            assert_eq!(conn.recv(&mut buf), Ok(0));
+
+            // the rest of the skipped message comes too late
+            if id == 1 {
+                assert_eq!(
+                    store_fragment(&mut conn, 1, 1, 2, 1, clock.now()),
+                    Err(ErrorKind::MessageOlderThanThreshold.into())
+                );
+            }
+            connection.channels.insert(id, channel);
+        }
+        assert_eq!(connections.conn[&0].channels[&1].acks.next_recv_ordered(), Some(4));
+        assert_eq!(connections.conn[&0].channels[&0].acks.next_recv_ordered(), Some(1));
+    }
+
+    #[test]
+    fn expire_reassemblies_forgets_sequenced_partial() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = connected_server();
+        let mut channel = Channel::new(0, Send::Unreliable, Receive::Sequenced);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        store_fragment(&mut conn, 4, 0, 2, 0, now).unwrap();
+        assert_eq!(channel.recv_partial, Some(4));
+
+        let timeout = Duration::from_secs(1);
+        assert_eq!(channel.expire_reassemblies(now + timeout, timeout, &mut pool), 0);
+        assert_eq!(channel.expire_reassemblies(now + 2 * timeout, timeout, &mut pool), 1);
+        assert_eq!(channel.recv_partial, None);
+        assert_eq!(pool.in_use(), 0);
+    }
+
+    /// XORs the payload with a key and appends a checksum (of the header and plaintext).
+    struct XorProtector {
+        key: u8,
+    }
+
+    impl XorProtector {
+        fn checksum(header: &Header, plaintext: &[u8]) -> u8 {
+            let packet_number = match *header {
+                Header::Long { packet_number, .. } | Header::Short { packet_number, .. } => packet_number,
+            };
+            plaintext
+                .iter()
+                .fold(packet_number as u8, |sum, byte| sum.wrapping_add(*byte))
+        }
+    }
+
+    impl PacketProtector for XorProtector {
+        fn overhead(&self) -> usize {
+            1
+        }
+
+        fn seal(&self, header: &Header, payload: &mut [u8]) -> io::Result<usize> {
+            let (plaintext, tag) = payload.split_at_mut(payload.len() - 1);
+            tag[0] = Self::checksum(header, plaintext);
+            payload.iter_mut().for_each(|byte| *byte ^= self.key);
+            Ok(payload.len())
+        }
+
+        fn open(&self, header: &Header, payload: &mut [u8]) -> io::Result<usize> {
+            payload.iter_mut().for_each(|byte| *byte ^= self.key);
+            let (plaintext, tag) = payload
+                .split_last()
+                .map(|(tag, plaintext)| (plaintext, *tag))
+                .ok_or_else(|| io::Error::new_const(io::ErrorKind::InvalidData, &"missing tag"))?;
+            if tag != Self::checksum(header, plaintext) {
+                return Err(io::Error::new_const(io::ErrorKind::InvalidData, &"tag mismatch"));
+            }
+            Ok(plaintext.len())
+        }
+    }
+
+    /// Writes a data packet sealed by `protector` into `data` and returns its length.
+    fn write_sealed_data_packet(
+        protector: &dyn PacketProtector,
+        data: &mut [u8],
+        packet_number: u64,
+        payload: &[u8],
+    ) -> usize {
+        let header = Header::Short {
+            packet_number,
+            packet_type: PacketType::Data,
+            dst_id: 1,
+        };
+        let frame = Frame::Data {
+            channel_id: 5,
+            channel_sequence: 0,
+            fragment_index: 0,
+            fragment_count: 1,
+            len: payload.len() as u16,
+        };
+
+        let mut buf = BytesMut::new(data);
+        header.write(&mut buf).unwrap();
+        let payload_start = buf.position();
+        frame.write(&mut buf).unwrap();
+        buf.copy_from_slice(payload).unwrap();
+        let plaintext_end = buf.position();
+
+        let sealed_len = protector
+            .seal(&header, &mut data[payload_start..(plaintext_end + protector.overhead())])
+            .unwrap();
+        payload_start + sealed_len
+    }
+
+    /// Returns a `Connections` constructed from `config`, with the channels of
+    /// [`reliable_channels`], a pool of 8 buffers, and the connections in `conn`.
+    fn endpoint(config: Config, conn: HashMap<ConnectionId, Connection>) -> Connections {
+        let mut connections = Connections::new(config);
+        connections.set_channel_config(reliable_channels());
+        connections.pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        connections.conn = conn;
+        connections
+    }
+
+    /// Declares channels `0..16` as [`Reliable`](Send::Reliable) and [`Ordered`](Receive::Ordered).
+    fn reliable_channels() -> ChannelConfig {
+        (0..16).fold(ChannelConfig::new(), |config, id| {
+            config.channel(id, Send::Reliable, Receive::Ordered)
+        })
+    }
+
+    fn connected_server() -> Connection {
+        connected_server_with(Config::default(), Instant::now())
+    }
+
+    /// Returns a connected server like [`connected_server`], with `config`, created at `now`.
+    fn connected_server_with(config: Config, now: Instant) -> Connection {
+        let mut server = Connection::new(1, 0, "127.0.0.1:0".parse().unwrap(), Role::Server, config, now);
+        server.handle_request(Request::Connect);
+        server.events.clear();
+        server
+    }
+
+    #[test]
+    fn protector_round_trips_data_packet() {
+        let protector = XorProtector { key: 0x5a };
+        let mut server = connected_server();
+
+        let mut data = [0u8; 64];
+        let len = write_sealed_data_packet(&protector, &mut data, 3, b"hello");
+        assert!(!data[..len].windows(5).any(|window| window == b"hello"));
+
+        let mut buf = BytesMut::new(&mut data[..len]);
+        let header = Header::read(&mut buf).unwrap();
+        let payload_start = buf.position();
+        let payload_len = server
+            .open_payload(&protector, &header, &mut data[payload_start..len])
+            .unwrap();
+
+        let mut buf = BytesMut::new(&mut data[payload_start..(payload_start + payload_len)]);
+        match Frame::read(&mut buf).unwrap() {
+            Frame::Data { channel_id, len, .. } => {
+                assert_eq!(channel_id, 5);
+                assert_eq!(buf.as_ref().get(..len as usize), Some(&b"hello"[..]));
+            },
+            frame => panic!("unexpected frame: {:?}", frame),
+        }
+        assert_eq!(server.state(), ConnectionState::Connected);
+
+        // the null protector doesn't change anything
+        let mut data = [0u8; 64];
+        let len = write_sealed_data_packet(&NullProtector, &mut data, 3, b"hello");
+        assert_eq!(&data[(len - 5)..len], b"hello");
+    }
+
+    #[test]
+    fn tampered_packet_disconnects() {
+        let protector = XorProtector { key: 0x5a };
+        let mut server = connected_server();
+
+        let mut data = [0u8; 64];
+        let len = write_sealed_data_packet(&protector, &mut data, 3, b"hello");
+        data[len - 3] ^= 0x01;
+
+        let mut buf = BytesMut::new(&mut data[..len]);
+        let header = Header::read(&mut buf).unwrap();
+        let payload_start = buf.position();
+        assert!(server
+            .open_payload(&protector, &header, &mut data[payload_start..len])
+            .is_err());
+
+        assert_eq!(server.state(), ConnectionState::Disconnecting(DisconnectReason::EncryptionInvalid));
+        assert_eq!(
+            server.events.drain(..).collect::<Vec<_>>(),
+            [ConnectionEvent::Disconnected(1, DisconnectReason::EncryptionInvalid)],
+        );
+    }
+
+    #[test]
+    fn authenticated_packet_from_new_address_migrates() {
+        let old_addr: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let new_addr: SocketAddr = "198.51.100.7:5001".parse().unwrap();
+        let server = |key| {
+            let mut connection = connected_server();
+            connection.peer_addr = old_addr;
+            let mut connections = endpoint(Config::default(), HashMap::from([(1, connection)]));
+            connections.set_protector(XorProtector { key });
+            connections
+        };
+        let mut data = [0u8; 64];
+        let len = write_sealed_data_packet(&XorProtector { key: 0x5a }, &mut data, 3, b"hello");
+
+        // a packet that can't be authenticated doesn't move the connection
+        let mut forged = server(0x33);
+        forged.handle_datagram(new_addr, &data[..len], Instant::now()).unwrap();
+        assert_eq!(forged.conn[&1].peer_addr, old_addr);
+        assert!(!forged.poll().any(|event| matches!(event, ConnectionEvent::Migrated { .. })));
+
+        let mut genuine = server(0x5a);
+        genuine.handle_datagram(new_addr, &data[..len], Instant::now()).unwrap();
+        assert_eq!(genuine.conn[&1].peer_addr, new_addr);
+        assert_eq!(
+            genuine.poll().collect::<Vec<_>>(),
+            [
+                ConnectionEvent::Migrated { connection: 1, from: old_addr, to: new_addr },
+                ConnectionEvent::MessageReceived { connection: 1, channel: 5, len: 5 },
+            ],
+        );
+
+        // packets from the address it's already at don't migrate it again
+        let len = write_sealed_data_packet(&XorProtector { key: 0x5a }, &mut data, 4, b"world");
+        genuine.handle_datagram(new_addr, &data[..len], Instant::now()).unwrap();
+        assert!(!genuine.poll().any(|event| matches!(event, ConnectionEvent::Migrated { .. })));
+
+        // nor does a packet from the old address that was overtaken by the migration
+        let len = write_sealed_data_packet(&XorProtector { key: 0x5a }, &mut data, 2, b"stale");
+        genuine.handle_datagram(old_addr, &data[..len], Instant::now()).unwrap();
+        assert_eq!(genuine.conn[&1].peer_addr, new_addr);
+        assert!(!genuine.poll().any(|event| matches!(event, ConnectionEvent::Migrated { .. })));
+    }
+
+    #[test]
+    fn update_expires_connect_token() {
+        let now = Instant::now();
+        let config = Config::builder()
+            .connect_token_lifetime(Duration::from_secs(10))
+            .build()
+            .unwrap();
+        let mut client = Connection::new(
+            0,
+            1,
+            "127.0.0.1:0".parse().unwrap(),
+            Role::Client,
+            config.clone(),
+            now,
+        );
+        assert_eq!(client.token_expire_time(), now + Duration::from_secs(10));
+
+        client.connect(now);
+        client.update(client.token_expire_time());
+        assert_eq!(client.state(), ConnectionState::Disconnecting(DisconnectReason::ConnectTokenExpired));
+        assert_eq!(
+            client.events.drain(..).collect::<Vec<_>>(),
+            [ConnectionEvent::Disconnected(0, DisconnectReason::ConnectTokenExpired)],
+        );
+
+        // the token doesn't matter once connected
+        let mut server = Connection::new(
+            1,
+            0,
+            "127.0.0.1:0".parse().unwrap(),
+            Role::Server,
+            config,
+            now,
+        );
+        server.handle_request(Request::Connect);
+        let later = server.token_expire_time() + Duration::from_secs(1);
+        server.time_latest_recv = Some(later);
+        server.update(later);
+        assert_eq!(server.state(), ConnectionState::Connected);
+    }
+
+    #[test]
+    fn disconnect_flushes_send_window() {
+        let clock = ManualClock::new(Instant::now());
+        let config = Config::default();
+        let server = connected_server_with(config.clone(), clock.now());
+        let mut connections = endpoint(config.clone(), HashMap::from([(1, server)]));
+        connections.set_clock(clock.clone());
+
+        connections.send_message(1, 5, b"hello").unwrap();
+        connections.send_message(1, 5, b"world").unwrap();
+        connections.send_message(1, 2, b"!").unwrap();
+        assert_eq!(connections.pool.capacity_remaining(), 5);
+
+        clock.advance(config.idle_timeout());
+        let events = connections.poll().collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            [
+                ConnectionEvent::Disconnected(1, DisconnectReason::ConnectionIdleTimeout),
+                ConnectionEvent::DeliveryLost { connection: 1, channel: 2, sequence: 0 },
+                ConnectionEvent::DeliveryLost { connection: 1, channel: 5, sequence: 0 },
+                ConnectionEvent::DeliveryLost { connection: 1, channel: 5, sequence: 1 },
+            ],
+        );
+        assert_eq!(connections.pool.capacity_remaining(), 8);
+        assert!(connections.conn[&1].channels.values().all(|channel| !channel.send_window_full()));
+
+        // the peer is told why
+        let mut data = [0u8; 64];
+        let mut buf = BytesMut::new(&mut data);
+        assert!(connections.conn[&1].write_close(&mut buf).unwrap());
+        let len = buf.position();
+
+        let mut client = Connection::new(
+            0,
+            1,
+            "127.0.0.1:0".parse().unwrap(),
+            Role::Client,
+            config,
+            clock.now(),
+        );
+        client.state = ConnectionState::Connected;
+        let mut buf = BytesMut::new(&mut data[..len]);
+        assert!(matches!(Header::read(&mut buf).unwrap(), Header::Short { dst_id: 0, .. }));
+        match Frame::read(&mut buf).unwrap() {
+            Frame::Close { reason } => client.handle_close(reason),
+            frame => panic!("unexpected frame: {:?}", frame),
+        }
+        assert_eq!(
+            client.events.drain(..).collect::<Vec<_>>(),
+            [ConnectionEvent::Disconnected(0, DisconnectReason::PeerConnectionIdleTimeout)],
+        );
+    }
+
+    #[test]
+    fn new_sizes_pool_from_config() {
+        let config = Config::builder()
+            .max_connections(2)
+            .max_fragments(4)
+            .max_packets_in_flight(8)
+            .build()
+            .unwrap();
+        let connections = Connections::new(config);
+        assert_eq!(connections.pool.capacity(), 2 * (8 + 4));
+        assert_eq!(
+            connections.pool.buffer_size(),
+            Header::short_header_bytes() + DATA_FRAME_BYTES + MAX_FRAGMENT_BYTES,
+        );
+        assert!(connections.pool.buffer_size() >= MAX_PAYLOAD_BYTES);
+
+        // small fragments still leave room for a whole datagram
+        let config = Config::builder().max_fragment_bytes(100).build().unwrap();
+        assert_eq!(Connections::new(config).pool.buffer_size(), MAX_PAYLOAD_BYTES);
+    }
+
+    #[test]
+    fn poll_transmit_sends_scheduled_fragments() {
+        let now = Instant::now();
+        let mut connections = guarded_connections(8);
+        connections.channel_config = reliable_channels().channel(16, Send::Unreliable, Receive::Unordered);
+        for _ in 0..3 {
+            connections.send_message(1, 0, &[1; 500]).unwrap();
+        }
+        connections.send_message(1, 16, &[2; 500]).unwrap();
+
+        // the unreliable message jumps the queue
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        let channels = |data: &mut [u8]| {
+            read_frames(data)
+                .into_iter()
+                .filter_map(|frame| match frame {
+                    Frame::Data { channel_id, .. } => Some(channel_id),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let (_, len) = connections.poll_transmit(&mut data, now).unwrap().unwrap();
+        assert_eq!(channels(&mut data[..len]), [16, 0]);
+        let (_, len) = connections.poll_transmit(&mut data, now).unwrap().unwrap();
+        assert_eq!(channels(&mut data[..len]), [0, 0]);
+        assert_eq!(connections.poll_transmit(&mut data, now).unwrap(), None);
+        assert!(connections.conn[&1].scheduled.is_empty());
+    }
+
+    #[test]
+    fn poll_transmit_writes_frames_in_canonical_order() {
+        let mut connections = guarded_connections(8);
+        let now = connections.conn[&1].time_created;
+        let peer_addr = connections.conn[&1].peer_addr;
+
+        // a message from the remote endpoint, so that there's something to acknowledge
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        let mut buf = BytesMut::new(&mut data);
+        Header::Short {
+            packet_number: 0,
+            packet_type: PacketType::Data,
+            dst_id: 1,
+        }
+        .write(&mut buf)
+        .unwrap();
+        Channel::new(0, Send::Reliable, Receive::Ordered).open_frame().write(&mut buf).unwrap();
+        Frame::Data {
+            channel_id: 0,
+            channel_sequence: 0,
+            fragment_index: 0,
+            fragment_count: 1,
+            len: 5,
+        }
+        .write(&mut buf)
+        .unwrap();
+        buf.copy_from_slice(b"hello").unwrap();
+        let len = buf.position();
+        connections.handle_datagram(peer_addr, &data[..len].to_vec(), now).unwrap();
+        for channel in [0, 1] {
+            connections.send_message(1, channel, b"world").unwrap();
+        }
+
+        // acknowledgements, then the data of each channel
+        let (_, len) = connections.poll_transmit(&mut data, now).unwrap().unwrap();
+        let frames = read_frames(&mut data[..len]);
+        assert!(matches!(
+            frames[..],
+            [
+                Frame::Ack { ack_sequence: 0, .. },
+                Frame::ChannelAck { channel_id: 0, ack_sequence: 0, .. },
+                Frame::OpenChannel { channel_id: 0, .. },
+                Frame::Data { channel_id: 0, .. },
+                Frame::OpenChannel { channel_id: 1, .. },
+                Frame::Data { channel_id: 1, .. },
+            ]
+        ));
+        assert!(frames.windows(2).all(|pair| pair[0].rank() <= pair[1].rank()));
+
+        // padding goes last
+        let later = now + Config::default().mtu_probe_interval();
+        let (_, len) = connections.poll_transmit(&mut data, later).unwrap().unwrap();
+        let frames = read_frames(&mut data[..len]);
+        assert!(matches!(frames[..], [Frame::Ack { .. }, Frame::Ping, Frame::Padding { .. }]));
+        assert!(frames.windows(2).all(|pair| pair[0].rank() <= pair[1].rank()));
+    }
+
+    #[test]
+    fn poll_transmit_paces_data_packets() {
+        let clock = ManualClock::new(Instant::now());
+        let mut connections = endpoint(Config::default(), HashMap::from([(1, connected_server())]));
+        connections.pool = BufferPool::new(MAX_PAYLOAD_BYTES, 32);
+        connections.set_clock(clock.clone());
+        for _ in 0..20 {
+            connections.send_message(1, 0, &[7; 1000]).unwrap();
+        }
+
+        // 120 bytes per millisecond
+        let connection = connections.conn.get_mut(&1).unwrap();
+        connection.delivery_rate = 96_000.0;
+        assert_eq!(connection.pacing_rate(), 120_000.0);
+        assert_eq!(connection.pacing_interval(), Duration::from_millis(10));
+
+        // the burst goes out at once
+        let open_bytes = Channel::new(0, Send::Reliable, Receive::Ordered).open_frame().encoded_len();
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        let start = clock.now();
+        let mut send_times = Vec::new();
+        while let Some((_, len)) = connections.poll_transmit(&mut data, start).unwrap() {
+            assert_eq!(len, Header::short_header_bytes() + open_bytes + DATA_FRAME_BYTES + 1000);
+            send_times.push(start);
+        }
+        assert_eq!(send_times.len(), PACING_BURST_PACKETS);
+
+        // then the rest are spread out
+        for _ in 0..100 {
+            clock.advance(Duration::from_millis(1));
+            let now = clock.now();
+            if connections.poll_transmit(&mut data, now).unwrap().is_some() {
+                send_times.push(now);
+            }
+            assert_eq!(connections.poll_transmit(&mut data, now).unwrap(), None);
+        }
+
+        // 1049 bytes take a little under 9ms to earn
+        let paced = &send_times[PACING_BURST_PACKETS..];
+        assert!(paced.len() >= 10 && paced.len() <= 12);
+        assert!(paced.windows(2).all(|pair| pair[1] - pair[0] >= Duration::from_millis(8)));
+    }
+
+    #[test]
+    fn flush_sends_everything_at_once() {
+        let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client_socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server_socket.set_nonblocking(true).unwrap();
+
+        let mut server = connected_server();
+        server.peer_addr = client_socket.local_addr().unwrap();
+        let mut connections = endpoint(Config::default(), HashMap::from([(1, server)]));
+        connections.pool = BufferPool::new(MAX_PAYLOAD_BYTES, 32);
+        for channel_id in 0..2 {
+            for _ in 0..10 {
+                connections.send_message(1, channel_id, &[7; 1000]).unwrap();
+            }
+        }
+
+        // the pacer would only let a burst through, a flush sends one packet per message
+        assert_eq!(connections.flush(1, &server_socket).unwrap(), 20);
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        for _ in 0..20 {
+            client_socket.recv_from(&mut data).unwrap();
+        }
+        assert!(!connections.conn[&1].has_pending_fragments());
+        assert_eq!(connections.flush(1, &server_socket).unwrap(), 0);
+
+        // the fragments are tracked like any others
+        for channel in connections.conn[&1].channels.values() {
+            for sequence in 0..10 {
+                let message = channel.send_buffer.get(sequence).unwrap().as_ref().unwrap();
+                assert!(message.time_sent.is_some());
+                assert_eq!(message.fragment_status[0], SendStatus::Sent);
+            }
+        }
+        assert_eq!(connections.conn[&1].acks.sent_packets_in_flight(), 20);
+
+        let err = connections.flush(2, &server_socket).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn poll_transmit_sends_close_once() {
+        let mut connections = guarded_connections(8);
+        connections.conn.get_mut(&1).unwrap().disconnect(DisconnectReason::Closed);
+
+        let now = Instant::now();
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        let (_, len) = connections.poll_transmit(&mut data, now).unwrap().unwrap();
+        assert_eq!(connections.poll_transmit(&mut data, now).unwrap(), None);
+
+        let mut client = Connection::new(
+            0,
+            1,
+            "127.0.0.1:0".parse().unwrap(),
+            Role::Client,
+            Config::default(),
+            now,
+        );
+        client.state = ConnectionState::Connected;
+        let mut buf = BytesMut::new(&mut data[..len]);
+        Header::read(&mut buf).unwrap();
+        match Frame::read(&mut buf).unwrap() {
+            Frame::Close { reason } => client.handle_close(reason),
+            frame => panic!("unexpected frame: {:?}", frame),
         }
+        assert_eq!(
+            client.events.drain(..).collect::<Vec<_>>(),
+            [ConnectionEvent::Disconnected(0, DisconnectReason::PeerClosed)],
+        );
 
-        self.acks.latest_send_acked = Some(acked);
-        self.acks.latest_recv = Some(recv);
-        self.acks.latest_recv_mask = {
-            if gap >= REDUNDANT_ACK_MASK_BITS {
-                1
-            } else {
-                (self.latest_recv_mask << gap) | 1   
-            }
+        // the peer doesn't answer with a close of its own
+        assert!(client.close_sent);
+    }
+
+    #[test]
+    fn send_window_fills_until_acked() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, DEFAULT_SEND_WINDOW_SIZE + 1);
+        let mut connection = connected_server();
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
         };
 
-        let start = self.acks.oldest_send_unacked.unwrap_or(0);
-        let end = acked;
+        for _ in 0..DEFAULT_SEND_WINDOW_SIZE {
+            conn.store_outgoing_data(b"hello", now).unwrap();
+        }
+        assert!(conn.channel.send_window_full());
+        assert_eq!(conn.store_outgoing_data(b"hello", now), Err(ErrorKind::SendWindowFull.into()));
+        assert_eq!(conn.channel.time_send_window_full, Some(now));
 
-        for sequence in start..=end {
-            if let Some(packet) = self.send_buffer.get(sequence) {
-                if acked < sequence {
-                    // All unacknowledged packets in flight are newer.
-                    return;
-                }
-                
-                let gap = acked - sequence;
-                if (gap >= REDUNDANT_ACK_MASK_BITS as u64) || ((acked_mask & (1 << gap)) == 0) {
-                    // Packet was *probably* lost.
-                } else {
-                    // Packet was delivered.
-                }
-    
-                self.send_buffer.remove(sequence);
+        // acknowledging the oldest message makes room for one more
+        conn.acknowledge(0, 1, now);
+        assert!(!conn.channel.send_window_full());
+        assert_eq!(conn.channel.time_send_window_full, None);
+        conn.store_outgoing_data(b"hello", now).unwrap();
+        assert_eq!(conn.store_outgoing_data(b"hello", now), Err(ErrorKind::SendWindowFull.into()));
+    }
+
+    #[test]
+    fn backfilled_header_completes_fragment_packet() {
+        let now = Instant::now();
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut connection = connected_server();
+        let mut channel = Channel::new(4, Send::Reliable, Receive::Ordered);
+        let mut conn = ConnectionRef {
+            connection: &mut connection,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+
+        conn.store_outgoing_data(b"hello", now).unwrap();
+        let (handle, len) = conn.backfill_header(0, 0, 42).unwrap();
+
+        let slice = unsafe { MaybeUninit::slice_assume_init_mut(conn.pool.get_mut(handle).unwrap()) };
+        let mut packet = BytesMut::new(&mut slice[..len]);
+        assert_eq!(
+            Header::read(&mut packet).unwrap(),
+            Header::Short {
+                packet_number: 42,
+                packet_type: PacketType::Data,
+                dst_id: 0,
             }
-        }       
+        );
+        assert_eq!(
+            Frame::read(&mut packet).unwrap(),
+            Frame::Data {
+                channel_id: 4,
+                channel_sequence: 0,
+                fragment_index: 0,
+                fragment_count: 1,
+                len: 5,
+            }
+        );
+        assert_eq!(packet.as_ref(), b"hello");
+
+        // resending the fragment in another packet renumbers it
+        conn.backfill_header(0, 0, 43).unwrap();
+        let slice = unsafe { MaybeUninit::slice_assume_init_mut(conn.pool.get_mut(handle).unwrap()) };
+        let header = Header::read(&mut BytesMut::new(slice)).unwrap();
+        assert!(matches!(header, Header::Short { packet_number: 43, .. }));
+
+        assert!(conn.backfill_header(1, 0, 44).is_err());
+        assert!(conn.backfill_header(0, 1, 44).is_err());
     }
-}
 
-pub enum ErrorKind {
-    FragmentIndexInvalid,
-    FragmentIndexAlreadyReceived,
-    FragmentCountInvalid,
-    FragmentCountExceedsMax,
-    MessageOlderThanThreshold,
-    NotEnoughBuffersAvailable,
-    SendMessageZeroLength,
-}
+    #[test]
+    fn update_resends_connection_request_until_attempts_exhausted() {
+        let clock = ManualClock::new(Instant::now());
+        let config = Config::builder()
+            .request_timeout(Duration::from_millis(500))
+            .max_connection_attempts(3)
+            .build()
+            .unwrap();
+        let mut client = Connection::new(0, 1, "127.0.0.1:0".parse().unwrap(), Role::Client, config, clock.now());
+        client.connect(clock.now());
+        assert_eq!(client.pending_request.take(), Some(Request::Connect));
 
-pub struct ConnectionRef<'a> {
-    connection: &'a mut Connection,
-    channel: &'a mut Channel,
-    pool: &'a mut BufferPool,
-}
+        // each unanswered request is sent again after the timeout
+        for attempt in 2..=3 {
+            clock.advance(Duration::from_millis(499));
+            client.update(clock.now());
+            assert_eq!(client.pending_request, None);
 
-impl<'a> ConnectionRef<'a> {
+            clock.advance(Duration::from_millis(1));
+            client.update(clock.now());
+            assert_eq!(client.pending_request.take(), Some(Request::Connect));
+            assert_eq!(client.state(), ConnectionState::Connecting(attempt, clock.now()));
+        }
+
+        // the last one gets its full timeout too
+        clock.advance(Duration::from_millis(499));
+        client.update(clock.now());
+        assert!(matches!(client.state(), ConnectionState::Connecting(3, _)));
+
+        clock.advance(Duration::from_millis(1));
+        client.update(clock.now());
+        assert_eq!(client.pending_request, None);
+        assert_eq!(client.state(), ConnectionState::Disconnecting(DisconnectReason::ConnectionAttemptsExhausted));
+        assert_eq!(
+            client.events.drain(..).collect::<Vec<_>>(),
+            [ConnectionEvent::Disconnected(0, DisconnectReason::ConnectionAttemptsExhausted)],
+        );
+    }
 
-    pub fn read(&mut self, handle: BufferHandle) {
+    #[test]
+    fn update_disconnects_idle_connection() {
+        let clock = ManualClock::new(Instant::now());
+        let timeout = Duration::from_secs(2);
+        let config = Config::builder().idle_timeout(timeout).build().unwrap();
+        let mut server = Connection::new(1, 0, "127.0.0.1:0".parse().unwrap(), Role::Server, config, clock.now());
+        server.handle_request(Request::Connect);
+        server.events.clear();
 
+        // hearing from the peer resets the timeout
+        clock.advance(timeout - Duration::from_millis(1));
+        server.update(clock.now());
+        server.time_latest_recv = Some(clock.now());
+        clock.advance(timeout - Duration::from_millis(1));
+        server.update(clock.now());
+        assert_eq!(server.state(), ConnectionState::Connected);
+
+        clock.advance(Duration::from_millis(1));
+        server.update(clock.now());
+        assert_eq!(server.state(), ConnectionState::Disconnecting(DisconnectReason::ConnectionIdleTimeout));
+        assert_eq!(
+            server.events.drain(..).collect::<Vec<_>>(),
+            [ConnectionEvent::Disconnected(1, DisconnectReason::ConnectionIdleTimeout)],
+        );
+    }
+
+    #[test]
+    fn update_disconnects_jammed_send_window() {
         let now = Instant::now();
+        let timeout = Config::default().send_window_timeout();
+        let mut server = connected_server();
+        let mut channel = Channel::new(0, Send::Reliable, Receive::Ordered);
+        channel.time_send_window_full = Some(now);
+        server.channels.insert(0, channel);
 
-        // TODO: need to read all data frames
-        let buf = {
-            let slice = unsafe {
-                MaybeUninit::slice_assume_init_mut(self.pool.get_mut(handle)?)
-            };
-            BytesMut::new(slice)
-        };
+        // still hearing from the peer, so only the send window matters
+        server.time_latest_recv = Some(now + timeout);
+        server.update(now + timeout / 2);
+        assert_eq!(server.state(), ConnectionState::Connected);
 
-        match Frame::read(&mut buf).unwrap() {
-            Frame::Padding { len } => {
-                todo!();
-            },
-            Frame::Ping => {
-                todo!();
-            },
-            Frame::Ack {
-                ack_sequence,
-                ack_mask,
-            } => {
-                todo!();
-            },
-            Frame::Data {
-                // TODO: channel_type,
-                channel_id,
-                channel_sequence,
-                fragment_index,
-                fragment_count,
-                len,
-            } => {
-                self.connection.channels
-                    .entry(&channel_id)
-                    .or_insert(Channel::new(channel_id, send_guarantee, recv_guarantee))
-                    .store_incoming_data(
-                        channel_sequence,
-                        fragment_index,
-                        fragment_count,
-                        handle,
-                        buf.position(),
-                        buf.position() + len as usize,
-                        now,
-                    );
-            },
-        }
+        server.update(now + timeout);
+        assert_eq!(server.state(), ConnectionState::Disconnecting(DisconnectReason::SendBufferIsFull));
+        assert_eq!(
+            server.events.drain(..).collect::<Vec<_>>(),
+            [ConnectionEvent::Disconnected(1, DisconnectReason::SendBufferIsFull)],
+        );
     }
 
-    // TODO: len is optional field (LSB in frame type 1 == has length, 0 == full length)
-    pub fn store_incoming_data(
-        &mut self,
-        sequence: u64,
-        fragment_index: u8,
-        fragment_count: u8,
-        handle: BufferHandle,
-        start: usize,
-        end: usize,
-        instant: Instant,
-    ) -> io::Result<()> {
-        match self.channel.recv_guarantee {
-            Receive::Unordered => {
-                if let Some(latest_recv) = self.channel.acks.latest_recv {
-                    if sequence < latest_recv.saturating_sub(self.channel.recv_buffer.capacity() as u64) {
-                        return Err(ErrorKind::MessageOlderThanThreshold);
-                    }
-                }
-            },
-            Receive::Ordered => {
-                if let Some(next_recv_ordered) = self.channel.acks.next_recv_ordered {
-                    if sequence < next_recv_ordered {
-                        return Err(ErrorKind::MessageOlderThanThreshold);
-                    }
-                }
-            },
-            Receive::Sequenced => {
-                if let Some(latest_recv) = self.channel.acks.latest_recv {
-                    if sequence < latest_recv {
-                        return Err(ErrorKind::MessageOlderThanThreshold);
-                    }
-                }
-            },
-        }
+    /// Acknowledges `count` messages on an unreliable channel, reporting every `lost_every`-th
+    /// one as lost, then updates the connection once per 100 ms until `until`.
+    fn acknowledge_with_loss(server: &mut Connection, lost_every: u64, start: Instant, until: Duration) -> Option<Instant> {
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut channel = Channel::new(0, Send::Unreliable, Receive::Unordered);
+        let mut sequence = 0;
+        let mut time = start;
 
-        let message = {
-            if let Some(Some(message)) = self.channel.recv_buffer.get_mut(sequence) {
-                if fragment_count != message.fragment_count {
-                    return Err(ErrorKind::FragmentCountInvalid);
-                }
-                if fragment_index >= message.fragment_count {
-                    return Err(ErrorKind::FragmentIndexInvalid);
-                }
-                if message.fragment_data[fragment_index as usize].is_some() {
-                    return Err(ErrorKind::FragmentIndexAlreadyReceived);
-                }
-                message
+        while time <= start + until {
+            for _ in 0..8 {
+                insert_sent(&mut channel, sent_message(sequence, time));
+                let mask = if sequence % lost_every == 0 { 0 } else { 1 };
+                let mut conn = ConnectionRef {
+                    connection: server,
+                    channel: &mut channel,
+                    pool: &mut pool,
+                };
+                conn.acknowledge(sequence, mask, time);
+                sequence += 1;
             }
-            else {
-                let index = self.channel.recv_buffer.index_of(sequence);
-                if let (Some(sequence), Some(message)) = self.channel.recv_buffer.remove_index(index) {
-                    // release buffers held by old message
-                    for location in message.fragment_data.iter().flatten() {
-                        self.pool.release(location.0);
-                    }
-                }
-                self.channel.recv_buffer.insert(
-                    sequence,
-                    RecvMessage {
-                        sequence,
-                        fragment_count,
-                        fragment_recv: 0,
-                        fragment_data: [None; MAX_FRAGMENTS],
-                        time_created: instant,
-                        time_recv: None,
-                    },
-                )
+
+            server.time_latest_recv = Some(time);
+            server.update(time);
+            if server.state() != ConnectionState::Connected {
+                return Some(time);
             }
-        };
+            time += Duration::from_millis(100);
+        }
 
-        message.fragment_recv += 1;
-        message.fragment_data[fragment_index as usize] = Some((handle, start, end));
-       
-        if message.fragment_recv == message.fragment_count {
-            self.connection.time_latest_recv = Some(instant);
-            self.channel.time_latest_recv = Some(instant);
-            message.time_recv = Some(instant);
+        None
+    }
+
+    #[test]
+    fn congestion_window_halves_on_loss_and_recovers_over_clean_round_trips() {
+        let start = Instant::now();
+        let mut server = connected_server();
+        let mtu = server.mtu();
+        let rtt = server.rtt();
+        let initial = server.congestion_window();
+        assert_eq!(initial, INITIAL_CONGESTION_WINDOW * mtu);
 
-            let prev_recv = self.channel.acks.latest_recv.take();
-            self.channel.acks.latest_recv = match prev_recv {
-                None => Some(sequence),
-                Some(latest_recv) => Some(latest_recv.max(sequence)),
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let mut channel = Channel::new(0, Send::Unreliable, Receive::Unordered);
+        let mut sequence = 0;
+        // acknowledges the next message (sent a round trip earlier) at `time`
+        let mut ack = |server: &mut Connection, lost: bool, time: Instant| {
+            insert_sent(&mut channel, sent_message(sequence, time - rtt));
+            let mut conn = ConnectionRef {
+                connection: server,
+                channel: &mut channel,
+                pool: &mut pool,
             };
-            
-            match self.recv_guarantee {
-                Receive::Unordered => (),
-                Receive::Ordered => {
-                    // return messages in the order they were sent
-                    let start = self.channel.acks.next_recv_ordered.unwrap_or(0);
-                    let end = self.channel.acks.latest_recv.unwrap_or(start);
-                    for sequence in start..=end {
-                        if let Some(Some(message)) = self.channel.recv_buffer.get(sequence) {
-                            if message.fragment_recv == message.fragment_count {
-                                // push event
-                                self.channel.acks.next_recv_ordered = Some(sequence + 1);
-                                continue;
-                            }
-                        }
-                        self.channel.acks.next_recv_ordered = Some(sequence);
-                        break;
-                    }
-                },
-                Receive::Sequenced => {
-                    let start = prev_recv.unwrap_or(0);
-                    let end = self.channel.acks.latest_recv.unwrap_or(start);
-                    for sequence in start..=end {
-                        // complete messages that are not already delivered
-                        todo!();
-                    }
-                },
-            }
+            conn.acknowledge(sequence, if lost { 0 } else { 1 }, time);
+            sequence += 1;
+            server.congestion_window()
+        };
+
+        assert_eq!(ack(&mut server, false, start), initial);
+        assert_eq!(ack(&mut server, true, start + Duration::from_millis(10)), initial / 2);
+        // losses within the same round trip only count once
+        let recovery = start + Duration::from_millis(20);
+        assert_eq!(ack(&mut server, true, recovery), initial / 2);
+
+        // then one more packet per round trip without losses
+        assert_eq!(ack(&mut server, false, recovery + rtt - Duration::from_millis(1)), initial / 2);
+        for round in 1..=5 {
+            assert_eq!(ack(&mut server, false, recovery + rtt * round), initial / 2 + round as usize * mtu);
         }
+        assert_eq!(server.congestion_window(), initial);
 
-        Ok(())
+        // a loss later on halves it again
+        assert_eq!(ack(&mut server, true, recovery + rtt * 6), initial / 2);
     }
-    
-    pub fn store_outgoing_data(&mut self, data: &[u8], instant: Instant) -> io::Result<()> {
-        // TODO: Check for exceeded send window.
-        if data.len() == 0 {
-            return Err(ErrorKind::SendMessageZeroLength);
-        }
-        
-        // calculate the number of fragments and check that it's valid
-        let fragment_count = (data.len() / MAX_FRAGMENT_BYTES) + 
-                                  ((data.len() % MAX_FRAGMENT_BYTES) != 0) as usize;
-        if fragment_count > MAX_FRAGMENTS {
-            return Err(ErrorKind::FragmentCountExceedsMax);
-        }
-        if fragment_count > self.pool.capacity_remaining() {
-            return Err(ErrorKind::NotEnoughBuffersAvailable)
-        }
 
-        // TODO: add buffer for user data
-        let sequence = self.channel.acks.next_send;
-        self.channel.acks.next_send += 1;
+    #[test]
+    fn congestion_window_holds_data_back() {
+        let now = Instant::now();
+        let mut connections = endpoint(Config::default(), HashMap::from([(1, connected_server())]));
+        connections.set_clock(ManualClock::new(now));
+        let peer_addr = connections.conn[&1].peer_addr;
+        connections.conn.get_mut(&1).unwrap().congestion_window = 1;
+        assert_eq!(connections.conn[&1].send_budget(), 1);
 
-        let message = self.channel.send_buffer
-            .insert(
-                sequence,
-                SendMessage {
-                    sequence,
-                    fragment_count: u8::from(fragment_count),
-                    fragment_sent: 0,
-                    fragment_data: [None; MAX_FRAGMENTS],
-                    fragment_status: [SendStatus::Unsent; MAX_FRAGMENTS],
-                    time_created: instant,
-                    time_sent: None,
-                }
-            );
-        
-        // write fragment frames
-        for index in 0..fragment_count {
-            let handle = self.pool.acquire()?;
-            let buf = {
-                let slice = unsafe {
-                    MaybeUninit::slice_assume_init_mut(self.pool.get_mut(handle)?)
-                };
-                BytesMut::new(slice)
-            };
-            
-            let start = index * MAX_FRAGMENT_BYTES;
-            let end = (start + MAX_FRAGMENT_BYTES).min(data.len());
-            let len = end - start;
-            
-            let header = Header::Short {
-                dst_id: self.connection.dst_id,
-                packet_type: PacketType::Data,
-                packet_number: self.connection.acks.next_send,
-            };
-            
-            let frame = Frame::Data {
-                channel_id: self.channel.id,
-                channel_sequence: sequence,
-                fragment_count: u8::from(fragment_count),
-                fragment_index: u8::from(index),
-                len: u16::from(len),
-            };
-            
-            // skip writing the header since we don't know what the packet sequence number is
-            buf.advance(Header::short_header_bytes())?;
-            frame.write(&mut buf)?;
-            message.fragment_data[index] = Some((handle, buf.position(), len));
-            buf.copy_from_slice(&data[start..end])?;
+        // the window isn't full until something is in flight
+        connections.send_message(1, 0, b"hello").unwrap();
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        assert!(connections.poll_transmit(&mut data, now).unwrap().is_some());
+        connections.send_message(1, 1, b"world").unwrap();
+        assert_eq!(connections.conn[&1].send_budget(), 0);
+        assert!(connections.conn[&1].in_flight_full());
+        assert_eq!(connections.poll_transmit(&mut data, now).unwrap(), None);
+
+        // an acknowledgement makes room again
+        let mut buf = BytesMut::new(&mut data);
+        Header::Short {
+            packet_number: 0,
+            packet_type: PacketType::Data,
+            dst_id: 1,
         }
-        
-        Ok(())
+        .write(&mut buf)
+        .unwrap();
+        Frame::Ack { ack_sequence: 0, ack_mask: 1 }.write(&mut buf).unwrap();
+        let len = buf.position();
+        connections.handle_datagram(peer_addr, &data[..len], now).unwrap();
+        assert!(!connections.conn[&1].in_flight_full());
+        assert!(connections.poll_transmit(&mut data, now).unwrap().is_some());
     }
 
-    pub fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        // split off into its own function
-        // pop from event queue
-        
-        // stack allocation
-        let scratch = [0u8; MAX_FRAGMENTS * MAX_FRAGMENT_BYTES];
-        
-        // channel -> recv buffer
-        // next packet
-        // write fragments into buf, pass up to caller
+    #[test]
+    fn update_disconnects_excessive_packet_loss() {
+        let start = Instant::now();
+        let timeout = Config::default().packet_loss_timeout();
+        let mut server = connected_server();
+
+        // 1 in 8 lost is above the 10% threshold
+        let time = acknowledge_with_loss(&mut server, 8, start, 2 * timeout).unwrap();
+        assert!(server.packet_loss() > Config::default().packet_loss_threshold());
+        assert!(time >= start + timeout);
+        assert!(time <= start + timeout + Duration::from_secs(1));
+        assert_eq!(
+            server.events.drain(..).filter(|event| matches!(event, ConnectionEvent::Disconnected(..))).collect::<Vec<_>>(),
+            [ConnectionEvent::Disconnected(1, DisconnectReason::ExcessivePacketLoss)],
+        );
     }
-    
-    pub fn send(&mut self, socket: impl Socket) -> io::Result<()> {
 
-        let instant = Instant::now();
+    #[test]
+    fn update_tolerates_packet_loss_below_threshold() {
+        let start = Instant::now();
+        let timeout = Config::default().packet_loss_timeout();
+        let mut server = connected_server();
 
-        // reliable non-sequenced has head of line blocking (prioritize resending lost messages)
-        // reliable sequenced is only reliable for the latest packet
+        // 1 in 12 lost stays just under the 10% threshold
+        assert_eq!(acknowledge_with_loss(&mut server, 12, start, 2 * timeout), None);
+        assert!(server.packet_loss() > 0.05);
+        assert!(server.packet_loss() <= Config::default().packet_loss_threshold());
+        assert_eq!(server.state(), ConnectionState::Connected);
+    }
 
-        // array of (sequence, fragment) to go with packet sequence
+    #[test]
+    fn send_message_over_loopback() {
+        let now = Instant::now();
+        let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut client = Connection::new(
+            0,
+            1,
+            server_socket.local_addr().unwrap(),
+            Role::Client,
+            Config::default(),
+            now,
+        );
+        client.state = ConnectionState::Connected;
+        let connecting = Connection::new(
+            2,
+            3,
+            server_socket.local_addr().unwrap(),
+            Role::Client,
+            Config::default(),
+            now,
+        );
 
-        // send from unreliable channels, then from reliable channels
-        // unreliable must send whole message
+        let mut connections = endpoint(Config::default(), HashMap::from([(0, client), (2, connecting)]));
 
-        use std::sync::mpsc::sync_channel;
-        let (sender, receiver) = sync_channel::<SendMessage>(1024);
-        sender.clone();
-        
-        match sender.try_send(t) {
-            Ok(_) => {
+        assert_eq!(connections.send_message(9, 5, b"hello"), Err(ErrorKind::ConnectionNotFound.into()));
+        assert_eq!(connections.send_message(2, 5, b"hello"), Err(ErrorKind::ConnectionNotConnected.into()));
+        assert_eq!(connections.send_message(0, 5, b""), Err(ErrorKind::SendMessageZeroLength.into()));
+        assert_eq!(connections.send_message(0, 99, b"hello"), Err(ErrorKind::ChannelNotDeclared.into()));
+        connections.send_message(0, 5, b"hello").unwrap();
 
-            },
-            Err(e) => {
+        // the channel was opened with the guarantees it was declared with
+        let client = connections.conn.get_mut(&0).unwrap();
+        let mut channel = client.channels.remove(&5).unwrap();
+        assert_eq!(channel.send_guarantee, Send::Reliable);
+        assert_eq!(channel.recv_guarantee, Receive::Ordered);
 
-            },
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        let mut packet = BytesMut::new(&mut data);
+        Header::Short {
+            packet_number: 0,
+            packet_type: PacketType::Data,
+            dst_id: client.dst_id(),
         }
+        .write(&mut packet)
+        .unwrap();
+        let mut conn = ConnectionRef {
+            connection: client,
+            channel: &mut channel,
+            pool: &mut connections.pool,
+        };
+        assert_eq!(conn.write_reliable(&mut packet, now).unwrap(), 1);
+        let len = packet.position();
+        client_socket
+            .send_to(&data[..len], server_socket.local_addr().unwrap())
+            .unwrap();
 
-        // for channel in unreliable channels with pending messages
-        // basically send all of them, packed as much as possible
+        let mut server = Connection::new(
+            1,
+            0,
+            client_socket.local_addr().unwrap(),
+            Role::Server,
+            Config::default(),
+            now,
+        );
+        let mut channel = Channel::new(5, Send::Reliable, Receive::Ordered);
+        let mut pool = BufferPool::new(MAX_PAYLOAD_BYTES, 8);
+        let handle = pool.acquire().unwrap();
+        let buf = unsafe { MaybeUninit::slice_assume_init_mut(pool.get_mut(handle).unwrap()) };
+        let (len, _) = server_socket.recv_from(buf).unwrap();
 
-        // for channel in reliable channels with lost and pending messages
-        // basically send all of them, packed as much as possible
+        let mut packet = BytesMut::new(&mut buf[..len]);
+        assert!(matches!(Header::read(&mut packet).unwrap(), Header::Short { dst_id: 1, .. }));
+        let (channel_sequence, fragment_index, fragment_count, len) = match Frame::read(&mut packet).unwrap() {
+            Frame::Data {
+                channel_id: 5,
+                channel_sequence,
+                fragment_index,
+                fragment_count,
+                len,
+            } => (channel_sequence, fragment_index, fragment_count, len as usize),
+            frame => panic!("unexpected frame: {:?}", frame),
+        };
+        let start = packet.position();
 
-        // If this exceeds upload bandwidth, can look into weighted queueing algorithms
-        // (e.g. deficit round-robin) and static priorities (e.g. unreliable > reliable).
+        let mut conn = ConnectionRef {
+            connection: &mut server,
+            channel: &mut channel,
+            pool: &mut pool,
+        };
+        conn.store_incoming_data(channel_sequence, fragment_index, fragment_count, handle, start, start + len, now)
+            .unwrap();
 
-        match self.send_guarantee {
-            Send::Unreliable => {
-                let sequence = 0;
-                let message = self.channel.send_buffer.get_mut(sequence).as_mut().unwrap();
-                // send fragments
-                // write header
+        let mut buf = [0u8; 16];
+        assert_eq!(conn.recv(&mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"hello");
+    }
 
-                // send and release
-            },
-            Send::Reliable => {
-                todo!();
-                // start from the oldest message whose delivery hasn't been confirmed
-                // iterate its fragments and write pending ones to a new payload
-                // TODO: check if entire message can be sent
-                // set time_sent once all fragments have been sent once
-                // write header
+    #[test]
+    fn mtu_probes_raise_fragment_size() {
+        let now = Instant::now();
+        let config = Config::builder()
+            .min_mtu(1200)
+            .mtu_probe_interval(Duration::from_secs(1))
+            .build()
+            .unwrap();
+        let server = connected_server_with(config.clone(), now);
+        let mut connections = endpoint(config, HashMap::from([(1, server)]));
+        connections.set_clock(ManualClock::new(now));
+        let peer_addr = connections.conn[&1].peer_addr;
+        assert_eq!(connections.conn[&1].mtu(), 1200);
+        let ack = |ack_sequence: u64| {
+            let mut data = [0u8; 64];
+            let mut buf = BytesMut::new(&mut data);
+            Header::Short {
+                packet_number: ack_sequence,
+                packet_type: PacketType::Data,
+                dst_id: 1,
+            }
+            .write(&mut buf)
+            .unwrap();
+            Frame::Ack { ack_sequence, ack_mask: 1 }.write(&mut buf).unwrap();
+            let len = buf.position();
+            data[..len].to_vec()
+        };
 
-                // send
-            },
-        }
+        // the first probe waits for the connection to settle
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        assert_eq!(connections.poll_transmit(&mut data, now).unwrap(), None);
+        let later = now + Duration::from_secs(1);
+        let (_, len) = connections.poll_transmit(&mut data, later).unwrap().unwrap();
+        assert_eq!(len + IPV6_HEADER_BYTES + UDP_HEADER_BYTES, 1200 + MTU_PROBE_STEP_BYTES);
+        assert!(matches!(read_frames(&mut data[..len])[..], [Frame::Ping, Frame::Padding { .. }]));
 
-        self.connection.time_latest_send = Some(instant);
-        self.channel.time_latest_send = Some(instant);
-        message.time_latest_send = Some(instant);
+        // one probe at a time
+        assert_eq!(connections.poll_transmit(&mut data, later).unwrap(), None);
 
-        Ok(())
+        connections.handle_datagram(peer_addr, &ack(0), later).unwrap();
+        assert_eq!(connections.conn[&1].mtu(), 1200 + MTU_PROBE_STEP_BYTES);
+
+        // messages are split into larger fragments
+        let fragment_bytes = connections.conn[&1].max_fragment_bytes();
+        connections.send_message(1, 0, &vec![0u8; fragment_bytes + 1]).unwrap();
+        let message = connections.conn[&1].channels[&0].send_buffer.get(0).unwrap().as_ref().unwrap();
+        assert_eq!(message.fragment_count, 2);
+        assert_eq!(message.fragment_data[0].unwrap().2, fragment_bytes);
+        assert_eq!(message.fragment_data[1].unwrap().2, 1);
+
+        // a probe the acknowledgement of a later packet skips over was lost
+        let later = later + Duration::from_secs(1);
+        let (_, len) = connections.poll_transmit(&mut data, later).unwrap().unwrap();
+        assert_eq!(len + IPV6_HEADER_BYTES + UDP_HEADER_BYTES, 1200 + 2 * MTU_PROBE_STEP_BYTES);
+        assert!(connections.poll_transmit(&mut data, later).unwrap().is_some());
+        connections.handle_datagram(peer_addr, &ack(2), later).unwrap();
+        assert_eq!(connections.conn[&1].mtu_probe, None);
+        assert_eq!(connections.conn[&1].mtu(), 1200 + MTU_PROBE_STEP_BYTES);
+    }
+
+    #[test]
+    fn ping_is_acknowledged_on_its_own() {
+        let now = Instant::now();
+        let mut connections = guarded_connections(8);
+        let peer_addr = connections.conn[&1].peer_addr;
+
+        let mut data = [0u8; MAX_PACKET_BYTES];
+        let mut buf = BytesMut::new(&mut data);
+        Header::Short {
+            packet_number: 0,
+            packet_type: PacketType::Data,
+            dst_id: 1,
+        }
+        .write(&mut buf)
+        .unwrap();
+        Frame::Ping.write(&mut buf).unwrap();
+        let len = buf.position();
+        connections.handle_datagram(peer_addr, &data[..len].to_vec(), now).unwrap();
+
+        let (_, len) = connections.poll_transmit(&mut data, now).unwrap().unwrap();
+        assert_eq!(read_frames(&mut data[..len]), [Frame::Ack { ack_sequence: 0, ack_mask: 1 }]);
+        assert_eq!(connections.poll_transmit(&mut data, now).unwrap(), None);
     }
-}
\ No newline at end of file
+}
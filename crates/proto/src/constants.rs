@@ -1,17 +1,43 @@
+use std::time::Duration;
+
 pub const STANDARD_HEADER_BYTES: usize = 5;
-pub const FRAGMENT_FRAME_BYTES: usize = 4;
-pub const ACK_FRAME_BYTES: usize = 8;
+// fragment index, fragment count, len
+pub const FRAGMENT_FRAME_BYTES: usize = 2 + 2 + 2;
+// type, ack sequence, ack mask
+pub const ACK_FRAME_BYTES: usize = 1 + 8 + 8;
 pub const ARRANGING_HEADER_BYTES: usize = 3;
 pub const IPV6_HEADER_BYTES: usize = 40;
 pub const UDP_HEADER_BYTES: usize = 8;
+pub const MIN_PACKET_BYTES: usize = 576;
 pub const MAX_PACKET_BYTES: usize = 1280; // min. 1280, max. 1500
 pub const MAX_PAYLOAD_BYTES: usize = MAX_PACKET_BYTES - IPV6_HEADER_BYTES - UDP_HEADER_BYTES; // min. 1232, max. 1452
-pub const MAX_FRAGMENTS: usize = 256;
+pub const MAX_FRAGMENTS: usize = u16::MAX as usize;
 pub const MAX_FRAGMENT_BYTES: usize = MAX_PAYLOAD_BYTES - FRAGMENT_FRAME_BYTES;
 pub const MAX_MESSAGE_BYTES: usize = MAX_FRAGMENTS * MAX_FRAGMENT_BYTES;
 pub const DEFAULT_RTT_MS: usize = 100;
+pub const MIN_RTO: Duration = Duration::from_millis(200);
+pub const MAX_RTO: Duration = Duration::from_secs(60);
 pub const DEFAULT_CHANNEL_ID: usize = 0;
 pub const PROTOCOL_VERSION: &str = "parrot-0.0.1";
+pub const PROTOCOL_VERSION_CHECKSUM: u32 = crate::packet::frames::version_checksum(PROTOCOL_VERSION);
 
+// the widest acknowledgement mask that fits in an ack frame
 pub(crate) const REDUNDANT_ACK_MASK_BITS: usize = 64;
 pub(crate) const DEFAULT_SEND_WINDOW_SIZE: usize = 256;
+// the fragments a message can be split into unless configured otherwise
+pub(crate) const DEFAULT_MAX_FRAGMENTS: usize = 256;
+pub(crate) const DRR_QUANTUM_BYTES: usize = MAX_FRAGMENT_BYTES;
+// type, channel id, channel sequence, fragment index, fragment count, len
+pub(crate) const DATA_FRAME_BYTES: usize = 1 + 8 + 8 + 2 + 2 + 2;
+pub(crate) const MTU_PROBE_STEP_BYTES: usize = 40;
+pub(crate) const MTU_PROBE_MAX_LOSSES: usize = 3;
+pub(crate) const PACKET_LOSS_WINDOW: usize = 64;
+pub(crate) const BANDWIDTH_WINDOW: usize = 16;
+// the number of full-size packets that can be sent back-to-back
+pub(crate) const PACING_BURST_PACKETS: usize = 2;
+// the packets per round trip assumed before the bandwidth has been estimated
+pub(crate) const INITIAL_PACING_WINDOW: usize = 10;
+// the full-size packets the congestion window starts at, and never drops below
+pub(crate) const INITIAL_CONGESTION_WINDOW: usize = 10;
+pub(crate) const MIN_CONGESTION_WINDOW: usize = 2;
+pub(crate) const MAX_HANDSHAKE_SOURCES: usize = 4096;
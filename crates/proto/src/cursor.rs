@@ -1,8 +1,15 @@
-use std::{io::{self, ErrorKind, SeekFrom}, mem, fmt::Result};
+use std::{io::{self, ErrorKind, SeekFrom}, mem};
 
-use num_traits::PrimInt;
+use num_traits::{FromBytes, PrimInt, ToBytes};
 
-use super::encoding::{ZigZagEncode, ZigZagDecode};
+use super::encoding::{
+    check_quantized, dequantize, dequantize_angle, quantize, quantize_angle, ZigZagDecode, ZigZagEncode,
+};
+
+/// A primitive integer that the byte cursors can read and write, in either byte order.
+pub trait FixedInt: PrimInt + ToBytes + FromBytes<Bytes = <Self as ToBytes>::Bytes> {}
+
+impl<T: PrimInt + ToBytes + FromBytes<Bytes = <T as ToBytes>::Bytes>> FixedInt for T {}
 
 /// A cursor on an immutable slice of bits.
 ///
@@ -128,6 +135,65 @@ impl<'a> Bits<'a> {
         let value = encoded.zig_zag_decode();
         Ok(value)
     }
+
+    /// Reads a value written by [`BitsMut::write_quantized`] with the same `bits`, `min`, and
+    /// `max`.
+    pub fn read_quantized(&mut self, bits: usize, min: f32, max: f32) -> Result<f32, String> {
+        check_quantized(bits, min, max)?;
+        Ok(dequantize(self.read(bits)?, bits, min, max))
+    }
+
+    /// Reads a vector written by [`BitsMut::write_quantized_vec3`] with the same `bits`, `min`,
+    /// and `max`.
+    pub fn read_quantized_vec3(&mut self, bits: usize, min: f32, max: f32) -> Result<[f32; 3], String> {
+        check_quantized(bits, min, max)?;
+        if 3 * bits > self.remaining() {
+            return Err(format!("not enough bits left"));
+        }
+
+        let mut value = [0.0; 3];
+        for component in value.iter_mut() {
+            *component = dequantize(self.read(bits)?, bits, min, max);
+        }
+        Ok(value)
+    }
+
+    /// Reads an angle written by [`BitsMut::write_angle`] with the same `bits`. The angle is in
+    /// `[0, 2π)`.
+    pub fn read_angle(&mut self, bits: usize) -> Result<f32, String> {
+        check_quantized(bits, 0.0, 1.0)?;
+        Ok(dequantize_angle(self.read(bits)?, bits))
+    }
+
+    /// Moves the cursor forward to the next byte boundary, unless it's already on one.
+    #[inline]
+    pub fn align_to_byte(&mut self) {
+        self.pos = (self.pos + 7) & !7;
+    }
+
+    /// The number of whole bytes before the cursor.
+    #[inline]
+    pub fn byte_position(&self) -> usize {
+        self.pos / 8
+    }
+
+    /// Reads `buf.len()` bytes into `buf`.
+    ///
+    /// The cursor must be on a byte boundary (see [`align_to_byte`](Self::align_to_byte)).
+    pub fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        if self.pos % 8 != 0 {
+            return Err(format!("cursor is not byte-aligned"));
+        }
+        if buf.len() * 8 > self.remaining() {
+            return Err(format!("not enough bits left"));
+        }
+
+        for byte in buf.iter_mut() {
+            *byte = unsafe { self.peek_unchecked(8) } as u8;
+            self.pos += 8;
+        }
+        Ok(())
+    }
 }
 
 impl<'a> BitsMut<'a> {
@@ -267,6 +333,108 @@ impl<'a> BitsMut<'a> {
         self.write(encoded, len)?;
         Ok(())
     }
+
+    /// Writes `value` in `bits` bits, as the nearest of `2^bits` evenly spaced steps from `min`
+    /// to `max`. Values outside of `[min, max]` are clamped (NaN becomes `min`).
+    ///
+    /// Reading it back gives a value within `(max - min) / (2^bits - 1) / 2` of the clamped one.
+    /// `bits` can be at most 32.
+    pub fn write_quantized(&mut self, value: f32, bits: usize, min: f32, max: f32) -> Result<(), String> {
+        check_quantized(bits, min, max)?;
+        self.write(quantize(value, bits, min, max), bits)
+    }
+
+    /// Writes each component of `value` with [`write_quantized`](Self::write_quantized). Nothing
+    /// is written unless there's room for all three.
+    pub fn write_quantized_vec3(&mut self, value: [f32; 3], bits: usize, min: f32, max: f32) -> Result<(), String> {
+        check_quantized(bits, min, max)?;
+        if 3 * bits > self.remaining() {
+            return Err(format!("not enough bits left"));
+        }
+
+        for component in value {
+            self.write(quantize(component, bits, min, max), bits)?;
+        }
+        Ok(())
+    }
+
+    /// Writes an angle (in radians) in `bits` bits, as the nearest of `2^bits` evenly spaced
+    /// steps around the circle.
+    ///
+    /// Reading it back gives an angle within `π / 2^bits` of this one (going around the circle).
+    /// `bits` can be at most 32.
+    pub fn write_angle(&mut self, radians: f32, bits: usize) -> Result<(), String> {
+        check_quantized(bits, 0.0, 1.0)?;
+        self.write(quantize_angle(radians, bits), bits)
+    }
+
+    /// Moves the cursor forward to the next byte boundary, unless it's already on one.
+    #[inline]
+    pub fn align_to_byte(&mut self) {
+        self.pos = (self.pos + 7) & !7;
+    }
+
+    /// The number of whole bytes before the cursor.
+    #[inline]
+    pub fn byte_position(&self) -> usize {
+        self.pos / 8
+    }
+
+    /// Reads `buf.len()` bytes into `buf`.
+    ///
+    /// The cursor must be on a byte boundary (see [`align_to_byte`](Self::align_to_byte)).
+    pub fn read_bytes(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        if self.pos % 8 != 0 {
+            return Err(format!("cursor is not byte-aligned"));
+        }
+        if buf.len() * 8 > self.remaining() {
+            return Err(format!("not enough bits left"));
+        }
+
+        for byte in buf.iter_mut() {
+            *byte = unsafe { self.peek_unchecked(8) } as u8;
+            self.pos += 8;
+        }
+        Ok(())
+    }
+
+    /// Copies the next `len` bits of `src` here, advancing both cursors.
+    ///
+    /// Neither cursor has to be aligned to anything. Bits are moved a word at a time.
+    pub fn copy_bits_from(&mut self, src: &mut Bits, len: usize) -> Result<(), String> {
+        if (len > src.remaining()) || (len > self.remaining()) {
+            return Err(format!("not enough bits left"));
+        }
+
+        let mut copied = 0;
+        while copied < len {
+            let chunk = (len - copied).min(u64::BITS as usize);
+            unsafe {
+                let value = src.peek_unchecked(chunk);
+                src.pos += chunk;
+                self.write_unchecked(value, chunk);
+            }
+            copied += chunk;
+        }
+        Ok(())
+    }
+
+    /// Writes the bytes in `src`.
+    ///
+    /// The cursor must be on a byte boundary (see [`align_to_byte`](Self::align_to_byte)).
+    pub fn write_bytes(&mut self, src: &[u8]) -> Result<(), String> {
+        if self.pos % 8 != 0 {
+            return Err(format!("cursor is not byte-aligned"));
+        }
+        if src.len() * 8 > self.remaining() {
+            return Err(format!("not enough bits left"));
+        }
+
+        for byte in src.iter() {
+            unsafe { self.write_unchecked(*byte as u64, 8) };
+        }
+        Ok(())
+    }
 }
 
 /// A cursor on an immutable slice of bytes.
@@ -354,9 +522,16 @@ impl<'a> Bytes<'a> {
     ///
     /// The first will contain all bytes from `[0, mid)` (excluding the index `mid` itself) and
     /// the second will contain all bytes from `[mid, len)` (excluding the index `len` itself).
-    pub fn split_at(&self, mid: usize) -> io::Result<(Bytes, Bytes)> {
+    /// Both halves start with their cursor at position 0.
+    ///
+    /// Returns an error if `mid > len`.
+    pub fn split_at(&self, mid: usize) -> io::Result<(Bytes<'a>, Bytes<'a>)> {
+        if mid > self.len() {
+            return Err(io::Error::new_const(ErrorKind::InvalidInput, &"split index out of bounds"));
+        }
+
         let (left, right) = self.inner.split_at(mid);
-        Ok(Bytes { inner: left, pos: 0 }, Bytes { inner: right, pos: 0 })
+        Ok((Bytes { inner: left, pos: 0 }, Bytes { inner: right, pos: 0 }))
     }
 
     /// Copies the contents of the referenced slice into a new [`Vec`].
@@ -425,18 +600,32 @@ impl<'a> BytesMut<'a> {
     ///
     /// The first will contain all bytes from `[0, mid)` (excluding the index `mid` itself) and
     /// the second will contain all bytes from `[mid, len)` (excluding the index `len` itself).
+    /// Both halves start with their cursor at position 0.
+    ///
+    /// Returns an error if `mid > len`.
     pub fn split_at(&self, mid: usize) -> io::Result<(Bytes, Bytes)> {
+        if mid > self.len() {
+            return Err(io::Error::new_const(ErrorKind::InvalidInput, &"split index out of bounds"));
+        }
+
         let (left, right) = self.inner.split_at(mid);
-        Ok(Bytes { inner: left, pos: 0 }, Bytes { inner: right, pos: 0 })
+        Ok((Bytes { inner: left, pos: 0 }, Bytes { inner: right, pos: 0 }))
     }
 
     /// Divides one `BytesMut` into two `BytesMut` at an index.
     ///
     /// The first will contain all bytes from `[0, mid)` (excluding the index `mid` itself) and
     /// the second will contain all bytes from `[mid, len)` (excluding the index `len` itself).
+    /// Both halves start with their cursor at position 0.
+    ///
+    /// Returns an error if `mid > len`.
     pub fn split_at_mut(&mut self, mid: usize) -> io::Result<(BytesMut, BytesMut)> {
+        if mid > self.len() {
+            return Err(io::Error::new_const(ErrorKind::InvalidInput, &"split index out of bounds"));
+        }
+
         let (left, right) = self.inner.split_at_mut(mid);
-        Ok(BytesMut { inner: left, pos: 0 }, BytesMut { inner: right, pos: 0 })
+        Ok((BytesMut { inner: left, pos: 0 }, BytesMut { inner: right, pos: 0 }))
     }
 
     /// Copies the contents of the referenced slice into a new [`Vec`].
@@ -445,40 +634,42 @@ impl<'a> BytesMut<'a> {
     }
 }
 
+// The cursor can be seeked past the end of the slice, in which case these are empty.
+
 impl<'a> AsRef<[u8]> for Bytes<'a> {
     fn as_ref(&self) -> &[u8] {
-        &self.buf[self.pos..]
+        self.inner.get(self.pos..).unwrap_or(&[])
     }
 }
 
 impl<'a> AsRef<[u8]> for BytesMut<'a> {
     fn as_ref(&self) -> &[u8] {
-        &self.buf[self.pos..]
+        self.inner.get(self.pos..).unwrap_or(&[])
     }
 }
 
 impl<'a> AsMut<[u8]> for BytesMut<'a> {
     fn as_mut(&mut self) -> &mut [u8] {
-        &mut self.buf[self.pos..]
+        self.inner.get_mut(self.pos..).unwrap_or(&mut [])
     }
 }
 
 impl<'a> Bytes<'a> {
     /// Reads a native endian integer from the current cursor position,
     /// without advancing the cursor.
-    pub fn peek<T: PrimInt>(&self) -> io::Result<T> {
+    pub fn peek<T: FixedInt>(&self) -> io::Result<T> {
         let src = self.as_ref();
         if src.len() < mem::size_of::<T>() {
             return Err(io::Error::new_const(ErrorKind::InvalidInput, &"buffer too short"));
         }
-        let mut dst = [0; mem::size_of::<T>()];
-        dst.copy_from_slice(&src);
-        Ok(T::from_be_bytes(dst))
+        let mut dst = T::zero().to_be_bytes();
+        dst.as_mut().copy_from_slice(&src[..mem::size_of::<T>()]);
+        Ok(T::from_be_bytes(&dst))
     }
 
     /// Reads a native endian integer from the current cursor position,
     /// advancing the cursor by [`mem::size_of::<T>()`] bytes.
-    pub fn read<T: PrimInt>(&mut self) -> io::Result<T> {
+    pub fn read<T: FixedInt>(&mut self) -> io::Result<T> {
         let val = self.peek::<T>();
         self.pos += mem::size_of::<T>();   
         val
@@ -488,19 +679,19 @@ impl<'a> Bytes<'a> {
 impl<'a> BytesMut<'a> {
     /// Reads a native endian integer from the current cursor position,
     /// without advancing the cursor.
-    pub fn peek<T: PrimInt>(&self) -> io::Result<T> {
+    pub fn peek<T: FixedInt>(&self) -> io::Result<T> {
         let src = self.as_ref();
         if src.len() < mem::size_of::<T>() {
             return Err(io::Error::new_const(ErrorKind::InvalidInput, &"buffer too short"));
         }
-        let mut dst = [0; mem::size_of::<T>()];
-        dst.copy_from_slice(&src);
-        Ok(T::from_be_bytes(dst))
+        let mut dst = T::zero().to_be_bytes();
+        dst.as_mut().copy_from_slice(&src[..mem::size_of::<T>()]);
+        Ok(T::from_be_bytes(&dst))
     }
 
     /// Reads a native endian integer from the current cursor position,
     /// advancing the cursor by [`mem::size_of::<T>()`] bytes.
-    pub fn read<T: PrimInt>(&mut self) -> io::Result<T> {
+    pub fn read<T: FixedInt>(&mut self) -> io::Result<T> {
         let val = self.peek::<T>()?;
         self.pos += mem::size_of::<T>();   
         Ok(val)
@@ -508,12 +699,12 @@ impl<'a> BytesMut<'a> {
 
     /// Writes a native endian integer at the current cursor position,
     /// advancing the cursor by [`mem::size_of::<T>()`] bytes.
-    pub fn write<T: PrimInt>(&mut self, val: T) -> io::Result<()> {
+    pub fn write<T: FixedInt>(&mut self, val: T) -> io::Result<()> {
         let dst = self.as_mut();
         if dst.len() <  mem::size_of::<T>() {
             return Err(io::Error::new_const(ErrorKind::InvalidInput, &"buffer too short"));        
         }
-        dst[..mem::size_of::<T>()].copy_from_slice(&T::to_be_bytes(val));
+        dst[..mem::size_of::<T>()].copy_from_slice(val.to_be_bytes().as_ref());
         self.pos += mem::size_of::<T>();
         Ok(())    
     }
@@ -524,7 +715,7 @@ impl<'a> BytesMut<'a> {
         if count > self.remaining() {
             return Err(io::Error::new_const(ErrorKind::InvalidInput, &"buffer too short"));
         }
-        let dst = self.as_mut()[..count];
+        let dst = &mut self.as_mut()[..count];
         dst.fill(val);
         self.pos += count;
         Ok(())
@@ -536,7 +727,7 @@ impl<'a> BytesMut<'a> {
         if src.len() > self.remaining() {
             return Err(io::Error::new_const(ErrorKind::InvalidInput, &"buffer too short"));
         }
-        let dst = self.as_mut()[..src.len()];
+        let dst = &mut self.as_mut()[..src.len()];
         dst.copy_from_slice(src);
         self.pos += src.len();
         Ok(())
@@ -544,56 +735,195 @@ impl<'a> BytesMut<'a> {
 }
 
 impl<'a> Bytes<'a> {
-    pub fn peek_varint(&self) -> io::Result<u64> {
-        let cursor = Bytes::new(&self.as_ref());
-        let desc = cursor.read_u8()?;        
-        
-        if desc == 0 {
-            return Err(io::Error::new_const(
-            ErrorKind::InvalidData,
-            &"variable-size integer has invalid encoded length"))
-        }
-    
-        let len = (desc.trailing_zeros() + 1) as usize;
-        
-        if cursor.remaining() < len {
-            return Err(io::Error::new_const(
-                ErrorKind::InvalidData,
-                &"variable-size integer len greater than remaining len"))
+    /// Reads a little-endian integer from the current cursor position,
+    /// without advancing the cursor.
+    pub fn peek_le<T: FixedInt>(&self) -> io::Result<T> {
+        let src = self.as_ref();
+        if src.len() < mem::size_of::<T>() {
+            return Err(io::Error::new_const(ErrorKind::InvalidInput, &"buffer too short"));
         }
-    
-        let val = cursor.read_uint(len)?;
+        let mut dst = T::zero().to_le_bytes();
+        dst.as_mut().copy_from_slice(&src[..mem::size_of::<T>()]);
+        Ok(T::from_le_bytes(&dst))
+    }
+
+    /// Reads a little-endian integer from the current cursor position,
+    /// advancing the cursor by [`mem::size_of::<T>()`] bytes.
+    pub fn read_le<T: FixedInt>(&mut self) -> io::Result<T> {
+        let val = self.peek_le::<T>()?;
+        self.pos += mem::size_of::<T>();
         Ok(val)
     }
 
-    pub fn read_varint(&mut self) -> io::Result<u64> {
-        let cursor = Bytes::new(&self.as_ref());
-        let desc = cursor.read_u8()?;        
-        
-        if desc == 0 {
-            return Err(io::Error::new_const(
-            ErrorKind::InvalidData,
-            &"variable-size integer has invalid encoded length"))
+    /// Reads a `len`-byte unsigned integer (big-endian) from the current cursor position,
+    /// without advancing the cursor.
+    ///
+    /// Returns an error if `len` is not in `1..=8`.
+    pub fn peek_uint(&self, len: usize) -> io::Result<u64> {
+        if len == 0 || len > mem::size_of::<u64>() {
+            return Err(io::Error::new_const(ErrorKind::InvalidInput, &"invalid integer width"));
         }
-    
-        let len = (desc.trailing_zeros() + 1) as usize;
-        
-        if cursor.remaining() < len {
-            return Err(io::Error::new_const(
-                ErrorKind::InvalidData,
-                &"variable-size integer len greater than remaining len"))
+        let src = self.as_ref();
+        if src.len() < len {
+            return Err(io::Error::new_const(ErrorKind::InvalidInput, &"buffer too short"));
         }
-    
-        let val = cursor.read_uint(len)?;
+        let mut dst = [0; mem::size_of::<u64>()];
+        dst[(mem::size_of::<u64>() - len)..].copy_from_slice(&src[..len]);
+        Ok(u64::from_be_bytes(dst))
+    }
+
+    /// Reads a `len`-byte unsigned integer (big-endian) from the current cursor position,
+    /// advancing the cursor by `len` bytes.
+    ///
+    /// Returns an error if `len` is not in `1..=8`.
+    pub fn read_uint(&mut self, len: usize) -> io::Result<u64> {
+        let val = self.peek_uint(len)?;
         self.pos += len;
         Ok(val)
     }
+
+    /// Reads a `len`-byte signed integer (big-endian, two's complement) from the current
+    /// cursor position, advancing the cursor by `len` bytes.
+    ///
+    /// Returns an error if `len` is not in `1..=8`.
+    pub fn read_int(&mut self, len: usize) -> io::Result<i64> {
+        let val = self.read_uint(len)?;
+        Ok(sign_extend(val, len))
+    }
+
+    /// Reads the byte at the current cursor position, without advancing the cursor.
+    pub fn peek_u8(&self) -> io::Result<u8> {
+        self.as_ref()
+            .first()
+            .copied()
+            .ok_or_else(|| io::Error::new_const(ErrorKind::InvalidInput, &"buffer too short"))
+    }
+
+    /// Reads the byte at the current cursor position, advancing the cursor by one byte.
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        let val = self.peek_u8()?;
+        self.pos += 1;
+        Ok(val)
+    }
 }
 
 impl<'a> BytesMut<'a> {
+    /// Reads a little-endian integer from the current cursor position,
+    /// without advancing the cursor.
+    pub fn peek_le<T: FixedInt>(&self) -> io::Result<T> {
+        Bytes::new(self.as_ref()).peek_le::<T>()
+    }
+
+    /// Reads a little-endian integer from the current cursor position,
+    /// advancing the cursor by [`mem::size_of::<T>()`] bytes.
+    pub fn read_le<T: FixedInt>(&mut self) -> io::Result<T> {
+        let val = self.peek_le::<T>()?;
+        self.pos += mem::size_of::<T>();
+        Ok(val)
+    }
+
+    /// Writes a little-endian integer at the current cursor position,
+    /// advancing the cursor by [`mem::size_of::<T>()`] bytes.
+    pub fn write_le<T: FixedInt>(&mut self, val: T) -> io::Result<()> {
+        let dst = self.as_mut();
+        if dst.len() < mem::size_of::<T>() {
+            return Err(io::Error::new_const(ErrorKind::InvalidInput, &"buffer too short"));
+        }
+        dst[..mem::size_of::<T>()].copy_from_slice(val.to_le_bytes().as_ref());
+        self.pos += mem::size_of::<T>();
+        Ok(())
+    }
+
+    /// Reads a `len`-byte unsigned integer (big-endian) from the current cursor position,
+    /// without advancing the cursor.
+    ///
+    /// Returns an error if `len` is not in `1..=8`.
+    pub fn peek_uint(&self, len: usize) -> io::Result<u64> {
+        Bytes::new(self.as_ref()).peek_uint(len)
+    }
+
+    /// Reads a `len`-byte unsigned integer (big-endian) from the current cursor position,
+    /// advancing the cursor by `len` bytes.
+    ///
+    /// Returns an error if `len` is not in `1..=8`.
+    pub fn read_uint(&mut self, len: usize) -> io::Result<u64> {
+        let val = self.peek_uint(len)?;
+        self.pos += len;
+        Ok(val)
+    }
+
+    /// Reads a `len`-byte signed integer (big-endian, two's complement) from the current
+    /// cursor position, advancing the cursor by `len` bytes.
+    ///
+    /// Returns an error if `len` is not in `1..=8`.
+    pub fn read_int(&mut self, len: usize) -> io::Result<i64> {
+        let val = self.read_uint(len)?;
+        Ok(sign_extend(val, len))
+    }
+
+    /// Reads the byte at the current cursor position, without advancing the cursor.
+    pub fn peek_u8(&self) -> io::Result<u8> {
+        Bytes::new(self.as_ref()).peek_u8()
+    }
+
+    /// Reads the byte at the current cursor position, advancing the cursor by one byte.
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        let val = self.peek_u8()?;
+        self.pos += 1;
+        Ok(val)
+    }
+
+    /// Writes `val` as a `len`-byte unsigned integer (big-endian) at the current cursor
+    /// position, advancing the cursor by `len` bytes.
+    ///
+    /// Returns an error if `len` is not in `1..=8` or `val` doesn't fit in `len` bytes.
+    pub fn write_uint(&mut self, val: u64, len: usize) -> io::Result<()> {
+        if len == 0 || len > mem::size_of::<u64>() {
+            return Err(io::Error::new_const(ErrorKind::InvalidInput, &"invalid integer width"));
+        }
+        if len < mem::size_of::<u64>() && val >> (8 * len) != 0 {
+            return Err(io::Error::new_const(ErrorKind::InvalidInput, &"integer too large for width"));
+        }
+        let dst = self.as_mut();
+        if dst.len() < len {
+            return Err(io::Error::new_const(ErrorKind::InvalidInput, &"buffer too short"));
+        }
+        dst[..len].copy_from_slice(&val.to_be_bytes()[(mem::size_of::<u64>() - len)..]);
+        self.pos += len;
+        Ok(())
+    }
+
+    /// Writes `val` as a `len`-byte signed integer (big-endian, two's complement) at the
+    /// current cursor position, advancing the cursor by `len` bytes.
+    ///
+    /// Returns an error if `len` is not in `1..=8` or `val` doesn't fit in `len` bytes.
+    pub fn write_int(&mut self, val: i64, len: usize) -> io::Result<()> {
+        if len == 0 || len > mem::size_of::<u64>() {
+            return Err(io::Error::new_const(ErrorKind::InvalidInput, &"invalid integer width"));
+        }
+        if sign_extend(val as u64, len) != val {
+            return Err(io::Error::new_const(ErrorKind::InvalidInput, &"integer too large for width"));
+        }
+        let mask = u64::MAX >> (8 * (mem::size_of::<u64>() - len));
+        self.write_uint(val as u64 & mask, len)
+    }
+}
+
+/// Interprets the low `len` bytes of `val` as a two's complement integer.
+fn sign_extend(val: u64, len: usize) -> i64 {
+    let shift = 8 * (mem::size_of::<u64>() - len);
+    ((val << shift) as i64) >> shift
+}
+
+impl<'a> Bytes<'a> {
+    /// Reads a variable-size integer from the current cursor position, without advancing the
+    /// cursor.
+    ///
+    /// The integer is a descriptor byte followed by `n` bytes (big-endian), where `n - 1` is the
+    /// number of trailing zeros of the descriptor.
     pub fn peek_varint(&self) -> io::Result<u64> {
-        let cursor = Bytes::new(&self.as_ref());
-        let desc = cursor.read::<u8>()?;        
+        let mut cursor = Bytes::new(self.as_ref());
+        let desc = cursor.read_u8()?;        
         
         if desc == 0 {
             return Err(io::Error::new_const(
@@ -609,30 +939,36 @@ impl<'a> BytesMut<'a> {
                 &"variable-size integer len greater than remaining len"))
         }
     
-        let val = u64::from_be_bytes(cursor.as_ref()[..len]);
+        let val = cursor.read_uint(len)?;
         Ok(val)
     }
 
+    /// Reads a variable-size integer (see [`peek_varint`](Self::peek_varint)) from the current
+    /// cursor position, advancing the cursor past it.
     pub fn read_varint(&mut self) -> io::Result<u64> {
-        let cursor = Bytes::new(&self.as_ref());
-        let desc = cursor.read::<u8>()?;        
-        
-        if desc == 0 {
-            return Err(io::Error::new_const(
-            ErrorKind::InvalidData,
-            &"variable-size integer has invalid encoded length"))
-        }
-    
-        let len = (desc.trailing_zeros() + 1) as usize;
-        
-        if cursor.remaining() < len {
-            return Err(io::Error::new_const(
-                ErrorKind::InvalidData,
-                &"variable-size integer len greater than remaining len"))
-        }
-    
-        let val = u64::from_be_bytes(cursor.as_ref()[..len]);
-        self.pos += len;
+        let val = self.peek_varint()?;
+        // the descriptor says how many bytes follow it
+        let len = (self.peek_u8()?.trailing_zeros() + 1) as usize;
+        self.pos += 1 + len;
+        Ok(val)
+    }
+}
+
+impl<'a> BytesMut<'a> {
+    /// Reads a variable-size integer from the current cursor position, without advancing the
+    /// cursor.
+    ///
+    /// See [`Bytes::peek_varint`] for the encoding.
+    pub fn peek_varint(&self) -> io::Result<u64> {
+        Bytes::new(self.as_ref()).peek_varint()
+    }
+
+    /// Reads a variable-size integer from the current cursor position, advancing the cursor
+    /// past it.
+    pub fn read_varint(&mut self) -> io::Result<u64> {
+        let val = self.peek_varint()?;
+        let len = (self.peek_u8()?.trailing_zeros() + 1) as usize;
+        self.pos += 1 + len;
         Ok(val)
     }
 
@@ -640,3 +976,377 @@ impl<'a> BytesMut<'a> {
         todo!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bits_interleave_fields_and_bytes() {
+        let mut data = [0u64; 2];
+        let mut bits = BitsMut::new(&mut data);
+        bits.write(0b101, 3).unwrap();
+        assert_eq!(bits.byte_position(), 0);
+        // blobs have to start on a byte boundary
+        assert!(bits.write_bytes(b"abc").is_err());
+        bits.align_to_byte();
+        assert_eq!(bits.position(), 8);
+        bits.write_bytes(b"hello world").unwrap();
+        assert_eq!(bits.byte_position(), 12);
+        bits.write(0b11, 2).unwrap();
+
+        // aligning an aligned cursor does nothing
+        bits.align_to_byte();
+        assert_eq!(bits.position(), 104);
+        bits.align_to_byte();
+        assert_eq!(bits.position(), 104);
+        assert!(bits.write_bytes(&[0; 4]).is_err());
+
+        let mut bits = Bits::new(&data);
+        assert_eq!(bits.read(3), Ok(0b101));
+        let mut buf = [0u8; 11];
+        assert!(bits.read_bytes(&mut buf).is_err());
+        bits.align_to_byte();
+        bits.read_bytes(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello world");
+        assert_eq!(bits.read(2), Ok(0b11));
+    }
+
+    #[test]
+    fn quantized_values_round_trip_within_half_a_step() {
+        let (min, max) = (-100.0f32, 250.0f32);
+        for bits in [4, 8, 12, 16, 24] {
+            let values = (0..=64).map(|i| min + (max - min) * i as f32 / 64.0 + 0.37).collect::<Vec<_>>();
+            let mut data = [0u64; 32];
+            let mut bits_mut = BitsMut::new(&mut data);
+            for &value in values.iter() {
+                bits_mut.write_quantized(value, bits, min, max).unwrap();
+            }
+            assert_eq!(bits_mut.position(), values.len() * bits);
+
+            // half a step, plus what an f32 loses around these magnitudes
+            let bound = (max - min) as f64 / ((1u64 << bits) - 1) as f64 / 2.0 + 1e-4;
+            let mut reader = Bits::new(&data);
+            for &value in values.iter() {
+                let read = reader.read_quantized(bits, min, max).unwrap();
+                assert!((read as f64 - value.clamp(min, max) as f64).abs() <= bound);
+            }
+        }
+
+        // out of range values are clamped
+        let mut data = [0u64; 1];
+        let mut bits_mut = BitsMut::new(&mut data);
+        bits_mut.write_quantized(-1000.0, 8, min, max).unwrap();
+        bits_mut.write_quantized(1000.0, 8, min, max).unwrap();
+        assert!(bits_mut.write_quantized(0.0, 0, min, max).is_err());
+        assert!(bits_mut.write_quantized(0.0, 8, max, min).is_err());
+        let mut reader = Bits::new(&data);
+        assert_eq!(reader.read_quantized(8, min, max), Ok(min));
+        assert_eq!(reader.read_quantized(8, min, max), Ok(max));
+    }
+
+    #[test]
+    fn quantized_vectors_and_angles_round_trip() {
+        use std::f32::consts::{PI, TAU};
+
+        let mut data = [0u64; 2];
+        let mut bits = BitsMut::new(&mut data);
+        bits.write_quantized_vec3([1.5, -20.25, 63.0], 16, -64.0, 64.0).unwrap();
+        bits.write_angle(-PI / 2.0, 10).unwrap();
+        bits.write_angle(TAU + 0.5, 10).unwrap();
+        // all or nothing
+        let position = bits.position();
+        assert!(bits.write_quantized_vec3([0.0; 3], 30, -1.0, 1.0).is_err());
+        assert_eq!(bits.position(), position);
+
+        let mut reader = Bits::new(&data);
+        let vector = reader.read_quantized_vec3(16, -64.0, 64.0).unwrap();
+        for (read, value) in vector.into_iter().zip([1.5, -20.25, 63.0]) {
+            assert!((read - value).abs() <= 128.0 / 65535.0 / 2.0 + 1e-5);
+        }
+
+        let bound = PI / 1024.0 + 1e-6;
+        let angle = reader.read_angle(10).unwrap();
+        assert!((angle - 3.0 * PI / 2.0).abs() <= bound);
+        let angle = reader.read_angle(10).unwrap();
+        assert!((angle - 0.5).abs() <= bound);
+    }
+
+    #[test]
+    fn copy_bits_across_words() {
+        let mut seed = 0x9e37_79b9_7f4a_7c15u64;
+        let mut source = [0u64; 5];
+        for word in source.iter_mut() {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            *word = seed;
+        }
+
+        // (source offset, destination offset, len)
+        for (src_start, dst_start, len) in [(0, 0, 320), (13, 37, 200), (63, 1, 129), (5, 60, 64), (40, 8, 1)] {
+            let mut data = [!0u64; 6];
+            let mut dst = BitsMut::new(&mut data);
+            dst.seek(SeekFrom::Start(dst_start as u64)).unwrap();
+            let mut src = Bits::new(&source);
+            src.seek(SeekFrom::Start(src_start as u64)).unwrap();
+
+            dst.copy_bits_from(&mut src, len).unwrap();
+            assert_eq!(src.position(), src_start + len);
+            assert_eq!(dst.position(), dst_start + len);
+
+            let mut src = Bits::new(&source);
+            src.seek(SeekFrom::Start(src_start as u64)).unwrap();
+            let mut dst = Bits::new(&data);
+            // the bits around the run are untouched
+            for _ in 0..dst_start {
+                assert_eq!(dst.read(1), Ok(1));
+            }
+            for _ in 0..len {
+                assert_eq!(dst.read(1), src.read(1));
+            }
+            assert_eq!(dst.read(1), Ok(1));
+        }
+    }
+
+    #[test]
+    fn copy_bits_bounds() {
+        let source = [0u64; 1];
+        let mut data = [0u64; 2];
+        let mut dst = BitsMut::new(&mut data);
+        let mut src = Bits::new(&source);
+        src.seek(SeekFrom::Start(10)).unwrap();
+
+        // past the end of the source
+        assert!(dst.copy_bits_from(&mut src, 55).is_err());
+        assert_eq!((src.position(), dst.position()), (10, 0));
+
+        // past the end of the destination
+        dst.seek(SeekFrom::Start(100)).unwrap();
+        assert!(dst.copy_bits_from(&mut src, 40).is_err());
+        assert_eq!((src.position(), dst.position()), (10, 100));
+
+        dst.copy_bits_from(&mut src, 28).unwrap();
+        assert_eq!((src.position(), dst.position()), (38, 128));
+    }
+
+    #[test]
+    fn read_write_both_endiannesses() {
+        let mut data = [0u8; 16];
+        let mut bytes = BytesMut::new(&mut data);
+        bytes.write::<u32>(0x0102_0304).unwrap();
+        bytes.write_le::<u32>(0x0102_0304).unwrap();
+        bytes.write_le::<i64>(-2).unwrap();
+        assert_eq!(bytes.write_le::<u8>(0).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(&data[..8], &[1, 2, 3, 4, 4, 3, 2, 1]);
+
+        let mut bytes = Bytes::new(&data);
+        assert_eq!(bytes.peek_le::<u32>().unwrap(), 0x0403_0201);
+        assert_eq!(bytes.read::<u32>().unwrap(), 0x0102_0304);
+        assert_eq!(bytes.read_le::<u32>().unwrap(), 0x0102_0304);
+        assert_eq!(bytes.read_le::<i64>().unwrap(), -2);
+        assert_eq!(bytes.read_le::<u8>().unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        // every width round trips, in both byte orders
+        let mut data = [0u8; 64];
+        let mut bytes = BytesMut::new(&mut data);
+        bytes.write::<i16>(-300).unwrap();
+        bytes.write_le::<i16>(-300).unwrap();
+        bytes.write::<u128>(u128::MAX - 1).unwrap();
+        bytes.write_le::<u128>(u128::MAX - 1).unwrap();
+        bytes.write_le::<u8>(0xab).unwrap();
+        assert_eq!(bytes.position(), 37);
+        assert_eq!(&data[..4], &[0xfe, 0xd4, 0xd4, 0xfe]);
+        assert_eq!((data[19], data[20]), (0xfe, 0xfe));
+
+        let mut bytes = BytesMut::new(&mut data);
+        assert_eq!(bytes.peek::<i16>().unwrap(), -300);
+        assert_eq!(bytes.read::<i16>().unwrap(), -300);
+        assert_eq!(bytes.peek_le::<i16>().unwrap(), -300);
+        assert_eq!(bytes.read_le::<i16>().unwrap(), -300);
+        assert_eq!(bytes.read::<u128>().unwrap(), u128::MAX - 1);
+        assert_eq!(bytes.read_le::<u128>().unwrap(), u128::MAX - 1);
+        assert_eq!(bytes.read::<u8>().unwrap(), 0xab);
+    }
+
+    #[test]
+    fn write_bytes_and_copy_from_slice() {
+        let mut data = [0xffu8; 12];
+        let mut bytes = BytesMut::new(&mut data);
+        bytes.write_bytes(0, 4).unwrap();
+        assert_eq!(bytes.position(), 4);
+        bytes.copy_from_slice(b"hello").unwrap();
+        assert_eq!(bytes.position(), 9);
+        bytes.write_bytes(7, 0).unwrap();
+        bytes.copy_from_slice(&[]).unwrap();
+        assert_eq!(bytes.position(), 9);
+
+        // neither writes past the end, and a failed write changes nothing
+        assert_eq!(bytes.write_bytes(0, 4).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(bytes.copy_from_slice(b"abcd").unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(bytes.position(), 9);
+        bytes.write_bytes(1, 3).unwrap();
+        assert_eq!(bytes.remaining(), 0);
+
+        assert_eq!(data, [0, 0, 0, 0, b'h', b'e', b'l', b'l', b'o', 1, 1, 1]);
+        let mut bytes = Bytes::new(&data);
+        bytes.seek(SeekFrom::Start(4)).unwrap();
+        assert_eq!(&bytes.as_ref()[..5], b"hello");
+    }
+
+    #[test]
+    fn read_write_partial_widths() {
+        for len in 1..=8 {
+            let max = u64::MAX >> (8 * (8 - len));
+            let min = -1i64 << (8 * len - 1);
+            let mut data = [0u8; 32];
+            let mut bytes = BytesMut::new(&mut data);
+            bytes.write_uint(max, len).unwrap();
+            bytes.write_uint(1, len).unwrap();
+            bytes.write_int(min, len).unwrap();
+            bytes.write_int(-1, len).unwrap();
+            assert_eq!(bytes.position(), 4 * len);
+
+            let mut bytes = Bytes::new(&data);
+            assert_eq!(bytes.read_uint(len).unwrap(), max);
+            assert_eq!(bytes.read_uint(len).unwrap(), 1);
+            assert_eq!(bytes.read_int(len).unwrap(), min);
+            assert_eq!(bytes.read_int(len).unwrap(), -1);
+
+            if len < 8 {
+                let mut bytes = BytesMut::new(&mut data);
+                assert_eq!(bytes.write_uint(max + 1, len).unwrap_err().kind(), ErrorKind::InvalidInput);
+                assert_eq!(bytes.write_int(min - 1, len).unwrap_err().kind(), ErrorKind::InvalidInput);
+                assert_eq!(bytes.position(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn read_uint_every_width() {
+        let data = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0xff];
+        for len in 1..=8 {
+            let expected = data[..len].iter().fold(0u64, |val, &byte| (val << 8) | byte as u64);
+            let mut bytes = Bytes::new(&data);
+            assert_eq!(bytes.peek_uint(len).unwrap(), expected);
+            assert_eq!(bytes.position(), 0);
+            assert_eq!(bytes.read_uint(len).unwrap(), expected);
+            assert_eq!(bytes.position(), len);
+            assert_eq!(bytes.read_u8().unwrap(), data[len]);
+            assert_eq!(bytes.position(), len + 1);
+        }
+
+        let mut data = data;
+        let mut bytes = BytesMut::new(&mut data[7..]);
+        assert_eq!(bytes.peek_u8().unwrap(), 0x08);
+        assert_eq!(bytes.read_u8().unwrap(), 0x08);
+        assert_eq!(bytes.read_u8().unwrap(), 0xff);
+        assert_eq!(bytes.peek_u8().unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(bytes.read_u8().unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(bytes.position(), 2);
+    }
+
+    #[test]
+    fn read_varint_uses_length_prefix() {
+        // a one-byte integer, then a three-byte one
+        let data = [0b0000_0001, 0x2a, 0b0000_0100, 0x01, 0x02, 0x03, 0b1000_0000];
+        let mut bytes = Bytes::new(&data);
+        assert_eq!(bytes.peek_varint().unwrap(), 0x2a);
+        assert_eq!(bytes.read_varint().unwrap(), 0x2a);
+        assert_eq!(bytes.position(), 2);
+        assert_eq!(bytes.read_varint().unwrap(), 0x01_0203);
+        assert_eq!(bytes.position(), 6);
+
+        // eight bytes are announced but none follow
+        assert_eq!(bytes.read_varint().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(bytes.position(), 6);
+
+        let mut data = [0u8, 0x2a];
+        let mut bytes = BytesMut::new(&mut data);
+        assert_eq!(bytes.read_varint().unwrap_err().kind(), ErrorKind::InvalidData);
+        data[0] = 1;
+        let mut bytes = BytesMut::new(&mut data);
+        assert_eq!(bytes.read_varint().unwrap(), 0x2a);
+        assert_eq!(bytes.remaining(), 0);
+    }
+
+    #[test]
+    fn partial_width_bounds() {
+        let mut data = [0u8; 4];
+        let mut bytes = BytesMut::new(&mut data);
+        for len in [0, 9] {
+            assert_eq!(bytes.write_uint(0, len).unwrap_err().kind(), ErrorKind::InvalidInput);
+            assert_eq!(bytes.read_uint(len).unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+
+        // not enough bytes left
+        assert_eq!(bytes.write_uint(0, 5).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(bytes.read_int(5).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(bytes.position(), 0);
+    }
+
+    #[test]
+    fn split_at() {
+        let data = [0u8, 1, 2, 3, 4, 5, 6, 7];
+        let mut bytes = Bytes::new(&data);
+        bytes.seek(SeekFrom::Start(3)).unwrap();
+
+        let (left, right) = bytes.split_at(3).unwrap();
+        assert_eq!(left.position(), 0);
+        assert_eq!(right.position(), 0);
+        assert_eq!(left.to_vec(), [0, 1, 2]);
+        assert_eq!(right.to_vec(), [3, 4, 5, 6, 7]);
+
+        let (left, right) = bytes.split_at(0).unwrap();
+        assert_eq!(left.len(), 0);
+        assert_eq!(right.len(), data.len());
+
+        let (left, right) = bytes.split_at(data.len()).unwrap();
+        assert_eq!(left.len(), data.len());
+        assert_eq!(right.len(), 0);
+
+        let err = bytes.split_at(data.len() + 1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn split_at_mut() {
+        let mut data = [0u8, 1, 2, 3, 4, 5, 6, 7];
+        let len = data.len();
+        let mut bytes = BytesMut::new(&mut data);
+
+        let (left, right) = bytes.split_at(0).unwrap();
+        assert_eq!(left.len(), 0);
+        assert_eq!(right.len(), len);
+
+        let (left, right) = bytes.split_at_mut(len).unwrap();
+        assert_eq!(left.len(), len);
+        assert_eq!(right.len(), 0);
+
+        assert_eq!(bytes.split_at(len + 1).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(bytes.split_at_mut(len + 1).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        let (mut left, right) = bytes.split_at_mut(2).unwrap();
+        left.as_mut().copy_from_slice(&[9, 9]);
+        assert_eq!(right.to_vec(), [2, 3, 4, 5, 6, 7]);
+        assert_eq!(bytes.to_vec(), [9, 9, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn as_ref_past_end_is_empty() {
+        let data = [0u8, 1, 2, 3];
+        let mut bytes = Bytes::new(&data);
+        bytes.seek(SeekFrom::Start(2)).unwrap();
+        assert_eq!(bytes.as_ref(), [2, 3]);
+
+        bytes.seek(SeekFrom::Start(10)).unwrap();
+        assert!(bytes.as_ref().is_empty());
+        assert_eq!(bytes.remaining(), 0);
+
+        let mut data = [0u8, 1, 2, 3];
+        let mut bytes = BytesMut::new(&mut data);
+        bytes.seek(SeekFrom::End(1)).unwrap();
+        assert!(bytes.as_ref().is_empty());
+        assert!(bytes.as_mut().is_empty());
+    }
+}
@@ -107,3 +107,53 @@ impl RadixEncode<u64> for f64 {
         todo!()
     }
 }
+
+/// The widest quantized value (in bits). An `f32` can't tell more steps apart.
+pub(crate) const MAX_QUANTIZED_BITS: usize = 32;
+
+/// Checks that values can be quantized into `bits` bits between `min` and `max`.
+pub(crate) fn check_quantized(bits: usize, min: f32, max: f32) -> Result<(), String> {
+    if bits == 0 || bits > MAX_QUANTIZED_BITS {
+        return Err(format!("quantized values take 1 to {} bits", MAX_QUANTIZED_BITS));
+    }
+    if !(min.is_finite() && max.is_finite() && min < max) {
+        return Err(format!("quantization bounds must be finite and increasing"));
+    }
+    Ok(())
+}
+
+/// Maps `value` (clamped to `[min, max]`) to the nearest of `2^bits` evenly spaced steps from
+/// `min` to `max`. NaN maps to `min`.
+///
+/// [`dequantize`] gets back a value within half a step, `(max - min) / (2^bits - 1) / 2`, of the
+/// clamped one.
+pub(crate) fn quantize(value: f32, bits: usize, min: f32, max: f32) -> u64 {
+    let steps = ((1u64 << bits) - 1) as f64;
+    let t = ((value as f64 - min as f64) / (max as f64 - min as f64)).clamp(0.0, 1.0);
+    // NaN saturates to 0
+    (t * steps).round() as u64
+}
+
+/// Returns the value of step `quantized` out of `2^bits` between `min` and `max`.
+pub(crate) fn dequantize(quantized: u64, bits: usize, min: f32, max: f32) -> f32 {
+    let steps = ((1u64 << bits) - 1) as f64;
+    (min as f64 + (quantized as f64 / steps) * (max as f64 - min as f64)) as f32
+}
+
+/// Maps an angle (in radians, any turn) to the nearest of `2^bits` evenly spaced steps around
+/// the circle.
+///
+/// [`dequantize_angle`] gets back an angle in `[0, 2π)` within half a step, `π / 2^bits`, of the
+/// original (going around the circle).
+pub(crate) fn quantize_angle(radians: f32, bits: usize) -> u64 {
+    let steps = (1u64 << bits) as f64;
+    let turns = (radians as f64 / std::f64::consts::TAU).rem_euclid(1.0);
+    // a full turn is the same as none
+    ((turns * steps).round() as u64) & ((1u64 << bits) - 1)
+}
+
+/// Returns the angle (in radians, in `[0, 2π)`) of step `quantized` out of `2^bits`.
+pub(crate) fn dequantize_angle(quantized: u64, bits: usize) -> f32 {
+    let steps = (1u64 << bits) as f64;
+    (quantized as f64 / steps * std::f64::consts::TAU) as f32
+}
@@ -1,4 +1,6 @@
+use std::{net::SocketAddr, time::Instant};
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ConnectionState {
     Created,
     Connecting(usize, Instant),
@@ -7,10 +9,16 @@ pub enum ConnectionState {
     /// actual data can be sent. If the connection is closed, the local peer
     /// will mark any unacknowledged as lost. 
     Connected,
-    Disconnecting,
-    Disconnected,
+    /// The connection was closed for the given reason. The remote endpoint is told why, and
+    /// messages that haven't been acknowledged are lost.
+    Disconnecting(DisconnectReason),
+    /// The connection is closed and lingers until the given time, so that packets still on
+    /// their way are absorbed instead of being mistaken for a new connection's. Its id is freed
+    /// once that time has passed.
+    Disconnected(Instant),
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum DisconnectReason {
     ConnectTokenExpired,
     ConnectTokenInvalid,
@@ -30,12 +38,159 @@ pub enum DisconnectReason {
     PeerRecvBufferIsFull,
     ExcessivePacketLoss,
     Unknown,
+    /// The server already has as many connections as it allows.
+    ServerFull,
 }
 
-#[derive(Copy, Clone, Debug)]
+impl DisconnectReason {
+    /// Returns the byte that represents this reason on the wire.
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            DisconnectReason::ConnectTokenExpired => 0x00,
+            DisconnectReason::ConnectTokenInvalid => 0x01,
+            DisconnectReason::ConnectionRequestTimeout => 0x02,
+            DisconnectReason::ConnectionAuthenticationTimeout => 0x03,
+            DisconnectReason::EncryptionInvalid => 0x04,
+            DisconnectReason::ProtocolVersionInvalid => 0x05,
+            DisconnectReason::ConnectionDenied => 0x06,
+            DisconnectReason::ConnectionAttemptsExhausted => 0x07,
+            DisconnectReason::ConnectionIdleTimeout => 0x08,
+            DisconnectReason::PeerConnectionIdleTimeout => 0x09,
+            DisconnectReason::Closed => 0x0a,
+            DisconnectReason::PeerClosed => 0x0b,
+            DisconnectReason::SendBufferIsFull => 0x0c,
+            DisconnectReason::RecvBufferIsFull => 0x0d,
+            DisconnectReason::PeerSendBufferIsFull => 0x0e,
+            DisconnectReason::PeerRecvBufferIsFull => 0x0f,
+            DisconnectReason::ExcessivePacketLoss => 0x10,
+            DisconnectReason::Unknown => 0x11,
+            DisconnectReason::ServerFull => 0x12,
+        }
+    }
+
+    /// Returns the reason represented by `byte`, or `None` if it's unknown.
+    pub(crate) fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            0x00 => Some(DisconnectReason::ConnectTokenExpired),
+            0x01 => Some(DisconnectReason::ConnectTokenInvalid),
+            0x02 => Some(DisconnectReason::ConnectionRequestTimeout),
+            0x03 => Some(DisconnectReason::ConnectionAuthenticationTimeout),
+            0x04 => Some(DisconnectReason::EncryptionInvalid),
+            0x05 => Some(DisconnectReason::ProtocolVersionInvalid),
+            0x06 => Some(DisconnectReason::ConnectionDenied),
+            0x07 => Some(DisconnectReason::ConnectionAttemptsExhausted),
+            0x08 => Some(DisconnectReason::ConnectionIdleTimeout),
+            0x09 => Some(DisconnectReason::PeerConnectionIdleTimeout),
+            0x0a => Some(DisconnectReason::Closed),
+            0x0b => Some(DisconnectReason::PeerClosed),
+            0x0c => Some(DisconnectReason::SendBufferIsFull),
+            0x0d => Some(DisconnectReason::RecvBufferIsFull),
+            0x0e => Some(DisconnectReason::PeerSendBufferIsFull),
+            0x0f => Some(DisconnectReason::PeerRecvBufferIsFull),
+            0x10 => Some(DisconnectReason::ExcessivePacketLoss),
+            0x11 => Some(DisconnectReason::Unknown),
+            0x12 => Some(DisconnectReason::ServerFull),
+            _ => None,
+        }
+    }
+
+    /// Returns the reason the remote endpoint should see when the local endpoint disconnects for
+    /// this reason.
+    pub(crate) fn to_peer(self) -> Self {
+        match self {
+            DisconnectReason::ConnectionIdleTimeout => DisconnectReason::PeerConnectionIdleTimeout,
+            DisconnectReason::Closed => DisconnectReason::PeerClosed,
+            DisconnectReason::SendBufferIsFull => DisconnectReason::PeerSendBufferIsFull,
+            DisconnectReason::RecvBufferIsFull => DisconnectReason::PeerRecvBufferIsFull,
+            reason => reason,
+        }
+    }
+}
+
+/// Which side of the handshake the local endpoint is on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Sends the connection request.
+    Client,
+    /// Accepts or denies connection requests.
+    Server,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Request {
     Connect,
     Disconnect,
     Accept,
-    Deny,
-}
\ No newline at end of file
+    /// Refuses a connection request, and says why.
+    Deny(DisconnectReason),
+}
+
+impl Request {
+    /// Returns the byte that represents this request on the wire.
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            Request::Connect => 0x00,
+            Request::Disconnect => 0x01,
+            Request::Accept => 0x02,
+            Request::Deny(_) => 0x03,
+        }
+    }
+
+    /// Returns the request represented by `byte`, or `None` if it's unknown.
+    ///
+    /// A [`Deny`](Request::Deny) isn't returned, since its reason comes in the next byte.
+    pub(crate) fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            0x00 => Some(Request::Connect),
+            0x01 => Some(Request::Disconnect),
+            0x02 => Some(Request::Accept),
+            _ => None,
+        }
+    }
+}
+
+/// Something that happened on a connection, returned by
+/// [`Connections::poll`](crate::connection::Connections::poll).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// The handshake completed and data can be sent.
+    Connected(u64),
+    /// The connection was closed for the given reason.
+    Disconnected(u64, DisconnectReason),
+    /// A message is ready to be received.
+    MessageReceived {
+        connection: u64,
+        channel: u64,
+        len: usize,
+    },
+    /// The remote endpoint confirmed it received a message.
+    DeliveryConfirmed {
+        connection: u64,
+        channel: u64,
+        sequence: u64,
+    },
+    /// A message was probably lost. It won't be resent.
+    ///
+    /// Only unreliable messages are lost while connected. When a connection closes, every
+    /// message that hasn't been acknowledged yet is lost.
+    DeliveryLost {
+        connection: u64,
+        channel: u64,
+        sequence: u64,
+    },
+    /// An ordered channel gave up on a message that was holding up later ones (see
+    /// [`ChannelConfig::skip_gaps`](crate::config::ChannelConfig::skip_gaps)). It won't be
+    /// received.
+    MessageSkipped {
+        connection: u64,
+        channel: u64,
+        sequence: u64,
+    },
+    /// An authenticated packet arrived from a new address, so the remote endpoint is now
+    /// reached there (e.g. a client that switched networks).
+    Migrated {
+        connection: u64,
+        from: SocketAddr,
+        to: SocketAddr,
+    },
+}
@@ -0,0 +1,67 @@
+use std::{error::Error, fmt, io};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    FragmentIndexInvalid,
+    FragmentIndexAlreadyReceived,
+    FragmentCountInvalid,
+    FragmentCountExceedsMax,
+    MessageOlderThanThreshold,
+    /// The message was already received in full (and delivered).
+    MessageAlreadyReceived,
+    NotEnoughBuffersAvailable,
+    SendMessageZeroLength,
+    /// The message is larger than the configured
+    /// [`max_payload_bytes`](crate::config::Config::max_payload_bytes).
+    SendMessageTooLarge,
+    /// The buffer passed to [`recv`](crate::connection::ConnectionRef::recv) cannot hold the
+    /// message. Contains the number of bytes needed.
+    RecvBufferTooSmall(usize),
+    /// The remote endpoint opened a channel that already exists with different guarantees.
+    ChannelGuaranteesMismatch,
+    /// The channel wasn't declared in the [`ChannelConfig`](crate::config::ChannelConfig).
+    ChannelNotDeclared,
+    /// The channel must be [`Reliable`](crate::connection::Send::Reliable) and
+    /// [`Ordered`](crate::connection::Receive::Ordered), e.g. to carry a
+    /// [`SendStream`](crate::stream::SendStream).
+    ChannelNotReliableOrdered,
+    /// The send window of a reliable channel is full. Nothing more can be sent on it until the
+    /// oldest message is acknowledged.
+    SendWindowFull,
+    /// There is no connection with the given id.
+    ConnectionNotFound,
+    /// The connection isn't [`Connected`](crate::enums::ConnectionState::Connected).
+    ConnectionNotConnected,
+}
+
+/// An error returned by a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtoError {
+    /// The caller or the remote endpoint broke a rule of the protocol.
+    Protocol(ErrorKind),
+    /// Reading or writing a buffer failed.
+    Io(io::ErrorKind),
+}
+
+impl From<ErrorKind> for ProtoError {
+    fn from(kind: ErrorKind) -> Self {
+        ProtoError::Protocol(kind)
+    }
+}
+
+impl From<io::Error> for ProtoError {
+    fn from(err: io::Error) -> Self {
+        ProtoError::Io(err.kind())
+    }
+}
+
+impl fmt::Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtoError::Protocol(kind) => write!(f, "protocol error: {:?}", kind),
+            ProtoError::Io(kind) => write!(f, "io error: {}", kind),
+        }
+    }
+}
+
+impl Error for ProtoError {}
@@ -0,0 +1,100 @@
+type ConnectionId = u64;
+
+/// Hands out connection ids made of a slot index (the low 32 bits) and the slot's generation
+/// (the high 32 bits).
+///
+/// Freeing an id increments the generation of its slot, so when the slot is reused, the new id
+/// is different from the old one. A peer still using the old id can be told apart from the
+/// connection that now holds the slot.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionIds {
+    /// The current generation of each slot.
+    generations: Vec<u32>,
+    /// The slots that aren't in use.
+    free: Vec<u32>,
+}
+
+impl ConnectionIds {
+    /// Constructs a new `ConnectionIds` without any slots.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns an unused id, reusing a freed slot if there is one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if all [`u32::MAX`] slots are in use.
+    pub(crate) fn allocate(&mut self) -> ConnectionId {
+        let index = self.free.pop().unwrap_or_else(|| {
+            let index = u32::try_from(self.generations.len()).expect("out of connection ids");
+            self.generations.push(0);
+            index
+        });
+        compose(index, self.generations[index as usize])
+    }
+
+    /// Frees `id` so that its slot can be reused. Returns `false` if `id` isn't in use.
+    pub(crate) fn free(&mut self, id: ConnectionId) -> bool {
+        if !self.is_current(id) {
+            return false;
+        }
+
+        let generation = &mut self.generations[index(id) as usize];
+        *generation = generation.wrapping_add(1);
+        self.free.push(index(id));
+        true
+    }
+
+    /// Returns `true` if `id` is from the current generation of its slot.
+    pub(crate) fn is_current(&self, id: ConnectionId) -> bool {
+        self.generations.get(index(id) as usize) == Some(&generation(id))
+    }
+
+    /// Returns `true` if `id` was handed out for its slot, but has been freed since.
+    pub(crate) fn is_stale(&self, id: ConnectionId) -> bool {
+        self.generations
+            .get(index(id) as usize)
+            .map_or(false, |&current| current != generation(id))
+    }
+}
+
+fn compose(index: u32, generation: u32) -> ConnectionId {
+    ((generation as u64) << 32) | index as u64
+}
+
+fn index(id: ConnectionId) -> u32 {
+    id as u32
+}
+
+fn generation(id: ConnectionId) -> u32 {
+    (id >> 32) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_slot_is_reused_with_new_generation() {
+        let mut ids = ConnectionIds::new();
+        let first = ids.allocate();
+        let second = ids.allocate();
+        assert_ne!(first, second);
+
+        assert!(ids.free(first));
+        assert!(!ids.free(first));
+        let reused = ids.allocate();
+        assert_eq!(index(reused), index(first));
+        assert_ne!(reused, first);
+
+        assert!(ids.is_current(reused));
+        assert!(ids.is_current(second));
+        assert!(!ids.is_current(first));
+        assert!(ids.is_stale(first));
+
+        // ids that were never handed out aren't stale, just unknown
+        assert!(!ids.is_stale(compose(7, 0)));
+        assert!(!ids.is_current(compose(7, 0)));
+    }
+}
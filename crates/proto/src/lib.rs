@@ -1,9 +1,16 @@
 #![feature(new_uninit)]
 #![feature(maybe_uninit_slice, maybe_uninit_write_slice)]
+pub(crate) mod accept;
+pub(crate) mod clock;
 pub(crate) mod config;
 pub(crate) mod connection;
 pub(crate) mod constants;
 pub(crate) mod enums;
+pub(crate) mod error;
+pub(crate) mod id;
 pub(crate) mod packet;
+pub(crate) mod pacing;
+pub(crate) mod rate_limit;
+pub(crate) mod stream;
 pub(crate) mod cursor;
 pub(crate) mod encoding;
\ No newline at end of file
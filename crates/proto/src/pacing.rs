@@ -0,0 +1,90 @@
+use std::time::{Duration, Instant};
+
+/// Spreads the packets of a connection out over time, so that they leave at the pacing rate
+/// instead of all at once.
+///
+/// Sending a packet costs credit (in bytes), which builds up at the pacing rate, up to a small
+/// burst. A packet can be sent once there's enough credit for a full-size one. Whatever is left
+/// after sending carries over to the next packet.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Pacer {
+    credit: f64,
+    time_latest_refill: Instant,
+}
+
+impl Pacer {
+    /// Constructs a new `Pacer` holding `burst` bytes of credit.
+    pub(crate) fn new(burst: usize, now: Instant) -> Self {
+        Self {
+            credit: burst as f64,
+            time_latest_refill: now,
+        }
+    }
+
+    /// Adds the credit earned at `rate` bytes per second since the last refill, up to `burst`.
+    fn refill(&mut self, rate: f64, burst: usize, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.time_latest_refill);
+        self.credit = (self.credit + elapsed.as_secs_f64() * rate).min(burst as f64);
+        self.time_latest_refill = now;
+    }
+
+    /// Returns `true` if there's enough credit to send a packet of `max_packet_bytes`.
+    pub(crate) fn can_send(&mut self, rate: f64, burst: usize, max_packet_bytes: usize, now: Instant) -> bool {
+        self.refill(rate, burst, now);
+        self.credit >= max_packet_bytes as f64
+    }
+
+    /// Spends the credit for a packet of `bytes` that was just sent.
+    pub(crate) fn on_sent(&mut self, bytes: usize) {
+        self.credit -= bytes as f64;
+    }
+
+    /// Returns how long it takes to earn the credit for a packet of `max_packet_bytes` at
+    /// `rate` bytes per second.
+    pub(crate) fn interval(rate: f64, max_packet_bytes: usize) -> Duration {
+        Duration::from_secs_f64(max_packet_bytes as f64 / rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pacer_spaces_packets_after_burst() {
+        let start = Instant::now();
+        let mut pacer = Pacer::new(2000, start);
+
+        // the burst goes out right away
+        assert!(pacer.can_send(10_000.0, 2000, 1000, start));
+        pacer.on_sent(1000);
+        assert!(pacer.can_send(10_000.0, 2000, 1000, start));
+        pacer.on_sent(1000);
+        assert!(!pacer.can_send(10_000.0, 2000, 1000, start));
+
+        // then one packet every 100ms
+        assert_eq!(Pacer::interval(10_000.0, 1000), Duration::from_millis(100));
+        assert!(!pacer.can_send(10_000.0, 2000, 1000, start + Duration::from_millis(99)));
+        assert!(pacer.can_send(10_000.0, 2000, 1000, start + Duration::from_millis(100)));
+        pacer.on_sent(1000);
+        assert!(!pacer.can_send(10_000.0, 2000, 1000, start + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn pacer_carries_leftover_credit() {
+        let start = Instant::now();
+        let mut pacer = Pacer::new(1000, start);
+
+        // a small packet leaves most of the credit for the next one
+        assert!(pacer.can_send(10_000.0, 1000, 1000, start));
+        pacer.on_sent(400);
+        assert!(!pacer.can_send(10_000.0, 1000, 1000, start));
+        assert!(pacer.can_send(10_000.0, 1000, 1000, start + Duration::from_millis(50)));
+
+        // idle time doesn't earn more than the burst
+        pacer.on_sent(1000);
+        assert!(pacer.can_send(10_000.0, 1000, 1000, start + Duration::from_secs(10)));
+        pacer.on_sent(1000);
+        assert!(!pacer.can_send(10_000.0, 1000, 1000, start + Duration::from_secs(10)));
+    }
+}
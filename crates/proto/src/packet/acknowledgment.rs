@@ -1,52 +1,202 @@
-type PacketNumber = u64;
+use super::sequence_buffer::{sequence_distance, sequence_greater_than};
 
-pub struct Acknowledgement {
+pub type PacketNumber = u64;
+
+/// Tracks which packets have been sent to and received from the remote endpoint.
+///
+/// This is connection-level state. Every packet gets a number and is acknowledged as a whole,
+/// no matter which channels its frames belong to. Messages are acknowledged separately, per
+/// channel (see [`ChannelAcknowledgment`](crate::connection::ChannelAcknowledgment)).
+pub struct PacketAcknowledgment {
     pub(crate) next_packet_number: PacketNumber,
     pub(crate) last_delivered_packet_number: Option<PacketNumber>,
     pub(crate) last_recv_packet_number: Option<PacketNumber>,
-    pub(crate) last_recv_packet_mask: u32,
+    pub(crate) last_recv_packet_mask: u64,
 }
 
-impl Acknowledgement {
+impl Default for PacketAcknowledgment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PacketAcknowledgment {
     pub fn new() -> Self {
-        Acknowledgement {
+        PacketAcknowledgment {
             next_packet_number: 0, // make random?
-            last_delivered_packet_number: u16::max_value(), // make random?
-            last_recv_packet_number: u16::max_value(), // make random?
+            last_delivered_packet_number: None,
+            last_recv_packet_number: None,
             last_recv_packet_mask: 0,
         }
     }
-    
-    /// The next packet to send to the remote endpoint of this channel.
+
+    /// The next packet to send to the remote endpoint.
     pub fn packet_number(&self) -> PacketNumber {
         self.next_packet_number
     }
-    
-    /// The last packet we received from the remote endpoint on this channel.
-    pub fn ack_packet_number(&self) -> PacketNumber {
+
+    /// The last packet we received from the remote endpoint.
+    pub fn ack_packet_number(&self) -> Option<PacketNumber> {
         self.last_recv_packet_number
     }
-    
-    /// Bitset of the last 32 packets received from the remote endpoint.
-    pub fn ack_packet_mask(&self) -> u32 {
+
+    /// Bitset of the last packets received from the remote endpoint (bit `n` is set if
+    /// `last_recv_packet_number - n` was received). Only the low
+    /// [`ack_mask_bits`](crate::config::Config::ack_mask_bits) bits are used.
+    pub fn ack_packet_mask(&self) -> u64 {
         self.last_recv_packet_mask
     }
 
     /// The number of sent packets newer than the one that was last acknowledged.
     pub fn sent_packets_in_flight(&self) -> usize {
-        self.sent_packets.len()
+        let oldest_in_flight = self
+            .last_delivered_packet_number
+            .map_or(0, |packet_number| packet_number.wrapping_add(1));
+        self.next_packet_number.wrapping_sub(oldest_in_flight) as usize
+    }
+
+    /// Records that the packet `packet_number` was received from the remote endpoint.
+    ///
+    /// Only the 64 newest packets are tracked in the mask.
+    pub(crate) fn record_recv(&mut self, packet_number: PacketNumber) {
+        match self.last_recv_packet_number {
+            None => {
+                self.last_recv_packet_number = Some(packet_number);
+                self.last_recv_packet_mask = 1;
+            },
+            Some(last_recv) if sequence_greater_than(packet_number, last_recv) => {
+                let gap = packet_number.wrapping_sub(last_recv);
+                self.last_recv_packet_number = Some(packet_number);
+                self.last_recv_packet_mask = if gap >= u64::BITS as u64 {
+                    1
+                } else {
+                    (self.last_recv_packet_mask << gap) | 1
+                };
+            },
+            Some(last_recv) => {
+                let gap = last_recv.wrapping_sub(packet_number);
+                if gap < u64::BITS as u64 {
+                    self.last_recv_packet_mask |= 1 << gap;
+                }
+            },
+        }
     }
 
-    pub fn packet_distance(p1: PacketNumber, p2: PacketNumber) -> Result<i16, Error> {
-        // If you want to use non power-of-two bit lengths, you need to left shift
-        // both arguments by WORD_LENGTH - packet_LENGTH bits, then subtract, then
-        // right shift the result back down by the same amount.
-        let distance = p1.wrapping_sub(p2) as i16;
-        if distance == i16::min_value() {
-            // The order of two numbers 2^(N-1) apart is undefined in signed arithmetic.
-            return Err(());
+    /// Records that the remote endpoint acknowledged packets up to `ack_sequence`.
+    ///
+    /// An acknowledgement for a packet that hasn't been sent yet, or that is older than the last
+    /// one, is ignored.
+    pub(crate) fn record_delivered(&mut self, ack_sequence: PacketNumber) {
+        if !sequence_greater_than(self.next_packet_number, ack_sequence) {
+            return;
         }
-    
-        Ok(distance)
+        if let Some(last_delivered) = self.last_delivered_packet_number {
+            if !sequence_greater_than(ack_sequence, last_delivered) {
+                return;
+            }
+        }
+        self.last_delivered_packet_number = Some(ack_sequence);
+    }
+
+    /// Returns how far ahead of `p2` the packet `p1` is (negative if `p1` is behind `p2`),
+    /// accounting for wraparound.
+    ///
+    /// Returns `None` if the packets are exactly half the range apart, since their order is
+    /// undefined.
+    pub fn packet_distance(p1: PacketNumber, p2: PacketNumber) -> Option<i64> {
+        let distance = sequence_distance(p1, p2);
+        if distance == i64::MIN {
+            return None;
+        }
+
+        Some(distance)
     }
-}
\ No newline at end of file
+}
+
+/// Returns the acknowledgement mask with the low `bits` bits set (`bits` is 32 or 64).
+pub(crate) fn ack_mask(bits: usize) -> u64 {
+    u64::MAX >> (u64::BITS as usize - bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_distance() {
+        assert_eq!(PacketAcknowledgment::packet_distance(5, 3), Some(2));
+        assert_eq!(PacketAcknowledgment::packet_distance(3, 5), Some(-2));
+        assert_eq!(PacketAcknowledgment::packet_distance(3, 3), Some(0));
+    }
+
+    #[test]
+    fn packet_distance_across_wraparound() {
+        assert_eq!(PacketAcknowledgment::packet_distance(0, u64::MAX), Some(1));
+        assert_eq!(PacketAcknowledgment::packet_distance(u64::MAX, 0), Some(-1));
+        assert_eq!(PacketAcknowledgment::packet_distance(2, u64::MAX - 1), Some(4));
+
+        // undefined order
+        assert_eq!(PacketAcknowledgment::packet_distance(1 << 63, 0), None);
+        assert_eq!(PacketAcknowledgment::packet_distance(0, 1 << 63), None);
+    }
+
+    #[test]
+    fn ack_mask_keeps_low_bits() {
+        assert_eq!(ack_mask(32), u32::MAX as u64);
+        assert_eq!(ack_mask(64), u64::MAX);
+    }
+
+    #[test]
+    fn sent_packets_in_flight() {
+        let mut acks = PacketAcknowledgment::new();
+        assert_eq!(acks.sent_packets_in_flight(), 0);
+
+        acks.next_packet_number = 4;
+        assert_eq!(acks.sent_packets_in_flight(), 4);
+
+        acks.last_delivered_packet_number = Some(2);
+        assert_eq!(acks.sent_packets_in_flight(), 1);
+
+        // across wraparound
+        acks.next_packet_number = 1;
+        acks.last_delivered_packet_number = Some(u64::MAX - 1);
+        assert_eq!(acks.sent_packets_in_flight(), 2);
+    }
+
+    #[test]
+    fn record_recv_tracks_mask() {
+        let mut acks = PacketAcknowledgment::new();
+        acks.record_recv(3);
+        assert_eq!((acks.ack_packet_number(), acks.ack_packet_mask()), (Some(3), 0b1));
+        acks.record_recv(5);
+        assert_eq!((acks.ack_packet_number(), acks.ack_packet_mask()), (Some(5), 0b101));
+        // late and duplicate packets only fill in the mask
+        acks.record_recv(4);
+        acks.record_recv(5);
+        assert_eq!((acks.ack_packet_number(), acks.ack_packet_mask()), (Some(5), 0b111));
+
+        // a gap wider than the mask forgets the older packets
+        acks.record_recv(5 + 64);
+        assert_eq!((acks.ack_packet_number(), acks.ack_packet_mask()), (Some(5 + 64), 0b1));
+        acks.record_recv(5);
+        assert_eq!(acks.ack_packet_mask(), 0b1);
+    }
+
+    #[test]
+    fn record_delivered_shrinks_packets_in_flight() {
+        let mut acks = PacketAcknowledgment::new();
+        acks.next_packet_number = 4;
+
+        // not sent yet
+        acks.record_delivered(4);
+        assert_eq!(acks.sent_packets_in_flight(), 4);
+
+        acks.record_delivered(2);
+        assert_eq!(acks.sent_packets_in_flight(), 1);
+        // an older acknowledgement arriving late doesn't undo a newer one
+        acks.record_delivered(1);
+        assert_eq!(acks.sent_packets_in_flight(), 1);
+        acks.record_delivered(3);
+        assert_eq!(acks.sent_packets_in_flight(), 0);
+    }
+}
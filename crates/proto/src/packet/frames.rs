@@ -1,19 +1,30 @@
 use std::io::{self, ErrorKind};
 
-use crate::cursor::BytesMut;
+use crate::{
+    connection::{Receive, Send},
+    constants::{ACK_FRAME_BYTES, DATA_FRAME_BYTES},
+    cursor::BytesMut,
+    enums::{DisconnectReason, Request},
+};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum PacketType {
     Handshake,
     Data,
 }
 
-#[derive(Copy, Clone, Debug)]
+/// The header at the front of every packet.
+///
+/// Integers are written in network (big-endian) byte order, and the header ends with a 16-bit
+/// checksum over its fields, so that a corrupted header is dropped instead of being routed to the
+/// wrong connection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Header {
     Long {
         packet_number: u64,
         packet_type: PacketType,
-        // TODO: Add version checksum.
+        /// The [`version_checksum`] of the sender's protocol version.
+        version: u32,
         src_id: u64,
         dst_id: u64,
     },
@@ -25,17 +36,63 @@ pub enum Header {
 }
 
 impl Header {
+    /// The size of a [`Header::Short`] (in bytes): packet number, packet type, destination id,
+    /// and header checksum.
+    #[inline]
+    pub const fn short_header_bytes() -> usize {
+        8 + 1 + 8 + 2
+    }
+
+    /// The size of a [`Header::Long`] (in bytes): packet number, packet type, version checksum,
+    /// source id, destination id, and header checksum.
+    #[inline]
+    pub const fn long_header_bytes() -> usize {
+        8 + 1 + 4 + 8 + 8 + 2
+    }
+
+    /// The number of bytes [`write`](Self::write) writes for this header.
+    #[inline]
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Header::Long { .. } => Header::long_header_bytes(),
+            Header::Short { .. } => Header::short_header_bytes(),
+        }
+    }
+
+    /// Returns the size (in bytes) of the header at the cursor position, going by its packet
+    /// type. The cursor isn't moved.
+    ///
+    /// This is enough to find where the payload starts without reading the whole header.
+    pub fn peek_len(buf: &BytesMut) -> io::Result<usize> {
+        // the packet type comes after the packet number
+        match buf.as_ref().get(8) {
+            Some(0x01) => Ok(Header::long_header_bytes()),
+            Some(0x10) => Ok(Header::short_header_bytes()),
+            Some(_) => Err(io::Error::new_const(ErrorKind::InvalidData, &"unknown packet type")),
+            None => Err(io::Error::new_const(ErrorKind::InvalidInput, &"buffer too short")),
+        }
+    }
+
+    /// Reads a header and checks it against the checksum that follows it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with [`ErrorKind::InvalidData`] if the packet type is unknown or the
+    /// checksum doesn't match.
     pub fn read(buf: &mut BytesMut) -> io::Result<Self> {
         let packet_number = buf.read::<u64>()?;
         let packet_type = buf.read::<u8>()?;
         let header = match packet_type {
             0x01 => {
+                // checked by whoever handles the handshake, so a mismatch can be answered
+                let version = buf.read::<u32>()?;
                 let src_id = buf.read::<u64>()?;
                 let dst_id = buf.read::<u64>()?;
 
                 Header::Long {
                     packet_number,
                     packet_type: PacketType::Handshake,
+                    version,
                     src_id,
                     dst_id,
                 }
@@ -49,63 +106,232 @@ impl Header {
                     dst_id,
                 }
             },
+            _ => {
+                return Err(io::Error::new_const(ErrorKind::InvalidData, &"unknown packet type"));
+            },
         };
+        if buf.read::<u16>()? != header.checksum() {
+            return Err(io::Error::new_const(ErrorKind::InvalidData, &"header checksum mismatch"));
+        }
 
         Ok(header)
     }
 
+    /// Writes the header at `pos` instead of the cursor position. The cursor isn't moved.
+    ///
+    /// This fills in space that was reserved for the header before its packet number was known.
+    pub fn write_at(&self, buf: &mut BytesMut, pos: usize) -> io::Result<()> {
+        let (_, mut reserved) = buf.split_at_mut(pos)?;
+        self.write(&mut reserved)
+    }
+
     pub fn write(&self, buf: &mut BytesMut) -> io::Result<()> {
         match self {
             Header::Long {
                 packet_number,
                 packet_type,
+                version,
                 src_id,
                 dst_id,
             } => {
-                buf.write::<u64>(packet_number);
-                buf.write::<u8>(0x01);
-                buf.write::<u64>(src_id);
-                buf.write::<u64>(dst_id);
+                buf.write::<u64>(*packet_number)?;
+                buf.write::<u8>(0x01)?;
+                buf.write::<u32>(*version)?;
+                buf.write::<u64>(*src_id)?;
+                buf.write::<u64>(*dst_id)?;
             },
             Header::Short {
                 packet_number,
                 packet_type,
                 dst_id,
             } => {
-                buf.write::<u64>(packet_number);
-                buf.write::<u8>(0x10);
-                buf.write::<u64>(dst_id);
+                buf.write::<u64>(*packet_number)?;
+                buf.write::<u8>(0x10)?;
+                buf.write::<u64>(*dst_id)?;
             },
         };
+        buf.write::<u16>(self.checksum())?;
+
+        Ok(())
+    }
+
+    /// Returns the checksum written after the header's fields (32-bit FNV-1a over their
+    /// encoding, folded in half).
+    fn checksum(&self) -> u16 {
+        let hash = match *self {
+            Header::Long {
+                packet_number,
+                version,
+                src_id,
+                dst_id,
+                ..
+            } => {
+                let hash = fnv1a(FNV_OFFSET_BASIS, &packet_number.to_be_bytes());
+                let hash = fnv1a(hash, &[0x01]);
+                let hash = fnv1a(hash, &version.to_be_bytes());
+                let hash = fnv1a(hash, &src_id.to_be_bytes());
+                fnv1a(hash, &dst_id.to_be_bytes())
+            },
+            Header::Short {
+                packet_number,
+                dst_id,
+                ..
+            } => {
+                let hash = fnv1a(FNV_OFFSET_BASIS, &packet_number.to_be_bytes());
+                let hash = fnv1a(hash, &[0x10]);
+                fnv1a(hash, &dst_id.to_be_bytes())
+            },
+        };
+        (hash >> 16) as u16 ^ hash as u16
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+const FNV_OFFSET_BASIS: u32 = 0x811c_9dc5;
+
+/// Folds `bytes` into the 32-bit FNV-1a `hash`.
+const fn fnv1a(mut hash: u32, bytes: &[u8]) -> u32 {
+    let mut index = 0;
+    while index < bytes.len() {
+        hash ^= bytes[index] as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+        index += 1;
+    }
+    hash
+}
+
+/// Returns the checksum a [`Header::Long`] carries for the protocol `version` (32-bit FNV-1a).
+///
+/// Endpoints only talk to each other if the checksums of their
+/// [`PROTOCOL_VERSION`](crate::constants::PROTOCOL_VERSION) strings match.
+pub const fn version_checksum(version: &str) -> u32 {
+    fnv1a(FNV_OFFSET_BASIS, version.as_bytes())
+}
+
+/// The payload of a [`Handshake`](PacketType::Handshake) packet, written after a [`Header::Long`].
+///
+/// On the wire, this is the connection id followed by the request.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    /// The id the sender wants to be addressed by.
+    pub connection_id: u64,
+    pub request: Request,
+}
+
+impl Handshake {
+    /// Reads a handshake payload.
+    pub fn read(buf: &mut BytesMut) -> io::Result<Self> {
+        let connection_id = buf.read::<u64>()?;
+        let request = match buf.read::<u8>()? {
+            // a denial says why
+            0x03 => {
+                let reason = DisconnectReason::from_u8(buf.read::<u8>()?).ok_or_else(|| {
+                    io::Error::new_const(ErrorKind::InvalidData, &"unknown disconnect reason")
+                })?;
+                Request::Deny(reason)
+            },
+            byte => Request::from_u8(byte).ok_or_else(|| {
+                io::Error::new_const(ErrorKind::InvalidData, &"unknown handshake request")
+            })?,
+        };
+
+        Ok(Handshake {
+            connection_id,
+            request,
+        })
+    }
+
+    pub fn write(&self, buf: &mut BytesMut) -> io::Result<()> {
+        buf.write::<u64>(self.connection_id)?;
+        buf.write::<u8>(self.request.to_u8())?;
+        if let Request::Deny(reason) = self.request {
+            buf.write::<u8>(reason.to_u8())?;
+        }
+        Ok(())
+    }
+}
+
+/// A frame in the payload of a [`Data`](PacketType::Data) packet.
+///
+/// Frames are written in a canonical order (see [`rank`](Self::rank)): the [`Ack`](Frame::Ack)
+/// first, so that the remote endpoint makes room for more packets before it gets to the rest,
+/// then the channel acknowledgements, then the data, then any padding. Frames are read in
+/// whatever order they come in, but acknowledgements are always handled before any message
+/// they arrived with is delivered.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Frame {
     Padding {
         len: u16,
     },
     Ping,
+    /// Acknowledges packets (connection-level).
     Ack {
         ack_sequence: u64,
         ack_mask: u64,
     },
+    /// Acknowledges the messages received on a channel.
+    ///
+    /// `ack_sequence` is the newest complete message, and bit `n` of `ack_mask` is set if
+    /// message `ack_sequence - n` is complete too.
+    ChannelAck {
+        channel_id: u64,
+        ack_sequence: u64,
+        ack_mask: u64,
+    },
+    /// Tells the remote endpoint to open a channel with the given guarantees.
+    OpenChannel {
+        channel_id: u64,
+        send_guarantee: Send,
+        recv_guarantee: Receive,
+    },
     Data {
         channel_id: u64,
         channel_sequence: u64,
-        fragment_index: u8,
-        fragment_count: u8,
+        fragment_index: u16,
+        fragment_count: u16,
         len: u16,
     },
+    /// Tells the remote endpoint that the connection is closed, and why.
+    Close {
+        reason: DisconnectReason,
+    },
 }
 
 impl Frame {
+    /// Where the frame goes in a packet. A frame is never written after one of a higher rank.
+    pub(crate) fn rank(&self) -> u8 {
+        match self {
+            Frame::Ack { .. } => 0,
+            Frame::ChannelAck { .. } => 1,
+            Frame::Ping | Frame::Close { .. } => 2,
+            Frame::OpenChannel { .. } | Frame::Data { .. } => 3,
+            Frame::Padding { .. } => 4,
+        }
+    }
+
+    /// The number of bytes [`write`](Self::write) writes for this frame, including its type
+    /// byte.
+    ///
+    /// For a [`Data`](Frame::Data) frame, this doesn't include the `len` bytes of the fragment
+    /// that follow it.
+    pub fn encoded_len(&self) -> usize {
+        match *self {
+            // padding is all type bytes
+            Frame::Padding { len } => len as usize,
+            Frame::Ping => 1,
+            Frame::Ack { .. } => ACK_FRAME_BYTES,
+            Frame::ChannelAck { .. } => 1 + 8 + 8 + 8,
+            Frame::OpenChannel { .. } => 1 + 8 + 1 + 1,
+            Frame::Data { .. } => DATA_FRAME_BYTES,
+            Frame::Close { .. } => 1 + 1,
+        }
+    }
+
     pub fn read(buf: &mut BytesMut) -> io::Result<Self> {
         let frame_type = buf.read::<u8>()?;
         let frame = match frame_type {
             0x00 => {
                 let mut len = 1;
-                while buf.peek::<u8>() == Ok(0x00) {
+                while matches!(buf.peek::<u8>(), Ok(0x00)) {
                     buf.read::<u8>()?;
                     len += 1;
                 }
@@ -122,11 +348,37 @@ impl Frame {
                     ack_mask,
                 }
             },
+            0x21 => {
+                let channel_id = buf.read::<u64>()?;
+                let ack_sequence = buf.read::<u64>()?;
+                let ack_mask = buf.read::<u64>()?;
+
+                Frame::ChannelAck {
+                    channel_id,
+                    ack_sequence,
+                    ack_mask,
+                }
+            },
+            0x30 => {
+                let channel_id = buf.read::<u64>()?;
+                let send_guarantee = Send::from_u8(buf.read::<u8>()?).ok_or_else(|| {
+                    io::Error::new_const(ErrorKind::InvalidData, &"unknown send guarantee")
+                })?;
+                let recv_guarantee = Receive::from_u8(buf.read::<u8>()?).ok_or_else(|| {
+                    io::Error::new_const(ErrorKind::InvalidData, &"unknown receive guarantee")
+                })?;
+
+                Frame::OpenChannel {
+                    channel_id,
+                    send_guarantee,
+                    recv_guarantee,
+                }
+            },
             0x31 => {
                 let channel_id = buf.read::<u64>()?;
                 let channel_sequence = buf.read::<u64>()?;
-                let fragment_index = buf.read::<u8>()?;
-                let fragment_count = buf.read::<u8>()?;
+                let fragment_index = buf.read::<u16>()?;
+                let fragment_count = buf.read::<u16>()?;
                 let len = buf.read::<u16>()?;
 
                 Frame::Data {
@@ -137,14 +389,23 @@ impl Frame {
                     len,
                 }
             },
-            _ => return Err(ErrorKind::InvalidData),
+            0x40 => {
+                let reason = DisconnectReason::from_u8(buf.read::<u8>()?).ok_or_else(|| {
+                    io::Error::new_const(ErrorKind::InvalidData, &"unknown disconnect reason")
+                })?;
+
+                Frame::Close { reason }
+            },
+            _ => {
+                return Err(io::Error::new_const(ErrorKind::InvalidData, &"unknown frame type"));
+            },
         };
 
         Ok(frame)
     }
 
     pub fn write(&self, buf: &mut BytesMut) -> io::Result<()> {
-        match self {
+        match *self {
             Frame::Padding { len } => {
                 buf.write_bytes(0x00, len as usize)?;
             },
@@ -159,6 +420,26 @@ impl Frame {
                 buf.write::<u64>(ack_sequence)?;
                 buf.write::<u64>(ack_mask)?;
             },
+            Frame::ChannelAck {
+                channel_id,
+                ack_sequence,
+                ack_mask,
+            } => {
+                buf.write::<u8>(0x21)?;
+                buf.write::<u64>(channel_id)?;
+                buf.write::<u64>(ack_sequence)?;
+                buf.write::<u64>(ack_mask)?;
+            },
+            Frame::OpenChannel {
+                channel_id,
+                send_guarantee,
+                recv_guarantee,
+            } => {
+                buf.write::<u8>(0x30)?;
+                buf.write::<u64>(channel_id)?;
+                buf.write::<u8>(send_guarantee.to_u8())?;
+                buf.write::<u8>(recv_guarantee.to_u8())?;
+            },
             Frame::Data {
                 channel_id,
                 channel_sequence,
@@ -169,12 +450,402 @@ impl Frame {
                 buf.write::<u8>(0x31)?;
                 buf.write::<u64>(channel_id)?;
                 buf.write::<u64>(channel_sequence)?;
-                buf.write::<u8>(fragment_index)?;
-                buf.write::<u8>(fragment_count)?;
+                buf.write::<u16>(fragment_index)?;
+                buf.write::<u16>(fragment_count)?;
                 buf.write::<u16>(len)?;
             },
+            Frame::Close { reason } => {
+                buf.write::<u8>(0x40)?;
+                buf.write::<u8>(reason.to_u8())?;
+            },
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{PROTOCOL_VERSION, PROTOCOL_VERSION_CHECKSUM};
+
+    /// Fills `buf` with bytes from a xorshift generator so the "fuzz" cases are reproducible.
+    fn fill_pseudo_random(buf: &mut [u8], seed: &mut u64) {
+        for byte in buf.iter_mut() {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            *byte = *seed as u8;
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let headers = [
+            Header::Long {
+                packet_number: 7,
+                packet_type: PacketType::Handshake,
+                version: PROTOCOL_VERSION_CHECKSUM,
+                src_id: 1,
+                dst_id: 2,
+            },
+            Header::Short {
+                packet_number: u64::MAX,
+                packet_type: PacketType::Data,
+                dst_id: 3,
+            },
+        ];
+
+        for header in headers {
+            let mut data = [0u8; 32];
+            let mut buf = BytesMut::new(&mut data);
+            header.write(&mut buf).unwrap();
+            let written = buf.position();
+
+            let mut buf = BytesMut::new(&mut data);
+            assert_eq!(Header::read(&mut buf).unwrap(), header);
+            assert_eq!(buf.position(), written);
+        }
+    }
+
+    #[test]
+    fn header_len_matches_write() {
+        let long = Header::Long {
+            packet_number: 7,
+            packet_type: PacketType::Handshake,
+            version: PROTOCOL_VERSION_CHECKSUM,
+            src_id: 1,
+            dst_id: 2,
+        };
+        let short = Header::Short {
+            packet_number: 7,
+            packet_type: PacketType::Data,
+            dst_id: 2,
+        };
+
+        for (header, expected) in [(long, Header::long_header_bytes()), (short, Header::short_header_bytes())] {
+            let mut data = [0u8; 64];
+            let mut buf = BytesMut::new(&mut data);
+            header.write(&mut buf).unwrap();
+            assert_eq!(buf.position(), expected);
+
+            let mut buf = BytesMut::new(&mut data);
+            assert_eq!(Header::peek_len(&buf).unwrap(), expected);
+            assert_eq!(buf.position(), 0);
+            Header::read(&mut buf).unwrap();
+            assert_eq!(buf.position(), expected);
+        }
+
+        // the packet type is needed to tell
+        let mut data = [0u8; 8];
+        let buf = BytesMut::new(&mut data);
+        assert_eq!(Header::peek_len(&buf).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        let mut data = [0u8; 32];
+        data[8] = 0x02;
+        let buf = BytesMut::new(&mut data);
+        assert_eq!(Header::peek_len(&buf).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn encoded_len_matches_write() {
+        let headers = [
+            Header::Long {
+                packet_number: 7,
+                packet_type: PacketType::Handshake,
+                version: PROTOCOL_VERSION_CHECKSUM,
+                src_id: 1,
+                dst_id: 2,
+            },
+            Header::Short {
+                packet_number: 7,
+                packet_type: PacketType::Data,
+                dst_id: 2,
+            },
+        ];
+        for header in headers {
+            let mut data = [0u8; 64];
+            let mut buf = BytesMut::new(&mut data);
+            header.write(&mut buf).unwrap();
+            assert_eq!(buf.position(), header.encoded_len());
+        }
+
+        let frames = [
+            Frame::Padding { len: 1 },
+            Frame::Padding { len: 13 },
+            Frame::Ping,
+            Frame::Ack {
+                ack_sequence: 9,
+                ack_mask: u64::MAX,
+            },
+            Frame::ChannelAck {
+                channel_id: 3,
+                ack_sequence: 9,
+                ack_mask: 0b1011,
+            },
+            Frame::OpenChannel {
+                channel_id: 3,
+                send_guarantee: Send::Reliable,
+                recv_guarantee: Receive::Ordered,
+            },
+            Frame::Data {
+                channel_id: 3,
+                channel_sequence: 9,
+                fragment_index: 1,
+                fragment_count: 2,
+                len: 500,
+            },
+            Frame::Close {
+                reason: DisconnectReason::ConnectionIdleTimeout,
+            },
+        ];
+        for frame in frames {
+            let mut data = [0u8; 64];
+            let mut buf = BytesMut::new(&mut data);
+            buf.write::<u8>(0xff).unwrap();
+            frame.write(&mut buf).unwrap();
+            assert_eq!(buf.position() - 1, frame.encoded_len(), "{:?}", frame);
+        }
+    }
+
+    #[test]
+    fn header_write_at_backfills_reserved_space() {
+        let header = Header::Short {
+            packet_number: 42,
+            packet_type: PacketType::Data,
+            dst_id: 3,
+        };
+
+        let mut data = [0u8; 32];
+        let mut buf = BytesMut::new(&mut data);
+        buf.write::<u8>(0xff).unwrap();
+        buf.write_bytes(0, Header::short_header_bytes()).unwrap();
+        buf.write::<u8>(0xee).unwrap();
+
+        header.write_at(&mut buf, 1).unwrap();
+        assert_eq!(buf.position(), Header::short_header_bytes() + 2);
+        assert_eq!(data[0], 0xff);
+        assert_eq!(data[Header::short_header_bytes() + 1], 0xee);
+
+        let mut buf = BytesMut::new(&mut data[1..]);
+        assert_eq!(Header::read(&mut buf).unwrap(), header);
+
+        // the reserved space has to fit the header
+        let mut data = [0u8; 8];
+        let mut buf = BytesMut::new(&mut data);
+        assert!(header.write_at(&mut buf, 0).is_err());
+    }
+
+    #[test]
+    fn header_rejects_unknown_packet_type() {
+        for packet_type in (0..=u8::MAX).filter(|&b| b != 0x01 && b != 0x10) {
+            let mut data = [0u8; 32];
+            data[8] = packet_type;
+            let mut buf = BytesMut::new(&mut data);
+            let err = Header::read(&mut buf).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn header_rejects_flipped_bits() {
+        let headers = [
+            Header::Long {
+                packet_number: 7,
+                packet_type: PacketType::Handshake,
+                version: PROTOCOL_VERSION_CHECKSUM,
+                src_id: 1,
+                dst_id: 2,
+            },
+            Header::Short {
+                packet_number: 42,
+                packet_type: PacketType::Data,
+                dst_id: 3,
+            },
+        ];
+
+        for header in headers {
+            let mut data = [0u8; 32];
+            header.write(&mut BytesMut::new(&mut data)).unwrap();
+
+            // a corrupted destination id (or any other field) is caught instead of mis-routed
+            for bit in 0..header.encoded_len() * 8 {
+                let mut corrupted = data;
+                corrupted[bit / 8] ^= 1 << (bit % 8);
+                let err = Header::read(&mut BytesMut::new(&mut corrupted)).unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::InvalidData, "bit {}", bit);
+            }
+        }
+    }
+
+    #[test]
+    fn frame_rejects_unknown_frame_type() {
+        for frame_type in (0..=u8::MAX).filter(|b| ![0x00, 0x10, 0x20, 0x21, 0x30, 0x31, 0x40].contains(b)) {
+            let mut data = [0u8; 32];
+            data[0] = frame_type;
+            let mut buf = BytesMut::new(&mut data);
+            let err = Frame::read(&mut buf).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn read_random_bytes_does_not_panic() {
+        let mut seed = 0x2545_f491_4f6c_dd1d;
+        for len in 0..64 {
+            for _ in 0..64 {
+                let mut data = vec![0u8; len];
+                fill_pseudo_random(&mut data, &mut seed);
+
+                let _ = Header::read(&mut BytesMut::new(&mut data));
+
+                let mut buf = BytesMut::new(&mut data);
+                while buf.remaining() > 0 {
+                    if Frame::read(&mut buf).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn close_round_trip() {
+        let frame = Frame::Close {
+            reason: DisconnectReason::ConnectionIdleTimeout,
+        };
+
+        let mut data = [0u8; 2];
+        frame.write(&mut BytesMut::new(&mut data)).unwrap();
+        assert_eq!(Frame::read(&mut BytesMut::new(&mut data)).unwrap(), frame);
+
+        // unknown reason
+        data[1] = 0xff;
+        let err = Frame::read(&mut BytesMut::new(&mut data)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn close_round_trips_every_reason() {
+        let reasons = (0..=u8::MAX).filter_map(DisconnectReason::from_u8).collect::<Vec<_>>();
+        assert_eq!(reasons.len(), 19);
+
+        for reason in reasons {
+            assert_eq!(DisconnectReason::from_u8(reason.to_u8()), Some(reason));
+
+            let frame = Frame::Close { reason };
+            let mut data = [0u8; 2];
+            frame.write(&mut BytesMut::new(&mut data)).unwrap();
+            assert_eq!(data[1], reason.to_u8());
+            assert_eq!(Frame::read(&mut BytesMut::new(&mut data)).unwrap(), frame);
+        }
+    }
+
+    #[test]
+    fn channel_ack_round_trip() {
+        let frame = Frame::ChannelAck {
+            channel_id: 3,
+            ack_sequence: 9,
+            ack_mask: 0b1011,
+        };
+
+        let mut data = [0u8; 25];
+        frame.write(&mut BytesMut::new(&mut data)).unwrap();
+        assert_eq!(Frame::read(&mut BytesMut::new(&mut data)).unwrap(), frame);
+
+        // truncated
+        assert!(Frame::read(&mut BytesMut::new(&mut data[..24])).is_err());
+    }
+
+    #[test]
+    fn open_channel_round_trip() {
+        let frame = Frame::OpenChannel {
+            channel_id: 3,
+            send_guarantee: Send::Reliable,
+            recv_guarantee: Receive::Sequenced,
+        };
+
+        let mut data = [0u8; 16];
+        frame.write(&mut BytesMut::new(&mut data)).unwrap();
+        assert_eq!(Frame::read(&mut BytesMut::new(&mut data)).unwrap(), frame);
+
+        // unknown receive guarantee
+        data[10] = 0xff;
+        let err = Frame::read(&mut BytesMut::new(&mut data)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn padding_overwrites_stale_bytes() {
+        // padding is zeroes, so whatever was in the buffer before can't be mistaken for frames
+        let mut data = [0xffu8; 8];
+        let mut buf = BytesMut::new(&mut data);
+        Frame::Padding { len: 5 }.write(&mut buf).unwrap();
+        Frame::Ping.write(&mut buf).unwrap();
+        assert_eq!(buf.position(), 6);
+
+        let mut buf = BytesMut::new(&mut data[..6]);
+        assert_eq!(Frame::read(&mut buf).unwrap(), Frame::Padding { len: 5 });
+        assert_eq!(Frame::read(&mut buf).unwrap(), Frame::Ping);
+        assert_eq!(buf.remaining(), 0);
+
+        // padding past the end of the buffer isn't written at all
+        let mut data = [0xffu8; 4];
+        let err = Frame::Padding { len: 5 }.write(&mut BytesMut::new(&mut data)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(data, [0xff; 4]);
+    }
+
+    #[test]
+    fn handshake_round_trip() {
+        let handshake = Handshake {
+            connection_id: 42,
+            request: Request::Connect,
+        };
+
+        let mut data = [0u8; 64];
+        handshake.write(&mut BytesMut::new(&mut data)).unwrap();
+        assert_eq!(Handshake::read(&mut BytesMut::new(&mut data)).unwrap(), handshake);
+    }
+
+    #[test]
+    fn deny_handshake_carries_reason() {
+        let handshake = Handshake {
+            connection_id: 42,
+            request: Request::Deny(DisconnectReason::ServerFull),
+        };
+
+        let mut data = [0u8; 64];
+        let mut buf = BytesMut::new(&mut data);
+        handshake.write(&mut buf).unwrap();
+        let len = buf.position();
+        assert_eq!(Handshake::read(&mut BytesMut::new(&mut data)).unwrap(), handshake);
+
+        // the reason is required
+        let err = Handshake::read(&mut BytesMut::new(&mut data[..len - 1])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        data[len - 1] = 0xff;
+        let err = Handshake::read(&mut BytesMut::new(&mut data)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn long_header_carries_version_checksum() {
+        assert_eq!(PROTOCOL_VERSION_CHECKSUM, version_checksum(PROTOCOL_VERSION));
+        // any change to the version string changes the checksum
+        assert_ne!(version_checksum("parrot-0.0.1"), version_checksum("parrot-0.0.2"));
+        assert_ne!(version_checksum("parrot-0.0.1"), version_checksum("parrot-0.0."));
+
+        let header = Header::Long {
+            packet_number: 0,
+            packet_type: PacketType::Handshake,
+            version: version_checksum("parrot-9.9.9"),
+            src_id: 1,
+            dst_id: 2,
+        };
+        let mut data = [0u8; 32];
+        header.write(&mut BytesMut::new(&mut data)).unwrap();
+        // a mismatch is left for the receiver to answer
+        assert_eq!(Header::read(&mut BytesMut::new(&mut data)).unwrap(), header);
+    }
+}
@@ -1,4 +1,7 @@
 pub(crate) mod acknowledgment;
 pub(crate) mod frames;
+pub(crate) mod mtu;
+pub(crate) mod packer;
 pub(crate) mod pool;
+pub(crate) mod protection;
 pub(crate) mod sequence_buffer;
\ No newline at end of file
@@ -0,0 +1,180 @@
+use std::time::{Duration, Instant};
+
+use crate::constants::*;
+
+/// Finds the largest packet size the path to the remote endpoint can carry.
+///
+/// Starting from a floor that is assumed to always work, a padded probe of the next candidate
+/// size is sent every interval. If it's acknowledged, the MTU is raised to that size. If it's
+/// lost too many times in a row, the MTU isn't raised anymore. Once there is nothing left to
+/// try, probes of the current MTU are sent instead, and if those are lost too many times in a
+/// row, the path has changed and the MTU is lowered (down to the floor).
+#[derive(Clone, Debug)]
+pub(crate) struct MtuDiscovery {
+    mtu: usize,
+    min_mtu: usize,
+    /// The largest size worth probing.
+    search_max: usize,
+    probe_interval: Duration,
+    /// The size of the probe in flight and when it was sent.
+    probe: Option<(usize, Instant)>,
+    /// When the last probe was sent (or discovery started, before the first one).
+    time_latest_probe: Instant,
+    /// The number of probes of the same size lost in a row.
+    losses: usize,
+}
+
+impl MtuDiscovery {
+    /// Constructs a new `MtuDiscovery` that starts at `time`. The first probe is due one
+    /// interval later, once the connection has settled.
+    pub fn new(min_mtu: usize, probe_interval: Duration, time: Instant) -> Self {
+        debug_assert!(min_mtu <= MAX_PACKET_BYTES);
+        Self {
+            mtu: min_mtu,
+            min_mtu,
+            search_max: MAX_PACKET_BYTES,
+            probe_interval,
+            probe: None,
+            time_latest_probe: time,
+            losses: 0,
+        }
+    }
+
+    /// The largest packet size (in bytes) known to work.
+    #[inline]
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// The size of the next probe.
+    fn candidate(&self) -> usize {
+        (self.mtu + MTU_PROBE_STEP_BYTES).min(self.search_max)
+    }
+
+    /// Returns the size of the probe that should be sent now, if any.
+    ///
+    /// A probe that hasn't been acknowledged within the probe interval is considered lost.
+    pub fn poll(&mut self, time: Instant) -> Option<usize> {
+        if let Some((_, time_sent)) = self.probe {
+            if time.saturating_duration_since(time_sent) < self.probe_interval {
+                return None;
+            }
+            self.on_probe_lost();
+        }
+
+        if time.saturating_duration_since(self.time_latest_probe) < self.probe_interval {
+            return None;
+        }
+
+        let size = self.candidate();
+        self.probe = Some((size, time));
+        self.time_latest_probe = time;
+        Some(size)
+    }
+
+    /// Processes the acknowledgment of the probe in flight.
+    pub fn on_probe_acked(&mut self) {
+        let Some((size, _)) = self.probe.take() else {
+            return;
+        };
+
+        if size > self.mtu {
+            self.mtu = size;
+        }
+        self.losses = 0;
+    }
+
+    /// Processes the loss of the probe in flight.
+    pub fn on_probe_lost(&mut self) {
+        let Some((size, _)) = self.probe.take() else {
+            return;
+        };
+
+        self.losses += 1;
+        if self.losses < MTU_PROBE_MAX_LOSSES {
+            return;
+        }
+
+        self.losses = 0;
+        if size > self.mtu {
+            // stop raising it
+            self.search_max = self.mtu;
+        } else {
+            // the current MTU doesn't fit anymore
+            self.mtu = self.mtu.saturating_sub(MTU_PROBE_STEP_BYTES).max(self.min_mtu);
+            self.search_max = self.mtu;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn acked_probes_raise_mtu() {
+        let created = Instant::now();
+        let start = created + INTERVAL;
+        let mut discovery = MtuDiscovery::new(1200, INTERVAL, created);
+        assert_eq!(discovery.mtu(), 1200);
+
+        // not right away
+        assert_eq!(discovery.poll(created), None);
+        assert_eq!(discovery.poll(start), Some(1200 + MTU_PROBE_STEP_BYTES));
+        // only one probe per interval
+        assert_eq!(discovery.poll(start + INTERVAL / 2), None);
+        discovery.on_probe_acked();
+        assert_eq!(discovery.mtu(), 1200 + MTU_PROBE_STEP_BYTES);
+
+        // keeps probing until the maximum
+        let mut time = start;
+        while discovery.mtu() < MAX_PACKET_BYTES {
+            time += INTERVAL;
+            assert!(discovery.poll(time).unwrap() > discovery.mtu());
+            discovery.on_probe_acked();
+        }
+        assert_eq!(discovery.mtu(), MAX_PACKET_BYTES);
+
+        // then only confirms it
+        assert_eq!(discovery.poll(time + INTERVAL), Some(MAX_PACKET_BYTES));
+    }
+
+    #[test]
+    fn lost_probes_stop_raising_mtu() {
+        let start = Instant::now() + INTERVAL;
+        let mut discovery = MtuDiscovery::new(1200, INTERVAL, start - INTERVAL);
+
+        let candidate = 1200 + MTU_PROBE_STEP_BYTES;
+        for i in 0..MTU_PROBE_MAX_LOSSES {
+            assert_eq!(discovery.poll(start + INTERVAL * i as u32), Some(candidate));
+        }
+
+        // the last probe times out, so the candidate is given up on
+        assert_eq!(discovery.poll(start + INTERVAL * MTU_PROBE_MAX_LOSSES as u32), Some(1200));
+        assert_eq!(discovery.mtu(), 1200);
+    }
+
+    #[test]
+    fn lost_probes_lower_mtu() {
+        let start = Instant::now() + INTERVAL;
+        let mut discovery = MtuDiscovery::new(1200, INTERVAL, start - INTERVAL);
+        discovery.mtu = MAX_PACKET_BYTES;
+
+        for i in 0..MTU_PROBE_MAX_LOSSES {
+            assert_eq!(discovery.poll(start + INTERVAL * i as u32), Some(MAX_PACKET_BYTES));
+            discovery.on_probe_lost();
+        }
+        assert_eq!(discovery.mtu(), MAX_PACKET_BYTES - MTU_PROBE_STEP_BYTES);
+
+        // never below the floor
+        let mut time = start + INTERVAL * MTU_PROBE_MAX_LOSSES as u32;
+        for _ in 0..(MTU_PROBE_MAX_LOSSES * 10) {
+            time += INTERVAL;
+            discovery.poll(time);
+            discovery.on_probe_lost();
+        }
+        assert_eq!(discovery.mtu(), 1200);
+    }
+}
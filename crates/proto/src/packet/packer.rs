@@ -0,0 +1,402 @@
+use std::{collections::VecDeque, io, time::Instant};
+
+use crate::{
+    connection::{Connection, ConnectionRef, SendPacket},
+    constants::DATA_FRAME_BYTES,
+    cursor::BytesMut,
+    packet::{
+        frames::{Frame, Header, PacketType},
+        pool::BufferPool,
+        sequence_buffer::SequenceNumber,
+    },
+};
+
+type ChannelId = u64;
+
+/// Packs scheduled message fragments into a packet.
+///
+/// Each packet starts with a header and (once something has been received) an
+/// [`Ack`](Frame::Ack) frame, followed by a [`ChannelAck`](Frame::ChannelAck) frame for each
+/// channel that has received messages since its last one, then gets as many
+/// [`Data`](Frame::Data) frames as fit (each
+/// channel's first one preceded by its [`OpenChannel`](Frame::OpenChannel) frame), then
+/// [`Padding`](Frame::Padding) if it's shorter than [`pad_to`](Self::pad_to) asks for. This is
+/// the canonical frame order (see [`Frame::rank`]).
+pub(crate) struct PacketPacker {
+    /// The size every packet is padded to (in bytes).
+    min_packet_bytes: usize,
+}
+
+impl PacketPacker {
+    /// Constructs a new `PacketPacker`.
+    pub(crate) fn new() -> Self {
+        Self { min_packet_bytes: 0 }
+    }
+
+    /// Pads every packet to at least `min_packet_bytes` (or as much as the buffer it's written
+    /// into can hold, if that's smaller), so that their contents can't be guessed from their
+    /// size.
+    pub(crate) fn pad_to(mut self, min_packet_bytes: usize) -> Self {
+        self.min_packet_bytes = min_packet_bytes;
+        self
+    }
+
+    /// Writes the fragments at the front of `scheduled` into a packet of `connection` in `buf`,
+    /// in order, marks them as sent, and removes them from `scheduled`.
+    ///
+    /// `scheduled` is expected to come from [`Connection::schedule`]. A fragment that has been
+    /// sent (or dropped) since it was scheduled is skipped, and so is one too large to fit in an
+    /// empty packet. At most as many fragments as a [`SendPacket`] can track are packed, the
+    /// rest are left in `scheduled` for the next packet.
+    ///
+    /// Returns the packet written (its size doesn't include the protector's tag), or `None` if
+    /// there was nothing to pack. Nothing is written into `buf` in that case.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `buf` can't even hold the header and the acknowledgment.
+    pub(crate) fn pack(
+        &self,
+        connection: &mut Connection,
+        pool: &BufferPool,
+        scheduled: &mut VecDeque<(ChannelId, SequenceNumber, u16)>,
+        buf: &mut BytesMut,
+        instant: Instant,
+    ) -> io::Result<Option<SendPacket>> {
+        let packet_number = connection.acks.packet_number();
+        let header = Header::Short {
+            packet_number,
+            packet_type: PacketType::Data,
+            dst_id: connection.dst_id,
+        };
+        // piggyback the latest acknowledgment on every packet
+        let ack = connection.ack_frame();
+
+        // the header and the frames in front of the data are only written once there's data to
+        // go with them, so that `buf` is left untouched if there isn't
+        let start = buf.position();
+        let frames_bytes = header.encoded_len() + ack.as_ref().map_or(0, Frame::encoded_len);
+        if buf.remaining() < frames_bytes {
+            return Err(io::Error::new_const(io::ErrorKind::InvalidInput, &"buffer too short"));
+        }
+
+        // so do the channel acknowledgements that are due, as many as fit
+        let mut channel_ids = connection.channels.keys().copied().collect::<Vec<_>>();
+        channel_ids.sort_unstable();
+        let mut channel_acks = Vec::new();
+        let mut acks_bytes = 0;
+        for channel_id in channel_ids {
+            let channel = &connection.channels[&channel_id];
+            let Some(frame) = channel.ack_frame().filter(|_| channel.ack_pending()) else {
+                continue;
+            };
+            if frames_bytes + acks_bytes + frame.encoded_len() > buf.remaining() {
+                break;
+            }
+            acks_bytes += frame.encoded_len();
+            channel_acks.push((channel_id, frame));
+        }
+        buf.seek(io::SeekFrom::Start((start + frames_bytes + acks_bytes) as u64))?;
+
+        let mut included = [None; 8];
+        let mut count = 0;
+        let mut opened = Vec::new();
+        while count < included.len() {
+            let Some(&(channel_id, sequence, index)) = scheduled.front() else {
+                break;
+            };
+            let len = connection
+                .channels
+                .get(&channel_id)
+                .and_then(|channel| channel.fragment_len(sequence, index));
+            let Some(len) = len else {
+                // sent, acknowledged, or cancelled since it was scheduled
+                scheduled.pop_front();
+                continue;
+            };
+            let channel = connection.channels.get_mut(&channel_id).unwrap();
+
+            // OpenChannel frames aren't acknowledged, so each packet carrying data for a channel
+            // opens it again
+            let open = (!opened.contains(&channel_id)).then(|| channel.open_frame());
+            let needed = open.as_ref().map_or(0, Frame::encoded_len) + DATA_FRAME_BYTES + len;
+            if buf.remaining() < needed {
+                if count == 0 && acks_bytes > 0 && buf.remaining() + acks_bytes >= needed {
+                    // the fragment needs the room more, the acknowledgements can go in the next one
+                    channel_acks.clear();
+                    acks_bytes = 0;
+                    buf.seek(io::SeekFrom::Start((start + frames_bytes) as u64))?;
+                    continue;
+                }
+                if count == 0 {
+                    // it won't fit in any packet
+                    scheduled.pop_front();
+                    continue;
+                }
+                break;
+            }
+
+            if let Some(open) = open {
+                open.write(buf)?;
+                opened.push(channel_id);
+            }
+            channel.write_fragment(pool, buf, sequence, index, instant)?;
+            scheduled.pop_front();
+            included[count] = Some((channel_id, sequence, index));
+            count += 1;
+        }
+
+        if count == 0 {
+            buf.seek(io::SeekFrom::Start(start as u64))?;
+            return Ok(None);
+        }
+        // padding always goes last
+        ConnectionRef::write_padding(buf, start + self.min_packet_bytes)?;
+
+        let end = buf.position();
+        buf.seek(io::SeekFrom::Start(start as u64))?;
+        header.write(buf)?;
+        if let Some(ack) = ack {
+            ack.write(buf)?;
+            connection.ping_pending = false;
+        }
+        for (channel_id, frame) in channel_acks {
+            frame.write(buf)?;
+            connection.channels.get_mut(&channel_id).unwrap().on_ack_sent();
+        }
+        buf.seek(io::SeekFrom::Start(end as u64))?;
+
+        connection.acks.next_packet_number = packet_number.wrapping_add(1);
+        connection.time_latest_send = Some(instant);
+        Ok(Some(SendPacket {
+            sequence: packet_number,
+            included,
+            size: end - start,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        connection::{Receive, Send, SendMessage, SendStatus},
+        constants::*,
+        enums::Role,
+    };
+
+    /// Queues a message of `fragments` fragments, `len` bytes each, on channel `id`.
+    fn queue_message(connection: &mut Connection, pool: &mut BufferPool, id: ChannelId, fragments: usize, len: usize) {
+        let channel = connection.open_channel(id, Send::Reliable, Receive::Ordered).unwrap();
+        let sequence = channel.acks.next_send;
+        channel.acks.next_send += 1;
+
+        let handle = pool.acquire().unwrap();
+        let mut message = SendMessage {
+            sequence,
+            fragment_count: fragments as u16,
+            fragment_sent: 0,
+            fragment_data: vec![None; fragments].into_boxed_slice(),
+            fragment_status: vec![SendStatus::Unsent; fragments].into_boxed_slice(),
+            time_created: Instant::now(),
+            time_sent: None,
+            time_latest_send: None,
+            retransmits: 0,
+        };
+        for index in 0..fragments {
+            message.fragment_data[index] = Some((handle, 0, len));
+        }
+        channel.send_buffer.insert(sequence, message);
+    }
+
+    fn packing_connection() -> (Connection, BufferPool) {
+        let connection = Connection::new(
+            0,
+            1,
+            "127.0.0.1:0".parse().unwrap(),
+            Role::Client,
+            Config::default(),
+            Instant::now(),
+        );
+        (connection, BufferPool::new(MAX_PAYLOAD_BYTES, 16))
+    }
+
+    /// Packs the next packet from `scheduled` into a buffer of `max_packet_bytes`.
+    fn pack_next(
+        packer: &PacketPacker,
+        connection: &mut Connection,
+        pool: &BufferPool,
+        scheduled: &mut VecDeque<(ChannelId, SequenceNumber, u16)>,
+        max_packet_bytes: usize,
+    ) -> Option<(Vec<u8>, SendPacket)> {
+        let mut data = vec![0; max_packet_bytes];
+        let mut buf = BytesMut::new(&mut data);
+        let packet = packer.pack(connection, pool, scheduled, &mut buf, Instant::now()).unwrap()?;
+        let len = buf.position();
+        data.truncate(len);
+        Some((data, packet))
+    }
+
+    fn included(packet: &SendPacket) -> Vec<(ChannelId, SequenceNumber, u16)> {
+        packet.included.iter().flatten().copied().collect()
+    }
+
+    fn frames(data: &mut [u8]) -> Vec<Frame> {
+        let mut buf = BytesMut::new(data);
+        Header::read(&mut buf).unwrap();
+        let mut frames = Vec::new();
+        while buf.remaining() > 0 {
+            let frame = Frame::read(&mut buf).unwrap();
+            if let Frame::Data { len, .. } = frame {
+                buf.advance(len as usize).unwrap();
+            }
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn pack_fills_packets_densely() {
+        let (mut connection, mut pool) = packing_connection();
+        for id in 0..3 {
+            queue_message(&mut connection, &mut pool, id, 1, 100);
+        }
+        connection.acks.last_recv_packet_number = Some(9);
+        connection.acks.last_recv_packet_mask = 0b101;
+
+        let mut scheduled = connection.schedule(usize::MAX).into();
+        let (mut data, packet) = pack_next(&PacketPacker::new(), &mut connection, &pool, &mut scheduled, 1200).unwrap();
+
+        // everything goes into one packet
+        assert!(scheduled.is_empty());
+        assert_eq!(included(&packet), [(0, 0, 0), (1, 0, 0), (2, 0, 0)]);
+        assert_eq!(packet.sequence, 0);
+        assert_eq!(connection.acks.packet_number(), 1);
+        let open_bytes = connection.channels[&0].open_frame().encoded_len();
+        assert_eq!(
+            data.len(),
+            Header::short_header_bytes() + ACK_FRAME_BYTES + 3 * (open_bytes + DATA_FRAME_BYTES + 100)
+        );
+        assert_eq!(packet.size, data.len());
+
+        // the acknowledgment leads
+        assert_eq!(
+            frames(&mut data)[0],
+            Frame::Ack {
+                ack_sequence: 9,
+                ack_mask: 0b101,
+            }
+        );
+
+        for id in 0..3 {
+            let message = connection.channels[&id].send_buffer.get(0).unwrap().as_ref().unwrap();
+            assert_eq!(message.fragment_status[0], SendStatus::Sent);
+        }
+    }
+
+    #[test]
+    fn pack_writes_frames_in_canonical_order() {
+        let (mut connection, mut pool) = packing_connection();
+        queue_message(&mut connection, &mut pool, 0, 2, 100);
+        queue_message(&mut connection, &mut pool, 1, 1, 100);
+        connection.acks.last_recv_packet_number = Some(3);
+        connection.acks.last_recv_packet_mask = 1;
+        connection.channels.get_mut(&1).unwrap().acks.latest_recv = Some(0);
+
+        let mut scheduled = connection.schedule(usize::MAX).into();
+        let packer = PacketPacker::new().pad_to(600);
+        let (mut data, _) = pack_next(&packer, &mut connection, &pool, &mut scheduled, 1200).unwrap();
+        assert_eq!(data.len(), 600);
+
+        // acks, then data (each channel opened once), then padding
+        let frames = frames(&mut data);
+        assert!(matches!(frames[0], Frame::Ack { ack_sequence: 3, .. }));
+        assert!(matches!(frames[1], Frame::ChannelAck { channel_id: 1, ack_sequence: 0, .. }));
+        assert!(!connection.channels[&1].ack_pending());
+        assert_eq!(frames.iter().filter(|frame| matches!(frame, Frame::OpenChannel { .. })).count(), 2);
+        assert_eq!(frames.iter().filter(|frame| matches!(frame, Frame::Data { .. })).count(), 3);
+        assert!(matches!(frames.last(), Some(Frame::Padding { .. })));
+        assert!(frames.windows(2).all(|pair| pair[0].rank() <= pair[1].rank()));
+    }
+
+    #[test]
+    fn pack_spills_at_mtu() {
+        let (mut connection, mut pool) = packing_connection();
+        queue_message(&mut connection, &mut pool, 0, 3, 400);
+        queue_message(&mut connection, &mut pool, 1, 1, 400);
+
+        // room for two fragments per packet, but not three
+        let open_bytes = connection.channels[&0].open_frame().encoded_len();
+        let max_packet_bytes = Header::short_header_bytes() + open_bytes + 2 * (DATA_FRAME_BYTES + 400) + 100;
+        let mut scheduled = connection.schedule(usize::MAX).into();
+        let packer = PacketPacker::new();
+        let (first, packet) = pack_next(&packer, &mut connection, &pool, &mut scheduled, max_packet_bytes).unwrap();
+        assert_eq!(included(&packet), [(0, 0, 0), (0, 0, 1)]);
+        assert!(first.len() <= max_packet_bytes);
+
+        let (second, packet) = pack_next(&packer, &mut connection, &pool, &mut scheduled, max_packet_bytes).unwrap();
+        assert_eq!(included(&packet), [(0, 0, 2), (1, 0, 0)]);
+        assert_eq!(packet.sequence, 1);
+        assert!(second.len() <= max_packet_bytes);
+        assert!(pack_next(&packer, &mut connection, &pool, &mut scheduled, max_packet_bytes).is_none());
+    }
+
+    #[test]
+    fn pack_leaves_the_rest_scheduled() {
+        let (mut connection, mut pool) = packing_connection();
+        queue_message(&mut connection, &mut pool, 0, 4, 1000);
+
+        let mut scheduled = connection.schedule(usize::MAX).into();
+        let packer = PacketPacker::new();
+        let (_, packet) = pack_next(&packer, &mut connection, &pool, &mut scheduled, 1200).unwrap();
+        assert_eq!(included(&packet), [(0, 0, 0)]);
+        assert_eq!(scheduled.len(), 3);
+        let message = connection.channels[&0].send_buffer.get(0).unwrap().as_ref().unwrap();
+        assert_eq!(message.fragment_sent, 1);
+        assert_eq!(message.time_sent, None);
+
+        // a packet tracks no more than 8 fragments
+        queue_message(&mut connection, &mut pool, 1, 10, 10);
+        let mut scheduled = connection.schedule(usize::MAX).into_iter().filter(|(id, ..)| *id == 1).collect();
+        let (_, packet) = pack_next(&packer, &mut connection, &pool, &mut scheduled, 1200).unwrap();
+        assert_eq!(included(&packet).len(), 8);
+        assert_eq!(scheduled.len(), 2);
+    }
+
+    #[test]
+    fn pack_skips_fragments_it_cannot_send() {
+        let (mut connection, mut pool) = packing_connection();
+        queue_message(&mut connection, &mut pool, 0, 1, 1000);
+        queue_message(&mut connection, &mut pool, 1, 1, 100);
+        queue_message(&mut connection, &mut pool, 2, 1, 100);
+
+        let mut scheduled: VecDeque<_> = connection.schedule(usize::MAX).into();
+        let packer = PacketPacker::new();
+        let (_, packet) = pack_next(&packer, &mut connection, &pool, &mut scheduled, 500).unwrap();
+        assert_eq!(included(&packet), [(1, 0, 0), (2, 0, 0)]);
+
+        // sent already, and on a channel that's gone, so nothing is written
+        scheduled.extend([(1, 0, 0), (9, 0, 0)]);
+        let mut data = vec![0xff; 500];
+        let mut buf = BytesMut::new(&mut data);
+        assert!(packer.pack(&mut connection, &pool, &mut scheduled, &mut buf, Instant::now()).unwrap().is_none());
+        assert_eq!(buf.position(), 0);
+        assert!(data.iter().all(|&byte| byte == 0xff));
+    }
+
+    #[test]
+    fn write_fragment_rejects_unknown_fragment() {
+        let (mut connection, mut pool) = packing_connection();
+        queue_message(&mut connection, &mut pool, 0, 2, 100);
+
+        let mut data = vec![0; 1200];
+        let mut packet = BytesMut::new(&mut data);
+        let channel = connection.channels.get_mut(&0).unwrap();
+        for (sequence, index) in [(0, 2), (0, u16::MAX), (1, 0)] {
+            let err = channel.write_fragment(&pool, &mut packet, sequence, index, Instant::now()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        }
+        assert_eq!(packet.position(), 0);
+    }
+}
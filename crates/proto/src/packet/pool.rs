@@ -9,6 +9,8 @@ pub struct BufferPool {
     buffer_size: usize,
     capacity: usize,
     capacity_remaining: usize,
+    /// The head of the list of released buffers.
+    free: Option<usize>,
 }
 
 pub struct BufferMetadata {
@@ -18,42 +20,49 @@ pub struct BufferMetadata {
     pub(super) next: Option<usize>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct BufferHandle {
     generation: u32,
     index: u32,
 }
 
 impl BufferPool {
+    /// Constructs a pool of up to `capacity` buffers of `buffer_size` bytes each.
+    ///
+    /// Buffers are only allocated once they're needed, so a pool sized for the worst case
+    /// doesn't cost that much memory up front.
     pub fn new(buffer_size: usize, capacity: usize) -> Self {
-        let mut meta = Vec::with_capacity(capacity);        
-        let mut bufs = Vec::with_capacity(capacity);       
-        
-        for i in 0..capacity {
-            let metadata = {
-                BufferMetadata {
-                    holder: None,
-                    generation: 0,
-                    prev: if i == 0 { None } else { Some(i - 1) },
-                    next: if i == (capacity - 1) { None } else { Some(i + 1) },
-                }
-            };
-            
-            meta.push(metadata);
-            bufs.push(Box::<[u8]>::new_uninit_slice(buffer_size));
-        }
-        
         Self {
-            bufs,
-            meta,
+            bufs: Vec::new(),
+            meta: Vec::new(),
             buffer_size,
             capacity,
             capacity_remaining: capacity,
+            free: None,
         }
     }
 
+    /// The total number of buffers in the pool.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
     pub fn capacity_remaining(&self) -> usize {
         self.capacity_remaining
     }
+
+    /// The number of buffers that have been acquired and not released yet.
+    #[inline]
+    pub fn in_use(&self) -> usize {
+        self.capacity - self.capacity_remaining
+    }
+
+    /// The size of each buffer (in bytes).
+    #[inline]
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
     
     pub fn get(&self, handle: BufferHandle) -> Option<&[MaybeUninit<u8>]> {
         self.meta
@@ -62,58 +71,214 @@ impl BufferPool {
                 if handle.generation != metadata.generation {
                     return None;
                 }
-                let buf = self.bufs.get(handle.index as usize).unwrap();
+                let buf = self.bufs.get(handle.index as usize)?;
                 Some(buf.as_ref())
             })
     }
 
-    pub fn get_mut(&self, handle: BufferHandle) -> Option<&mut [MaybeUninit<u8>]> {
+    pub fn get_mut(&mut self, handle: BufferHandle) -> Option<&mut [MaybeUninit<u8>]> {
         self.meta
             .get(handle.index as usize)
             .and_then(|metadata| {
                 if handle.generation != metadata.generation {
                     return None;
                 }
-                let buf = self.bufs.get_mut(handle.index as usize).unwrap();
+                let buf = self.bufs.get_mut(handle.index as usize)?;
                 Some(buf.as_mut())
             })
     }
     
-    pub fn acquire(&mut self) -> Result<BufferHandle, ()> {
-        if self.capacity_remaining > 0 {
-            // TODO: pop freelist
-            self.capacity_remaining -= 1;
-
-            let handle = {
-                BufferHandle { 
-                    index: 0,
-                    generation: 0
-                }
-            };
+    /// Copies `len` bytes, starting at `src_start` in the buffer `src`, to the start of the
+    /// buffer `dst`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if either handle doesn't belong to this pool or is stale, both are the same
+    /// buffer, or the bytes don't fit in a buffer.
+    pub fn copy(&mut self, src: BufferHandle, src_start: usize, dst: BufferHandle, len: usize) -> Result<(), ()> {
+        if self.get(src).is_none() || self.get(dst).is_none() || src.index == dst.index {
+            return Err(());
+        }
+        let src_end = src_start.checked_add(len).ok_or(())?;
+        if src_end > self.buffer_size {
+            return Err(());
+        }
 
-            Ok(handle)
+        // the buffers are distinct, so the slice can be split between them
+        let (src_index, dst_index) = (src.index as usize, dst.index as usize);
+        let (src, dst) = if src_index < dst_index {
+            let (head, tail) = self.bufs.split_at_mut(dst_index);
+            (&head[src_index], &mut tail[0])
         } else {
-            Err(())
+            let (head, tail) = self.bufs.split_at_mut(src_index);
+            (&tail[0], &mut head[dst_index])
+        };
+        dst[..len].copy_from_slice(&src[src_start..src_end]);
+
+        Ok(())
+    }
+
+    pub fn acquire(&mut self) -> Result<BufferHandle, ()> {
+        if self.free.is_none() && self.bufs.len() < self.capacity {
+            self.meta.push(BufferMetadata {
+                holder: None,
+                generation: 0,
+                prev: None,
+                next: None,
+            });
+            self.bufs.push(Box::<[u8]>::new_uninit_slice(self.buffer_size));
+            self.free = Some(self.bufs.len() - 1);
         }
+
+        let index = self.free.ok_or(())?;
+        let metadata = &mut self.meta[index];
+        self.free = metadata.next.take();
+        if let Some(next) = self.free {
+            self.meta[next].prev = None;
+        }
+        self.capacity_remaining -= 1;
+
+        Ok(BufferHandle {
+            index: index as u32,
+            generation: self.meta[index].generation,
+        })
     }
-    
+
+    /// Returns the buffer to the pool.
+    ///
+    /// The handle (and any copy of it) is stale afterward, so releasing it again fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the handle doesn't belong to this pool or is stale.
     pub fn release(&mut self, handle: BufferHandle) -> Result<(), ()> {
-        self.meta
-            .get_mut(handle.index)
-            .and_then(|metadata| {
-                metadata.holder = None;
-                metadata.generation += 1;
-                // TODO: push freelist
-                self.capacity_remaining += 1;
-            })
-            .ok_or(0);
+        let index = handle.index as usize;
+        let metadata = self.meta.get_mut(index).ok_or(())?;
+        if metadata.generation != handle.generation {
+            return Err(());
+        }
+
+        metadata.holder = None;
+        metadata.generation = metadata.generation.wrapping_add(1);
+        metadata.prev = None;
+        metadata.next = self.free;
+        if let Some(next) = self.free {
+            self.meta[next].prev = Some(index);
+        }
+        self.free = Some(index);
+        self.capacity_remaining += 1;
+
+        Ok(())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accessors_track_usage() {
+        let mut pool = BufferPool::new(1232, 8);
+        assert_eq!(pool.capacity(), 8);
+        assert_eq!(pool.buffer_size(), 1232);
+        assert_eq!(pool.in_use(), 0);
+        // nothing is allocated until it's needed
+        assert!(pool.bufs.is_empty());
+
+        let handles = (0..3).map(|_| pool.acquire().unwrap()).collect::<Vec<_>>();
+        assert_eq!(pool.in_use(), 3);
+        assert_eq!(pool.in_use() + pool.capacity_remaining(), pool.capacity());
+        for handle in &handles {
+            assert_eq!(pool.get(*handle).unwrap().len(), pool.buffer_size());
+        }
+
+        while pool.acquire().is_ok() {}
+        assert_eq!(pool.in_use(), pool.capacity());
+        assert_eq!(pool.capacity_remaining(), 0);
+    }
 
-fn main() {
-    // capacity = max connections * (2 * max packets per tick)
-    let mut pool = BufferPool::new(1232, 1024);
-    let handle = pool.acquire().unwrap();
-    let buf = pool.get_mut(handle).unwrap();
-}
\ No newline at end of file
+    #[test]
+    fn get_rejects_invalid_handle() {
+        let mut pool = BufferPool::new(1232, 2);
+        let handle = BufferHandle {
+            generation: 0,
+            index: 2,
+        };
+        assert!(pool.get(handle).is_none());
+        assert!(pool.get_mut(handle).is_none());
+    }
+    #[test]
+    fn copy_between_buffers() {
+        let mut pool = BufferPool::new(16, 3);
+        let src = pool.acquire().unwrap();
+        let dst = pool.acquire().unwrap();
+        for (i, byte) in pool.get_mut(src).unwrap().iter_mut().enumerate() {
+            byte.write(i as u8);
+        }
+
+        pool.copy(src, 4, dst, 8).unwrap();
+        let copied = unsafe { MaybeUninit::slice_assume_init_ref(&pool.get(dst).unwrap()[..8]) };
+        assert_eq!(copied, &[4, 5, 6, 7, 8, 9, 10, 11]);
+
+        // either order works
+        pool.copy(dst, 0, src, 16).unwrap();
+
+        assert_eq!(pool.copy(src, 0, src, 4), Err(()));
+        assert_eq!(pool.copy(src, 8, dst, 9), Err(()));
+        assert_eq!(pool.copy(src, usize::MAX, dst, 1), Err(()));
+        pool.release(dst).unwrap();
+        assert_eq!(pool.copy(src, 0, dst, 4), Err(()));
+    }
+
+    #[test]
+    fn release_returns_buffer() {
+        let mut pool = BufferPool::new(1232, 2);
+        let first = pool.acquire().unwrap();
+        let second = pool.acquire().unwrap();
+        assert_ne!(first, second);
+        assert!(pool.acquire().is_err());
+
+        assert_eq!(pool.release(first), Ok(()));
+        assert_eq!(pool.capacity_remaining(), 1);
+        assert!(pool.get(first).is_none());
+
+        // the buffer is reused with a new generation
+        let third = pool.acquire().unwrap();
+        assert_eq!(third.index, first.index);
+        assert_ne!(third, first);
+        assert!(pool.get(third).is_some());
+    }
+
+    #[test]
+    fn release_rejects_stale_handle() {
+        let mut pool = BufferPool::new(1232, 2);
+        let handle = pool.acquire().unwrap();
+        pool.release(handle).unwrap();
+        let reused = pool.acquire().unwrap();
+
+        // the old handle can't release the buffer from under its new holder
+        assert_eq!(pool.release(handle), Err(()));
+        assert_eq!(pool.in_use(), 1);
+        assert!(pool.get(reused).is_some());
+
+        let foreign = BufferHandle {
+            generation: 0,
+            index: 7,
+        };
+        assert_eq!(pool.release(foreign), Err(()));
+    }
+
+    #[test]
+    fn release_twice_fails() {
+        let mut pool = BufferPool::new(1232, 2);
+        let handle = pool.acquire().unwrap();
+        assert_eq!(pool.release(handle), Ok(()));
+        assert_eq!(pool.release(handle), Err(()));
+        assert_eq!(pool.capacity_remaining(), 2);
+
+        // the free list wasn't corrupted
+        let handles = [pool.acquire().unwrap(), pool.acquire().unwrap()];
+        assert_ne!(handles[0].index, handles[1].index);
+        assert!(pool.acquire().is_err());
+    }
+}
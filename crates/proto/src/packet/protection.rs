@@ -0,0 +1,42 @@
+use std::io;
+
+use super::frames::Header;
+
+/// Encrypts and authenticates the payload of each packet (everything after its [`Header`]).
+///
+/// The header is sent in the clear, so that the receiver can find the connection, but it should
+/// still be authenticated (e.g. as the associated data of an AEAD).
+pub trait PacketProtector {
+    /// The number of bytes that sealing adds to a payload (e.g. an authentication tag).
+    fn overhead(&self) -> usize {
+        0
+    }
+
+    /// Seals the payload in place and returns its new length.
+    ///
+    /// `payload` holds the plaintext followed by [`overhead`](Self::overhead) spare bytes.
+    fn seal(&self, header: &Header, payload: &mut [u8]) -> io::Result<usize>;
+
+    /// Opens a sealed payload in place and returns the length of the plaintext.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the payload cannot be authenticated. The packet must be dropped.
+    fn open(&self, header: &Header, payload: &mut [u8]) -> io::Result<usize>;
+}
+
+/// A [`PacketProtector`] that leaves payloads as they are.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NullProtector;
+
+impl PacketProtector for NullProtector {
+    #[inline]
+    fn seal(&self, _header: &Header, payload: &mut [u8]) -> io::Result<usize> {
+        Ok(payload.len())
+    }
+
+    #[inline]
+    fn open(&self, _header: &Header, payload: &mut [u8]) -> io::Result<usize> {
+        Ok(payload.len())
+    }
+}
@@ -1,7 +1,52 @@
 use core::ops::Range;
 
+use num_traits::{PrimInt, Signed, Unsigned, WrappingSub, Zero};
+
 pub type SequenceNumber = u64;
 
+/// An unsigned integer that is used as a sequence number that can wrap around.
+pub trait WrappingSequence: PrimInt + Unsigned + WrappingSub {
+    /// The signed integer with the same width.
+    type Distance: PrimInt + Signed;
+
+    /// Returns `self - other` (wrapping), reinterpreted as a signed integer.
+    fn wrapping_distance(self, other: Self) -> Self::Distance;
+}
+
+macro_rules! impl_wrapping_sequence {
+    ($unsigned:ty, $signed:ty) => {
+        impl WrappingSequence for $unsigned {
+            type Distance = $signed;
+
+            #[inline]
+            fn wrapping_distance(self, other: Self) -> Self::Distance {
+                self.wrapping_sub(other) as $signed
+            }
+        }
+    };
+}
+
+impl_wrapping_sequence!(u8, i8);
+impl_wrapping_sequence!(u16, i16);
+impl_wrapping_sequence!(u32, i32);
+impl_wrapping_sequence!(u64, i64);
+
+/// Returns how far ahead of `b` the sequence `a` is (negative if `a` is behind `b`),
+/// accounting for wraparound.
+///
+/// Two sequences exactly half the range apart have no defined order. The distance between
+/// them is always the minimum value of the signed type.
+#[inline]
+pub fn sequence_distance<S: WrappingSequence>(a: S, b: S) -> S::Distance {
+    a.wrapping_distance(b)
+}
+
+/// Returns `true` if `a` is newer than `b`, accounting for wraparound.
+#[inline]
+pub fn sequence_greater_than<S: WrappingSequence>(a: S, b: S) -> bool {
+    sequence_distance(a, b) > S::Distance::zero()
+}
+
 pub(crate) struct SequenceBuffer<T> {
     sequences: Box<[Option<SequenceNumber>]>,
     data: Box<[Option<T>]>,
@@ -11,12 +56,12 @@ impl<T> SequenceBuffer<T> {
     pub fn with_capacity(capacity: usize) -> Self {
         Self {
             sequences: vec![None; capacity].into_boxed_slice(),
-            data: vec![None; capacity].into_boxed_slice(),
+            data: (0..capacity).map(|_| None).collect(),
         }
     }
 
     pub fn capacity(&self) -> usize {
-        self.sequences.capacity()
+        self.sequences.len()
     }
 
     #[inline]
@@ -73,8 +118,8 @@ impl<T> SequenceBuffer<T> {
 
     pub fn insert(&mut self, sequence: SequenceNumber, data: T) -> &mut T {
         let index = self.index_of(sequence);
-        *self.sequences[index] = Some(sequence);
-        *self.data[index] = Some(data);
+        self.sequences[index] = Some(sequence);
+        self.data[index] = Some(data);
         self.data[index].as_mut().unwrap()
     }
 
@@ -88,19 +133,208 @@ impl<T> SequenceBuffer<T> {
         (self.sequences[index].take(), self.data[index].take())
     }
 
+    /// Removes the entries in the slots of `range` (`range.end` is excluded). The range can wrap
+    /// around. A range as long as the buffer clears all of it.
     pub fn remove_range(&mut self, range: Range<SequenceNumber>) {
+        let len = range.end.wrapping_sub(range.start);
+        if len == 0 {
+            return;
+        }
+        if len >= self.capacity() as u64 {
+            self.sequences.fill(None);
+            self.data.fill_with(|| None);
+            return;
+        }
+
         let start_idx = self.index_of(range.start);
         let end_idx = self.index_of(range.end);
 
-        if end_idx < start_idx {
+        if end_idx <= start_idx {
             self.sequences[..end_idx].fill(None);
             self.sequences[start_idx..].fill(None);
-            self.entries[..end_idx].fill(None);
-            self.entries[start_idx..].fill(None);
+            self.data[..end_idx].fill_with(|| None);
+            self.data[start_idx..].fill_with(|| None);
         } else {
             self.sequences[start_idx..end_idx].fill(None);
-            self.entries[start_idx..end_idx].fill(None);
+            self.data[start_idx..end_idx].fill_with(|| None);
+        }
+    }
+
+    /// Removes every entry, passing each one to `f` so the caller can release whatever it holds.
+    pub fn clear<F: FnMut(SequenceNumber, T)>(&mut self, mut f: F) {
+        for (sequence, data) in self.sequences.iter_mut().zip(self.data.iter_mut()) {
+            if let (Some(sequence), Some(data)) = (sequence.take(), data.take()) {
+                f(sequence, data);
+            }
+        }
+    }
+
+    /// Returns the length of the run of consecutive entries starting at `from` for which `pred`
+    /// holds. The run ends at the first missing entry or the first entry `pred` rejects.
+    pub fn ready_len<F: Fn(&T) -> bool>(&self, from: SequenceNumber, pred: F) -> usize {
+        (0..self.capacity() as u64)
+            .take_while(|&offset| {
+                matches!(self.get(from.wrapping_add(offset)), Some(Some(data)) if pred(data))
+            })
+            .count()
+    }
+
+    /// Removes and yields the run of consecutive entries starting at `from` for which `pred`
+    /// holds (see [`ready_len`](Self::ready_len)), in order.
+    ///
+    /// Entries that aren't yielded (because the iterator is dropped early) stay in the buffer.
+    #[allow(dead_code)]
+    pub fn drain_ready<F: Fn(&T) -> bool>(
+        &mut self,
+        from: SequenceNumber,
+        pred: F,
+    ) -> impl Iterator<Item = (SequenceNumber, T)> + '_ {
+        let len = self.ready_len(from, pred) as u64;
+        (0..len).map(move |offset| {
+            let sequence = from.wrapping_add(offset);
+            (sequence, self.remove(sequence).unwrap())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_comparisons() {
+        assert_eq!(sequence_distance(5u64, 3), 2);
+        assert_eq!(sequence_distance(3u64, 5), -2);
+        assert!(sequence_greater_than(5u64, 3));
+        assert!(!sequence_greater_than(3u64, 5));
+        assert!(!sequence_greater_than(3u64, 3));
+    }
+
+    #[test]
+    fn sequence_comparisons_across_wraparound() {
+        assert_eq!(sequence_distance(1u64, u64::MAX), 2);
+        assert_eq!(sequence_distance(u64::MAX, 1u64), -2);
+        assert!(sequence_greater_than(0u64, u64::MAX));
+        assert!(!sequence_greater_than(u64::MAX, 0u64));
+
+        assert_eq!(sequence_distance(2u8, 254), 4);
+        assert!(sequence_greater_than(2u8, 254));
+        assert!(!sequence_greater_than(254u8, 2));
+
+        assert_eq!(sequence_distance(0u16, u16::MAX - 9), 10);
+        assert!(sequence_greater_than(0u16, u16::MAX - 9));
+    }
+
+    #[test]
+    fn sequence_comparisons_half_range_apart() {
+        // neither is newer than the other
+        assert_eq!(sequence_distance(128u8, 0), i8::MIN);
+        assert_eq!(sequence_distance(0u8, 128), i8::MIN);
+        assert!(!sequence_greater_than(128u8, 0));
+        assert!(!sequence_greater_than(0u8, 128));
+    }
+
+    fn filled(capacity: usize, sequences: Range<SequenceNumber>) -> SequenceBuffer<SequenceNumber> {
+        let mut buffer = SequenceBuffer::with_capacity(capacity);
+        for sequence in sequences {
+            buffer.insert(sequence, sequence);
         }
+        buffer
+    }
+
+    #[test]
+    fn remove_range() {
+        let mut buffer = filled(8, 0..8);
+        buffer.remove_range(2..5);
+        for sequence in 0..8 {
+            assert_eq!(buffer.contains(sequence), !(2..5).contains(&sequence));
+        }
+        assert!(buffer.get_index(3).1.is_none());
+    }
+
+    #[test]
+    fn remove_range_across_wraparound() {
+        // 14..18 covers slots 6, 7, 0, and 1, which hold 14, 15, 8, and 9
+        let mut buffer = filled(8, 8..16);
+        buffer.remove_range(14..18);
+        for sequence in 8..16 {
+            assert_eq!(buffer.contains(sequence), (10..14).contains(&sequence));
+        }
+        for index in [0, 1, 6, 7] {
+            assert!(buffer.get_index(index).1.is_none());
+        }
+
+        // the range can also wrap around the sequence numbers themselves
+        let mut buffer = filled(8, 0..4);
+        buffer.insert(u64::MAX - 1, u64::MAX - 1);
+        buffer.insert(u64::MAX, u64::MAX);
+        buffer.remove_range(u64::MAX - 1..1);
+        assert!(!buffer.contains(u64::MAX - 1));
+        assert!(!buffer.contains(u64::MAX));
+        assert!(!buffer.contains(0));
+        assert!(buffer.contains(1));
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn remove_range_empty_or_full() {
+        let mut buffer = filled(8, 0..8);
+        buffer.remove_range(3..3);
+        assert!((0..8).all(|sequence| buffer.contains(sequence)));
+
+        buffer.remove_range(4..12);
+        assert!((0..8).all(|sequence| !buffer.contains(sequence)));
+    }
+
+    #[test]
+    fn clear_yields_removed_entries() {
+        let mut buffer = filled(8, 5..11);
+        let mut removed = Vec::new();
+        buffer.clear(|sequence, data| removed.push((sequence, data)));
+        removed.sort_unstable();
+        assert_eq!(removed, [(5, 5), (6, 6), (7, 7), (8, 8), (9, 9), (10, 10)]);
+        assert!((0..16).all(|sequence| buffer.get(sequence).is_none()));
+
+        // clearing an empty buffer yields nothing
+        buffer.clear(|_, _| panic!("buffer should be empty"));
+    }
+
+    #[test]
+    fn drain_ready_contiguous_run() {
+        let mut buffer = filled(8, 3..7);
+        let drained = buffer.drain_ready(3, |_| true).collect::<Vec<_>>();
+        assert_eq!(drained, [(3, 3), (4, 4), (5, 5), (6, 6)]);
+        assert!((3..7).all(|sequence| !buffer.contains(sequence)));
+
+        // the run can wrap around the sequence numbers
+        let mut buffer = filled(8, 0..2);
+        buffer.insert(u64::MAX, u64::MAX);
+        let drained = buffer.drain_ready(u64::MAX, |_| true).collect::<Vec<_>>();
+        assert_eq!(drained, [(u64::MAX, u64::MAX), (0, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn drain_ready_stops_at_gap() {
+        let mut buffer = filled(8, 0..8);
+        buffer.remove(3);
+        let drained = buffer.drain_ready(0, |_| true).collect::<Vec<_>>();
+        assert_eq!(drained, [(0, 0), (1, 1), (2, 2)]);
+        assert!((4..8).all(|sequence| buffer.contains(sequence)));
+
+        // an entry the predicate rejects is a gap too
+        let drained = buffer.drain_ready(4, |&data| data != 6).collect::<Vec<_>>();
+        assert_eq!(drained, [(4, 4), (5, 5)]);
+        assert!(buffer.contains(6));
+        assert!(buffer.contains(7));
+    }
+
+    #[test]
+    fn drain_ready_empty() {
+        let mut buffer = SequenceBuffer::<SequenceNumber>::with_capacity(8);
+        assert_eq!(buffer.drain_ready(0, |_| true).count(), 0);
+
+        // stale entries in the slots don't count
+        let mut buffer = filled(8, 0..8);
+        assert_eq!(buffer.drain_ready(8, |_| true).count(), 0);
+        assert!((0..8).all(|sequence| buffer.contains(sequence)));
+    }
+}
@@ -0,0 +1,120 @@
+use std::{collections::HashMap, net::IpAddr, time::Instant};
+
+use super::{config::Config, constants::MAX_HANDSHAKE_SOURCES};
+
+/// Allows events at a steady rate, with bursts of up to `capacity` events.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TokenBucket {
+    tokens: f32,
+    time_latest_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Constructs a new `TokenBucket` holding `capacity` tokens.
+    pub(crate) fn new(capacity: usize, now: Instant) -> Self {
+        Self {
+            tokens: capacity as f32,
+            time_latest_refill: now,
+        }
+    }
+
+    /// Adds the tokens earned at `rate` per second since the last refill, up to `capacity`.
+    fn refill(&mut self, rate: f32, capacity: usize, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.time_latest_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f32() * rate).min(capacity as f32);
+        self.time_latest_refill = now;
+    }
+
+    /// Takes a token if there is one and returns `true`, otherwise returns `false`.
+    pub(crate) fn try_take(&mut self, rate: f32, capacity: usize, now: Instant) -> bool {
+        self.refill(rate, capacity, now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Returns `true` if the bucket will have refilled completely by `now`.
+    fn is_full(&self, rate: f32, capacity: usize, now: Instant) -> bool {
+        let mut bucket = *self;
+        bucket.refill(rate, capacity, now);
+        bucket.tokens >= capacity as f32
+    }
+}
+
+/// Limits how many handshake packets each source address can send, so that a single host
+/// can't flood the endpoint with connection attempts.
+pub(crate) struct HandshakeLimiter {
+    buckets: HashMap<IpAddr, TokenBucket>,
+    rate: f32,
+    burst: usize,
+}
+
+impl HandshakeLimiter {
+    /// Constructs a new `HandshakeLimiter` with the rate and burst from `config`.
+    pub(crate) fn new(config: &Config) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            rate: config.handshake_rate(),
+            burst: config.handshake_burst(),
+        }
+    }
+
+    /// Returns `true` if a handshake packet from `addr` should be processed, or `false` if the
+    /// address has sent too many recently.
+    pub(crate) fn allow(&mut self, addr: IpAddr, now: Instant) -> bool {
+        if self.buckets.len() >= MAX_HANDSHAKE_SOURCES && !self.buckets.contains_key(&addr) {
+            // a full bucket is no different from a new one
+            let (rate, burst) = (self.rate, self.burst);
+            self.buckets.retain(|_, bucket| !bucket.is_full(rate, burst, now));
+            if self.buckets.len() >= MAX_HANDSHAKE_SOURCES {
+                return false;
+            }
+        }
+
+        self.buckets
+            .entry(addr)
+            .or_insert_with(|| TokenBucket::new(self.burst, now))
+            .try_take(self.rate, self.burst, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn token_bucket_refills_at_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, start);
+        assert!(bucket.try_take(4.0, 2, start));
+        assert!(bucket.try_take(4.0, 2, start));
+        assert!(!bucket.try_take(4.0, 2, start));
+
+        // a token every 250ms
+        assert!(!bucket.try_take(4.0, 2, start + Duration::from_millis(200)));
+        assert!(bucket.try_take(4.0, 2, start + Duration::from_millis(250)));
+
+        // never more than the capacity
+        let later = start + Duration::from_secs(10);
+        assert!(bucket.try_take(4.0, 2, later));
+        assert!(bucket.try_take(4.0, 2, later));
+        assert!(!bucket.try_take(4.0, 2, later));
+    }
+
+    #[test]
+    fn handshake_limiter_tracks_each_source() {
+        let now = Instant::now();
+        let config = Config::builder().handshake_burst(3).build().unwrap();
+        let mut limiter = HandshakeLimiter::new(&config);
+
+        let flooder = "10.0.0.1".parse().unwrap();
+        let allowed = (0..10).filter(|_| limiter.allow(flooder, now)).count();
+        assert_eq!(allowed, 3);
+
+        // other sources aren't affected
+        assert!(limiter.allow("10.0.0.2".parse().unwrap(), now));
+    }
+}
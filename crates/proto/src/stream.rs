@@ -0,0 +1,286 @@
+use std::{collections::HashMap, io::{self, Read}};
+
+use super::{
+    connection::{Connections, Receive, Send},
+    cursor::{Bytes, BytesMut},
+    error::{ErrorKind, ProtoError},
+};
+
+type ConnectionId = u64;
+type ChannelId = u64;
+
+// stream id, flags
+pub(crate) const STREAM_HEADER_BYTES: usize = 8 + 1;
+/// Set on the last chunk of a stream.
+const STREAM_FINAL: u8 = 0x01;
+
+/// Sends a stream of bytes of any length (e.g. a large asset) as a series of messages, or
+/// chunks, on a reliable and ordered channel.
+///
+/// Each chunk starts with the stream id and a flag that marks the last one, and is as large as
+/// a message on the connection can be. The remote endpoint puts the stream back together with
+/// [`RecvStreams`]. Since the channel delivers the chunks in order, several streams can share
+/// it as long as their ids differ.
+pub struct SendStream<R> {
+    id: u64,
+    reader: R,
+    /// A chunk that has been read but couldn't be queued yet.
+    pending: Option<Vec<u8>>,
+    /// Whether the last chunk has been read.
+    finished: bool,
+}
+
+impl<R: Read> SendStream<R> {
+    /// Constructs a new `SendStream` that sends everything `reader` returns as the stream `id`.
+    pub fn new(id: u64, reader: R) -> Self {
+        Self {
+            id,
+            reader,
+            pending: None,
+            finished: false,
+        }
+    }
+
+    /// The id of the stream.
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Queues as many chunks of the stream as fit on `channel` of the connection `conn`, and
+    /// returns `true` once the last one has been queued.
+    ///
+    /// Once the channel's send window is full or there are no buffers left, this returns
+    /// `false`. Call it again once the remote endpoint has acknowledged some of the chunks.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ChannelNotReliableOrdered`](ErrorKind::ChannelNotReliableOrdered) if the
+    /// channel wasn't declared as reliable and ordered, an error if `reader` fails, or the
+    /// reason a chunk couldn't be queued (see [`Connections::send_message`]). A chunk that
+    /// couldn't be queued is tried again by the next call.
+    pub fn send(
+        &mut self,
+        connections: &mut Connections,
+        conn: ConnectionId,
+        channel: ChannelId,
+    ) -> Result<bool, ProtoError> {
+        match connections.channel_config().guarantees(channel) {
+            Some((Send::Reliable, Receive::Ordered)) => {},
+            Some(_) => return Err(ErrorKind::ChannelNotReliableOrdered.into()),
+            None => return Err(ErrorKind::ChannelNotDeclared.into()),
+        }
+        let max_chunk_bytes = connections
+            .connection(conn)
+            .ok_or(ErrorKind::ConnectionNotFound)?
+            .max_message_bytes();
+
+        loop {
+            let chunk = match self.pending.take() {
+                Some(chunk) => chunk,
+                None => match self.next_chunk(max_chunk_bytes)? {
+                    Some(chunk) => chunk,
+                    None => return Ok(true),
+                },
+            };
+
+            match connections.send_message(conn, channel, &chunk) {
+                Ok(()) => {},
+                // the rest has to wait until the remote endpoint catches up
+                Err(ProtoError::Protocol(ErrorKind::SendWindowFull | ErrorKind::NotEnoughBuffersAvailable)) => {
+                    self.pending = Some(chunk);
+                    return Ok(false);
+                },
+                Err(err) => {
+                    self.pending = Some(chunk);
+                    return Err(err);
+                },
+            }
+        }
+    }
+
+    /// Reads the next chunk of the stream, at most `max_chunk_bytes` long (header included).
+    /// Returns `None` once the last chunk has been returned.
+    ///
+    /// A chunk is only cut short when the reader runs out (which makes it the last one) or
+    /// would block. If the stream ends right after a full chunk, the last chunk is empty.
+    pub fn next_chunk(&mut self, max_chunk_bytes: usize) -> io::Result<Option<Vec<u8>>> {
+        if self.finished {
+            return Ok(None);
+        }
+        if max_chunk_bytes <= STREAM_HEADER_BYTES {
+            return Err(io::Error::new_const(io::ErrorKind::InvalidInput, &"chunk too small"));
+        }
+
+        let mut chunk = vec![0; max_chunk_bytes];
+        let mut len = STREAM_HEADER_BYTES;
+        while len < chunk.len() {
+            match self.reader.read(&mut chunk[len..]) {
+                Ok(0) => {
+                    self.finished = true;
+                    break;
+                },
+                Ok(n) => len += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+                // send what has been read so far
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock && len > STREAM_HEADER_BYTES => break,
+                Err(err) => return Err(err),
+            }
+        }
+        chunk.truncate(len);
+
+        let mut buf = BytesMut::new(&mut chunk);
+        buf.write::<u64>(self.id)?;
+        buf.write::<u8>(if self.finished { STREAM_FINAL } else { 0 })?;
+
+        Ok(Some(chunk))
+    }
+}
+
+/// Puts the streams sent with [`SendStream`] back together.
+#[derive(Debug, Default)]
+pub struct RecvStreams {
+    /// The bytes received so far of each stream that hasn't ended yet.
+    partial: HashMap<u64, Vec<u8>>,
+}
+
+impl RecvStreams {
+    /// Constructs a new `RecvStreams` without any streams in progress.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a chunk received from the remote endpoint (a whole message) to its stream.
+    ///
+    /// Returns the id and the bytes of the stream once its last chunk has arrived. The chunks
+    /// must be passed in the order they were received.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with [`InvalidData`](io::ErrorKind::InvalidData) if `chunk` isn't part
+    /// of a stream.
+    pub fn recv(&mut self, chunk: &[u8]) -> io::Result<Option<(u64, Vec<u8>)>> {
+        let mut buf = Bytes::new(chunk);
+        let id = buf.read::<u64>().map_err(|_| invalid_chunk())?;
+        let flags = buf.read::<u8>().map_err(|_| invalid_chunk())?;
+        if flags & !STREAM_FINAL != 0 {
+            return Err(invalid_chunk());
+        }
+
+        let data = self.partial.entry(id).or_default();
+        data.extend_from_slice(&chunk[STREAM_HEADER_BYTES..]);
+        if flags & STREAM_FINAL == 0 {
+            return Ok(None);
+        }
+
+        Ok(self.partial.remove(&id).map(|data| (id, data)))
+    }
+
+    /// The number of streams that have started but not ended yet.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.partial.len()
+    }
+
+    /// Returns `true` if no stream is in progress.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.partial.is_empty()
+    }
+}
+
+fn invalid_chunk() -> io::Error {
+    io::Error::new_const(io::ErrorKind::InvalidData, &"invalid stream chunk")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns `len` bytes that don't repeat with any short period.
+    fn pattern(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stream_round_trips_multi_megabyte_buffer() {
+        let data = pattern(3 * 1024 * 1024 + 123);
+        let mut stream = SendStream::new(7, &data[..]);
+        let mut streams = RecvStreams::new();
+
+        let max_chunk_bytes = 64 * 1024;
+        let mut chunks = 0;
+        let mut received = None;
+        while let Some(chunk) = stream.next_chunk(max_chunk_bytes).unwrap() {
+            assert!(chunk.len() <= max_chunk_bytes);
+            assert!(received.is_none());
+            received = streams.recv(&chunk).unwrap();
+            chunks += 1;
+        }
+
+        assert_eq!(chunks, data.len() / (max_chunk_bytes - STREAM_HEADER_BYTES) + 1);
+        let (id, bytes) = received.unwrap();
+        assert_eq!(id, 7);
+        assert!(bytes == data);
+        assert!(streams.is_empty());
+    }
+
+    #[test]
+    fn streams_can_be_interleaved() {
+        let first = pattern(1000);
+        let second = pattern(2500);
+        let mut streams = [SendStream::new(1, &first[..]), SendStream::new(2, &second[..])];
+        let mut recv = RecvStreams::new();
+
+        let mut done = Vec::new();
+        while done.len() < 2 {
+            for stream in streams.iter_mut() {
+                if let Some(chunk) = stream.next_chunk(STREAM_HEADER_BYTES + 300).unwrap() {
+                    done.extend(recv.recv(&chunk).unwrap());
+                }
+            }
+        }
+
+        assert_eq!(done, [(1, first), (2, second)]);
+    }
+
+    #[test]
+    fn last_chunk_can_be_empty() {
+        let data = pattern(200);
+        let mut stream = SendStream::new(3, &data[..]);
+        let mut streams = RecvStreams::new();
+
+        // the stream ends exactly at the end of the second chunk
+        for _ in 0..2 {
+            let chunk = stream.next_chunk(STREAM_HEADER_BYTES + 100).unwrap().unwrap();
+            assert_eq!(streams.recv(&chunk).unwrap(), None);
+        }
+        assert_eq!(streams.len(), 1);
+        let chunk = stream.next_chunk(STREAM_HEADER_BYTES + 100).unwrap().unwrap();
+        assert_eq!(chunk.len(), STREAM_HEADER_BYTES);
+        assert_eq!(streams.recv(&chunk).unwrap(), Some((3, data.clone())));
+        assert_eq!(stream.next_chunk(STREAM_HEADER_BYTES + 100).unwrap(), None);
+    }
+
+    #[test]
+    fn recv_rejects_invalid_chunks() {
+        let mut streams = RecvStreams::new();
+        assert_eq!(streams.recv(&[0; 4]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(streams.recv(&[0, 0, 0, 0, 0, 0, 0, 1, 0x80]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(streams.is_empty());
+
+        let mut stream = SendStream::new(1, &[1, 2, 3][..]);
+        assert_eq!(
+            stream.next_chunk(STREAM_HEADER_BYTES).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput,
+        );
+    }
+}
@@ -1,3 +1,7 @@
+use std::time::{Duration, Instant};
+
+use crate::{duration_of, ticks_in, Tick, TickClock};
+
 /// Controls the capabilities of the application.
 pub enum AppMode {
     /// Playable, can connect to a server or relay, not (fully) authoritative.
@@ -63,11 +67,42 @@ pub enum Updates {
     Filtered,
 }
 
-enum Unit {
-    Time(std::time::Duration),
+/// An amount of time, given either directly or as a number of simulation steps.
+pub enum Unit {
+    Time(Duration),
     Ticks(usize),
 }
 
+impl Unit {
+    /// Returns the number of ticks this amount covers at `tick_rate` ticks per second. A
+    /// partial tick counts as a whole one, so that the ticks always cover the time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tick_rate` is zero.
+    pub fn to_ticks(&self, tick_rate: usize) -> usize {
+        assert!(tick_rate > 0);
+        match *self {
+            Unit::Time(duration) => ticks_in(duration, tick_rate) as usize,
+            Unit::Ticks(ticks) => ticks,
+        }
+    }
+
+    /// Returns the time this amount covers at `tick_rate` ticks per second (rounded up to the
+    /// nanosecond).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tick_rate` is zero.
+    pub fn to_duration(&self, tick_rate: usize) -> Duration {
+        assert!(tick_rate > 0);
+        match *self {
+            Unit::Time(duration) => duration,
+            Unit::Ticks(ticks) => duration_of(ticks as Tick, tick_rate),
+        }
+    }
+}
+
 /// Controls how the server compensates lag between clients, i.e. for hit detection.
 pub enum Rewind {
     /// The server does not rewind state.
@@ -93,4 +128,73 @@ pub struct Simulation {
     max_entities: usize,
     max_players: usize,
     max_ping: Unit,
+}
+
+impl Simulation {
+    /// Constructs a new `Simulation` that steps `tick_rate` times per second, for up to
+    /// `max_entities` entities and `max_players` players whose ping is at most `max_ping`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tick_rate` is zero.
+    pub fn new(tick_rate: usize, max_entities: usize, max_players: usize, max_ping: Unit) -> Self {
+        assert!(tick_rate > 0);
+        Self {
+            tick_rate,
+            max_entities,
+            max_players,
+            max_ping,
+        }
+    }
+
+    /// Returns the number of ticks per second.
+    #[inline]
+    pub fn tick_rate(&self) -> usize {
+        self.tick_rate
+    }
+
+    /// Returns a clock for the simulation's ticks, with tick 0 beginning at `start`.
+    pub fn tick_clock(&self, start: Instant) -> TickClock {
+        TickClock::new(self.tick_rate, start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_converts_time_to_ticks_and_back() {
+        // 50ms is exactly 3 ticks at 60Hz, despite the ticks not being whole nanoseconds
+        let ping = Unit::Time(Duration::from_millis(50));
+        assert_eq!(ping.to_ticks(60), 3);
+        assert_eq!(ping.to_duration(60), Duration::from_millis(50));
+        assert_eq!(Unit::Ticks(ping.to_ticks(60)).to_duration(60), Duration::from_millis(50));
+
+        // a partial tick is rounded up
+        assert_eq!(Unit::Time(Duration::from_millis(51)).to_ticks(60), 4);
+        assert_eq!(Unit::Time(Duration::ZERO).to_ticks(60), 0);
+        for ticks in 0..240 {
+            assert_eq!(Unit::Time(Unit::Ticks(ticks).to_duration(60)).to_ticks(60), ticks);
+        }
+    }
+
+    #[test]
+    fn unit_converts_ticks_to_time() {
+        let delay = Unit::Ticks(3);
+        assert_eq!(delay.to_duration(30), Duration::from_millis(100));
+        assert_eq!(delay.to_ticks(30), 3);
+        assert_eq!(Unit::Time(delay.to_duration(30)).to_ticks(30), 3);
+    }
+
+    #[test]
+    fn simulation_clock_ticks_at_its_rate() {
+        let simulation = Simulation::new(60, 1024, 8, Unit::Time(Duration::from_millis(150)));
+        let start = Instant::now();
+        let clock = simulation.tick_clock(start);
+        assert_eq!(clock.tick_rate(), simulation.tick_rate());
+        assert_eq!(clock.tick(start + Duration::from_millis(49)), 2);
+        assert_eq!(clock.tick(start + Duration::from_millis(50)), 3);
+        assert_eq!(clock.instant(3), start + Duration::from_millis(50));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,324 @@
+use std::{collections::BTreeMap, fmt};
+
+use crate::{EntityId, PlayerId};
+
+/// The networked state at one point in time, as the serialized fields of each entity.
+pub type Snapshot = BTreeMap<EntityId, Vec<Vec<u8>>>;
+
+/// The entity is written in full (it's new or its number of fields changed).
+const ENTITY_FULL: u8 = 0;
+/// Only the fields of the entity that changed are written.
+const ENTITY_FIELDS: u8 = 1;
+
+/// The reason a delta couldn't be applied to its baseline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeltaError {
+    /// The delta ends in the middle of a value.
+    Truncated,
+    /// The delta has bytes left over after its last entity.
+    TrailingBytes,
+    /// The delta removes or changes an entity that isn't in the baseline.
+    EntityNotInBaseline,
+    /// The delta changes a field that the entity doesn't have.
+    FieldIndexInvalid,
+    /// The delta encodes an entity in a way that doesn't exist.
+    EntityKindInvalid,
+}
+
+impl fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            DeltaError::Truncated => "delta is truncated",
+            DeltaError::TrailingBytes => "delta has trailing bytes",
+            DeltaError::EntityNotInBaseline => "entity is not in the baseline",
+            DeltaError::FieldIndexInvalid => "field index is out of range",
+            DeltaError::EntityKindInvalid => "unknown entity encoding",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for DeltaError {}
+
+/// Encodes snapshots as their difference from a baseline snapshot the receiver already has.
+///
+/// This is what makes [`Updates::Filtered`](crate::Updates::Filtered) cheap: entities
+/// that didn't change aren't sent at all.
+pub struct DeltaWriter<'a> {
+    baseline: &'a Snapshot,
+}
+
+impl<'a> DeltaWriter<'a> {
+    /// Constructs a new `DeltaWriter` that encodes snapshots against `baseline`.
+    pub fn new(baseline: &'a Snapshot) -> Self {
+        Self { baseline }
+    }
+
+    /// Appends the delta from the baseline to `current` to `out`.
+    ///
+    /// The delta lists the entities that were removed, then the ones that were added or
+    /// changed. An entity that has the same number of fields in both snapshots only has the
+    /// fields that changed written. Otherwise, it's written in full.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an entity has more than [`u16::MAX`] fields.
+    pub fn write(&self, current: &Snapshot, out: &mut Vec<u8>) {
+        let removed = self
+            .baseline
+            .keys()
+            .filter(|entity| !current.contains_key(entity))
+            .collect::<Vec<_>>();
+        put_u32(out, removed.len() as u32);
+        for entity in removed {
+            put_entity_id(out, entity);
+        }
+
+        // the count is filled in once it's known
+        let count_pos = out.len();
+        put_u32(out, 0);
+        let mut changed = 0u32;
+        for (entity, fields) in current {
+            assert!(fields.len() <= u16::MAX as usize, "too many fields");
+            match self.baseline.get(entity) {
+                Some(baseline) if baseline == fields => continue,
+                Some(baseline) if baseline.len() == fields.len() => {
+                    put_entity_id(out, entity);
+                    out.push(ENTITY_FIELDS);
+                    let diff = (0..fields.len())
+                        .filter(|&index| baseline[index] != fields[index])
+                        .collect::<Vec<_>>();
+                    put_u16(out, diff.len() as u16);
+                    for index in diff {
+                        put_u16(out, index as u16);
+                        put_bytes(out, &fields[index]);
+                    }
+                }
+                _ => {
+                    put_entity_id(out, entity);
+                    out.push(ENTITY_FULL);
+                    put_u16(out, fields.len() as u16);
+                    for field in fields {
+                        put_bytes(out, field);
+                    }
+                }
+            }
+            changed += 1;
+        }
+        out[count_pos..(count_pos + 4)].copy_from_slice(&changed.to_le_bytes());
+    }
+}
+
+/// Reconstructs snapshots from their baseline and a delta written by [`DeltaWriter`].
+pub struct DeltaReader<'a> {
+    baseline: &'a Snapshot,
+}
+
+impl<'a> DeltaReader<'a> {
+    /// Constructs a new `DeltaReader` that applies deltas to `baseline`.
+    pub fn new(baseline: &'a Snapshot) -> Self {
+        Self { baseline }
+    }
+
+    /// Applies `delta` to the baseline and returns the snapshot it was written from.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `delta` is malformed or was written against a different baseline.
+    pub fn read(&self, mut delta: &[u8]) -> Result<Snapshot, DeltaError> {
+        let buf = &mut delta;
+        let mut snapshot = self.baseline.clone();
+
+        for _ in 0..take_u32(buf)? {
+            let entity = take_entity_id(buf)?;
+            snapshot
+                .remove(&entity)
+                .ok_or(DeltaError::EntityNotInBaseline)?;
+        }
+
+        for _ in 0..take_u32(buf)? {
+            let entity = take_entity_id(buf)?;
+            match take::<1>(buf)?[0] {
+                ENTITY_FULL => {
+                    let fields = (0..take_u16(buf)?)
+                        .map(|_| take_bytes(buf))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    snapshot.insert(entity, fields);
+                }
+                ENTITY_FIELDS => {
+                    let fields = snapshot
+                        .get_mut(&entity)
+                        .ok_or(DeltaError::EntityNotInBaseline)?;
+                    for _ in 0..take_u16(buf)? {
+                        let index = take_u16(buf)? as usize;
+                        let value = take_bytes(buf)?;
+                        *fields.get_mut(index).ok_or(DeltaError::FieldIndexInvalid)? = value;
+                    }
+                }
+                _ => return Err(DeltaError::EntityKindInvalid),
+            }
+        }
+
+        if !buf.is_empty() {
+            return Err(DeltaError::TrailingBytes);
+        }
+
+        Ok(snapshot)
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Writes the length of `bytes`, then `bytes`.
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+fn put_player_id(out: &mut Vec<u8>, player: Option<PlayerId>) {
+    match player {
+        Some(PlayerId(id)) => {
+            out.push(1);
+            put_u32(out, id);
+        }
+        None => out.push(0),
+    }
+}
+
+fn put_entity_id(out: &mut Vec<u8>, entity: &EntityId) {
+    out.extend_from_slice(&entity.id.to_le_bytes());
+    put_player_id(out, entity.input_source);
+    put_player_id(out, entity.state_source);
+}
+
+/// Takes the next `N` bytes off the front of `buf`.
+fn take<const N: usize>(buf: &mut &[u8]) -> Result<[u8; N], DeltaError> {
+    if buf.len() < N {
+        return Err(DeltaError::Truncated);
+    }
+    let (bytes, rest) = buf.split_at(N);
+    *buf = rest;
+    Ok(bytes.try_into().unwrap())
+}
+
+fn take_u16(buf: &mut &[u8]) -> Result<u16, DeltaError> {
+    take(buf).map(u16::from_le_bytes)
+}
+
+fn take_u32(buf: &mut &[u8]) -> Result<u32, DeltaError> {
+    take(buf).map(u32::from_le_bytes)
+}
+
+fn take_bytes(buf: &mut &[u8]) -> Result<Vec<u8>, DeltaError> {
+    let len = take_u32(buf)? as usize;
+    if buf.len() < len {
+        return Err(DeltaError::Truncated);
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(bytes.to_vec())
+}
+
+fn take_player_id(buf: &mut &[u8]) -> Result<Option<PlayerId>, DeltaError> {
+    match take::<1>(buf)?[0] {
+        0 => Ok(None),
+        _ => Ok(Some(PlayerId(take_u32(buf)?))),
+    }
+}
+
+fn take_entity_id(buf: &mut &[u8]) -> Result<EntityId, DeltaError> {
+    Ok(EntityId {
+        id: take(buf).map(u64::from_le_bytes)?,
+        input_source: take_player_id(buf)?,
+        state_source: take_player_id(buf)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity(id: u64) -> EntityId {
+        EntityId {
+            id,
+            input_source: Some(PlayerId(7)),
+            state_source: None,
+        }
+    }
+
+    fn fields(values: &[&[u8]]) -> Vec<Vec<u8>> {
+        values.iter().map(|value| value.to_vec()).collect()
+    }
+
+    #[test]
+    fn delta_reconstructs_snapshot() {
+        let baseline = Snapshot::from([
+            (entity(1), fields(&[b"unchanged", b"fields"])),
+            (entity(2), fields(&[b"removed"])),
+            (entity(3), fields(&[b"position", b"velocity", b"health"])),
+            (entity(4), fields(&[b"one field"])),
+        ]);
+        let current = Snapshot::from([
+            (entity(1), fields(&[b"unchanged", b"fields"])),
+            (entity(3), fields(&[b"position", b"VELOCITY", b"health"])),
+            (entity(4), fields(&[b"one field", b"and another"])),
+            (entity(5), fields(&[b"added"])),
+        ]);
+
+        let mut delta = Vec::new();
+        DeltaWriter::new(&baseline).write(&current, &mut delta);
+        assert_eq!(DeltaReader::new(&baseline).read(&delta), Ok(current));
+
+        // only what changed is sent
+        let contains = |bytes: &[u8]| delta.windows(bytes.len()).any(|window| window == bytes);
+        assert!(!contains(b"unchanged"));
+        assert!(!contains(b"position"));
+        assert!(!contains(b"removed"));
+        assert!(contains(b"VELOCITY"));
+        assert!(contains(b"and another"));
+        assert!(contains(b"added"));
+    }
+
+    #[test]
+    fn delta_of_same_snapshot_is_empty() {
+        let baseline = Snapshot::from([(entity(1), fields(&[b"a", b"b"]))]);
+
+        let mut delta = Vec::new();
+        DeltaWriter::new(&baseline).write(&baseline, &mut delta);
+        assert_eq!(delta, [0; 8]);
+        assert_eq!(DeltaReader::new(&baseline).read(&delta), Ok(baseline));
+    }
+
+    #[test]
+    fn delta_against_wrong_baseline_fails() {
+        let baseline = Snapshot::from([(entity(1), fields(&[b"a", b"b"]))]);
+        let current = Snapshot::from([(entity(1), fields(&[b"a", b"c"]))]);
+
+        let mut delta = Vec::new();
+        DeltaWriter::new(&baseline).write(&current, &mut delta);
+
+        let other = Snapshot::from([(entity(2), fields(&[b"a", b"b"]))]);
+        assert_eq!(
+            DeltaReader::new(&other).read(&delta),
+            Err(DeltaError::EntityNotInBaseline)
+        );
+        let other = Snapshot::from([(entity(1), fields(&[b"a"]))]);
+        assert_eq!(
+            DeltaReader::new(&other).read(&delta),
+            Err(DeltaError::FieldIndexInvalid)
+        );
+
+        let reader = DeltaReader::new(&baseline);
+        for len in 0..delta.len() {
+            assert_eq!(reader.read(&delta[..len]), Err(DeltaError::Truncated));
+        }
+        delta.push(0);
+        assert_eq!(reader.read(&delta), Err(DeltaError::TrailingBytes));
+    }
+}
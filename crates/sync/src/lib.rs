@@ -1,12 +1,158 @@
 mod config;
+mod delta;
+mod lockstep;
+mod rewind;
 mod time;
 
+pub use config::*;
+pub use delta::*;
+pub use lockstep::*;
+pub use rewind::*;
 pub use time::*;
 
+/// The number of a simulation step.
+pub type Tick = u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct PlayerId(u32);
 
+impl PlayerId {
+    /// Constructs a new `PlayerId`.
+    pub fn new(id: u32) -> Self {
+        Self(id)
+    }
+
+    /// Returns the underlying id.
+    pub fn id(&self) -> u32 {
+        self.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct EntityId {
     id: u64,
     input_source: Option<PlayerId>,
     state_source: Option<PlayerId>,
-}
\ No newline at end of file
+}
+
+impl EntityId {
+    /// Constructs a new `EntityId` that no player controls.
+    pub fn new(id: u64) -> Self {
+        Self {
+            id,
+            input_source: None,
+            state_source: None,
+        }
+    }
+
+    /// Makes `player` the one whose inputs drive the entity.
+    pub fn with_input_source(mut self, player: PlayerId) -> Self {
+        self.input_source = Some(player);
+        self
+    }
+
+    /// Makes `player` the owner of the entity's state.
+    ///
+    /// Only matters with [`Client`](Authority::Client) or
+    /// [`Distributed`](Authority::Distributed) authority.
+    pub fn with_state_source(mut self, player: PlayerId) -> Self {
+        self.state_source = Some(player);
+        self
+    }
+
+    /// Returns the underlying id.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the player whose inputs drive the entity, if there is one.
+    pub fn input_source(&self) -> Option<PlayerId> {
+        self.input_source
+    }
+
+    /// Returns the player that owns the entity's state, if there is one.
+    pub fn state_source(&self) -> Option<PlayerId> {
+        self.state_source
+    }
+
+    /// Returns `true` if `player` may send inputs for the entity.
+    ///
+    /// Only its input source can, no matter who has authority over its state.
+    pub fn can_write_input(&self, player: PlayerId) -> bool {
+        self.input_source == Some(player)
+    }
+
+    /// Returns `true` if `player` may write the entity's state under the given `authority`.
+    ///
+    /// - With [`Server`](Authority::Server) authority, no player can.
+    /// - With [`Client`](Authority::Client) authority, its state source can. An entity without
+    ///   one belongs to its input source.
+    /// - With [`Distributed`](Authority::Distributed) authority, its state source can. An
+    ///   entity without one belongs to the server.
+    pub fn can_write_state(&self, player: PlayerId, authority: &Authority) -> bool {
+        let owner = match authority {
+            Authority::Server => None,
+            Authority::Client => self.state_source.or(self.input_source),
+            Authority::Distributed => self.state_source,
+        };
+        owner == Some(player)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: PlayerId = PlayerId(1);
+    const BOB: PlayerId = PlayerId(2);
+
+    #[test]
+    fn server_authority() {
+        let entity = EntityId::new(0)
+            .with_input_source(ALICE)
+            .with_state_source(BOB);
+
+        assert!(entity.can_write_input(ALICE));
+        assert!(!entity.can_write_input(BOB));
+        assert!(!entity.can_write_state(ALICE, &Authority::Server));
+        assert!(!entity.can_write_state(BOB, &Authority::Server));
+    }
+
+    #[test]
+    fn client_authority() {
+        let controlled = EntityId::new(0).with_input_source(ALICE);
+        assert!(controlled.can_write_input(ALICE));
+        assert!(controlled.can_write_state(ALICE, &Authority::Client));
+        assert!(!controlled.can_write_state(BOB, &Authority::Client));
+
+        // the state source takes precedence
+        let handed_off = controlled.with_state_source(BOB);
+        assert!(handed_off.can_write_input(ALICE));
+        assert!(!handed_off.can_write_input(BOB));
+        assert!(!handed_off.can_write_state(ALICE, &Authority::Client));
+        assert!(handed_off.can_write_state(BOB, &Authority::Client));
+
+        let unowned = EntityId::new(1);
+        assert!(!unowned.can_write_input(ALICE));
+        assert!(!unowned.can_write_state(ALICE, &Authority::Client));
+    }
+
+    #[test]
+    fn distributed_authority() {
+        // the server keeps what no player was given
+        let controlled = EntityId::new(0).with_input_source(ALICE);
+        assert!(controlled.can_write_input(ALICE));
+        assert!(!controlled.can_write_state(ALICE, &Authority::Distributed));
+
+        let owned = controlled.with_state_source(ALICE);
+        assert!(owned.can_write_state(ALICE, &Authority::Distributed));
+        assert!(!owned.can_write_state(BOB, &Authority::Distributed));
+
+        let shared = EntityId::new(1)
+            .with_input_source(ALICE)
+            .with_state_source(BOB);
+        assert!(shared.can_write_input(ALICE));
+        assert!(!shared.can_write_state(ALICE, &Authority::Distributed));
+        assert!(shared.can_write_state(BOB, &Authority::Distributed));
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use crate::{PlayerId, Tick};
+
+/// The reason an input was rejected by [`Lockstep::submit_input`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockstepError {
+    /// The player isn't part of the simulation.
+    UnknownPlayer,
+    /// The tick has already been stepped.
+    TickAlreadyStepped,
+    /// The tick is further ahead than inputs are buffered for.
+    TickTooFarAhead,
+    /// The player already submitted an input for the tick.
+    InputAlreadySubmitted,
+}
+
+impl fmt::Display for LockstepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            LockstepError::UnknownPlayer => "player is not part of the simulation",
+            LockstepError::TickAlreadyStepped => "tick has already been stepped",
+            LockstepError::TickTooFarAhead => "tick is too far ahead",
+            LockstepError::InputAlreadySubmitted => "input was already submitted for this tick",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for LockstepError {}
+
+/// Gates a deterministic simulation so that a tick is only stepped once every player's input
+/// for it has arrived, i.e. [`Prediction::None`](crate::Prediction::None).
+///
+/// Inputs for later ticks are buffered until their tick comes up, as long as it's within
+/// `max_ticks_ahead` of the next one.
+pub struct Lockstep {
+    players: BTreeSet<PlayerId>,
+    next_tick: Tick,
+    max_ticks_ahead: Tick,
+    /// The inputs that have arrived for each tick that hasn't been stepped yet.
+    pending: BTreeMap<Tick, BTreeMap<PlayerId, Vec<u8>>>,
+    /// The inputs of the tick last returned by [`try_advance`](Self::try_advance).
+    stepped: BTreeMap<PlayerId, Vec<u8>>,
+}
+
+impl Lockstep {
+    /// Constructs a new `Lockstep` for `players` that starts at `first_tick` and buffers inputs
+    /// for up to `max_ticks_ahead` ticks after the next one.
+    ///
+    /// Without any players, there's no input to wait for and no one to step for, so it never
+    /// advances.
+    pub fn new(
+        players: impl IntoIterator<Item = PlayerId>,
+        first_tick: Tick,
+        max_ticks_ahead: Tick,
+    ) -> Self {
+        Self {
+            players: players.into_iter().collect(),
+            next_tick: first_tick,
+            max_ticks_ahead,
+            pending: BTreeMap::new(),
+            stepped: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the tick that will be stepped next.
+    #[inline]
+    pub fn next_tick(&self) -> Tick {
+        self.next_tick
+    }
+
+    /// Records `player`'s input for `tick`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `player` isn't part of the simulation, `tick` has already been
+    /// stepped or is more than `max_ticks_ahead` after the next one, or `player` already
+    /// submitted an input for `tick`. Inputs can't be changed once submitted, since every peer
+    /// has to step with the same ones.
+    pub fn submit_input(
+        &mut self,
+        player: PlayerId,
+        tick: Tick,
+        bytes: &[u8],
+    ) -> Result<(), LockstepError> {
+        if !self.players.contains(&player) {
+            return Err(LockstepError::UnknownPlayer);
+        }
+        if tick < self.next_tick {
+            return Err(LockstepError::TickAlreadyStepped);
+        }
+        if tick - self.next_tick > self.max_ticks_ahead {
+            return Err(LockstepError::TickTooFarAhead);
+        }
+
+        let inputs = self.pending.entry(tick).or_default();
+        if inputs.contains_key(&player) {
+            return Err(LockstepError::InputAlreadySubmitted);
+        }
+        inputs.insert(player, bytes.to_vec());
+        Ok(())
+    }
+
+    /// Returns the players whose input for [`next_tick`](Self::next_tick) hasn't arrived yet.
+    pub fn blocking_players(&self) -> impl Iterator<Item = PlayerId> + '_ {
+        let inputs = self.pending.get(&self.next_tick);
+        self.players
+            .iter()
+            .copied()
+            .filter(move |player| inputs.is_none_or(|inputs| !inputs.contains_key(player)))
+    }
+
+    /// Returns the next tick if every player's input for it has arrived, so that it can be
+    /// stepped. Its inputs are available from [`inputs`](Self::inputs) until the next call.
+    ///
+    /// Returns `None` (and stays on the same tick) if some are still missing, or if there are
+    /// no players.
+    pub fn try_advance(&mut self) -> Option<Tick> {
+        if self.players.is_empty() || self.blocking_players().next().is_some() {
+            return None;
+        }
+
+        let tick = self.next_tick;
+        self.stepped = self.pending.remove(&tick).unwrap_or_default();
+        self.next_tick += 1;
+        Some(tick)
+    }
+
+    /// Returns each player's input for the tick last returned by
+    /// [`try_advance`](Self::try_advance).
+    pub fn inputs(&self) -> &BTreeMap<PlayerId, Vec<u8>> {
+        &self.stepped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: PlayerId = PlayerId(1);
+    const BOB: PlayerId = PlayerId(2);
+
+    #[test]
+    fn late_player_stalls_step() {
+        let mut lockstep = Lockstep::new([ALICE, BOB], 10, 8);
+        assert_eq!(
+            lockstep.blocking_players().collect::<Vec<_>>(),
+            [ALICE, BOB]
+        );
+
+        lockstep.submit_input(ALICE, 10, b"left").unwrap();
+        // inputs for later ticks don't help
+        lockstep.submit_input(BOB, 11, b"right").unwrap();
+        assert_eq!(lockstep.try_advance(), None);
+        assert_eq!(lockstep.try_advance(), None);
+        assert_eq!(lockstep.next_tick(), 10);
+        assert_eq!(lockstep.blocking_players().collect::<Vec<_>>(), [BOB]);
+
+        lockstep.submit_input(BOB, 10, b"jump").unwrap();
+        assert_eq!(lockstep.blocking_players().next(), None);
+        assert_eq!(lockstep.try_advance(), Some(10));
+        assert_eq!(lockstep.inputs()[&ALICE], b"left");
+        assert_eq!(lockstep.inputs()[&BOB], b"jump");
+
+        // the buffered input counts for its own tick
+        assert_eq!(lockstep.blocking_players().collect::<Vec<_>>(), [ALICE]);
+        assert_eq!(lockstep.try_advance(), None);
+    }
+
+    #[test]
+    fn complete_inputs_advance() {
+        let mut lockstep = Lockstep::new([ALICE, BOB], 0, 8);
+        for tick in 0..3 {
+            lockstep.submit_input(ALICE, tick, &[tick as u8]).unwrap();
+            lockstep
+                .submit_input(BOB, tick, &[tick as u8 + 10])
+                .unwrap();
+        }
+
+        for tick in 0..3 {
+            assert_eq!(lockstep.try_advance(), Some(tick));
+            assert_eq!(lockstep.inputs()[&ALICE], [tick as u8]);
+            assert_eq!(lockstep.inputs()[&BOB], [tick as u8 + 10]);
+        }
+        assert_eq!(lockstep.try_advance(), None);
+        assert_eq!(lockstep.next_tick(), 3);
+    }
+
+    #[test]
+    fn rejects_invalid_inputs() {
+        let mut lockstep = Lockstep::new([ALICE], 5, 8);
+        assert_eq!(
+            lockstep.submit_input(BOB, 5, b""),
+            Err(LockstepError::UnknownPlayer)
+        );
+        assert_eq!(
+            lockstep.submit_input(ALICE, 4, b""),
+            Err(LockstepError::TickAlreadyStepped)
+        );
+        lockstep.submit_input(ALICE, 13, b"").unwrap();
+        assert_eq!(
+            lockstep.submit_input(ALICE, 14, b""),
+            Err(LockstepError::TickTooFarAhead)
+        );
+
+        lockstep.submit_input(ALICE, 5, b"a").unwrap();
+        assert_eq!(
+            lockstep.submit_input(ALICE, 5, b"b"),
+            Err(LockstepError::InputAlreadySubmitted)
+        );
+        assert_eq!(lockstep.try_advance(), Some(5));
+        assert_eq!(lockstep.inputs()[&ALICE], b"a");
+        assert_eq!(
+            lockstep.submit_input(ALICE, 5, b"c"),
+            Err(LockstepError::TickAlreadyStepped)
+        );
+        // the window moves along with the next tick
+        lockstep.submit_input(ALICE, 14, b"").unwrap();
+    }
+
+    #[test]
+    fn no_players_never_advances() {
+        let mut lockstep = Lockstep::new([], 0, 8);
+        assert_eq!(lockstep.blocking_players().next(), None);
+        assert_eq!(lockstep.try_advance(), None);
+        assert_eq!(lockstep.next_tick(), 0);
+    }
+}
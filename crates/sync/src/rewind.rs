@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{Tick, Unit};
+
+/// State that can be blended between two points in time.
+pub trait Interpolate {
+    /// Returns the state `alpha` of the way from `self` (at 0) to `to` (at 1).
+    fn interpolate(&self, to: &Self, alpha: f64) -> Self;
+}
+
+impl Interpolate for f32 {
+    fn interpolate(&self, to: &Self, alpha: f64) -> Self {
+        self + (to - self) * alpha as f32
+    }
+}
+
+impl Interpolate for f64 {
+    fn interpolate(&self, to: &Self, alpha: f64) -> Self {
+        self + (to - self) * alpha
+    }
+}
+
+impl<T: Interpolate, const N: usize> Interpolate for [T; N] {
+    fn interpolate(&self, to: &Self, alpha: f64) -> Self {
+        std::array::from_fn(|i| self[i].interpolate(&to[i], alpha))
+    }
+}
+
+/// Interpolates the entries present in both maps. The others are kept as they are in `self`,
+/// e.g. an entity that was despawned by the later state.
+impl<K: Ord + Clone, V: Interpolate + Clone> Interpolate for BTreeMap<K, V> {
+    fn interpolate(&self, to: &Self, alpha: f64) -> Self {
+        self.iter()
+            .map(|(key, value)| {
+                let value = match to.get(key) {
+                    Some(to) => value.interpolate(to, alpha),
+                    None => value.clone(),
+                };
+                (key.clone(), value)
+            })
+            .collect()
+    }
+}
+
+/// Keeps the state of the last few ticks, so that the server can look at the world the way a
+/// client saw it, e.g. to check whether a shot hit.
+///
+/// See [`Rewind`](crate::Rewind). With [`NearestTick`](crate::Rewind::NearestTick), use
+/// [`rewind_to`](Self::rewind_to). With [`Exact`](crate::Rewind::Exact), use
+/// [`rewind_to_exact`](Self::rewind_to_exact) with the tick and interpolation alpha the client
+/// rendered (see [`TickClock`](crate::TickClock)).
+pub struct RewindBuffer<S> {
+    /// The state of each stored tick, oldest first.
+    history: VecDeque<(Tick, S)>,
+    capacity: usize,
+}
+
+impl<S> RewindBuffer<S> {
+    /// Constructs a new `RewindBuffer` that keeps the state of the last `capacity` ticks.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        Self {
+            history: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Constructs a new `RewindBuffer` that reaches `max_ping` back from the newest tick, at
+    /// `tick_rate` ticks per second.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tick_rate` is zero.
+    pub fn with_max_ping(max_ping: &Unit, tick_rate: usize) -> Self {
+        Self::new(max_ping.to_ticks(tick_rate) + 1)
+    }
+
+    /// Returns the number of ticks of history kept (including the newest).
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of ticks stored.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Returns `true` if no tick is stored.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.history.is_empty()
+    }
+
+    /// Returns the oldest tick stored, if there is one.
+    pub fn oldest_tick(&self) -> Option<Tick> {
+        self.history.front().map(|&(tick, _)| tick)
+    }
+
+    /// Returns the newest tick stored, if there is one.
+    pub fn newest_tick(&self) -> Option<Tick> {
+        self.history.back().map(|&(tick, _)| tick)
+    }
+
+    /// Stores the state of `tick`, and forgets the ticks that are now too old.
+    ///
+    /// Ticks can be skipped, but history is still kept for [`capacity`](Self::capacity) ticks
+    /// (not states) back from `tick`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tick` isn't after the newest tick stored.
+    pub fn push(&mut self, tick: Tick, state: S) {
+        assert!(
+            self.newest_tick().is_none_or(|newest| tick > newest),
+            "ticks must be pushed in order"
+        );
+        while self
+            .oldest_tick()
+            .is_some_and(|oldest| oldest + self.capacity as Tick <= tick)
+        {
+            self.history.pop_front();
+        }
+        self.history.push_back((tick, state));
+    }
+
+    /// Returns the state stored for exactly `tick`.
+    pub fn get(&self, tick: Tick) -> Option<&S> {
+        let index = self.history.binary_search_by_key(&tick, |&(tick, _)| tick).ok()?;
+        Some(&self.history[index].1)
+    }
+
+    /// Returns the stored tick nearest to `tick` and its state. A tie goes to the older tick.
+    ///
+    /// Returns `None` if `tick` is older than the history goes back, so that e.g. a shot from a
+    /// client with a ping over the limit can be rejected. Ticks past the newest get the newest.
+    pub fn rewind_to(&self, tick: Tick) -> Option<(Tick, &S)> {
+        if self.oldest_tick()? > tick {
+            return None;
+        }
+
+        // the first stored tick after `tick`, the one before it is at or before `tick`
+        let index = self.history.partition_point(|&(stored, _)| stored <= tick);
+        let (before, state) = &self.history[index - 1];
+        match self.history.get(index) {
+            Some((after, next)) if after - tick < tick - before => Some((*after, next)),
+            _ => Some((*before, state)),
+        }
+    }
+}
+
+impl<S: Interpolate + Clone> RewindBuffer<S> {
+    /// Returns the state `alpha` of the way from `tick` to the tick after it, interpolated
+    /// between the stored ticks on either side. If a tick was skipped, the interpolation spans
+    /// the gap.
+    ///
+    /// Returns `None` if `tick` is older than the history goes back. Instants past the newest
+    /// tick get its state as is.
+    pub fn rewind_to_exact(&self, tick: Tick, alpha: f64) -> Option<S> {
+        if self.oldest_tick()? > tick {
+            return None;
+        }
+
+        let index = self.history.partition_point(|&(stored, _)| stored <= tick);
+        let (before, from) = &self.history[index - 1];
+        let Some((after, to)) = self.history.get(index) else {
+            return Some(from.clone());
+        };
+
+        let alpha = ((tick - before) as f64 + alpha) / (after - before) as f64;
+        Some(from.interpolate(to, alpha))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::EntityId;
+
+    /// Returns a buffer with ticks 10 through 14, where every entity moves one unit along x per
+    /// tick.
+    fn moving_entities() -> RewindBuffer<BTreeMap<EntityId, [f64; 2]>> {
+        let mut buffer = RewindBuffer::new(8);
+        for tick in 10..15 {
+            let x = tick as f64;
+            buffer.push(
+                tick,
+                BTreeMap::from([(EntityId::new(0), [x, 0.0]), (EntityId::new(1), [-x, 5.0])]),
+            );
+        }
+        buffer
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut buffer = RewindBuffer::with_max_ping(&Unit::Time(Duration::from_millis(50)), 60);
+        assert_eq!(buffer.capacity(), 4);
+
+        for tick in 0..10 {
+            buffer.push(tick, tick);
+        }
+        assert_eq!(buffer.len(), 4);
+        assert_eq!(buffer.oldest_tick(), Some(6));
+        assert_eq!(buffer.newest_tick(), Some(9));
+
+        // skipped ticks still count
+        buffer.push(12, 12);
+        assert_eq!(buffer.oldest_tick(), Some(9));
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.get(9), Some(&9));
+        assert_eq!(buffer.get(10), None);
+    }
+
+    #[test]
+    fn rewind_to_nearest_tick() {
+        let mut buffer = RewindBuffer::new(16);
+        assert_eq!(buffer.rewind_to(0), None);
+        for tick in [10, 11, 12, 16] {
+            buffer.push(tick, tick * 100);
+        }
+
+        assert_eq!(buffer.rewind_to(9), None);
+        assert_eq!(buffer.rewind_to(10), Some((10, &1000)));
+        assert_eq!(buffer.rewind_to(12), Some((12, &1200)));
+        // between 12 and 16, a tie goes to the older one
+        assert_eq!(buffer.rewind_to(13), Some((12, &1200)));
+        assert_eq!(buffer.rewind_to(14), Some((12, &1200)));
+        assert_eq!(buffer.rewind_to(15), Some((16, &1600)));
+        assert_eq!(buffer.rewind_to(20), Some((16, &1600)));
+
+        let entities = moving_entities();
+        let (tick, state) = entities.rewind_to(12).unwrap();
+        assert_eq!(tick, 12);
+        assert_eq!(state[&EntityId::new(0)], [12.0, 0.0]);
+    }
+
+    #[test]
+    fn rewind_to_exact_interpolates_between_ticks() {
+        let buffer = moving_entities();
+
+        let state = buffer.rewind_to_exact(12, 0.25).unwrap();
+        assert_eq!(state[&EntityId::new(0)], [12.25, 0.0]);
+        assert_eq!(state[&EntityId::new(1)], [-12.25, 5.0]);
+
+        assert_eq!(buffer.rewind_to_exact(11, 0.0).as_ref(), buffer.get(11));
+        assert_eq!(buffer.rewind_to_exact(14, 0.5).as_ref(), buffer.get(14));
+        assert_eq!(buffer.rewind_to_exact(9, 0.5), None);
+    }
+
+    #[test]
+    fn rewind_to_exact_spans_skipped_ticks() {
+        let mut buffer = RewindBuffer::new(8);
+        buffer.push(0, 0.0);
+        buffer.push(2, 10.0);
+        buffer.push(3, 20.0);
+
+        assert_eq!(buffer.rewind_to_exact(0, 0.5), Some(2.5));
+        assert_eq!(buffer.rewind_to_exact(1, 0.0), Some(5.0));
+        assert_eq!(buffer.rewind_to_exact(1, 0.5), Some(7.5));
+        assert_eq!(buffer.rewind_to_exact(2, 0.5), Some(15.0));
+
+        // entities missing from the later tick keep their state
+        let from = BTreeMap::from([(0, 1.0f32), (1, 2.0)]);
+        let to = BTreeMap::from([(0, 3.0)]);
+        assert_eq!(from.interpolate(&to, 0.5), BTreeMap::from([(0, 2.0), (1, 2.0)]));
+    }
+}
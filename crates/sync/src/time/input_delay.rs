@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use crate::{TimeSeries, Unit};
+
+/// Returns the number of ticks a client with [`Bounded`](crate::Prediction::Bounded) prediction
+/// should delay its inputs by, so that predicting `prediction` ahead plus the delay covers its
+/// round-trip time (the mean of `rtt`, in seconds) at `tick_rate` ticks per second.
+///
+/// The delay is clamped to `[min_input_delay, max_input_delay]`, both rounded up to whole ticks.
+///
+/// # Panics
+///
+/// Panics if `tick_rate` is zero or `min_input_delay` is longer than `max_input_delay`.
+pub fn input_delay(
+    rtt: &TimeSeries,
+    tick_rate: usize,
+    prediction: &Unit,
+    min_input_delay: &Unit,
+    max_input_delay: &Unit,
+) -> usize {
+    let prediction_ticks = prediction.to_ticks(tick_rate);
+    let min_input_delay = min_input_delay.to_ticks(tick_rate);
+    let max_input_delay = max_input_delay.to_ticks(tick_rate);
+    assert!(min_input_delay <= max_input_delay);
+
+    // a partial tick still has to be covered
+    let rtt = Unit::Time(Duration::from_secs_f64(rtt.mean().max(0.0)));
+    let delay = rtt.to_ticks(tick_rate).saturating_sub(prediction_ticks);
+    delay.clamp(min_input_delay, max_input_delay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rtt_of(ms: u64) -> TimeSeries {
+        let mut rtt = TimeSeries::with_capacity(8);
+        for _ in 0..8 {
+            rtt.push(Duration::from_millis(ms).as_secs_f64());
+        }
+        rtt
+    }
+
+    fn delay_in_ticks(
+        rtt: &TimeSeries,
+        tick_rate: usize,
+        prediction: usize,
+        min: usize,
+        max: usize,
+    ) -> usize {
+        input_delay(
+            rtt,
+            tick_rate,
+            &Unit::Ticks(prediction),
+            &Unit::Ticks(min),
+            &Unit::Ticks(max),
+        )
+    }
+
+    #[test]
+    fn rtt_within_horizon_needs_no_delay() {
+        // 50ms is 3 ticks at 60Hz
+        assert_eq!(delay_in_ticks(&rtt_of(50), 60, 4, 0, 10), 0);
+        assert_eq!(delay_in_ticks(&rtt_of(50), 60, 3, 0, 10), 0);
+        assert_eq!(
+            delay_in_ticks(&TimeSeries::with_capacity(8), 60, 3, 0, 10),
+            0
+        );
+    }
+
+    #[test]
+    fn rtt_beyond_horizon_needs_delay() {
+        // 150ms is 9 ticks at 60Hz
+        assert_eq!(delay_in_ticks(&rtt_of(150), 60, 4, 0, 10), 5);
+        // 160ms is 9.6 ticks, rounded up
+        assert_eq!(delay_in_ticks(&rtt_of(160), 60, 4, 0, 10), 6);
+        assert_eq!(delay_in_ticks(&rtt_of(150), 30, 4, 0, 10), 1);
+    }
+
+    #[test]
+    fn delay_is_clamped() {
+        assert_eq!(delay_in_ticks(&rtt_of(50), 60, 4, 2, 10), 2);
+        assert_eq!(delay_in_ticks(&rtt_of(500), 60, 4, 2, 10), 10);
+    }
+
+    #[test]
+    fn bounds_can_be_given_in_time() {
+        // 50ms is 3 ticks at 60Hz and 80ms is 5, rounded up
+        let delay = input_delay(
+            &rtt_of(150),
+            60,
+            &Unit::Time(Duration::from_millis(50)),
+            &Unit::Ticks(0),
+            &Unit::Time(Duration::from_millis(80)),
+        );
+        assert_eq!(delay, 5);
+    }
+}
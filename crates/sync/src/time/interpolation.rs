@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use crate::{TimeSeries, Unit};
+
+/// Picks how far behind the latest received state a client should render, so that state
+/// updates arrive in time despite network jitter.
+///
+/// The delay is a whole number of ticks, chosen so that updates arrive before they are needed
+/// with the target probability. Higher probabilities are smoother but add latency.
+pub struct InterpolationDelay {
+    rtt: TimeSeries,
+    arrival_probability: f64,
+    min_delay: Duration,
+    max_delay: Duration,
+}
+
+impl InterpolationDelay {
+    /// Constructs a new `InterpolationDelay` that keeps `capacity` round-trip time samples.
+    ///
+    /// The recommended delay is clamped to `[min_delay, max_delay]`, e.g. the minimum input
+    /// delay and maximum ping of the `LagCompensation` config.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `arrival_probability` is not in `(0, 1)` or `min_delay > max_delay`.
+    pub fn new(
+        capacity: usize,
+        arrival_probability: f64,
+        min_delay: Duration,
+        max_delay: Duration,
+    ) -> Self {
+        assert!(
+            arrival_probability > 0.0 && arrival_probability < 1.0,
+            "arrival probability must be in (0, 1)"
+        );
+        assert!(min_delay <= max_delay);
+        Self {
+            rtt: TimeSeries::with_capacity(capacity),
+            arrival_probability,
+            min_delay,
+            max_delay,
+        }
+    }
+
+    /// Adds a round-trip time sample.
+    pub fn push(&mut self, rtt: Duration) {
+        self.rtt.push(rtt.as_secs_f64());
+    }
+
+    /// Returns the round-trip time samples.
+    #[inline]
+    pub fn rtt(&self) -> &TimeSeries {
+        &self.rtt
+    }
+
+    /// Returns the target probability that a state update arrives before it is needed.
+    #[inline]
+    pub fn arrival_probability(&self) -> f64 {
+        self.arrival_probability
+    }
+
+    /// Returns the number of ticks of state to buffer before rendering, at `tick_rate` ticks
+    /// per second.
+    ///
+    /// One tick is always buffered, since updates are spaced one tick apart. The rest covers
+    /// how late an update can be (half of the round-trip time's deviation above the mean).
+    pub fn buffer_ticks(&self, tick_rate: usize) -> usize {
+        assert!(tick_rate > 0);
+        let tick = 1.0 / tick_rate as f64;
+        let jitter = (self.rtt.inverse_cdf_from_mean(self.arrival_probability) / 2.0).max(0.0);
+        1 + (jitter / tick).ceil() as usize
+    }
+
+    /// Returns the recommended interpolation delay at `tick_rate` ticks per second.
+    pub fn recommended_delay(&self, tick_rate: usize) -> Duration {
+        let delay = Unit::Ticks(self.buffer_ticks(tick_rate)).to_duration(tick_rate);
+        delay.clamp(self.min_delay, self.max_delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delay_with_jitter(jitter_ms: u64) -> InterpolationDelay {
+        let mut delay =
+            InterpolationDelay::new(32, 0.99, Duration::ZERO, Duration::from_millis(500));
+        for i in 0..32 {
+            let rtt = if i % 2 == 0 { 100 - jitter_ms } else { 100 + jitter_ms };
+            delay.push(Duration::from_millis(rtt));
+        }
+        delay
+    }
+
+    #[test]
+    fn more_jitter_needs_more_delay() {
+        let steady = delay_with_jitter(1);
+        let jittery = delay_with_jitter(40);
+        assert!((steady.rtt().mean() - jittery.rtt().mean()).abs() < 1e-9);
+
+        let tick_rate = 60;
+        assert!(jittery.buffer_ticks(tick_rate) > steady.buffer_ticks(tick_rate));
+        assert!(jittery.recommended_delay(tick_rate) > steady.recommended_delay(tick_rate));
+    }
+
+    #[test]
+    fn recommended_delay_is_clamped() {
+        let mut delay = InterpolationDelay::new(
+            32,
+            0.99,
+            Duration::from_millis(50),
+            Duration::from_millis(200),
+        );
+        for _ in 0..32 {
+            delay.push(Duration::from_millis(100));
+        }
+        // no jitter, so only one tick is buffered
+        assert_eq!(delay.buffer_ticks(60), 1);
+        assert_eq!(delay.recommended_delay(60), Duration::from_millis(50));
+
+        for i in 0..32 {
+            delay.push(Duration::from_millis(if i % 2 == 0 { 0 } else { 1000 }));
+        }
+        assert_eq!(delay.recommended_delay(60), Duration::from_millis(200));
+    }
+}
@@ -1,8 +1,14 @@
 mod fixed_timestep;
+mod input_delay;
+mod interpolation;
 mod stats;
+mod tick_clock;
 #[allow(clippy::module_inception)]
 mod time;
 
 pub use fixed_timestep::*;
+pub use input_delay::*;
+pub use interpolation::*;
 pub use stats::*;
+pub use tick_clock::*;
 pub use time::*;
@@ -5,7 +5,9 @@ pub struct TimeSeries {
     mean: f64,
     var_sum: f64,
     variance: f64,
+    /// The position of the newest data point.
     index: usize,
+    capacity: usize,
     samples: Vec<f64>,
 }
 
@@ -17,17 +19,49 @@ impl TimeSeries {
             var_sum: 0.0,
             variance: 0.0,
             index: 0,
+            capacity,
             samples: Vec::with_capacity(capacity),
         }
     }
 
+    /// Removes all data points.
+    pub fn reset(&mut self) {
+        self.mean = 0.0;
+        self.var_sum = 0.0;
+        self.variance = 0.0;
+        self.index = 0;
+        self.samples.clear();
+    }
+
+    /// Changes the number of data points the series can hold, keeping as many of the newest ones
+    /// as fit and recalculating the mean and variance from them.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn resize(&mut self, capacity: usize) {
+        assert!(capacity > 0, "capacity must be positive");
+
+        // oldest first
+        let (newer, older) = self.samples.split_at((self.index + 1).min(self.samples.len()));
+        let samples = older.iter().chain(newer).copied().collect::<Vec<_>>();
+        let kept = &samples[samples.len().saturating_sub(capacity)..];
+
+        self.reset();
+        self.capacity = capacity;
+        self.samples = Vec::with_capacity(capacity);
+        for &value in kept {
+            self.push(value);
+        }
+    }
+
     /// Adds a new data point. If the series is at full capacity, the oldest data point is removed.
     pub fn push(&mut self, value: f64) {
         assert!(value.is_finite());
         let prev_mean = self.mean;
-        if self.samples.len() < self.samples.capacity() {
-            self.index += 1;
+        if self.samples.len() < self.capacity {
             self.samples.push(value);
+            self.index = self.samples.len() - 1;
             self.mean += (value - prev_mean) / (self.samples.len() as f64);
             self.var_sum += (value - self.mean) * (value - prev_mean);
         } else {
@@ -80,6 +114,34 @@ impl TimeSeries {
             })
     }
 
+    /// Returns the value below which `p` percent of the currently stored data points fall,
+    /// interpolating linearly between the nearest ranks. Returns `None` if there are none.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `p` is not in `[0, 100]`.
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        assert!((0.0..=100.0).contains(&p), "percentile must be in [0, 100]");
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted = self.samples.clone();
+        sorted.sort_unstable_by_key(|f| FloatOrd(*f));
+
+        let rank = (p / 100.0) * (sorted.len() - 1) as f64;
+        let lower = rank.floor() as usize;
+        let upper = rank.ceil() as usize;
+        let weight = rank - lower as f64;
+        Some(sorted[lower] + (sorted[upper] - sorted[lower]) * weight)
+    }
+
+    /// Returns the median of the currently stored data points, or `None` if there are none.
+    #[inline]
+    pub fn median(&self) -> Option<f64> {
+        self.percentile(50.0)
+    }
+
     /// Returns the mean value of the currently stored data points.
     #[inline]
     pub fn mean(&self) -> f64 {
@@ -98,19 +160,225 @@ impl TimeSeries {
         self.variance.sqrt()
     }
 
+    /// Returns the probability that a data point is less than or equal to `value`,
+    /// assuming the data points are normally distributed.
     pub fn cdf(&self, value: f64) -> f64 {
-        todo!()
+        self.cdf_from_mean(value - self.mean)
     }
 
+    /// Returns the probability that a data point exceeds the mean by no more than `value`,
+    /// assuming the data points are normally distributed.
     pub fn cdf_from_mean(&self, value: f64) -> f64 {
-        todo!()
+        let sd = self.standard_deviation();
+        if sd == 0.0 {
+            return if value >= 0.0 { 1.0 } else { 0.0 };
+        }
+
+        0.5 * (1.0 + erf(value / (sd * std::f64::consts::SQRT_2)))
     }
 
+    /// Returns the value that a data point is less than or equal to with probability `p`,
+    /// assuming the data points are normally distributed.
     pub fn inverse_cdf(&self, p: f64) -> f64 {
-        todo!()
+        self.mean + self.inverse_cdf_from_mean(p)
     }
 
+    /// Returns how far above the mean a data point is with probability `p`,
+    /// assuming the data points are normally distributed.
     pub fn inverse_cdf_from_mean(&self, p: f64) -> f64 {
-        todo!()
+        assert!(p > 0.0 && p < 1.0, "probability must be in (0, 1)");
+        self.standard_deviation() * probit(p)
+    }
+}
+
+/// Approximates the error function (Abramowitz and Stegun 7.1.26, max. error 1.5e-7).
+fn erf(x: f64) -> f64 {
+    const P: f64 = 0.3275911;
+    const A: [f64; 5] = [
+        0.254829592,
+        -0.284496736,
+        1.421413741,
+        -1.453152027,
+        1.061405429,
+    ];
+
+    let sign = x.signum();
+    let x = x.abs();
+    let t = 1.0 / (1.0 + P * x);
+    let poly = A.iter().rev().fold(0.0, |acc, a| acc * t + a) * t;
+    sign * (1.0 - poly * (-x * x).exp())
+}
+
+/// Approximates the inverse of the standard normal CDF (Acklam's algorithm, max. relative
+/// error 1.15e-9).
+fn probit(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969683028665376e+01,
+        2.209460984245205e+02,
+        -2.759285104469687e+02,
+        1.38357751867269e+02,
+        -3.066479806614716e+01,
+        2.506628277459239e+00,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e+01,
+        1.615858368580409e+02,
+        -1.556989798598866e+02,
+        6.680131188771972e+01,
+        -1.328068155288572e+01,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-03,
+        -3.223964580411365e-01,
+        -2.400758277161838e+00,
+        -2.549732539343734e+00,
+        4.374664141464968e+00,
+        2.938163982698783e+00,
+    ];
+    const D: [f64; 4] = [
+        7.784695709041462e-03,
+        3.224671290700398e-01,
+        2.445134137142996e+00,
+        3.754408661907416e+00,
+    ];
+    const P_LOW: f64 = 0.02425;
+
+    let horner = |coefficients: &[f64], x: f64| coefficients.iter().fold(0.0, |acc, c| acc * x + c);
+
+    // the tails are symmetric
+    let tail = |p: f64| {
+        let q = (-2.0 * p.ln()).sqrt();
+        horner(&C, q) / (horner(&D, q) * q + 1.0)
+    };
+
+    if p < P_LOW {
+        tail(p)
+    } else if p <= 1.0 - P_LOW {
+        let q = p - 0.5;
+        let r = q * q;
+        horner(&A, r) * q / (horner(&B, r) * r + 1.0)
+    } else {
+        -tail(1.0 - p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cdf_matches_inverse_cdf() {
+        let mut series = TimeSeries::with_capacity(16);
+        for i in 0..16 {
+            series.push(100.0 + (i % 4) as f64 * 10.0);
+        }
+
+        assert!((series.inverse_cdf(0.5) - series.mean()).abs() < 1e-9);
+        assert!((series.cdf(series.mean()) - 0.5).abs() < 1e-6);
+        for p in [0.01, 0.1, 0.5, 0.9, 0.975, 0.999] {
+            assert!((series.cdf(series.inverse_cdf(p)) - p).abs() < 1e-6);
+        }
+
+        // 97.5% of a normal distribution is within 1.96 standard deviations above the mean
+        let z = series.inverse_cdf_from_mean(0.975) / series.standard_deviation();
+        assert!((z - 1.959964).abs() < 1e-5);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut series = TimeSeries::with_capacity(16);
+        assert_eq!(series.percentile(50.0), None);
+        assert_eq!(series.median(), None);
+
+        // only the stored samples count, not the whole capacity
+        for value in [40.0, 10.0, 30.0, 20.0, 50.0] {
+            series.push(value);
+        }
+
+        assert_eq!(series.percentile(0.0), Some(10.0));
+        assert_eq!(series.percentile(50.0), Some(30.0));
+        assert_eq!(series.median(), Some(30.0));
+        assert_eq!(series.percentile(100.0), Some(50.0));
+        // rank 3.8, between 40 and 50
+        assert!((series.percentile(95.0).unwrap() - 48.0).abs() < 1e-9);
+
+        series.push(60.0);
+        assert_eq!(series.median(), Some(35.0));
+    }
+
+    #[test]
+    fn percentiles_after_wrapping() {
+        let mut series = TimeSeries::with_capacity(4);
+        for value in 0..10 {
+            series.push(value as f64);
+        }
+
+        // 6, 7, 8, 9 are left
+        assert_eq!(series.percentile(0.0), Some(6.0));
+        assert_eq!(series.median(), Some(7.5));
+        assert_eq!(series.percentile(100.0), Some(9.0));
+    }
+
+    fn assert_stats_match(series: &TimeSeries, values: &[f64]) {
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let variance = values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / (n - 1.0);
+        assert!((series.mean() - mean).abs() < 1e-9);
+        assert!((series.variance() - variance).abs() < 1e-9);
+    }
+
+    #[test]
+    fn resize_shrinks_to_newest() {
+        let mut series = TimeSeries::with_capacity(8);
+        for value in 0..11 {
+            series.push((value * value) as f64);
+        }
+
+        series.resize(3);
+        assert_eq!(series.latest(), 100.0);
+        assert_eq!(series.min(), Some(64.0));
+        assert_stats_match(&series, &[64.0, 81.0, 100.0]);
+
+        // and keeps the new capacity
+        series.push(121.0);
+        assert_eq!(series.min(), Some(81.0));
+        assert_stats_match(&series, &[81.0, 100.0, 121.0]);
+    }
+
+    #[test]
+    fn resize_grows_without_losing_samples() {
+        let mut series = TimeSeries::with_capacity(4);
+        for value in [5.0, 1.0, 4.0, 2.0, 3.0, 7.0] {
+            series.push(value);
+        }
+
+        series.resize(6);
+        assert_eq!(series.latest(), 7.0);
+        assert_stats_match(&series, &[4.0, 2.0, 3.0, 7.0]);
+
+        // the series fills up before dropping anything
+        series.push(9.0);
+        series.push(6.0);
+        assert_stats_match(&series, &[4.0, 2.0, 3.0, 7.0, 9.0, 6.0]);
+        series.push(8.0);
+        assert_eq!(series.min(), Some(2.0));
+        assert_stats_match(&series, &[2.0, 3.0, 7.0, 9.0, 6.0, 8.0]);
+    }
+
+    #[test]
+    fn reset_clears_samples() {
+        let mut series = TimeSeries::with_capacity(4);
+        for value in [1.0, 2.0, 3.0] {
+            series.push(value);
+        }
+
+        series.reset();
+        assert_eq!(series.mean(), 0.0);
+        assert_eq!(series.variance(), 0.0);
+        assert_eq!(series.median(), None);
+
+        series.push(10.0);
+        series.push(20.0);
+        assert_stats_match(&series, &[10.0, 20.0]);
     }
 }
@@ -0,0 +1,150 @@
+use std::time::{Duration, Instant};
+
+use crate::Tick;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Maps wall-clock time to the ticks of a simulation that steps `tick_rate` times per second,
+/// starting with tick 0 at [`start`](TickClock::start).
+///
+/// Amounts of time are converted to and from ticks with [`Unit`](crate::Unit), at
+/// [`tick_rate`](TickClock::tick_rate).
+///
+/// Tick `n` covers `[instant(n), instant(n + 1))`. Instants are computed from the tick number
+/// directly (instead of by adding up tick durations), so they don't drift, even when a tick
+/// isn't a whole number of nanoseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TickClock {
+    start: Instant,
+    tick_rate: usize,
+}
+
+impl TickClock {
+    /// Constructs a new `TickClock` that ticks `tick_rate` times per second, starting at `start`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `tick_rate` is zero.
+    pub fn new(tick_rate: usize, start: Instant) -> Self {
+        assert!(tick_rate > 0);
+        Self { start, tick_rate }
+    }
+
+    /// Returns the [`Instant`] tick 0 began.
+    #[inline]
+    pub fn start(&self) -> Instant {
+        self.start
+    }
+
+    /// Returns the number of ticks per second.
+    #[inline]
+    pub fn tick_rate(&self) -> usize {
+        self.tick_rate
+    }
+
+    /// Returns the nominal length of a tick (rounded down to the nanosecond).
+    #[inline]
+    pub fn tick_duration(&self) -> Duration {
+        Duration::from_secs(1) / self.tick_rate as u32
+    }
+
+    /// Returns the tick in progress at `instant`. Instants before the start are in tick 0.
+    pub fn tick(&self, instant: Instant) -> Tick {
+        (self.elapsed_ticks(instant) / NANOS_PER_SEC) as Tick
+    }
+
+    /// Returns the [`Instant`] `tick` begins.
+    pub fn instant(&self, tick: Tick) -> Instant {
+        self.start + duration_of(tick, self.tick_rate)
+    }
+
+    /// Returns how far `instant` is into its tick, from 0 (inclusive) at the start of the tick
+    /// to 1 (exclusive) at the start of the next.
+    ///
+    /// This is how far to interpolate between the states at [`tick`](Self::tick) and the tick
+    /// after it.
+    pub fn alpha(&self, instant: Instant) -> f64 {
+        (self.elapsed_ticks(instant) % NANOS_PER_SEC) as f64 / NANOS_PER_SEC as f64
+    }
+
+    /// Returns the tick in progress right now.
+    pub fn current_tick(&self) -> Tick {
+        self.tick(Instant::now())
+    }
+
+    /// Returns how far into the current tick it is right now (see [`alpha`](Self::alpha)).
+    pub fn current_alpha(&self) -> f64 {
+        self.alpha(Instant::now())
+    }
+
+    /// Returns the ticks elapsed since the start, scaled by one billion.
+    fn elapsed_ticks(&self, instant: Instant) -> u128 {
+        instant.saturating_duration_since(self.start).as_nanos() * self.tick_rate as u128
+    }
+}
+
+/// Returns the time `ticks` ticks take at `tick_rate` ticks per second.
+pub(crate) fn duration_of(ticks: Tick, tick_rate: usize) -> Duration {
+    // round up, so that the instant a tick begins is inside that tick
+    let nanos = (ticks as u128 * NANOS_PER_SEC).div_ceil(tick_rate as u128);
+    Duration::new(
+        (nanos / NANOS_PER_SEC) as u64,
+        (nanos % NANOS_PER_SEC) as u32,
+    )
+}
+
+/// Returns the fewest ticks at `tick_rate` ticks per second that take at least `duration`.
+pub(crate) fn ticks_in(duration: Duration, tick_rate: usize) -> Tick {
+    match duration.as_nanos() {
+        0 => 0,
+        nanos => ((nanos - 1) * tick_rate as u128 / NANOS_PER_SEC) as Tick + 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tick_boundaries() {
+        let start = Instant::now();
+        let clock = TickClock::new(64, start);
+        assert_eq!(clock.tick_duration(), Duration::from_micros(15625));
+
+        assert_eq!(clock.tick(start), 0);
+        assert_eq!(TickClock::new(64, start + Duration::from_secs(1)).tick(start), 0);
+        assert_eq!(clock.tick(start + Duration::from_micros(15624)), 0);
+        assert_eq!(clock.tick(start + Duration::from_micros(15625)), 1);
+        assert_eq!(clock.tick(start + Duration::from_secs(1)), 64);
+        assert_eq!(clock.instant(64), start + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn ticks_that_are_not_whole_nanoseconds_do_not_drift() {
+        let start = Instant::now();
+        let clock = TickClock::new(60, start);
+
+        for tick in [0, 1, 2, 59, 60, 61, 3599, 3600, 1_000_000] {
+            let instant = clock.instant(tick);
+            assert_eq!(clock.tick(instant), tick);
+            assert_eq!(clock.tick(instant - Duration::from_nanos(1)), tick.saturating_sub(1));
+            assert!(clock.alpha(instant) < 1e-6);
+        }
+        assert_eq!(clock.instant(60), start + Duration::from_secs(1));
+        assert_eq!(clock.instant(3600), start + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn alpha_between_ticks() {
+        let start = Instant::now();
+        let clock = TickClock::new(50, start);
+
+        let cases = [(0, 0.0), (5, 0.25), (10, 0.5), (15, 0.75), (20, 0.0), (30, 0.5)];
+        for (ms, alpha) in cases {
+            let instant = start + Duration::from_millis(ms);
+            assert!((clock.alpha(instant) - alpha).abs() < 1e-9);
+        }
+        assert!(clock.alpha(start + Duration::from_micros(19999)) < 1.0);
+        assert_eq!(clock.tick(start + Duration::from_millis(30)), 1);
+    }
+}